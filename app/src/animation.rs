@@ -0,0 +1,172 @@
+//! Windows animating instead of jumping, which is disorienting in a headset: growing and
+//! brightening when they are mapped, shrinking and darkening before they are removed, and
+//! gliding to their places when the layout is reset. xrdesktop has no window opacity, darkening
+//! stands in for it. All of them are updated by `App::animation_task`.
+use std::time::{Duration, Instant};
+
+/// Scale of a window that has faded out, relative to its own
+const MIN_SCALE: f32 = 0.05;
+
+/// How far a window is shown, from 0 (faded out) to 1 (fully shown), over time
+#[derive(Debug, Clone, Copy)]
+pub struct Fade {
+    from: f32,
+    to: f32,
+    start: Instant,
+    duration: Duration,
+}
+
+impl Fade {
+    /// Fade from `from` to `to`, starting at `start`. `duration` is for a full fade, fading only
+    /// part of the way takes that part of it, e.g. for a window removed while still fading in.
+    pub fn new(from: f32, to: f32, start: Instant, duration: Duration) -> Self {
+        Self {
+            from,
+            to,
+            start,
+            duration: duration.mul_f32((to - from).abs().min(1.0)),
+        }
+    }
+
+    pub fn value(&self, now: Instant) -> f32 {
+        let t = progress(self.start, self.duration, now);
+        self.from + (self.to - self.from) * t
+    }
+
+    pub fn target(&self) -> f32 {
+        self.to
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) >= self.duration
+    }
+}
+
+/// Scale of a window at fade `value`, relative to its own
+pub fn scale(value: f32) -> f32 {
+    MIN_SCALE + (1.0 - MIN_SCALE) * value
+}
+
+/// A window moving from one transformation to another
+#[derive(Debug, Clone, Copy)]
+pub struct Glide {
+    from: [f32; 16],
+    to: [f32; 16],
+    start: Instant,
+    duration: Duration,
+}
+
+impl Glide {
+    pub fn new(from: [f32; 16], to: [f32; 16], start: Instant, duration: Duration) -> Self {
+        Self {
+            from,
+            to,
+            start,
+            duration,
+        }
+    }
+
+    /// The transformation at `now`. Translation, rotation and scale are interpolated on their
+    /// own, the rotation along the shortest arc, so windows don't shear or shrink on the way.
+    pub fn transform(&self, now: Instant) -> graphene::Matrix {
+        let t = progress(self.start, self.duration, now);
+        graphene::Matrix::from_float(self.from)
+            .interpolate(&graphene::Matrix::from_float(self.to), t.into())
+    }
+
+    pub fn target(&self) -> graphene::Matrix {
+        graphene::Matrix::from_float(self.to)
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.start) >= self.duration
+    }
+}
+
+/// How far an animation of `duration` from `start` is at `now`, from 0 to 1, eased so it starts
+/// and ends gently
+fn progress(start: Instant, duration: Duration, now: Instant) -> f32 {
+    let elapsed = now.saturating_duration_since(start);
+    let t = if duration.is_zero() {
+        1.0
+    } else {
+        (elapsed.as_secs_f32() / duration.as_secs_f32()).min(1.0)
+    };
+    // Smoothstep
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DURATION: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn fade_in() {
+        let start = Instant::now();
+        let fade = Fade::new(0.0, 1.0, start, DURATION);
+        assert_eq!(fade.value(start), 0.0);
+        assert!((fade.value(start + DURATION / 2) - 0.5).abs() < 1e-6);
+        assert!(!fade.is_done(start + DURATION / 2));
+        assert_eq!(fade.value(start + DURATION), 1.0);
+        assert!(fade.is_done(start + DURATION));
+        // Stays there
+        assert_eq!(fade.value(start + DURATION * 2), 1.0);
+    }
+
+    #[test]
+    fn reversed_midway() {
+        let start = Instant::now();
+        let fade_in = Fade::new(0.0, 1.0, start, DURATION);
+        let now = start + DURATION / 2;
+        let fade_out = Fade::new(fade_in.value(now), 0.0, now, DURATION);
+        // Continues from where fading in got, without jumping
+        assert_eq!(fade_out.value(now), fade_in.value(now));
+        // Half the way back takes half as long
+        assert!(fade_out.is_done(now + DURATION / 2));
+        assert_eq!(fade_out.value(now + DURATION / 2), 0.0);
+    }
+
+    #[test]
+    fn without_duration() {
+        let start = Instant::now();
+        let fade = Fade::new(1.0, 0.0, start, Duration::ZERO);
+        assert!(fade.is_done(start));
+        assert_eq!(fade.value(start), 0.0);
+    }
+
+    #[test]
+    fn glide() {
+        let start = Instant::now();
+        let from = graphene::Matrix::new_translate(&graphene::Point3D::new(0.0, 1.0, -2.0));
+        let to = graphene::Matrix::new_rotate(90.0, &graphene::Vec3::y_axis()).multiply(
+            &graphene::Matrix::new_translate(&graphene::Point3D::new(2.0, 1.0, -2.0)),
+        );
+        let glide = Glide::new(from.to_float(), to.to_float(), start, DURATION);
+        let origin = graphene::Point3D::new(0.0, 0.0, 0.0);
+        let forward = graphene::Vec3::new(0.0, 0.0, -1.0);
+
+        let center = glide.transform(start).transform_point3d(&origin);
+        assert!(center.distance(&graphene::Point3D::new(0.0, 1.0, -2.0)).0 < 1e-4);
+
+        // Halfway there, turned halfway
+        let halfway = glide.transform(start + DURATION / 2);
+        let center = halfway.transform_point3d(&origin);
+        assert!(center.distance(&graphene::Point3D::new(1.0, 1.0, -2.0)).0 < 1e-4);
+        let facing = halfway.transform_vec3(&forward);
+        let expected =
+            graphene::Matrix::new_rotate(45.0, &graphene::Vec3::y_axis()).transform_vec3(&forward);
+        assert!(facing.subtract(&expected).length() < 1e-4);
+
+        assert!(glide.is_done(start + DURATION));
+        let center = glide.transform(start + DURATION).transform_point3d(&origin);
+        assert!(center.distance(&graphene::Point3D::new(2.0, 1.0, -2.0)).0 < 1e-4);
+    }
+
+    #[test]
+    fn scales() {
+        assert_eq!(scale(1.0), 1.0);
+        assert_eq!(scale(0.0), MIN_SCALE);
+    }
+}
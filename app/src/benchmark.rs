@@ -0,0 +1,149 @@
+//! `--benchmark`: time `gl::Gl::blit` on synthetic textures, through the same GL context the
+//! companion uses for windows, but without xrdesktop or a headset.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use x11rb::rust_connection::RustConnection;
+
+use crate::gl;
+
+/// Size of the synthetic textures, `<width>x<height>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl std::str::FromStr for Size {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s
+            .split_once('x')
+            .ok_or_else(|| format!("expected <width>x<height>, got {s:?}"))?;
+        let parse = |n: &str| match n.parse() {
+            Ok(0) | Err(_) => Err(format!("invalid size {s:?}")),
+            Ok(n) => Ok(n),
+        };
+        Ok(Self {
+            width: parse(width)?,
+            height: parse(height)?,
+        })
+    }
+}
+
+/// Blit a `size` texture into another one `iterations` times, and print the throughput and
+/// latency.
+pub async fn run(size: Size, iterations: u32) -> Result<()> {
+    let (x11, screen) = RustConnection::connect(None)?;
+    let gl = gl::Gl::new(Arc::new(x11), screen as u32, Default::default()).await?;
+    let Size { width, height } = size;
+    // A gradient, so the driver can't take shortcuts for uniform textures. Counted in u64, big
+    // sizes overflow u32.
+    let pixels = u64::from(width) * u64::from(height);
+    let data: Vec<u8> = (0..pixels)
+        .flat_map(|i| {
+            let (x, y) = (i % u64::from(width), i / u64::from(width));
+            [(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, 255]
+        })
+        .collect();
+    let src = gl.upload_texture(width, height, data).await?;
+    let dst = gl
+        .upload_texture(width, height, vec![0; pixels as usize * 4])
+        .await?;
+
+    // Warm up, the first blits compile shaders and allocate buffers
+    for _ in 0..iterations.min(10) {
+        gl.blit(
+            &src,
+            &dst,
+            None,
+            None,
+            None,
+            None,
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+    }
+    let mut latencies = Vec::with_capacity(iterations as usize);
+    let start = Instant::now();
+    for _ in 0..iterations {
+        let blit_start = Instant::now();
+        gl.blit(
+            &src,
+            &dst,
+            None,
+            None,
+            None,
+            None,
+            Default::default(),
+            Default::default(),
+        )
+        .await?;
+        latencies.push(blit_start.elapsed());
+    }
+    let total = start.elapsed();
+
+    gl.release_texture(src).await?;
+    gl.release_texture(dst).await?;
+    println!("{}", report(size, &mut latencies, total));
+    Ok(())
+}
+
+fn report(size: Size, latencies: &mut [Duration], total: Duration) -> String {
+    if latencies.is_empty() {
+        return "No blits".into();
+    }
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    let pixels = size.width as f64 * size.height as f64 * latencies.len() as f64;
+    format!(
+        "{} blits of {}x{}: {:.1} megapixels/s\n\
+         latency: min {:?}, median {:?}, p99 {:?}, max {:?}",
+        latencies.len(),
+        size.width,
+        size.height,
+        pixels / total.as_secs_f64() / 1e6,
+        latencies[0],
+        percentile(50),
+        percentile(99),
+        latencies[latencies.len() - 1],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(
+            "1920x1080".parse(),
+            Ok(Size {
+                width: 1920,
+                height: 1080
+            })
+        );
+        assert!("1920".parse::<Size>().is_err());
+        assert!("0x1080".parse::<Size>().is_err());
+        assert!("1920x-1".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn reports() {
+        let size = Size {
+            width: 1000,
+            height: 1000,
+        };
+        let ms = Duration::from_millis;
+        assert_eq!(
+            report(size, &mut [ms(3), ms(1), ms(2)], ms(3)),
+            "3 blits of 1000x1000: 1000.0 megapixels/s\n\
+             latency: min 1ms, median 2ms, p99 2ms, max 3ms"
+        );
+        assert_eq!(report(size, &mut [], ms(0)), "No blits");
+    }
+}
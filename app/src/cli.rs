@@ -0,0 +1,165 @@
+//! Command line options. They take precedence over the config file.
+use crate::{benchmark, gl, source, synth};
+
+pub const USAGE: &str = "\
+Usage: picom-xrdesktop-companion [OPTIONS]
+
+Options:
+    --input-backend <xdo|xtest>  How pointer input is synthesized
+    --backend <picom|x11|auto>   Find windows through picom's D-Bus interface, the X server, or
+                                 picom if it is running and the X server otherwise
+    --force-format <srgba8|rgba8|rgb10a2>
+                                 Share windows with VR in this texture format, instead of the
+                                 best one the drivers support
+    --mode <windows|fullscreen-mirror|monitors>
+                                 Mirror each window on its own, the whole screen as one
+                                 window, or each monitor as one window
+    --screen <N>                 Only mirror windows of this X screen, instead of all of them
+    --replace                    Replace the companion already running on this display
+    --no-hmd                     Keep running without a headset, tracking windows without
+                                 sending them to VR
+    --trace-timing <MS>          Log render pipeline steps that take longer than this
+    --benchmark <WIDTHxHEIGHT>   Time blitting a texture of this size, then exit
+    --iterations <N>             How many blits --benchmark times [default: 1000]
+    --dry-run                    Print which windows would be mirrored and why, then exit
+    -h, --help                   Print this help
+";
+
+/// What is mirrored, see `--mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Windows,
+    FullscreenMirror,
+    Monitors,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "windows" => Ok(Self::Windows),
+            "fullscreen-mirror" => Ok(Self::FullscreenMirror),
+            "monitors" => Ok(Self::Monitors),
+            _ => Err(format!(
+                "unknown mode {s:?}, expected windows, fullscreen-mirror or monitors"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Args {
+    pub help: bool,
+    pub input_backend: Option<synth::Backend>,
+    pub backend: Option<source::Backend>,
+    pub force_format: Option<gl::SharedFormat>,
+    pub mode: Option<Mode>,
+    pub screen: Option<usize>,
+    pub replace: bool,
+    pub no_hmd: bool,
+    /// Log spans that take longer than this
+    pub trace_timing: Option<std::time::Duration>,
+    pub benchmark: Option<benchmark::Size>,
+    pub iterations: Option<u32>,
+    pub dry_run: bool,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("unknown option {0}")]
+    Unknown(String),
+    #[error("{0} needs a value")]
+    MissingValue(String),
+    #[error("invalid value for {option}: {reason}")]
+    InvalidValue { option: String, reason: String },
+}
+
+impl Args {
+    /// Parse the arguments, without the program name. Options with values are accepted both as
+    /// `--option value` and `--option=value`.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Error> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (option, inline_value) = match arg.split_once('=') {
+                Some((option, value)) => (option.to_owned(), Some(value.to_owned())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline_value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| Error::MissingValue(option.clone()))
+            };
+            match option.as_str() {
+                "-h" | "--help" => parsed.help = true,
+                "--replace" => parsed.replace = true,
+                "--no-hmd" => parsed.no_hmd = true,
+                "--dry-run" => parsed.dry_run = true,
+                "--input-backend" => {
+                    parsed.input_backend =
+                        Some(value()?.parse().map_err(|reason| Error::InvalidValue {
+                            option: option.clone(),
+                            reason,
+                        })?)
+                }
+                "--backend" => {
+                    parsed.backend =
+                        Some(value()?.parse().map_err(|reason| Error::InvalidValue {
+                            option: option.clone(),
+                            reason,
+                        })?)
+                }
+                "--mode" => {
+                    parsed.mode = Some(value()?.parse().map_err(|reason| Error::InvalidValue {
+                        option: option.clone(),
+                        reason,
+                    })?)
+                }
+                "--force-format" => {
+                    parsed.force_format =
+                        Some(value()?.parse().map_err(|reason| Error::InvalidValue {
+                            option: option.clone(),
+                            reason,
+                        })?)
+                }
+                "--screen" => {
+                    parsed.screen =
+                        Some(value()?.parse().map_err(|e: std::num::ParseIntError| {
+                            Error::InvalidValue {
+                                option: option.clone(),
+                                reason: e.to_string(),
+                            }
+                        })?)
+                }
+                "--benchmark" => {
+                    parsed.benchmark =
+                        Some(value()?.parse().map_err(|reason| Error::InvalidValue {
+                            option: option.clone(),
+                            reason,
+                        })?)
+                }
+                "--iterations" => {
+                    parsed.iterations =
+                        Some(value()?.parse().map_err(|e: std::num::ParseIntError| {
+                            Error::InvalidValue {
+                                option: option.clone(),
+                                reason: e.to_string(),
+                            }
+                        })?)
+                }
+                "--trace-timing" => {
+                    let ms: u64 = value()?.parse().map_err(|e: std::num::ParseIntError| {
+                        Error::InvalidValue {
+                            option: option.clone(),
+                            reason: e.to_string(),
+                        }
+                    })?;
+                    parsed.trace_timing = Some(std::time::Duration::from_millis(ms));
+                }
+                _ => return Err(Error::Unknown(option)),
+            }
+        }
+        Ok(parsed)
+    }
+}
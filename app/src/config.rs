@@ -0,0 +1,1093 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+use x11rb::protocol::damage;
+
+use crate::{
+    gl::{Border, Filtering, SharedFormat},
+    input::ControllerButton,
+    picom,
+    placement::{FitMode, Preset},
+    source, synth, throttle, Result,
+};
+
+/// Scroll wheel synthesis settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ScrollConfig {
+    /// Accumulated scroll amount needed to produce one wheel detent
+    pub threshold: f64,
+    /// Invert the scroll direction on both axes
+    pub invert: bool,
+}
+
+impl Default for ScrollConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            invert: false,
+        }
+    }
+}
+
+impl ScrollConfig {
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.threshold.is_finite() && self.threshold > 0.0,
+            "threshold has to be a positive number, not {}",
+            self.threshold
+        );
+        Ok(())
+    }
+}
+
+/// Multi-click recognition settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DoubleClickConfig {
+    /// Maximum time between two presses of a double click, in milliseconds
+    pub interval: u64,
+    /// Maximum distance between two presses of a double click, in pixels
+    pub tolerance: f32,
+}
+
+impl Default for DoubleClickConfig {
+    fn default() -> Self {
+        Self {
+            interval: 500,
+            tolerance: 8.0,
+        }
+    }
+}
+
+impl DoubleClickConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+}
+
+/// Where windows are first placed in VR
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LayoutConfig {
+    /// Distance of the first window, in meters
+    pub depth: f32,
+    /// How much closer each further window is, in meters
+    pub z_spacing: f32,
+    /// Windows on a wall or on a desk, can be switched through D-Bus
+    pub preset: Preset,
+    /// Place windows again when the screen is resized or monitors are added or removed,
+    /// forgetting where they were moved in VR
+    pub relayout_on_screen_change: bool,
+    /// How long windows take to glide to their places when the layout is reset or redone, in
+    /// milliseconds, 0 to jump there
+    pub glide: u64,
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        Self {
+            depth: 8.0,
+            z_spacing: 0.05,
+            preset: Default::default(),
+            relayout_on_screen_change: true,
+            glide: 300,
+        }
+    }
+}
+
+impl LayoutConfig {
+    pub fn glide(&self) -> Duration {
+        Duration::from_millis(self.glide)
+    }
+
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.depth.is_finite() && self.depth > 0.0,
+            "depth has to be a positive number, not {}",
+            self.depth
+        );
+        anyhow::ensure!(
+            self.z_spacing.is_finite() && self.z_spacing > 0.0,
+            "z-spacing has to be a positive number, not {}",
+            self.z_spacing
+        );
+        Ok(())
+    }
+}
+
+/// Fullscreen windows moved to a large screen in front of the user, and back when they leave
+/// fullscreen
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct TheaterConfig {
+    pub enabled: bool,
+    /// Width of the screen, in meters
+    pub width: f32,
+    /// Distance of the screen, in meters
+    pub depth: f32,
+}
+
+impl Default for TheaterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            width: 6.0,
+            depth: 5.0,
+        }
+    }
+}
+
+/// An sRGB color, written as `#rrggbb` or `#rrggbbaa`
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Color(pub [f32; 4]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || format!("expected a color like \"#rrggbb\" or \"#rrggbbaa\", got {s:?}");
+        let hex = s.strip_prefix('#').ok_or_else(invalid)?;
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut color = [1.0; 4];
+        for (i, channel) in color.iter_mut().take(hex.len() / 2).enumerate() {
+            let value = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+            *channel = value as f32 / 255.0;
+        }
+        Ok(Self(color))
+    }
+}
+
+/// A frame drawn around windows in VR, so windows without decorations stand out
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BorderConfig {
+    pub enabled: bool,
+    /// In pixels of the window
+    pub width: u32,
+    pub color: Color,
+}
+
+impl Default for BorderConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            width: 3,
+            color: Color([0.24, 0.68, 0.91, 1.0]),
+        }
+    }
+}
+
+impl BorderConfig {
+    pub fn to_gl(&self) -> Option<Border> {
+        (self.enabled && self.width > 0).then(|| Border {
+            width: self.width,
+            color: self.color.0,
+        })
+    }
+}
+
+/// A frame drawn around the window with keyboard focus instead of `BorderConfig`'s, so it is
+/// clear where typing goes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FocusHighlightConfig {
+    pub enabled: bool,
+    /// In pixels of the window
+    pub width: u32,
+    pub color: Color,
+}
+
+impl Default for FocusHighlightConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            width: 4,
+            color: Color([0.96, 0.76, 0.26, 1.0]),
+        }
+    }
+}
+
+impl FocusHighlightConfig {
+    pub fn to_gl(&self) -> Option<Border> {
+        (self.enabled && self.width > 0).then(|| Border {
+            width: self.width,
+            color: self.color.0,
+        })
+    }
+}
+
+/// Window titles shown above the windows in VR, see `labels`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LabelsConfig {
+    pub enabled: bool,
+    /// Longer titles are cut off with an ellipsis
+    pub max_chars: usize,
+    /// In meters
+    pub height: f32,
+    /// Space between a window and its label, in meters
+    pub gap: f32,
+}
+
+impl Default for LabelsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_chars: 40,
+            height: 0.12,
+            gap: 0.03,
+        }
+    }
+}
+
+/// A cap on how many windows are mirrored at once, see `priority`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct WindowLimitConfig {
+    /// Windows mirrored at most, besides pinned ones, 0 for no limit
+    pub max_windows: usize,
+    /// How often windows are ranked again, in milliseconds. Focus changes rank them right away.
+    pub interval: u64,
+}
+
+impl Default for WindowLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_windows: 24,
+            interval: 1000,
+        }
+    }
+}
+
+impl WindowLimitConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.interval > 0,
+            "interval has to be at least 1 millisecond"
+        );
+        Ok(())
+    }
+}
+
+/// Rendering windows that draw too often less often, see `throttle`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ThrottleConfig {
+    pub enabled: bool,
+    /// Damage events per second above which a window is throttled
+    pub threshold: u32,
+    /// How often throttled windows are rendered, in Hz
+    pub rate: u32,
+    /// Seconds between log messages about a window that is still throttled
+    pub log_interval: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            threshold: 90,
+            rate: 30,
+            log_interval: 10,
+        }
+    }
+}
+
+impl ThrottleConfig {
+    /// None if throttling is disabled
+    pub fn limits(&self) -> Option<throttle::Limits> {
+        (self.enabled && self.rate > 0).then(|| throttle::Limits {
+            threshold: self.threshold,
+            interval: Duration::from_secs(1) / self.rate,
+            report_interval: Duration::from_secs(self.log_interval),
+        })
+    }
+}
+
+/// Dimming windows of applications that don't answer `_NET_WM_PING`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct UnresponsiveConfig {
+    pub enabled: bool,
+    /// Seconds without drawing anything before a window is pinged
+    pub idle: u64,
+    /// Seconds to answer before a window is dimmed
+    pub timeout: u64,
+    /// Brightness of unresponsive windows, 1.0 doesn't dim them at all
+    pub brightness: f32,
+}
+
+impl Default for UnresponsiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            idle: 5,
+            timeout: 5,
+            brightness: 0.4,
+        }
+    }
+}
+
+/// Windows growing in when they are mapped and shrinking away when they are removed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FadeConfig {
+    pub enabled: bool,
+    /// How long a window takes to fade in or out, in milliseconds
+    pub duration: u64,
+}
+
+impl Default for FadeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            duration: 200,
+        }
+    }
+}
+
+impl FadeConfig {
+    pub fn duration(&self) -> Duration {
+        Duration::from_millis(self.duration)
+    }
+}
+
+/// Windows that are not mirrored into VR
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct IgnoreConfig {
+    /// Matched exactly against both the instance and the class part of `WM_CLASS`
+    pub classes: Vec<String>,
+    /// Windows whose name contains any of these are ignored
+    pub names: Vec<String>,
+    /// Mirrored even though they ask to be left out of taskbars and pagers, which otherwise
+    /// keeps them out of VR. Matched like `classes`.
+    pub include_classes: Vec<String>,
+    /// Classes of VR runtime windows, on top of the known ones, see `vr_windows::CLASSES`.
+    /// These are never mirrored, `include_classes` doesn't apply.
+    pub vr_classes: Vec<String>,
+}
+
+impl IgnoreConfig {
+    pub fn matches(&self, name: &str, class_instance: &str, class_general: &str) -> bool {
+        self.classes
+            .iter()
+            .any(|c| c == class_instance || c == class_general)
+            || self.names.iter().any(|n| name.contains(n.as_str()))
+    }
+
+    pub fn includes(&self, class_instance: &str, class_general: &str) -> bool {
+        self.include_classes
+            .iter()
+            .any(|c| c == class_instance || c == class_general)
+    }
+}
+
+/// Size of windows in VR
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct WindowSizeConfig {
+    /// How windows are fit into a size from `classes`
+    pub fit: FitMode,
+    /// Width and height in meters of windows, by instance or class part of `WM_CLASS`. Other
+    /// windows are sized by their pixel size.
+    pub classes: HashMap<String, (f32, f32)>,
+}
+
+impl WindowSizeConfig {
+    pub fn get(&self, class_instance: &str, class_general: &str) -> Option<(f32, f32)> {
+        self.classes
+            .get(class_instance)
+            .or_else(|| self.classes.get(class_general))
+            .copied()
+    }
+}
+
+/// Mirroring the whole screen as one VR window, instead of each window on its own
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DesktopMirrorConfig {
+    pub enabled: bool,
+    /// How often the screen is copied, per second
+    pub refresh_rate: u32,
+    /// Also copy the screen when something draws on the root window, e.g. screen-wide effects,
+    /// instead of waiting for the next refresh
+    pub root_damage: bool,
+    /// One VR window per monitor, arranged like the monitors are, instead of one for the whole
+    /// screen
+    pub per_monitor: bool,
+}
+
+impl Default for DesktopMirrorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            refresh_rate: 30,
+            root_damage: false,
+            per_monitor: false,
+        }
+    }
+}
+
+impl DesktopMirrorConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1) / self.refresh_rate.max(1)
+    }
+}
+
+/// Auto-repeat of keys held on the VR keyboard
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KeyRepeatConfig {
+    pub enabled: bool,
+    /// Time before a held key starts repeating, in milliseconds. Defaults to the X server's.
+    pub delay: Option<u64>,
+    /// Repeats per second. Defaults to the X server's.
+    pub rate: Option<u32>,
+}
+
+impl Default for KeyRepeatConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            delay: None,
+            rate: None,
+        }
+    }
+}
+
+/// Controller vibration when the pointer moves onto a window and when clicking, see `haptics`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct HapticsConfig {
+    pub enabled: bool,
+    /// Pulse when the pointer moves onto a window, in milliseconds, 0 for none
+    pub hover_duration: u64,
+    /// Its strength, from 0 to 1
+    pub hover_amplitude: f32,
+    /// Pulse when a button is pressed on a window, in milliseconds, 0 for none
+    pub click_duration: u64,
+    pub click_amplitude: f32,
+    /// Vibration frequency, in Hz
+    pub frequency: f32,
+    /// At most one hover pulse per controller in this many milliseconds, so the pointer going
+    /// back and forth over the edge of a window doesn't buzz all the time
+    pub min_interval: u64,
+}
+
+impl Default for HapticsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hover_duration: 10,
+            hover_amplitude: 0.2,
+            click_duration: 25,
+            click_amplitude: 0.6,
+            frequency: 160.0,
+            min_interval: 150,
+        }
+    }
+}
+
+impl HapticsConfig {
+    pub fn hover_duration(&self) -> Duration {
+        Duration::from_millis(self.hover_duration)
+    }
+
+    pub fn click_duration(&self) -> Duration {
+        Duration::from_millis(self.click_duration)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_millis(self.min_interval)
+    }
+}
+
+/// Focus-follows-hover settings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct HoverFocusConfig {
+    /// Activate windows the pointer rests on, without clicking
+    pub enabled: bool,
+    /// How long the pointer has to stay on a window before it is activated, in milliseconds
+    pub delay: u64,
+}
+
+impl Default for HoverFocusConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delay: 300,
+        }
+    }
+}
+
+impl HoverFocusConfig {
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.delay)
+    }
+}
+
+/// Hiding all windows and showing them again by holding a controller button, see the
+/// `SetMirroring` D-Bus method
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ToggleMirroringConfig {
+    /// Button to hold, None to only toggle through D-Bus
+    pub button: Option<ControllerButton>,
+    /// How long it has to be held, in milliseconds
+    pub hold: u64,
+}
+
+impl Default for ToggleMirroringConfig {
+    fn default() -> Self {
+        Self {
+            button: None,
+            hold: 1000,
+        }
+    }
+}
+
+impl ToggleMirroringConfig {
+    pub fn hold(&self) -> Duration {
+        Duration::from_millis(self.hold)
+    }
+}
+
+/// Showing only the active window, large in front of the user
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct FocusModeConfig {
+    /// Hotkey toggling focus mode, like `reset-layout-key`. Empty to only toggle it through D-Bus
+    pub key: String,
+    /// How long focus has to stay on a window before it is shown, in milliseconds, so
+    /// alt-tabbing through windows doesn't flash each of them
+    pub debounce: u64,
+    /// Distance of the active window, in meters
+    pub depth: f32,
+    /// Width of the active window, in meters
+    pub width: f32,
+}
+
+impl Default for FocusModeConfig {
+    fn default() -> Self {
+        Self {
+            key: "Super+Shift+F".into(),
+            debounce: 300,
+            depth: 3.0,
+            width: 3.0,
+        }
+    }
+}
+
+impl FocusModeConfig {
+    pub fn debounce(&self) -> Duration {
+        Duration::from_millis(self.debounce)
+    }
+}
+
+/// Windows pinned to the head, following it like a HUD, see the `SetPinned` D-Bus method
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct PinConfig {
+    /// Button pinning or unpinning the window it is pressed on, None to only pin through D-Bus.
+    /// Its presses aren't passed on to windows then.
+    pub button: Option<ControllerButton>,
+    /// Where windows are pinned, in meters right, up and backwards from the head. Moving a
+    /// pinned window in VR pins it where it is left.
+    pub offset: [f32; 3],
+    /// Scale of pinned windows
+    pub scale: f32,
+    /// How often pinned windows are moved after the head, in milliseconds
+    pub interval: u64,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            button: None,
+            offset: [0.3, -0.2, -1.0],
+            scale: 0.5,
+            interval: 11,
+        }
+    }
+}
+
+impl PinConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.interval > 0,
+            "interval has to be at least 1 millisecond"
+        );
+        Ok(())
+    }
+}
+
+/// Windows far away, or scaled down, show more pixels than the headset has for them, and alias.
+/// Their textures are shared at a lower resolution, filtered down from the full window.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct DistantWindowsConfig {
+    /// Off by default, windows are shared at their full resolution
+    pub enabled: bool,
+    /// Resolution of the headset's displays, roughly
+    pub pixels_per_degree: f32,
+    /// Each level halves the resolution of the texture
+    pub max_level: u32,
+    /// How often the distance of windows is checked, in milliseconds
+    pub interval: u64,
+}
+
+impl Default for DistantWindowsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            pixels_per_degree: 20.0,
+            max_level: 3,
+            interval: 500,
+        }
+    }
+}
+
+impl DistantWindowsConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval)
+    }
+
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.interval > 0,
+            "interval has to be at least 1 millisecond"
+        );
+        Ok(())
+    }
+}
+
+/// Resizing windows in VR: holding a button on a window, the stick scales it instead of
+/// scrolling. Scales are kept across restarts, see `window_settings`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct ScaleGestureConfig {
+    /// Button to hold, None to not scale windows this way. Its presses aren't passed on to
+    /// windows then.
+    pub button: Option<ControllerButton>,
+    /// Factor a window grows or shrinks by per wheel detent of the stick
+    pub step: f32,
+    /// Smallest and largest scale
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for ScaleGestureConfig {
+    fn default() -> Self {
+        Self {
+            button: None,
+            step: 1.1,
+            min: 0.25,
+            max: 10.0,
+        }
+    }
+}
+
+impl ScaleGestureConfig {
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.step.is_finite() && self.step > 0.0,
+            "step has to be a positive number, not {}",
+            self.step
+        );
+        anyhow::ensure!(
+            self.min.is_finite() && self.max.is_finite() && self.min > 0.0 && self.min <= self.max,
+            "min and max have to be positive numbers, min no larger than max, not {} and {}",
+            self.min,
+            self.max
+        );
+        Ok(())
+    }
+}
+
+/// Dimming windows in VR like `[scale-gesture]`: holding a button on a window, the stick changes
+/// its brightness instead of scrolling.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct BrightnessGestureConfig {
+    /// Button to hold, None to only change brightness through D-Bus
+    pub button: Option<ControllerButton>,
+    /// Brightness change per wheel detent of the stick
+    pub step: f32,
+    /// Lowest and highest brightness, 1.0 is unchanged
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for BrightnessGestureConfig {
+    fn default() -> Self {
+        Self {
+            button: None,
+            step: 0.05,
+            min: 0.1,
+            max: 1.0,
+        }
+    }
+}
+
+impl BrightnessGestureConfig {
+    fn validate(&self) -> Result<()> {
+        anyhow::ensure!(
+            self.step.is_finite() && self.step > 0.0,
+            "step has to be a positive number, not {}",
+            self.step
+        );
+        anyhow::ensure!(
+            self.min.is_finite() && self.max.is_finite() && self.min >= 0.0 && self.min <= self.max,
+            "min and max have to be numbers from 0, min no larger than max, not {} and {}",
+            self.min,
+            self.max
+        );
+        Ok(())
+    }
+}
+
+/// Tinting all windows warmer, see `night_light`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct NightLightConfig {
+    /// Color temperature in Kelvin, 6500 leaves colors as they are
+    pub temperature: u32,
+    /// Follow GNOME's night light instead of `temperature`
+    pub follow_gnome: bool,
+}
+
+impl Default for NightLightConfig {
+    fn default() -> Self {
+        Self {
+            temperature: crate::night_light::NEUTRAL,
+            follow_gnome: false,
+        }
+    }
+}
+
+fn default_buttons() -> HashMap<ControllerButton, u8> {
+    [
+        (ControllerButton::Left, 1),
+        (ControllerButton::Middle, 2),
+        (ControllerButton::Right, 3),
+    ]
+    .into_iter()
+    .collect()
+}
+
+/// How X reports damage to windows, see the DAMAGE extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DamageReportLevel {
+    /// Every damaged rectangle, as it happens
+    RawRectangles,
+    /// Rectangles added to the damage since we last cleared it
+    DeltaRectangles,
+    /// When the bounding box of the damage grows
+    BoundingBox,
+    /// Only when the window goes from undamaged to damaged
+    NonEmpty,
+}
+
+impl DamageReportLevel {
+    pub fn to_x11(self) -> damage::ReportLevel {
+        match self {
+            Self::RawRectangles => damage::ReportLevel::RAW_RECTANGLES,
+            Self::DeltaRectangles => damage::ReportLevel::DELTA_RECTANGLES,
+            Self::BoundingBox => damage::ReportLevel::BOUNDING_BOX,
+            Self::NonEmpty => damage::ReportLevel::NON_EMPTY,
+        }
+    }
+}
+
+/// Configuration file, read from `$XDG_CONFIG_HOME/picom-xrdesktop-companion/config.toml`.
+/// Everything is optional, missing values take their defaults.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Config {
+    pub scroll: ScrollConfig,
+    pub double_click: DoubleClickConfig,
+    pub ignore: IgnoreConfig,
+    pub hover_focus: HoverFocusConfig,
+    pub toggle_mirroring: ToggleMirroringConfig,
+    pub focus_mode: FocusModeConfig,
+    pub pin: PinConfig,
+    pub distant_windows: DistantWindowsConfig,
+    pub scale_gesture: ScaleGestureConfig,
+    pub brightness_gesture: BrightnessGestureConfig,
+    pub night_light: NightLightConfig,
+    pub window_size: WindowSizeConfig,
+    pub layout: LayoutConfig,
+    pub theater: TheaterConfig,
+    pub border: BorderConfig,
+    pub focus_highlight: FocusHighlightConfig,
+    pub labels: LabelsConfig,
+    pub window_limit: WindowLimitConfig,
+    pub throttle: ThrottleConfig,
+    pub unresponsive: UnresponsiveConfig,
+    pub fade: FadeConfig,
+    pub desktop_mirror: DesktopMirrorConfig,
+    pub key_repeat: KeyRepeatConfig,
+    pub haptics: HapticsConfig,
+    /// Which X button each controller button clicks. Controller buttons not in this table are
+    /// ignored.
+    pub buttons: HashMap<ControllerButton, u8>,
+    /// Draw the application icon over the top left corner of mirrored windows
+    pub window_icons: bool,
+    /// Ask the window manager to focus windows clicked in VR, so keyboard input goes to them
+    pub activate_on_click: bool,
+    /// Stop updating windows while nobody is wearing the headset
+    pub suspend_when_absent: bool,
+    /// Don't pass VR input on to the desktop while nobody is wearing the headset
+    pub block_input_when_absent: bool,
+    /// Show a button in VR that closes the window last clicked, off by default
+    pub close_button: bool,
+    /// Only show windows of the current virtual desktop, and windows on all desktops
+    pub current_desktop_only: bool,
+    /// Share all windows with xrdesktop in this format, instead of the best one that works
+    pub force_format: Option<SharedFormat>,
+    /// Only mirror windows of this X screen, instead of all screens of the display
+    pub screen: Option<usize>,
+    /// How we sample textures we draw scaled, icons and distant windows. Not how xrdesktop samples
+    /// windows in VR.
+    pub filtering: Filtering,
+    /// Filtering for windows by instance or class part of `WM_CLASS`, instead of `filtering`
+    pub window_filtering: HashMap<String, Filtering>,
+    /// How pointer input is synthesized, the other backend is used if this one doesn't work
+    pub input_backend: synth::Backend,
+    /// How X reports damage, picked by `damage_report_level()` if unset
+    pub damage_report_level: Option<DamageReportLevel>,
+    /// Only blit the damaged part of windows, instead of all of them
+    pub partial_blit: bool,
+    /// Key combination grabbed on the desktop that resets the layout in VR, like
+    /// `Super+Shift+R`. Empty to not grab any key.
+    pub reset_layout_key: String,
+    /// Log a summary of the render statistics every this many seconds, 0 to not log them
+    pub stats_interval: u64,
+    /// Rebuild the textures of windows that were damaged but not rendered for this many seconds,
+    /// 0 to never rebuild them
+    pub render_stall_timeout: u64,
+    /// How many seconds to wait for the VR runtime at startup, 0 to wait forever
+    pub vr_runtime_timeout: u64,
+    /// Wait for the VR runtime to come back when it shuts down, instead of exiting
+    pub reconnect_runtime: bool,
+    /// Where the windows to mirror come from
+    pub backend: source::Backend,
+    /// picom's D-Bus name, `{display}` is replaced by the display, like `_0` for `:0`
+    pub picom_service: String,
+    /// How many times to look for picom's windows at startup before giving up, in case picom
+    /// isn't up yet
+    pub picom_retries: u32,
+    /// How many seconds to wait for picom's D-Bus service at startup, 0 to wait forever
+    pub picom_timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scroll: Default::default(),
+            double_click: Default::default(),
+            ignore: Default::default(),
+            window_size: Default::default(),
+            layout: Default::default(),
+            theater: Default::default(),
+            border: Default::default(),
+            focus_highlight: Default::default(),
+            labels: Default::default(),
+            window_limit: Default::default(),
+            throttle: Default::default(),
+            unresponsive: Default::default(),
+            fade: Default::default(),
+            desktop_mirror: Default::default(),
+            key_repeat: Default::default(),
+            haptics: Default::default(),
+            hover_focus: Default::default(),
+            toggle_mirroring: Default::default(),
+            focus_mode: Default::default(),
+            pin: Default::default(),
+            distant_windows: Default::default(),
+            scale_gesture: Default::default(),
+            brightness_gesture: Default::default(),
+            night_light: Default::default(),
+            buttons: default_buttons(),
+            window_icons: false,
+            activate_on_click: true,
+            suspend_when_absent: true,
+            block_input_when_absent: true,
+            close_button: false,
+            current_desktop_only: false,
+            force_format: None,
+            screen: None,
+            filtering: Default::default(),
+            window_filtering: Default::default(),
+            input_backend: Default::default(),
+            damage_report_level: None,
+            partial_blit: false,
+            reset_layout_key: "Super+Shift+R".into(),
+            stats_interval: 0,
+            render_stall_timeout: 10,
+            vr_runtime_timeout: 0,
+            reconnect_runtime: true,
+            backend: Default::default(),
+            picom_service: picom::DEFAULT_SERVICE_TEMPLATE.into(),
+            picom_retries: 5,
+            picom_timeout: 0,
+        }
+    }
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        glib::user_config_dir()
+            .join("picom-xrdesktop-companion")
+            .join("config.toml")
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path();
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("No config file at {}, using defaults", path.display());
+                return Ok(Default::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let config: Self = toml::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        config
+            .validate()
+            .with_context(|| format!("invalid settings in {}", path.display()))?;
+        Ok(config)
+    }
+
+    /// Catch values that would only fail, or panic, once they are used
+    fn validate(&self) -> Result<()> {
+        self.scroll.validate().context("in [scroll]")?;
+        self.layout.validate().context("in [layout]")?;
+        self.scale_gesture
+            .validate()
+            .context("in [scale-gesture]")?;
+        self.brightness_gesture
+            .validate()
+            .context("in [brightness-gesture]")?;
+        self.pin.validate().context("in [pin]")?;
+        self.window_limit.validate().context("in [window-limit]")?;
+        self.distant_windows
+            .validate()
+            .context("in [distant-windows]")?;
+        Ok(())
+    }
+
+    /// `damage_report_level`, or if unset, the bounding box of the damage with `partial_blit`, as
+    /// that is what gets blitted, and otherwise the fewest events
+    pub fn damage_report_level(&self) -> DamageReportLevel {
+        self.damage_report_level.unwrap_or(if self.partial_blit {
+            DamageReportLevel::BoundingBox
+        } else {
+            DamageReportLevel::NonEmpty
+        })
+    }
+
+    /// How windows of this class are sampled
+    pub fn filtering(&self, class_instance: &str, class_general: &str) -> Filtering {
+        self.window_filtering
+            .get(class_instance)
+            .or_else(|| self.window_filtering.get(class_general))
+            .copied()
+            .unwrap_or(self.filtering)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn defaults_valid() {
+        parse("").unwrap();
+    }
+
+    #[test]
+    fn scroll_threshold() {
+        parse("[scroll]\nthreshold = 0.5").unwrap();
+        parse("[scroll]\nthreshold = 0.0").unwrap_err();
+        parse("[scroll]\nthreshold = -1.0").unwrap_err();
+        parse("[scroll]\nthreshold = nan").unwrap_err();
+    }
+
+    #[test]
+    fn damage_report_levels() {
+        let level = |content| parse(content).unwrap().damage_report_level();
+        assert_eq!(level(""), DamageReportLevel::NonEmpty);
+        assert_eq!(level("partial-blit = true"), DamageReportLevel::BoundingBox);
+        assert_eq!(
+            level("partial-blit = true\ndamage-report-level = \"raw-rectangles\""),
+            DamageReportLevel::RawRectangles
+        );
+    }
+
+    #[test]
+    fn layout_distances() {
+        parse("[layout]\ndepth = 2.5\nz-spacing = 0.1").unwrap();
+        parse("[layout]\ndepth = 0.0").unwrap_err();
+        parse("[layout]\ndepth = -3.0").unwrap_err();
+        parse("[layout]\nz-spacing = 0.0").unwrap_err();
+        parse("[layout]\nz-spacing = inf").unwrap_err();
+    }
+
+    #[test]
+    fn scale_gesture_range() {
+        parse("[scale-gesture]\nmin = 0.5\nmax = 0.5").unwrap();
+        parse("[scale-gesture]\nmin = 2.0\nmax = 1.0").unwrap_err();
+        parse("[scale-gesture]\nmin = 0.0").unwrap_err();
+        parse("[scale-gesture]\nmax = inf").unwrap_err();
+        parse("[scale-gesture]\nstep = -1.1").unwrap_err();
+    }
+
+    #[test]
+    fn brightness_gesture_range() {
+        parse("[brightness-gesture]\nmin = 0.0\nmax = 2.0").unwrap();
+        parse("[brightness-gesture]\nmin = 0.8\nmax = 0.2").unwrap_err();
+        parse("[brightness-gesture]\nmin = -0.5").unwrap_err();
+        parse("[brightness-gesture]\nmax = nan").unwrap_err();
+    }
+
+    #[test]
+    fn pin_interval() {
+        parse("[pin]\ninterval = 20").unwrap();
+        parse("[pin]\ninterval = 0").unwrap_err();
+    }
+
+    #[test]
+    fn window_limit_interval() {
+        parse("[window-limit]\ninterval = 250").unwrap();
+        parse("[window-limit]\ninterval = 0").unwrap_err();
+    }
+
+    #[test]
+    fn distant_windows_interval() {
+        parse("[distant-windows]\ninterval = 250").unwrap();
+        parse("[distant-windows]\ninterval = 0").unwrap_err();
+    }
+}
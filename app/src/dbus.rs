@@ -0,0 +1,472 @@
+//! D-Bus interface to control the companion while it is running.
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use tokio::sync::{mpsc, oneshot};
+use zbus::{
+    dbus_interface,
+    fdo::{self, RequestNameFlags, RequestNameReply},
+    names::{BusName, WellKnownName},
+    SignalContext,
+};
+
+use crate::{gl, layouts, placement, stats, synth, Result};
+
+pub const OBJECT_PATH: &str = "/picom/XrdesktopCompanion";
+
+/// How long to wait for a replaced companion to remove its windows and exit
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often `wait_for_name` says it is still waiting
+const WAIT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bus name for the display, in the same format picom uses for its own name.
+pub fn service_name(display: &str) -> String {
+    format!("picom.XrdesktopCompanion.{}", display)
+}
+
+/// Bus name of the companion whatever the display, for scripts that don't want to work it out.
+/// With companions on several displays, the first one started has it.
+pub const ALIAS_NAME: &str = "org.xrdesktop.PicomCompanion";
+
+/// Ask for `ALIAS_NAME`, after `acquire_name`. If another companion has it, the bus queues us,
+/// and hands it to us once that one exits.
+pub async fn request_alias(connection: &zbus::Connection) -> Result<()> {
+    let proxy = fdo::DBusProxy::new(connection).await?;
+    let name = WellKnownName::try_from(ALIAS_NAME)?;
+    let reply = proxy
+        .request_name(name, RequestNameFlags::AllowReplacement.into())
+        .await?;
+    if matches!(reply, RequestNameReply::InQueue) {
+        tracing::info!("A companion on another display has {ALIAS_NAME}, waiting for it");
+    }
+    Ok(())
+}
+
+/// Take the bus name `name`, so only one companion runs per display. If another companion owns
+/// it, fail, or with `replace` take the name over and wait for the other companion to exit. It
+/// removes its windows from VR when it loses the name, see `App::run`.
+pub async fn acquire_name(connection: &zbus::Connection, name: &str, replace: bool) -> Result<()> {
+    let proxy = fdo::DBusProxy::new(connection).await?;
+    let name = WellKnownName::try_from(name)?;
+    // Subscribe first, so the previous owner can't leave unnoticed in between
+    let mut owner_changes = proxy.receive_name_owner_changed().await?;
+    let previous = proxy.get_name_owner(BusName::from(name.clone())).await.ok();
+    let mut flags = RequestNameFlags::DoNotQueue | RequestNameFlags::AllowReplacement;
+    if replace {
+        flags |= RequestNameFlags::ReplaceExisting;
+    }
+    match proxy.request_name(name, flags).await? {
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner => (),
+        _ => anyhow::bail!(
+            "Another companion is already running on this display, pass --replace to replace it"
+        ),
+    }
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return Ok(()),
+    };
+    tracing::info!("Replacing the companion at {previous}, waiting for it to exit");
+    let exited = async {
+        while let Some(change) = owner_changes.next().await {
+            let args = change.args()?;
+            if args.name.as_str() == previous.as_str() && args.new_owner.is_none() {
+                break;
+            }
+        }
+        Result::Ok(())
+    };
+    match tokio::time::timeout(REPLACE_TIMEOUT, exited).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!(
+                "The replaced companion didn't exit in time, its windows might be doubled"
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Wait until something owns `name`, for at most `timeout` if given.
+pub async fn wait_for_name(
+    connection: &zbus::Connection,
+    name: &str,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let proxy = fdo::DBusProxy::new(connection).await?;
+    let name = WellKnownName::try_from(name)?;
+    // Subscribe first, so the name can't appear unnoticed in between
+    let mut owner_changes = proxy.receive_name_owner_changed().await?;
+    if proxy.name_has_owner(BusName::from(name.clone())).await? {
+        return Ok(());
+    }
+    tracing::info!("Waiting for {name} to appear on the bus");
+    let appeared = async {
+        let mut log = tokio::time::interval(WAIT_LOG_INTERVAL);
+        // The first tick is immediate
+        log.tick().await;
+        loop {
+            tokio::select! {
+                change = owner_changes.next() => {
+                    let change = change.ok_or_else(|| anyhow::anyhow!("lost the bus connection"))?;
+                    let args = change.args()?;
+                    if args.name.as_str() == name.as_str() && args.new_owner.is_some() {
+                        return Result::Ok(());
+                    }
+                }
+                _ = log.tick() => tracing::info!("Still waiting for {name}"),
+            }
+        }
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, appeared)
+            .await
+            .map_err(|_| anyhow::anyhow!("{name} didn't appear within {timeout:?}"))?,
+        None => appeared.await,
+    }
+}
+
+/// Requests received over D-Bus, handled by the main loop.
+#[derive(Debug)]
+pub enum Request {
+    /// Stop mirroring a window, and don't mirror it again until it is destroyed
+    IgnoreWindow(u32),
+    /// Close a window, or the window last clicked in VR if None
+    CloseWindow(Option<u32>),
+    MinimizeWindow(u32),
+    RestoreWindow(u32),
+    /// Stop rendering windows until `Resume`
+    Suspend,
+    Resume,
+    /// Mirrored windows and their titles
+    ListWindows(oneshot::Sender<Vec<(u32, String)>>),
+    /// Hide a window in VR, without touching it on the desktop
+    HideWindow(u32),
+    ShowWindow(u32),
+    SetScale(u32, f32),
+    /// Brightness and gamma a window is drawn with, remembered for its class
+    SetBrightness(u32, f32),
+    SetGamma(u32, f32),
+    /// Tint all windows to this color temperature, in Kelvin
+    SetColorTemperature(u32),
+    /// Put all windows back where they were first placed
+    ResetLayout,
+    /// Place all windows again, in a different layout
+    SetPreset(placement::Preset),
+    /// Hide all windows in VR and stop mirroring them, or show them again. Toggles if None.
+    SetMirroring(Option<bool>),
+    /// Show only the active window, or all windows again. Toggles if None.
+    SetFocusMode(Option<bool>),
+    /// Mirror the whole screen as one window, or windows on their own. Toggles if None.
+    SetDesktopMirror(Option<bool>),
+    /// Make a window follow the head, or put it back where it was. Toggles if None.
+    SetPinned(u32, Option<bool>),
+    /// Save where windows are in VR as a named layout, see `layouts`
+    SaveLayout(String, oneshot::Sender<std::result::Result<(), String>>),
+    /// Move windows to where a saved layout has them
+    LoadLayout(String, oneshot::Sender<std::result::Result<(), String>>),
+    /// Export the contents of a window as a dmabuf
+    ExportWindow(
+        u32,
+        oneshot::Sender<std::result::Result<gl::DmaBuf, String>>,
+    ),
+}
+
+pub struct Control {
+    tx: mpsc::Sender<Request>,
+    input_backend: synth::Backend,
+    stats: Arc<stats::Stats>,
+}
+
+impl Control {
+    pub fn new(
+        tx: mpsc::Sender<Request>,
+        input_backend: synth::Backend,
+        stats: Arc<stats::Stats>,
+    ) -> Self {
+        Self {
+            tx,
+            input_backend,
+            stats,
+        }
+    }
+
+    async fn send(&self, request: Request) -> zbus::fdo::Result<()> {
+        self.tx.send(request).await.map_err(|_| shutting_down())
+    }
+}
+
+fn shutting_down() -> fdo::Error {
+    fdo::Error::Failed("shutting down".into())
+}
+
+fn check_layout_name(name: &str) -> zbus::fdo::Result<()> {
+    if layouts::is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(zbus::fdo::Error::InvalidArgs(format!(
+            "layout names can't be empty, start with a dot or contain a slash, got {name:?}"
+        )))
+    }
+}
+
+#[dbus_interface(name = "picom.XrdesktopCompanion")]
+impl Control {
+    /// Stop mirroring the window `id`, either the top level window or its client window.
+    async fn ignore_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::IgnoreWindow(id)).await
+    }
+
+    /// Close the window `id`, like the close button of the window manager would.
+    async fn close_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::CloseWindow(Some(id))).await
+    }
+
+    /// Minimize the window `id`. It keeps its place in VR, and goes back there when restored.
+    async fn minimize_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::MinimizeWindow(id)).await
+    }
+
+    /// Restore the minimized window `id`.
+    async fn restore_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::RestoreWindow(id)).await
+    }
+
+    /// Stop updating windows in VR, e.g. to save power. Input still works.
+    async fn suspend(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::Suspend).await
+    }
+
+    /// Undo `Suspend`. Rendering stays suspended while nobody is wearing the headset.
+    async fn resume(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::Resume).await
+    }
+
+    /// Hide all windows in VR and stop updating them and forwarding input to them, or show them
+    /// again. Windows keep being tracked meanwhile, and come back where they were.
+    async fn set_mirroring(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        self.send(Request::SetMirroring(Some(enabled))).await
+    }
+
+    /// Flip `SetMirroring`.
+    async fn toggle_mirroring(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::SetMirroring(None)).await
+    }
+
+    /// Show only the active window, large in front of you, and hide the others, or go back to
+    /// showing all windows where they were.
+    async fn set_focus_mode(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        self.send(Request::SetFocusMode(Some(enabled))).await
+    }
+
+    /// Flip `SetFocusMode`, like the `[focus-mode]` hotkey.
+    async fn toggle_focus_mode(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::SetFocusMode(None)).await
+    }
+
+    /// Mirror the whole screen as one big window, or each window on its own again, where it
+    /// was.
+    async fn set_desktop_mirror(&self, enabled: bool) -> zbus::fdo::Result<()> {
+        self.send(Request::SetDesktopMirror(Some(enabled))).await
+    }
+
+    /// Flip `SetDesktopMirror`.
+    async fn toggle_desktop_mirror(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::SetDesktopMirror(None)).await
+    }
+
+    /// Pin the window `id` to the head, so it follows it like a HUD, or put it back where it was
+    /// before.
+    async fn set_pinned(&self, id: u32, pinned: bool) -> zbus::fdo::Result<()> {
+        self.send(Request::SetPinned(id, Some(pinned))).await
+    }
+
+    /// Flip `SetPinned` for the window `id`, like the `[pin]` button.
+    async fn toggle_pinned(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::SetPinned(id, None)).await
+    }
+
+    /// Place all windows again for sitting or standing, "desk" or "wall". Windows moved in VR go
+    /// back into the layout.
+    async fn set_preset(&self, preset: &str) -> zbus::fdo::Result<()> {
+        let preset = match preset {
+            "wall" => placement::Preset::Wall,
+            "desk" => placement::Preset::Desk,
+            _ => {
+                return Err(zbus::fdo::Error::InvalidArgs(format!(
+                    "preset must be \"wall\" or \"desk\", got {preset:?}"
+                )))
+            }
+        };
+        self.send(Request::SetPreset(preset)).await
+    }
+
+    /// Mirrored windows, as (window id, title) pairs.
+    async fn list_windows(&self) -> zbus::fdo::Result<Vec<(u32, String)>> {
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::ListWindows(tx)).await?;
+        rx.await.map_err(|_| shutting_down())
+    }
+
+    /// Hide the window `id` in VR. It stays as it is on the desktop.
+    async fn hide_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::HideWindow(id)).await
+    }
+
+    /// Show the window `id` in VR again, after `HideWindow`, or if `[window-limit]` hid it.
+    async fn show_window(&self, id: u32) -> zbus::fdo::Result<()> {
+        self.send(Request::ShowWindow(id)).await
+    }
+
+    /// Scale the window `id` in VR, 1.0 being its initial size.
+    async fn set_scale(&self, id: u32, scale: f64) -> zbus::fdo::Result<()> {
+        if !(scale.is_finite() && scale > 0.0) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "scale must be positive, got {scale}"
+            )));
+        }
+        self.send(Request::SetScale(id, scale as f32)).await
+    }
+
+    /// Dim the window `id` in VR, 1.0 being as bright as on the desktop. Windows of the same
+    /// class get it too, also after a restart.
+    async fn set_brightness(&self, id: u32, brightness: f64) -> zbus::fdo::Result<()> {
+        if !(brightness.is_finite() && brightness >= 0.0) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "brightness can't be negative, got {brightness}"
+            )));
+        }
+        self.send(Request::SetBrightness(id, brightness as f32))
+            .await
+    }
+
+    /// Change the gamma of the window `id` in VR, 1.0 being unchanged and above darkening it. Kept
+    /// for the class, like `SetBrightness`.
+    async fn set_gamma(&self, id: u32, gamma: f64) -> zbus::fdo::Result<()> {
+        if !(gamma.is_finite() && gamma > 0.0) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "gamma must be positive, got {gamma}"
+            )));
+        }
+        self.send(Request::SetGamma(id, gamma as f32)).await
+    }
+
+    /// Tint all windows warmer, like a night light, to `kelvin` between 1000 and 6500. 6500
+    /// leaves colors as they are.
+    async fn set_color_temperature(&self, kelvin: u32) -> zbus::fdo::Result<()> {
+        if !(1000..=crate::night_light::NEUTRAL).contains(&kelvin) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "color temperature must be between 1000 and 6500 K, got {kelvin}"
+            )));
+        }
+        self.send(Request::SetColorTemperature(kelvin)).await
+    }
+
+    /// Move all windows back to where they were first placed in VR.
+    async fn reset_layout(&self) -> zbus::fdo::Result<()> {
+        self.send(Request::ResetLayout).await
+    }
+
+    /// Save where all windows are in VR, their scale, and whether they are pinned or hidden, as
+    /// the layout `name`, replacing a layout saved with that name before.
+    async fn save_layout(&self, name: &str) -> zbus::fdo::Result<()> {
+        check_layout_name(name)?;
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::SaveLayout(name.into(), tx)).await?;
+        rx.await
+            .map_err(|_| shutting_down())?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Move windows to where the layout `name` has them, see `SaveLayout`. Windows are matched by
+    /// class and title, then by class alone. Windows that aren't in the layout stay where they
+    /// are.
+    async fn load_layout(&self, name: &str) -> zbus::fdo::Result<()> {
+        check_layout_name(name)?;
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::LoadLayout(name.into(), tx)).await?;
+        rx.await
+            .map_err(|_| shutting_down())?
+            .map_err(zbus::fdo::Error::Failed)
+    }
+
+    /// Export the contents of the window `id` as a dmabuf, for tools that want to show or record
+    /// it without copying. Returns the fd, width, height, stride and DRM fourcc of the buffer.
+    ///
+    /// The caller owns the fd. It keeps referring to the same buffer after the window is resized
+    /// or closed, so export again once it changes size.
+    async fn export_window(
+        &self,
+        id: u32,
+    ) -> zbus::fdo::Result<(zbus::zvariant::OwnedFd, u32, u32, u32, u32)> {
+        use std::os::unix::io::{FromRawFd, IntoRawFd};
+        let (tx, rx) = oneshot::channel();
+        self.send(Request::ExportWindow(id, tx)).await?;
+        let buf = rx
+            .await
+            .map_err(|_| shutting_down())?
+            .map_err(zbus::fdo::Error::Failed)?;
+        let fd = unsafe { zbus::zvariant::OwnedFd::from_raw_fd(buf.fd.into_raw_fd()) };
+        Ok((
+            fd,
+            buf.width.into(),
+            buf.height.into(),
+            buf.stride.into(),
+            buf.fourcc,
+        ))
+    }
+
+    /// Switch xrdesktop to `mode`, "overlay" or "scene". Only overlay mode is supported for now,
+    /// see `mode`.
+    async fn set_mode(&self, mode: &str) -> zbus::fdo::Result<()> {
+        match mode {
+            "overlay" => Ok(()),
+            "scene" => Err(zbus::fdo::Error::NotSupported(
+                "scene mode is not supported yet".into(),
+            )),
+            _ => Err(zbus::fdo::Error::InvalidArgs(format!(
+                "mode must be \"overlay\" or \"scene\", got {mode:?}"
+            ))),
+        }
+    }
+
+    /// Emitted when a window starts or stops being mirrored.
+    #[dbus_interface(signal)]
+    pub async fn windows_changed(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+
+    /// Backend synthesizing pointer input, "xdo" or "xtest". Include it in bug reports about
+    /// input.
+    #[dbus_interface(property)]
+    fn input_backend(&self) -> String {
+        self.input_backend.to_string()
+    }
+
+    /// xrdesktop mode. Always "overlay": the companion refuses to start in scene mode.
+    #[dbus_interface(property)]
+    fn mode(&self) -> String {
+        "overlay".into()
+    }
+
+    /// Render counters of all windows since the companion started, including closed ones, and
+    /// whether VR input is blocked.
+    #[dbus_interface(property)]
+    fn stats(&self) -> HashMap<String, u64> {
+        let mut stats = self.stats.total().to_map();
+        stats.insert("uptime-seconds".into(), self.stats.uptime().as_secs());
+        stats.insert("input-blocked".into(), self.stats.input_blocked().into());
+        stats.insert(
+            "blocked-input-events".into(),
+            self.stats.blocked_input_events(),
+        );
+        stats
+    }
+
+    /// Render counters of each mirrored window, by window id.
+    #[dbus_interface(property)]
+    fn window_stats(&self) -> HashMap<u32, HashMap<String, u64>> {
+        self.stats
+            .windows()
+            .into_iter()
+            .map(|(wid, stats)| (wid, stats.to_map()))
+            .collect()
+    }
+}
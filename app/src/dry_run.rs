@@ -0,0 +1,48 @@
+//! `--dry-run`: decide for each window whether it would be mirrored, like when running, and
+//! print the decisions, without connecting to VR. For tuning `[ignore]` without a headset.
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::task::block_in_place;
+use x11rb::{
+    connection::Connection, protocol::xproto::ConnectionExt, rust_connection::RustConnection,
+};
+
+use crate::{config::Config, decide, display_id, placement, source, vr_windows, AtomCollection};
+
+pub async fn run(config: &Config) -> Result<()> {
+    let dbus = zbus::Connection::session().await?;
+    let (x11, screen) = RustConnection::connect(None)?;
+    let x11 = Arc::new(x11);
+    let root = x11.setup().roots[screen].root;
+    let atoms = AtomCollection::new(&*x11)?.reply()?;
+    let root_geometry: placement::Geometry =
+        (&block_in_place(|| Result::Ok(x11.get_geometry(root)?.reply()?))?).into();
+    let (backend, source) = source::connect(config, &dbus, &x11, root, &display_id()).await?;
+    println!("Finding windows through {backend}");
+    let vr_pids = vr_windows::pids();
+    for wid in source.initial_windows().await? {
+        let info = match source.window_info(wid).await {
+            Ok(info) => info,
+            Err(e) => {
+                println!("{wid:#010x}: gone ({e})");
+                continue;
+            }
+        };
+        let geometry = match block_in_place(|| x11.get_geometry(wid)?.reply()) {
+            Ok(g) => format!("{}x{}+{}+{}", g.width, g.height, g.x, g.y),
+            Err(_) => "gone".into(),
+        };
+        let decision = match block_in_place(|| {
+            decide(&x11, &atoms, config, wid, &info, &root_geometry, &vr_pids)
+        }) {
+            Ok(decision) => decision.to_string(),
+            Err(e) => format!("failed: {e}"),
+        };
+        println!(
+            "{wid:#010x}: {decision}: type {}, name {:?}, class {}.{}, geometry {geometry}",
+            info.ty, info.name, info.class_instance, info.class_general
+        );
+    }
+    Ok(())
+}
@@ -3,9 +3,18 @@ use glutin::platform::{
     unix::{RawHandle, WindowExtUnix},
     ContextTraitExt,
 };
-use std::{collections::HashMap, ffi::c_void, os::unix::prelude::RawFd, sync::Arc};
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    os::unix::prelude::{FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    sync::Arc,
+};
 
-use x11rb::{connection::Connection, protocol::xproto, rust_connection::RustConnection};
+use x11rb::{
+    connection::Connection,
+    protocol::{dri3::ConnectionExt as _, xproto},
+    rust_connection::RustConnection,
+};
 #[derive(Debug)]
 pub struct Texture {
     id: usize,
@@ -29,6 +38,134 @@ const GLX_TEXTURE_FORMAT_RGBA_EXT: libc::c_int = 0x20DA;
 const GLX_TEXTURE_2D_EXT: libc::c_int = 0x20DC;
 const GLX_FRONT_LEFT_EXT: libc::c_int = 0x20DE;
 
+/// `GL_EXT_texture_sRGB_decode`, not in the generated bindings
+const TEXTURE_SRGB_DECODE_EXT: u32 = 0x8A48;
+const SKIP_DECODE_EXT: i32 = 0x8A4A;
+
+/// `DRM_FORMAT_XRGB8888`, what X uses for depth 24 pixmaps
+pub const DRM_FORMAT_XRGB8888: u32 = 0x3432_5258;
+/// `DRM_FORMAT_ARGB8888`, what X uses for depth 32 pixmaps
+pub const DRM_FORMAT_ARGB8888: u32 = 0x3432_5241;
+
+/// Pixel format of the textures shared with xrdesktop. Drivers differ in which formats they can
+/// export from Vulkan and import into GL, so it is picked per window from what works, see
+/// `SharedFormat::candidates`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SharedFormat {
+    /// `R8G8B8A8_SRGB`, xrdesktop decodes colors when it samples them
+    #[default]
+    Srgba8,
+    /// `R8G8B8A8_UNORM`, for drivers that can't share sRGB images. Holds linear values, colors
+    /// are decoded when blitting into it.
+    Rgba8,
+    /// `A2B10G10R10_UNORM`, for windows with 30 bit visuals. There is no sRGB variant, so like
+    /// `Rgba8` it holds linear values.
+    Rgb10A2,
+}
+
+impl SharedFormat {
+    /// Formats for a window of visual depth `depth`, best first. None of them are smaller than 32
+    /// bits per pixel, drivers don't export 24 bit formats.
+    pub fn candidates(depth: u8) -> &'static [Self] {
+        match depth {
+            30 => &[Self::Rgb10A2, Self::Srgba8, Self::Rgba8],
+            // Two bits of alpha aren't enough for translucent windows
+            32 => &[Self::Srgba8, Self::Rgba8],
+            _ => &[Self::Srgba8, Self::Rgba8, Self::Rgb10A2],
+        }
+    }
+
+    /// Whether the texture holds linear values instead of sRGB encoded ones
+    fn linear_light(self) -> bool {
+        self != Self::Srgba8
+    }
+}
+
+impl std::fmt::Display for SharedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Srgba8 => "srgba8",
+            Self::Rgba8 => "rgba8",
+            Self::Rgb10A2 => "rgb10a2",
+        })
+    }
+}
+
+impl std::str::FromStr for SharedFormat {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "srgba8" => Ok(Self::Srgba8),
+            "rgba8" => Ok(Self::Rgba8),
+            "rgb10a2" => Ok(Self::Rgb10A2),
+            _ => Err(format!(
+                "unknown format {s:?}, expected srgba8, rgba8 or rgb10a2"
+            )),
+        }
+    }
+}
+
+/// How textures are sampled when they are drawn at another size, like window icons
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextureFilter {
+    /// Blocky, but keeps pixel edges sharp
+    Nearest,
+    #[default]
+    Linear,
+}
+
+/// Texture sampling settings for our blits: icons drawn over windows, and windows shared
+/// smaller, see `[distant-windows]`. xrdesktop draws the shared textures in VR with a sampler of
+/// its own, which these don't change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Filtering {
+    pub filter: TextureFilter,
+    /// Filter for textures drawn smaller, instead of `filter`
+    pub minify: Option<TextureFilter>,
+    /// Filter for textures drawn larger, instead of `filter`
+    pub magnify: Option<TextureFilter>,
+    /// Generate mipmaps for textures we upload, icons, so they don't alias when drawn smaller.
+    /// Textures bound from X pixmaps have none, windows shared smaller are sampled from a copy
+    /// with mipmaps either way.
+    pub mipmaps: bool,
+}
+
+impl Default for Filtering {
+    fn default() -> Self {
+        Self {
+            filter: TextureFilter::Linear,
+            minify: None,
+            magnify: None,
+            mipmaps: false,
+        }
+    }
+}
+
+impl Filtering {
+    fn sampler(&self, mipmapped: bool) -> glium::uniforms::SamplerBehavior {
+        use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+        let minify = self.minify.unwrap_or(self.filter);
+        let minify_filter = match (minify, mipmapped && self.mipmaps) {
+            (TextureFilter::Nearest, false) => MinifySamplerFilter::Nearest,
+            (TextureFilter::Nearest, true) => MinifySamplerFilter::NearestMipmapNearest,
+            (TextureFilter::Linear, false) => MinifySamplerFilter::Linear,
+            (TextureFilter::Linear, true) => MinifySamplerFilter::LinearMipmapLinear,
+        };
+        let magnify_filter = match self.magnify.unwrap_or(self.filter) {
+            TextureFilter::Nearest => MagnifySamplerFilter::Nearest,
+            TextureFilter::Linear => MagnifySamplerFilter::Linear,
+        };
+        glium::uniforms::SamplerBehavior {
+            minify_filter,
+            magnify_filter,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("{0}")]
@@ -60,6 +197,18 @@ pub enum Error {
     NoFbConfig(xproto::Visualid),
     #[error("failed to create GLXPixmap")]
     PixmapCreation,
+    #[error("texture isn't backed by an X pixmap")]
+    NotExportable,
+    #[error("can't export pixmaps of depth {0} with {1} bits per pixel")]
+    UnsupportedFormat(u8, u8),
+    #[error("colors are changed on the way to VR, {expected:?} came out as {got:?}")]
+    ColorMismatch { expected: Vec<u8>, got: Vec<u8> },
+    /// Freed, or made in a GL context that was reset since
+    #[error("texture {0} doesn't exist")]
+    UnknownTexture(usize),
+    /// E.g. after a GPU reset. Nothing drawn since shows up, until the context is reset.
+    #[error("the GL context was lost")]
+    ContextLost,
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -86,21 +235,84 @@ impl<'a> AsUniformValue for &'a AnyTexture2d {
     }
 }
 
+impl AnyTexture2d {
+    fn id(&self) -> u32 {
+        use glium::GlObject;
+        match self {
+            AnyTexture2d::Srgb(t) => t.get_id(),
+            AnyTexture2d::Linear(t) => t.get_id(),
+        }
+    }
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            AnyTexture2d::Srgb(t) => t.dimensions(),
+            AnyTexture2d::Linear(t) => t.dimensions(),
+        }
+    }
+    /// Sampled according to `filtering`, mipmaps are only used if the texture has them.
+    fn sampled(&self, filtering: Filtering) -> SampledTexture<'_> {
+        let levels = match self {
+            AnyTexture2d::Srgb(t) => t.get_mipmap_levels(),
+            AnyTexture2d::Linear(t) => t.get_mipmap_levels(),
+        };
+        SampledTexture(self, filtering.sampler(levels > 1))
+    }
+}
+
+struct SampledTexture<'a>(&'a AnyTexture2d, glium::uniforms::SamplerBehavior);
+
+impl<'a> AsUniformValue for SampledTexture<'a> {
+    fn as_uniform_value(&self) -> glium::uniforms::UniformValue<'_> {
+        use glium::uniforms::UniformValue;
+        match self.0 {
+            AnyTexture2d::Srgb(t) => UniformValue::SrgbTexture2d(t, Some(self.1)),
+            AnyTexture2d::Linear(t) => UniformValue::Texture2d(t, Some(self.1)),
+        }
+    }
+}
+
 struct TextureInner {
     texture: AnyTexture2d,
+    /// Holds linear values, colors are decoded from sRGB when blitted into it
+    linear_light: bool,
     glxpixmap: Option<libc::c_int>,
+    /// The X pixmap bound to the texture, if any
+    x11_pixmap: Option<xproto::Pixmap>,
+}
+
+/// A window's pixmap, exported with DRI3 so other processes can import it without copying.
+///
+/// The receiver owns `fd` and has to close it. The buffer is the pixmap picom named for the
+/// window when it was exported: it stays valid after the window is resized or unmapped, but
+/// stops being updated, so it has to be exported again.
+#[derive(Debug)]
+pub struct DmaBuf {
+    pub fd: OwnedFd,
+    pub width: u16,
+    pub height: u16,
+    pub stride: u16,
+    /// DRM fourcc of the pixel format
+    pub fourcc: u32,
 }
 
 struct GlInner {
+    /// Kept to create a new context in `reset_context`, there can only be one per process
+    event_loop: glutin::event_loop::EventLoop<()>,
     x11: Arc<RustConnection>,
     screen: u32,
+    /// Of all screens, windows of any screen can be bound
     x11depths: Vec<xproto::Depth>,
     glium: glium::Display,
     glx: glutin_glx_sys::glx::Glx,
     bind_tex_image: unsafe extern "C" fn(*mut c_void, libc::c_int, libc::c_int, *const c_void),
     gl: ffi::Gl,
     textures: HashMap<usize, TextureInner>,
+    /// Copies with mipmaps of textures that were blitted smaller, by the id of the texture
+    mipmapped: HashMap<usize, AnyTexture2d>,
     blit_shader: glium::Program,
+    filtering: Filtering,
+    /// Whether `GL_EXT_texture_sRGB_decode` is supported
+    srgb_decode: bool,
 }
 
 impl Drop for GlInner {
@@ -124,16 +336,238 @@ struct Vertex {
 }
 implement_vertex!(Vertex, position);
 
+fn create_display(el: &glutin::event_loop::EventLoop<()>) -> Result<glium::Display> {
+    let wb = glutin::window::WindowBuilder::new().with_visible(false);
+    // So a GPU reset loses the context instead of leaving it broken, see `Error::ContextLost`
+    let cb = glutin::ContextBuilder::new()
+        .with_vsync(false)
+        .with_multisampling(0)
+        .with_gl_robustness(glutin::Robustness::TryRobustLoseContextOnReset);
+    let display = glium::Display::new(wb, cb, el)?;
+    assert!(unsafe { display.gl_window().get_egl_display().is_none() });
+    Ok(display)
+}
+
+/// A frame drawn over the outermost pixels of a window, see `Gl::blit`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Border {
+    /// In pixels
+    pub width: u32,
+    /// sRGB, with alpha
+    pub color: [f32; 4],
+}
+
+/// Color adjustment of a window, see `Gl::blit`. Applied to the colors as they are displayed, sRGB
+/// encoded, alpha is left alone.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustment {
+    /// Multiplies the color, below 1 dims the window
+    pub brightness: f32,
+    /// Exponent of the color, above 1 darkens midtones and keeps white white
+    pub gamma: f32,
+    /// Applied last, the same for all windows, see `night_light`
+    pub color: [[f32; 3]; 3],
+}
+
+impl Default for Adjustment {
+    fn default() -> Self {
+        Self {
+            brightness: 1.0,
+            gamma: 1.0,
+            color: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        }
+    }
+}
+
+/// `rect` in pixels of a texture scaled by `scale_x` and `scale_y`, grown to whole pixels
+fn scale_rect(rect: &xproto::Rectangle, scale_x: f32, scale_y: f32) -> xproto::Rectangle {
+    let left = (f32::from(rect.x) * scale_x).floor();
+    let bottom = (f32::from(rect.y) * scale_y).floor();
+    let right = ((f32::from(rect.x) + f32::from(rect.width)) * scale_x).ceil();
+    let top = ((f32::from(rect.y) + f32::from(rect.height)) * scale_y).ceil();
+    xproto::Rectangle {
+        x: left as _,
+        y: bottom as _,
+        width: (right - left) as _,
+        height: (top - bottom) as _,
+    }
+}
+
+/// `length` pixels of a texture scaled by `scale`, at least one pixel unless it was none
+fn scale_length(length: u32, scale: f32) -> u32 {
+    if length == 0 {
+        0
+    } else {
+        ((length as f32 * scale).round() as u32).max(1)
+    }
+}
+
+/// The sRGB transfer function undone, like the blit shader does for linear shared formats
+fn srgb_to_linear(c: f32) -> f32 {
+    if c < 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Bounding box of `shape`, clipped to a `width` x `height` texture. Like X coordinates, with
+/// row 0 at the bottom of the texture.
+fn shape_bounds(shape: &[xproto::Rectangle], width: u32, height: u32) -> glium::Rect {
+    let (mut left, mut bottom, mut right, mut top) = (width, height, 0, 0);
+    for rect in shape {
+        let x = rect.x.max(0) as u32;
+        let y = rect.y.max(0) as u32;
+        left = left.min(x);
+        bottom = bottom.min(y);
+        right = right.max(x + u32::from(rect.width)).min(width);
+        top = top.max(y + u32::from(rect.height)).min(height);
+    }
+    glium::Rect {
+        left,
+        bottom,
+        width: right.saturating_sub(left),
+        height: top.saturating_sub(bottom),
+    }
+}
+
+/// The four strips of a `width` pixels wide frame just inside of `bounds`.
+fn border_rects(bounds: glium::Rect, width: u32) -> [glium::Rect; 4] {
+    let width = width.min(bounds.width / 2).min(bounds.height / 2);
+    [
+        // Bottom and top, full width
+        glium::Rect {
+            height: width,
+            ..bounds
+        },
+        glium::Rect {
+            bottom: bounds.bottom + bounds.height - width,
+            height: width,
+            ..bounds
+        },
+        // Left and right, between them
+        glium::Rect {
+            bottom: bounds.bottom + width,
+            width,
+            height: bounds.height - 2 * width,
+            ..bounds
+        },
+        glium::Rect {
+            left: bounds.left + bounds.width - width,
+            bottom: bounds.bottom + width,
+            width,
+            height: bounds.height - 2 * width,
+        },
+    ]
+}
+
+/// The pixels of a `width` x `height` texture that show `region` of a texture scaled by `scale_x`
+/// and `scale_y` to it, and the pixel around them, which filtering blends in
+fn damaged_rect(
+    region: &xproto::Rectangle,
+    scale_x: f32,
+    scale_y: f32,
+    width: u32,
+    height: u32,
+) -> glium::Rect {
+    let rect = scale_rect(region, scale_x, scale_y);
+    let clamp = |n: i32, max: u32| n.clamp(0, max as i32) as u32;
+    let left = clamp(i32::from(rect.x) - 1, width);
+    let bottom = clamp(i32::from(rect.y) - 1, height);
+    let right = clamp(i32::from(rect.x) + i32::from(rect.width) + 1, width);
+    let top = clamp(i32::from(rect.y) + i32::from(rect.height) + 1, height);
+    glium::Rect {
+        left,
+        bottom,
+        width: right - left,
+        height: top - bottom,
+    }
+}
+
+/// The part of `a` inside `b`, None if they don't overlap
+fn intersect(a: glium::Rect, b: glium::Rect) -> Option<glium::Rect> {
+    let left = a.left.max(b.left);
+    let bottom = a.bottom.max(b.bottom);
+    let right = (a.left + a.width).min(b.left + b.width);
+    let top = (a.bottom + a.height).min(b.bottom + b.height);
+    (right > left && top > bottom).then(|| glium::Rect {
+        left,
+        bottom,
+        width: right - left,
+        height: top - bottom,
+    })
+}
+
+/// Whether the context of `gl` supports the extension `name`
+fn has_extension(gl: &ffi::Gl, name: &str) -> bool {
+    let mut count = 0;
+    unsafe { gl.GetIntegerv(ffi::NUM_EXTENSIONS, &mut count) };
+    (0..count as u32).any(|i| {
+        let extension = unsafe { gl.GetStringi(ffi::EXTENSIONS, i) };
+        !extension.is_null()
+            && unsafe { std::ffi::CStr::from_ptr(extension.cast()) }.to_bytes() == name.as_bytes()
+    })
+}
+
+/// Colors stay sRGB encoded all the way from X to xrdesktop, which decodes them once, when it
+/// samples the `R8G8B8A8_SRGB` Vulkan texture shared with us:
+/// - Textures bound from X pixmaps and uploaded icons have linear GL formats, or sRGB decoding
+///   switched off, so sampling them returns the stored values.
+/// - `outputs_srgb` tells glium that the shader's output is sRGB already, so it disables
+///   `GL_FRAMEBUFFER_SRGB`, and the values are stored unchanged in the imported texture, although
+///   it is an sRGB texture.
+/// - Textures shared in the other `SharedFormat`s aren't sRGB textures, xrdesktop samples them as
+///   they are, so the shader decodes what it writes into them instead.
+///
+/// Decoding anywhere else on the way too makes windows look washed out or too dark in VR.
+/// `GlInner::check_colors` checks this at startup.
+fn blit_shader(display: &glium::Display) -> glium::Program {
+    use glium::program;
+    program!(display,
+        330 => {
+            vertex: "
+                #version 330
+                in vec2 position;
+                out vec2 tex_coord;
+                void main() {
+                    gl_Position = vec4(position, 0, 1);
+                    tex_coord = position / 2.0 + vec2(0.5);
+                }
+            ",
+            fragment: "
+                #version 330
+                uniform sampler2D tex;
+                uniform float brightness;
+                uniform float gamma;
+                uniform mat3 color_matrix;
+                uniform bool decode;
+                in vec4 gl_FragCoord;
+                in vec2 tex_coord;
+                out vec4 color;
+                void main() {
+                    vec4 c = texture(tex, tex_coord);
+                    vec3 rgb = color_matrix * (pow(c.rgb, vec3(gamma)) * brightness);
+                    if (decode) {
+                        rgb = mix(
+                            rgb / 12.92,
+                            pow((rgb + 0.055) / 1.055, vec3(2.4)),
+                            step(0.04045, rgb)
+                        );
+                    }
+                    color = vec4(rgb, c.a);
+                }
+            ",
+            outputs_srgb: true,
+        }
+    )
+    .unwrap()
+}
+
 impl GlInner {
-    fn new(x11: Arc<RustConnection>, screen: u32) -> Result<GlInner> {
+    fn new(x11: Arc<RustConnection>, screen: u32, filtering: Filtering) -> Result<GlInner> {
         use glutin::platform::unix::EventLoopBuilderExtUnix;
         let el = glutin::event_loop::EventLoopBuilder::<()>::new().with_any_thread(true).build();
-        let wb = glutin::window::WindowBuilder::new().with_visible(false);
-        let cb = glutin::ContextBuilder::new()
-            .with_vsync(false)
-            .with_multisampling(0);
-        let display = glium::Display::new(wb, cb, &el)?;
-        assert!(unsafe { display.gl_window().get_egl_display().is_none() });
+        let display = create_display(&el)?;
         let glx = unsafe {
             let libglx = libloading::Library::new("libGL.so")
                 .or_else(|_| libloading::Library::new("libGL.so.1"))?;
@@ -148,35 +582,19 @@ impl GlInner {
                     .unwrap_or(std::ptr::null_mut())
             })
         };
-        use glium::program;
-        let blit_shader = program!(&display,
-            330 => {
-                vertex: "
-                    #version 330
-                    in vec2 position;
-                    out vec2 tex_coord;
-                    void main() {
-                        gl_Position = vec4(position, 0, 1);
-                        tex_coord = position / 2.0 + vec2(0.5);
-                    }
-                ",
-                fragment: "
-                    #version 330
-                    uniform sampler2D tex;
-                    in vec4 gl_FragCoord;
-                    in vec2 tex_coord;
-                    out vec4 color;
-                    void main() {
-                        color = texture(tex, tex_coord);
-                    }
-                ",
-                outputs_srgb: true,
-            }
-        )
-        .unwrap();
+        let blit_shader = blit_shader(&display);
+        let gl = ffi::Gl::load_with(|s| display.gl_window().get_proc_address(s));
         Ok(GlInner {
-            x11depths: x11.setup().roots[screen as usize].allowed_depths.clone(),
-            gl: ffi::Gl::load_with(|s| display.gl_window().get_proc_address(s)),
+            event_loop: el,
+            filtering,
+            x11depths: x11
+                .setup()
+                .roots
+                .iter()
+                .flat_map(|root| root.allowed_depths.iter().cloned())
+                .collect(),
+            srgb_decode: has_extension(&gl, "GL_EXT_texture_sRGB_decode"),
+            gl,
             glium: display,
             blit_shader,
             bind_tex_image: unsafe {
@@ -193,9 +611,28 @@ impl GlInner {
             glx,
             x11,
             textures: Default::default(),
+            mipmapped: Default::default(),
         })
     }
 
+    /// Drop all textures and replace the GL context with a new one, to recover from a hung or
+    /// lost context. Textures created before are invalid afterwards.
+    fn reset_context(&mut self) -> Result<()> {
+        let raw_display = self.glium.gl_window().window().xlib_display().unwrap();
+        self.mipmapped.clear();
+        for (_, TextureInner { glxpixmap, .. }) in self.textures.drain() {
+            if let Some(glxpixmap) = glxpixmap {
+                unsafe { self.glx.DestroyPixmap(raw_display as _, glxpixmap as _) };
+            }
+        }
+        let display = create_display(&self.event_loop)?;
+        self.blit_shader = blit_shader(&display);
+        self.gl = ffi::Gl::load_with(|s| display.gl_window().get_proc_address(s));
+        self.srgb_decode = has_extension(&self.gl, "GL_EXT_texture_sRGB_decode");
+        self.glium = display;
+        Ok(())
+    }
+
     fn find_visual(&self, visual: xproto::Visualid) -> Option<(u8, &xproto::Visualtype)> {
         for d in &self.x11depths {
             for v in &d.visuals {
@@ -206,7 +643,12 @@ impl GlInner {
         }
         None
     }
-    fn find_fbconfig(&self, depth: u8, visual: &xproto::Visualtype) -> Result<*const libc::c_void> {
+    fn find_fbconfig(
+        &self,
+        screen: u32,
+        depth: u8,
+        visual: &xproto::Visualtype,
+    ) -> Result<*const libc::c_void> {
         use glutin_glx_sys::glx;
         let raw_display = self.glium.gl_window().window().xlib_display().unwrap();
         if visual.class != xproto::VisualClass::TRUE_COLOR {
@@ -242,7 +684,7 @@ impl GlInner {
         let config = unsafe {
             self.glx.ChooseFBConfig(
                 raw_display as _,
-                self.screen as _,
+                screen as _,
                 attrs.as_ptr(),
                 &mut num_config as *mut _,
             )
@@ -336,10 +778,18 @@ impl GlInner {
         let (depth, visual) = self
             .find_visual(visual)
             .ok_or(Error::InvalidVisual(visual))?;
-        let fbconfig = self.find_fbconfig(depth, visual)?;
-        log::info!("{:p}", raw_display);
-
         let geometry = xproto::get_geometry(self.x11.as_ref(), pixmap)?.reply()?;
+        // The FBConfig has to be of the screen the pixmap is on
+        let screen = self
+            .x11
+            .setup()
+            .roots
+            .iter()
+            .position(|root| root.root == geometry.root)
+            .map_or(self.screen, |screen| screen as u32);
+        let fbconfig = self.find_fbconfig(screen, depth, visual)?;
+        tracing::info!("{:p}", raw_display);
+
         let attrs = [
             GLX_TEXTURE_FORMAT_EXT,
             if depth == 32 {
@@ -351,6 +801,7 @@ impl GlInner {
             GLX_TEXTURE_2D_EXT,
             0,
         ];
+        let x11_pixmap = pixmap;
         let pixmap = unsafe {
             self.glx
                 .CreatePixmap(raw_display as _, fbconfig, pixmap.into(), attrs.as_ptr())
@@ -385,15 +836,20 @@ impl GlInner {
                 GLX_FRONT_LEFT_EXT,
                 std::ptr::null(),
             );
+            // Sampled as stored, even if the driver picked an sRGB format, see `blit_shader`
+            if self.srgb_decode {
+                self.gl
+                    .TexParameteri(ffi::TEXTURE_2D, TEXTURE_SRGB_DECODE_EXT, SKIP_DECODE_EXT);
+            }
             self.gl.BindTexture(ffi::TEXTURE_2D, old_texture_2d as _);
         }
         let texture = unsafe {
             glium::Texture2d::from_id(
                 &self.glium,
-                if depth == 32 {
-                    glium::texture::UncompressedFloatFormat::U8U8U8U8
-                } else {
-                    glium::texture::UncompressedFloatFormat::U8U8U8
+                match depth {
+                    32 => glium::texture::UncompressedFloatFormat::U8U8U8U8,
+                    30 => glium::texture::UncompressedFloatFormat::U10U10U10,
+                    _ => glium::texture::UncompressedFloatFormat::U8U8U8,
                 },
                 texture_id,
                 true,
@@ -409,7 +865,9 @@ impl GlInner {
             texture_id as _,
             TextureInner {
                 texture: AnyTexture2d::Linear(texture),
+                linear_light: false,
                 glxpixmap: Some(pixmap as _),
+                x11_pixmap: Some(x11_pixmap),
             },
         );
         Ok(Texture {
@@ -420,6 +878,7 @@ impl GlInner {
     }
     fn release_texture(&mut self, tex: Texture) -> Result<()> {
         let raw_display = self.glium.gl_window().window().xlib_display().unwrap();
+        self.mipmapped.remove(&(tex.id as _));
         if let Some(TextureInner {
             glxpixmap: Some(pixmap),
             ..
@@ -429,6 +888,28 @@ impl GlInner {
         }
         Ok(())
     }
+    fn export_dmabuf(&mut self, id: usize) -> Result<DmaBuf> {
+        let pixmap = self
+            .textures
+            .get(&id)
+            .and_then(|t| t.x11_pixmap)
+            .ok_or(Error::NotExportable)?;
+        // The version has to be negotiated before any other request
+        self.x11.dri3_query_version(1, 0)?.reply()?;
+        let reply = self.x11.dri3_buffer_from_pixmap(pixmap)?.reply()?;
+        let fourcc = match (reply.depth, reply.bpp) {
+            (24, 32) => DRM_FORMAT_XRGB8888,
+            (32, 32) => DRM_FORMAT_ARGB8888,
+            (depth, bpp) => return Err(Error::UnsupportedFormat(depth, bpp)),
+        };
+        Ok(DmaBuf {
+            fd: unsafe { OwnedFd::from_raw_fd(reply.pixmap_fd.into_raw_fd()) },
+            width: reply.width,
+            height: reply.height,
+            stride: reply.stride,
+            fourcc,
+        })
+    }
     fn capture(&mut self, start: bool) -> Result<()> {
         let mut rd = crate::RENDERDOC.lock().unwrap();
         if let Some(rd) = rd.as_mut() {
@@ -446,13 +927,227 @@ impl GlInner {
         }
         Ok(())
     }
-    fn blit(&mut self, src: usize, dst: usize) -> Result<()> {
+    fn upload_texture(&mut self, width: u32, height: u32, data: Vec<u8>) -> Result<Texture> {
+        use glium::{
+            texture::{MipmapsOption, RawImage2d, UncompressedFloatFormat},
+            GlObject,
+        };
+        // Linear, like the textures bound from X pixmaps, so the pixel values are copied as-is.
+        let mipmaps = if self.filtering.mipmaps {
+            MipmapsOption::AutoGeneratedMipmaps
+        } else {
+            MipmapsOption::NoMipmap
+        };
+        let texture = Texture2d::with_format(
+            &self.glium,
+            RawImage2d::from_raw_rgba(data, (width, height)),
+            UncompressedFloatFormat::U8U8U8U8,
+            mipmaps,
+        )?;
+        let id = texture.get_id() as _;
+        self.textures.insert(
+            id,
+            TextureInner {
+                texture: AnyTexture2d::Linear(texture),
+                linear_light: false,
+                glxpixmap: None,
+                x11_pixmap: None,
+            },
+        );
+        Ok(Texture { id, width, height })
+    }
+    /// Copy `texture` into its mipmapped copy in `mipmapped`, made if there is none of its size
+    /// yet, and generate the copy's mipmaps.
+    fn update_mipmaps(
+        glium: &glium::Display,
+        gl: &ffi::Gl,
+        mipmapped: &mut HashMap<usize, AnyTexture2d>,
+        id: usize,
+        texture: &AnyTexture2d,
+    ) -> Result<()> {
+        use glium::{
+            texture::{MipmapsOption, UncompressedFloatFormat},
+            uniforms::MagnifySamplerFilter,
+            BlitTarget,
+        };
+        let (width, height) = texture.dimensions();
+        if mipmapped.get(&id).map(AnyTexture2d::dimensions) != Some((width, height)) {
+            let copy = Texture2d::empty_with_format(
+                glium,
+                UncompressedFloatFormat::U8U8U8U8,
+                MipmapsOption::EmptyMipmaps,
+                width,
+                height,
+            )?;
+            mipmapped.insert(id, AnyTexture2d::Linear(copy));
+        }
+        let copy = &mipmapped[&id];
+        let src = glium::framebuffer::SimpleFrameBuffer::new(glium, texture)?;
+        let dst = glium::framebuffer::SimpleFrameBuffer::new(glium, copy)?;
+        src.blit_whole_color_to(
+            &dst,
+            &BlitTarget {
+                left: 0,
+                bottom: 0,
+                width: width as _,
+                height: height as _,
+            },
+            MagnifySamplerFilter::Nearest,
+        );
+        unsafe {
+            let mut old_texture_2d = 0;
+            gl.GetIntegerv(ffi::TEXTURE_BINDING_2D, &mut old_texture_2d);
+            gl.BindTexture(ffi::TEXTURE_2D, copy.id());
+            gl.GenerateMipmap(ffi::TEXTURE_2D);
+            gl.BindTexture(ffi::TEXTURE_2D, old_texture_2d as _);
+        }
+        Ok(())
+    }
+    /// Blit a gray gradient into an sRGB texture, like the ones shared with xrdesktop, and read it
+    /// back. The values have to come out as they went in, see `blit_shader`.
+    fn check_colors(&mut self) -> Result<()> {
+        use glium::{
+            texture::{MipmapsOption, SrgbFormat},
+            GlObject,
+        };
+        const LEVELS: [u8; 5] = [0, 32, 128, 188, 255];
+        let width = LEVELS.len() as u32;
+        let data = LEVELS.iter().flat_map(|&l| [l, l, l, 255]).collect();
+        let src = self.upload_texture(width, 1, data)?;
+        let dst = SrgbTexture2d::empty_with_format(
+            &self.glium,
+            SrgbFormat::U8U8U8U8,
+            MipmapsOption::NoMipmap,
+            width,
+            1,
+        )?;
+        let dst_id = dst.get_id() as usize;
+        self.textures.insert(
+            dst_id,
+            TextureInner {
+                texture: AnyTexture2d::Srgb(dst),
+                linear_light: false,
+                glxpixmap: None,
+                x11_pixmap: None,
+            },
+        );
+        let blitted = self.blit(
+            src.id,
+            dst_id,
+            None,
+            None,
+            None,
+            None,
+            Default::default(),
+            self.filtering,
+        );
+        let read: Option<Vec<Vec<(u8, u8, u8, u8)>>> = match self.textures.remove(&dst_id) {
+            Some(TextureInner {
+                texture: AnyTexture2d::Srgb(dst),
+                linear_light: false,
+                ..
+            }) => Some(dst.read()),
+            _ => None,
+        };
+        self.release_texture(src)?;
+        blitted?;
+        let got: Vec<u8> = read.iter().flatten().flatten().map(|&(r, ..)| r).collect();
+        if got.len() != LEVELS.len() || got.iter().zip(LEVELS).any(|(&g, l)| g.abs_diff(l) > 1) {
+            return Err(Error::ColorMismatch {
+                expected: LEVELS.to_vec(),
+                got,
+            });
+        }
+        Ok(())
+    }
+    /// Copy `src` into `dst`, and optionally draw `overlay` (id, width, height) over the top left
+    /// corner of `dst`. If `shape` is given, only the pixels inside it are copied, the rest of
+    /// `dst` is made transparent. If `region` is given, only the part of `dst` showing it is
+    /// drawn.
+    #[allow(clippy::too_many_arguments)]
+    fn blit(
+        &mut self,
+        src: usize,
+        dst: usize,
+        region: Option<xproto::Rectangle>,
+        overlay: Option<(usize, u32, u32)>,
+        shape: Option<Vec<xproto::Rectangle>>,
+        border: Option<Border>,
+        adjustment: Adjustment,
+        filtering: Filtering,
+    ) -> Result<()> {
         use glium::uniform;
-        let src = self.textures.get(&src).unwrap();
-        let dst = self.textures.get(&dst).unwrap();
+        let Adjustment {
+            brightness,
+            gamma,
+            color,
+        } = adjustment;
+        let src_id = src;
+        let src = self.textures.get(&src).ok_or(Error::UnknownTexture(src))?;
+        let dst = self.textures.get(&dst).ok_or(Error::UnknownTexture(dst))?;
+        let decode = dst.linear_light;
         let mut fb = glium::framebuffer::SimpleFrameBuffer::new(&self.glium, &dst.texture)?;
+        let (width, height) = fb.get_dimensions();
+        // Shrunk, like for distant windows, it is sampled from a copy with mipmaps so it doesn't
+        // alias. Shape, border and overlay are in pixels of `src`.
+        let (src_width, src_height) = src.texture.dimensions();
+        let minified = width < src_width || height < src_height;
+        if minified {
+            Self::update_mipmaps(
+                &self.glium,
+                &self.gl,
+                &mut self.mipmapped,
+                src_id,
+                &src.texture,
+            )?;
+        }
+        let scale_x = width as f32 / src_width.max(1) as f32;
+        let scale_y = height as f32 / src_height.max(1) as f32;
+        let scissor = region.map(|region| damaged_rect(&region, scale_x, scale_y, width, height));
+        let shape = shape.map(|shape| {
+            shape
+                .iter()
+                .map(|rect| scale_rect(rect, scale_x, scale_y))
+                .collect::<Vec<_>>()
+        });
+        let border = border.map(|border| Border {
+            width: scale_length(border.width, scale_x),
+            ..border
+        });
+        let overlay = overlay.map(|(overlay, width, height)| {
+            (
+                overlay,
+                scale_length(width, scale_x),
+                scale_length(height, scale_y),
+            )
+        });
+        let tex = match self.mipmapped.get(&src_id) {
+            Some(mipmapped) if minified => SampledTexture(
+                mipmapped,
+                Filtering {
+                    mipmaps: true,
+                    ..filtering
+                }
+                .sampler(true),
+            ),
+            _ => src.texture.sampled(filtering),
+        };
+        // The border goes around what is visible of the window
+        let bounds = match &shape {
+            Some(shape) => shape_bounds(shape, width, height),
+            None => glium::Rect {
+                left: 0,
+                bottom: 0,
+                width,
+                height,
+            },
+        };
         let uniform = uniform! {
-            tex: &src.texture
+            tex: tex,
+            brightness: brightness,
+            gamma: gamma,
+            color_matrix: color,
+            decode: decode,
         };
         let vbo = glium::VertexBuffer::new(
             &self.glium,
@@ -483,45 +1178,174 @@ impl GlInner {
         //    .unwrap()
         //    .as_secs_f64();
         //let color = f64::sin(time * 2.0);
-        //log::info!("!!{} {}", color, time);
+        //tracing::info!("!!{} {}", color, time);
         //fb.clear_color(color as f32, 0.0, 1.0, 1.0);
-        fb.draw(
-            &vbo,
-            &indices,
-            &self.blit_shader,
-            &uniform,
-            &Default::default(),
-        )?;
+        match shape {
+            Some(shape) => {
+                fb.clear(
+                    scissor.as_ref(),
+                    Some((0.0, 0.0, 0.0, 0.0)),
+                    false,
+                    None,
+                    None,
+                );
+                for rect in shape {
+                    // Row 0 of the X pixmap is at the bottom of the texture, so X coordinates can
+                    // be used as is.
+                    let rect = glium::Rect {
+                        left: rect.x.max(0) as _,
+                        bottom: rect.y.max(0) as _,
+                        width: rect.width.into(),
+                        height: rect.height.into(),
+                    };
+                    let rect = match scissor {
+                        Some(scissor) => match intersect(rect, scissor) {
+                            Some(rect) => rect,
+                            None => continue,
+                        },
+                        None => rect,
+                    };
+                    let params = glium::DrawParameters {
+                        scissor: Some(rect),
+                        ..Default::default()
+                    };
+                    fb.draw(&vbo, &indices, &self.blit_shader, &uniform, &params)?;
+                }
+            }
+            None => {
+                let params = glium::DrawParameters {
+                    scissor,
+                    ..Default::default()
+                };
+                fb.draw(&vbo, &indices, &self.blit_shader, &uniform, &params)?
+            }
+        }
+        if let Some(Border { width, color }) = border {
+            let [r, g, b, a] = color;
+            let [r, g, b] = if decode {
+                [r, g, b].map(srgb_to_linear)
+            } else {
+                [r, g, b]
+            };
+            for rect in border_rects(bounds, width) {
+                // The color is sRGB already, like the blit shader's output
+                fb.clear(Some(&rect), Some((r, g, b, a)), true, None, None);
+            }
+        }
+        let overlay = overlay.and_then(|(overlay, width, height)| {
+            // Row 0 of the X pixmap is at the bottom of the texture, so this is the top left
+            // corner of the window.
+            let viewport = glium::Rect {
+                left: 0,
+                bottom: 0,
+                width,
+                height,
+            };
+            // Blended over the window, so only where the window was drawn again
+            let clip = match scissor {
+                Some(scissor) => intersect(viewport, scissor)?,
+                None => viewport,
+            };
+            Some((overlay, viewport, clip))
+        });
+        if let Some((overlay, viewport, clip)) = overlay {
+            use glium::{BlendingFunction, LinearBlendingFactor};
+            let overlay = self
+                .textures
+                .get(&overlay)
+                .ok_or(Error::UnknownTexture(overlay))?;
+            let params = glium::DrawParameters {
+                blend: glium::Blend {
+                    color: BlendingFunction::Addition {
+                        source: LinearBlendingFactor::SourceAlpha,
+                        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                    },
+                    // Keep the window opaque where it was
+                    alpha: BlendingFunction::Addition {
+                        source: LinearBlendingFactor::One,
+                        destination: LinearBlendingFactor::OneMinusSourceAlpha,
+                    },
+                    constant_value: (0.0, 0.0, 0.0, 0.0),
+                },
+                viewport: Some(viewport),
+                scissor: Some(clip),
+                ..Default::default()
+            };
+            fb.draw(
+                &vbo,
+                &indices,
+                &self.blit_shader,
+                &uniform! {
+                    tex: overlay.texture.sampled(self.filtering),
+                    brightness: brightness,
+                    gamma: gamma,
+                    color_matrix: color,
+                    decode: decode,
+                },
+                &params,
+            )?;
+        }
         self.glium.get_context().finish();
+        if self.glium.get_context().is_context_lost() {
+            return Err(Error::ContextLost);
+        }
         Ok(())
     }
-    fn import_fd(&mut self, width: u32, height: u32, fd: RawFd, size: u64) -> Result<Texture> {
+    fn import_fd(
+        &mut self,
+        width: u32,
+        height: u32,
+        fd: RawFd,
+        size: u64,
+        format: SharedFormat,
+    ) -> Result<Texture> {
         use glium::texture::{
             Dimensions, ExternalTilingMode, ImportParameters, MipmapsOption, SrgbFormat,
+            UncompressedFloatFormat,
         };
-        use glium::GlObject;
         use std::os::unix::io::FromRawFd;
-        let texture = unsafe {
-            SrgbTexture2d::new_from_fd(
-                &self.glium,
-                SrgbFormat::U8U8U8U8,
-                MipmapsOption::NoMipmap,
-                Dimensions::Texture2d { width, height },
-                ImportParameters {
-                    dedicated_memory: true,
-                    size,
-                    offset: 0,
-                    tiling: ExternalTilingMode::Optimal,
-                },
-                std::fs::File::from_raw_fd(fd),
-            )
-        }?;
-        let id = texture.get_id() as _;
+        let params = ImportParameters {
+            dedicated_memory: true,
+            size,
+            offset: 0,
+            tiling: ExternalTilingMode::Optimal,
+        };
+        let dimensions = Dimensions::Texture2d { width, height };
+        let file = unsafe { std::fs::File::from_raw_fd(fd) };
+        let texture = match format {
+            SharedFormat::Srgba8 => AnyTexture2d::Srgb(unsafe {
+                SrgbTexture2d::new_from_fd(
+                    &self.glium,
+                    SrgbFormat::U8U8U8U8,
+                    MipmapsOption::NoMipmap,
+                    dimensions,
+                    params,
+                    file,
+                )
+            }?),
+            SharedFormat::Rgba8 | SharedFormat::Rgb10A2 => AnyTexture2d::Linear(unsafe {
+                Texture2d::new_from_fd(
+                    &self.glium,
+                    if format == SharedFormat::Rgb10A2 {
+                        UncompressedFloatFormat::U10U10U10U2
+                    } else {
+                        UncompressedFloatFormat::U8U8U8U8
+                    },
+                    MipmapsOption::NoMipmap,
+                    dimensions,
+                    params,
+                    file,
+                )
+            }?),
+        };
+        let id = texture.id() as _;
         self.textures.insert(
             id,
             TextureInner {
-                texture: AnyTexture2d::Srgb(texture),
+                texture,
+                linear_light: format.linear_light(),
                 glxpixmap: None,
+                x11_pixmap: None,
             },
         );
         Ok(Texture { id, width, height })
@@ -533,6 +1357,8 @@ use crate::utils::Remote;
 #[derive(Clone, Debug)]
 pub struct Gl {
     inner: Remote<GlInner>,
+    /// Whether textures of each format could be shared with xrdesktop, see `format_support`
+    formats: Arc<std::sync::Mutex<HashMap<SharedFormat, bool>>>,
 }
 
 #[allow(clippy::all)]
@@ -544,20 +1370,72 @@ use crate::gen_remote_fn;
 
 #[allow(dead_code)]
 impl Gl {
-    pub async fn new(x11: Arc<RustConnection>, screen: u32) -> Result<Self> {
+    pub async fn new(x11: Arc<RustConnection>, screen: u32, filtering: Filtering) -> Result<Self> {
         Ok(Self {
-            inner: Remote::new(move || GlInner::new(x11, screen)).await?,
+            inner: Remote::new(move || GlInner::new(x11, screen, filtering)).await?,
+            formats: Default::default(),
         })
     }
 
-    gen_remote_fn!(import_fd(width: u32, height: u32, fd: RawFd, size: u64) -> Texture);
+    gen_remote_fn!(
+        import_fd(width: u32, height: u32, fd: RawFd, size: u64, format: SharedFormat) -> Texture
+    );
     gen_remote_fn!(bind_texture(pixmap: xproto::Pixmap, visual: xproto::Visualid) -> Texture);
     gen_remote_fn!(capture(start: bool) -> ());
+    gen_remote_fn!(check_colors() -> ());
+    gen_remote_fn!(reset_context() -> ());
     gen_remote_fn!(release_texture(texture: Texture) -> ());
-    pub async fn blit(&self, src: &Texture, dst: &Texture) -> Result<()> {
+    gen_remote_fn!(upload_texture(width: u32, height: u32, data: Vec<u8>) -> Texture);
+    /// Copy `src` into `dst`. If `region` of `src` is given, only the part of `dst` showing it is
+    /// drawn again, the rest keeps what was blitted before. If `overlay` is given, it is drawn
+    /// over the top left corner, at the given width and height in pixels. If `shape` is given,
+    /// pixels outside of it are transparent. If `border` is given, it is drawn just inside the
+    /// bounding box of `shape`, or the edges of `dst`. The colors of `src` and `overlay` are
+    /// changed by `adjustment`. `src` is sampled according to `filtering`, `overlay` according to
+    /// the global filtering settings.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn blit(
+        &self,
+        src: &Texture,
+        dst: &Texture,
+        region: Option<xproto::Rectangle>,
+        overlay: Option<(&Texture, u32, u32)>,
+        shape: Option<&[xproto::Rectangle]>,
+        border: Option<Border>,
+        adjustment: Adjustment,
+        filtering: Filtering,
+    ) -> Result<()> {
         let src = src.id;
         let dst = dst.id;
-        self.inner.call(move |inner| inner.blit(src, dst)).await?
+        let overlay = overlay.map(|(o, width, height)| (o.id, width, height));
+        let shape = shape.map(<[_]>::to_vec);
+        self.inner
+            .call(move |inner| {
+                inner.blit(
+                    src, dst, region, overlay, shape, border, adjustment, filtering,
+                )
+            })
+            .await?
+    }
+    /// Whether textures of `format` could be shared with xrdesktop when last tried, None if
+    /// they weren't tried yet.
+    pub fn format_support(&self, format: SharedFormat) -> Option<bool> {
+        self.formats.lock().unwrap().get(&format).copied()
+    }
+    pub fn set_format_support(&self, format: SharedFormat, supported: bool) {
+        self.formats.lock().unwrap().insert(format, supported);
+    }
+    /// Forget which formats could be shared, for when the VR runtime is restarted, possibly on
+    /// another driver.
+    pub fn forget_formats(&self) {
+        self.formats.lock().unwrap().clear();
+    }
+    /// Export the pixmap bound to `texture` by `bind_texture`.
+    pub async fn export_dmabuf(&self, texture: &Texture) -> Result<DmaBuf> {
+        let id = texture.id;
+        self.inner
+            .call(move |inner| inner.export_dmabuf(id))
+            .await?
     }
     #[allow(dead_code)]
     pub async fn with_glium<R: 'static + Send>(
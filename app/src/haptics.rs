@@ -0,0 +1,181 @@
+//! Controller vibration when the pointer moves onto a window and when clicking, see
+//! `[haptics]`. xrdesktop vibrates through a haptic action of its own, but doesn't expose it, so
+//! another one for the same binding is made here.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use glib::{prelude::*, translate::from_glib_full};
+use xrd::ClientExt;
+
+use crate::config::HapticsConfig;
+
+const ACTION_SET: &[u8] = b"/actions/wm\0";
+const ACTION: &[u8] = b"/actions/wm/out/haptic\0";
+const HOVER_START: &[u8] = b"hover-start-event\0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pulse {
+    /// The pointer moved onto a window
+    Hover,
+    /// A button was pressed on a window
+    Click,
+}
+
+/// Drops pulses that come too soon after the last one of the same controller
+#[derive(Debug)]
+pub struct Limiter {
+    interval: Duration,
+    last: HashMap<u64, Instant>,
+}
+
+impl Limiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn allow(&mut self, controller: u64, now: Instant) -> bool {
+        match self.last.get(&controller) {
+            Some(last) if now.saturating_duration_since(*last) < self.interval => false,
+            _ => {
+                self.last.insert(controller, now);
+                true
+            }
+        }
+    }
+}
+
+pub struct Haptics {
+    // The action refers to its set
+    _action_set: glib::Object,
+    action: glib::Object,
+    config: HapticsConfig,
+    /// Only hover pulses are limited, every click is felt
+    hover_limiter: Mutex<Limiter>,
+}
+
+impl Haptics {
+    /// None if haptics are disabled, or the runtime has no haptic action. Has to be called on
+    /// the thread owning the glib main context, like `connect_client_signals`.
+    pub fn new(client: &xrd::Client, config: &HapticsConfig) -> Option<Self> {
+        if !config.enabled {
+            return None;
+        }
+        let context = client.gxr_context()?;
+        unsafe {
+            let action_set = gxr::sys::gxr_action_set_new_from_url(
+                context.as_ptr(),
+                ACTION_SET.as_ptr() as *mut _,
+            );
+            if action_set.is_null() {
+                return None;
+            }
+            let action_set: glib::Object = from_glib_full(action_set as *mut gobject_sys::GObject);
+            let action = gxr::sys::gxr_action_new_from_type_url(
+                context.as_ptr(),
+                action_set.as_ptr() as *mut _,
+                gxr::sys::GXR_ACTION_HAPTIC,
+                ACTION.as_ptr() as *mut _,
+            );
+            if action.is_null() {
+                tracing::warn!("The VR runtime has no haptic action, controllers won't vibrate");
+                return None;
+            }
+            Some(Self {
+                _action_set: action_set,
+                action: from_glib_full(action as *mut gobject_sys::GObject),
+                config: config.clone(),
+                hover_limiter: Mutex::new(Limiter::new(config.min_interval())),
+            })
+        }
+    }
+
+    /// Vibrate `controller` for `pulse`.
+    ///
+    /// # Safety
+    ///
+    /// `controller` has to be null or a controller passed in an xrdesktop event, on the thread
+    /// owning the glib main context.
+    pub unsafe fn pulse(&self, controller: *mut gxr::sys::GxrController, pulse: Pulse) {
+        if controller.is_null() {
+            return;
+        }
+        let handle = gxr::sys::gxr_device_get_handle(controller as *mut gxr::sys::GxrDevice);
+        let (duration, amplitude) = match pulse {
+            Pulse::Hover => {
+                let now = Instant::now();
+                if !self.hover_limiter.lock().unwrap().allow(handle, now) {
+                    return;
+                }
+                (self.config.hover_duration(), self.config.hover_amplitude)
+            }
+            Pulse::Click => (self.config.click_duration(), self.config.click_amplitude),
+        };
+        if duration.is_zero() {
+            return;
+        }
+        let triggered = gxr::sys::gxr_action_trigger_haptic(
+            self.action.as_ptr() as *mut _,
+            0.0,
+            duration.as_secs_f32(),
+            self.config.frequency,
+            amplitude.clamp(0.0, 1.0),
+            handle,
+        );
+        if triggered == 0 {
+            tracing::debug!("Failed to vibrate controller {handle}");
+        }
+    }
+
+    /// Pulse when a controller's pointer moves onto `window`.
+    pub fn connect_hover(self: &Arc<Self>, window: &xrd::Window) {
+        // The generated binding passes the controller on as a GdkEvent, so connect by hand
+        unsafe extern "C" fn hover_start(
+            _window: *mut xrd::sys::XrdWindow,
+            controller: *mut gxr::sys::GxrController,
+            data: glib::ffi::gpointer,
+        ) {
+            let haptics = &*(data as *const Haptics);
+            haptics.pulse(controller, Pulse::Hover);
+        }
+        unsafe extern "C" fn free(data: glib::ffi::gpointer, _closure: *mut gobject_sys::GClosure) {
+            drop(Arc::from_raw(data as *const Haptics));
+        }
+        type Callback = unsafe extern "C" fn(
+            *mut xrd::sys::XrdWindow,
+            *mut gxr::sys::GxrController,
+            glib::ffi::gpointer,
+        );
+        unsafe {
+            gobject_sys::g_signal_connect_data(
+                window.as_ptr() as *mut _,
+                HOVER_START.as_ptr() as *const _,
+                Some(std::mem::transmute(hover_start as Callback)),
+                Arc::into_raw(self.clone()) as _,
+                Some(free),
+                0,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limited_per_controller() {
+        let mut limiter = Limiter::new(Duration::from_millis(100));
+        let start = Instant::now();
+        assert!(limiter.allow(1, start));
+        assert!(!limiter.allow(1, start + Duration::from_millis(50)));
+        assert!(limiter.allow(2, start + Duration::from_millis(50)));
+        assert!(limiter.allow(1, start + Duration::from_millis(100)));
+        assert!(!limiter.allow(1, start + Duration::from_millis(150)));
+    }
+}
@@ -0,0 +1,125 @@
+//! Parsing of the EWMH `_NET_WM_ICON` property.
+
+/// Size, in pixels, of the longer side of icons when they are drawn.
+pub const ICON_SIZE: u32 = 32;
+
+/// One of the icons in `_NET_WM_ICON`. Pixels are ARGB, not premultiplied, row by row starting
+/// from the top left corner.
+#[derive(Debug)]
+pub struct Icon<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u32],
+}
+
+impl Icon<'_> {
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|p| {
+                let [b, g, r, a] = p.to_le_bytes();
+                [r, g, b, a]
+            })
+            .collect()
+    }
+}
+
+/// Split the content of `_NET_WM_ICON` into icons. Stops at the first malformed entry.
+pub fn parse(mut data: &[u32]) -> Vec<Icon<'_>> {
+    let mut icons = Vec::new();
+    while let [width, height, rest @ ..] = data {
+        let len = *width as usize * *height as usize;
+        if len == 0 || len > rest.len() {
+            break;
+        }
+        icons.push(Icon {
+            width: *width,
+            height: *height,
+            pixels: &rest[..len],
+        });
+        data = &rest[len..];
+    }
+    icons
+}
+
+/// Pick the smallest icon that is at least `size` pixels big, or the biggest icon if they are all
+/// smaller than that.
+pub fn pick<'a, 'b>(icons: &'b [Icon<'a>], size: u32) -> Option<&'b Icon<'a>> {
+    icons
+        .iter()
+        .filter(|i| i.width.min(i.height) >= size)
+        .min_by_key(|i| i.width * i.height)
+        .or_else(|| icons.iter().max_by_key(|i| i.width * i.height))
+}
+
+/// Size to draw a `width` x `height` icon at, so its longer side is `size` pixels and it keeps
+/// its aspect ratio.
+pub fn fit(width: u32, height: u32, size: u32) -> (u32, u32) {
+    let longer = u64::from(width.max(height).max(1));
+    let scale = |side: u32| ((u64::from(side) * u64::from(size) + longer / 2) / longer).max(1);
+    (scale(width) as u32, scale(height) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `_NET_WM_ICON` with a 2x1 and a 1x1 icon
+    const DATA: [u32; 7] = [2, 1, 0xff102030, 0x80405060, 1, 1, 0xffffffff];
+
+    #[test]
+    fn parsed() {
+        let icons = parse(&DATA);
+        assert_eq!(icons.len(), 2);
+        assert_eq!((icons[0].width, icons[0].height), (2, 1));
+        assert_eq!(icons[0].pixels, &[0xff102030, 0x80405060]);
+        assert_eq!((icons[1].width, icons[1].height), (1, 1));
+        // Truncated, or claiming more pixels than there are
+        assert_eq!(parse(&DATA[..5]).len(), 1);
+        assert_eq!(parse(&[4, 4, 0]).len(), 0);
+        assert_eq!(parse(&[0, 4]).len(), 0);
+    }
+
+    #[test]
+    fn picked() {
+        let pixels = [0; 64 * 64];
+        let icon = |width, height| Icon {
+            width,
+            height,
+            pixels: &pixels[..(width * height) as usize],
+        };
+        let icons = [icon(64, 64), icon(16, 16), icon(48, 32), icon(32, 48)];
+        // Both sides have to be big enough
+        assert_eq!(
+            pick(&icons, 32).map(|i| (i.width, i.height)),
+            Some((48, 32))
+        );
+        assert_eq!(
+            pick(&icons, 40).map(|i| (i.width, i.height)),
+            Some((64, 64))
+        );
+        assert_eq!(
+            pick(&icons, 128).map(|i| (i.width, i.height)),
+            Some((64, 64))
+        );
+        assert!(pick(&[], 32).is_none());
+    }
+
+    #[test]
+    fn converted() {
+        let icons = parse(&DATA);
+        assert_eq!(
+            icons[0].to_rgba(),
+            [0x10, 0x20, 0x30, 0xff, 0x40, 0x50, 0x60, 0x80]
+        );
+    }
+
+    #[test]
+    fn fitted() {
+        assert_eq!(fit(64, 64, 32), (32, 32));
+        assert_eq!(fit(16, 16, 32), (32, 32));
+        assert_eq!(fit(48, 32, 32), (32, 21));
+        assert_eq!(fit(32, 48, 32), (21, 32));
+        assert_eq!(fit(1000, 1, 32), (32, 1));
+    }
+}
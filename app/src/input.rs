@@ -0,0 +1,521 @@
+//! State machines used to turn xrdesktop input events into X input.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::config::DoubleClickConfig;
+
+/// How a window's texture relates to the window: texture pixel `(x, y)` shows window pixel
+/// `(crop_x + x * scale_x, crop_y + y * scale_y)`. Recorded when the texture is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputTransform {
+    pub scale_x: f32,
+    pub scale_y: f32,
+    pub crop_x: f32,
+    pub crop_y: f32,
+}
+
+impl Default for InputTransform {
+    fn default() -> Self {
+        Self {
+            scale_x: 1.0,
+            scale_y: 1.0,
+            crop_x: 0.0,
+            crop_y: 0.0,
+        }
+    }
+}
+
+impl InputTransform {
+    /// Transform for a `texture_width`x`texture_height` texture showing the `width`x`height`
+    /// area of the window starting at `(crop_x, crop_y)`.
+    pub fn new(
+        (crop_x, crop_y): (u32, u32),
+        (width, height): (u32, u32),
+        (texture_width, texture_height): (u32, u32),
+    ) -> Self {
+        Self {
+            scale_x: width as f32 / texture_width.max(1) as f32,
+            scale_y: height as f32 / texture_height.max(1) as f32,
+            crop_x: crop_x as f32,
+            crop_y: crop_y as f32,
+        }
+    }
+
+    /// Translate a position in texture space to window coordinates.
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            self.crop_x + x * self.scale_x,
+            self.crop_y + y * self.scale_y,
+        )
+    }
+}
+
+/// Where a mirrored window is on the root window, and how its texture in VR maps to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Placement {
+    /// Top left corner of the window on the root window, outside of its border
+    pub x: i16,
+    pub y: i16,
+    /// Size of the window, not including the border
+    pub width: u16,
+    pub height: u16,
+    pub border_width: u16,
+    pub transform: InputTransform,
+}
+
+impl Placement {
+    pub fn new(
+        geometry: &x11rb::protocol::xproto::GetGeometryReply,
+        transform: InputTransform,
+    ) -> Self {
+        Self {
+            x: geometry.x,
+            y: geometry.y,
+            width: geometry.width,
+            height: geometry.height,
+            border_width: geometry.border_width,
+            transform,
+        }
+    }
+
+    /// Translate a position in texture space to root window coordinates. The position is
+    /// clamped to the window, so a drag that left the window finishes on its edge.
+    pub fn to_root(&self, x: f32, y: f32) -> (i32, i32) {
+        let (x, y) = self.transform.apply(x, y);
+        let x = x.min(self.width as f32 - 1.0).max(0.0);
+        let y = y.min(self.height as f32 - 1.0).max(0.0);
+        let left = self.x as i32 + self.border_width as i32;
+        let top = self.y as i32 + self.border_width as i32;
+        (left + x as i32, top + y as i32)
+    }
+}
+
+/// Accumulates analog scroll amounts into discrete wheel detents. Amounts are in "detent" units,
+/// i.e. `1.0` is one notch of a traditional mouse wheel.
+#[derive(Debug, Default)]
+pub struct ScrollAccumulator {
+    x: f64,
+    y: f64,
+}
+
+impl ScrollAccumulator {
+    /// Add `(dx, dy)` to the accumulator, and returns how many whole detents have been crossed on
+    /// each axis. Leftovers are kept, so slow scrolling eventually produces a detent too.
+    pub fn accumulate(&mut self, dx: f64, dy: f64, threshold: f64) -> (i32, i32) {
+        self.x += dx;
+        self.y += dy;
+        let steps_x = (self.x / threshold).trunc();
+        let steps_y = (self.y / threshold).trunc();
+        self.x -= steps_x * threshold;
+        self.y -= steps_y * threshold;
+        (steps_x as i32, steps_y as i32)
+    }
+}
+
+/// X button emulating a wheel detent in the direction of `steps`. Positive vertical steps
+/// scroll down, positive horizontal steps scroll right.
+pub fn scroll_button(steps: i32, vertical: bool) -> i32 {
+    match (vertical, steps > 0) {
+        (true, false) => xrd::sys::SCROLL_UP,
+        (true, true) => xrd::sys::SCROLL_DOWN,
+        (false, false) => xrd::sys::SCROLL_LEFT,
+        (false, true) => xrd::sys::SCROLL_RIGHT,
+    }
+}
+
+/// Buttons as reported by xrdesktop's click events. Which physical controller button generates
+/// which of these is decided by the xrdesktop action bindings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControllerButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl ControllerButton {
+    pub fn from_xrd(button: xrd::sys::XrdInputSynthButton) -> Option<Self> {
+        match button {
+            xrd::sys::LEFT_BUTTON => Some(Self::Left),
+            xrd::sys::MIDDLE_BUTTON => Some(Self::Middle),
+            xrd::sys::RIGHT_BUTTON => Some(Self::Right),
+            _ => None,
+        }
+    }
+}
+
+/// What the stick does while a gesture button is held down on a window, instead of scrolling
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    /// `[scale-gesture]`
+    Scale,
+    /// `[brightness-gesture]`
+    Brightness,
+}
+
+/// A button held down on a window. Press and release always happen on the same window and X
+/// button, even if the pointer left the window, or the button mapping changed in between.
+#[derive(Debug, Clone, Copy)]
+pub struct Drag {
+    pub wid: u32,
+    pub button: u8,
+    /// Last pointer position inside `wid`
+    pub x: f32,
+    pub y: f32,
+}
+
+/// Last press, used to recognize multi-clicks.
+#[derive(Debug, Clone, Copy)]
+struct LastPress {
+    wid: u32,
+    button: ControllerButton,
+    x: f32,
+    y: f32,
+    time: Instant,
+    count: u32,
+}
+
+/// Recognizes double and triple clicks. VR pointers jitter a lot more than a mouse, so presses
+/// that are close enough in time and space are moved to the exact position of the first one,
+/// otherwise applications see them as unrelated clicks.
+#[derive(Debug, Default)]
+pub struct ClickTracker {
+    last: Option<LastPress>,
+}
+
+impl ClickTracker {
+    fn anchor(
+        &self,
+        wid: u32,
+        x: f32,
+        y: f32,
+        now: Instant,
+        config: &DoubleClickConfig,
+    ) -> Option<&LastPress> {
+        self.last.as_ref().filter(|last| {
+            last.wid == wid
+                && now.saturating_duration_since(last.time) <= config.interval()
+                && (last.x - x).hypot(last.y - y) <= config.tolerance
+        })
+    }
+
+    /// Position the pointer should be at. Positions close to the last press are snapped to it
+    /// while a multi-click can still happen.
+    pub fn snap(
+        &self,
+        wid: u32,
+        x: f32,
+        y: f32,
+        now: Instant,
+        config: &DoubleClickConfig,
+    ) -> (f32, f32) {
+        self.anchor(wid, x, y, now, config)
+            .map_or((x, y), |last| (last.x, last.y))
+    }
+
+    /// Register a press, returns where it should happen and how many clicks in a row it makes.
+    pub fn press(
+        &mut self,
+        wid: u32,
+        button: ControllerButton,
+        x: f32,
+        y: f32,
+        now: Instant,
+        config: &DoubleClickConfig,
+    ) -> (f32, f32, u32) {
+        let (x, y, count) = match self.anchor(wid, x, y, now, config) {
+            Some(last) if last.button == button => (last.x, last.y, last.count + 1),
+            _ => (x, y, 1),
+        };
+        self.last = Some(LastPress {
+            wid,
+            button,
+            x,
+            y,
+            time: now,
+            count,
+        });
+        (x, y, count)
+    }
+}
+
+/// Identifies a held button: (controller, button).
+pub type DragKey = (usize, ControllerButton);
+
+/// Sticky modifiers for the VR keyboard, which can't hold a modifier while another key is
+/// pressed. Tapping a modifier latches it for the next key, tapping it again locks it until it
+/// is tapped a third time. Everything is released when input focus moves to another window.
+#[derive(Debug, Default)]
+pub struct StickyModifiers {
+    /// Active modifier keysyms, and whether they are locked
+    active: Vec<(u32, bool)>,
+    /// Window that had input focus when we last saw a key
+    focus: Option<u32>,
+}
+
+impl StickyModifiers {
+    pub fn is_modifier(keysym: u32) -> bool {
+        // Shift_L ..= Hyper_R, and ISO_Level3_Shift
+        matches!(keysym, 0xffe1..=0xffee | 0xfe03)
+    }
+
+    fn check_focus(&mut self, focus: u32) {
+        if self.focus != Some(focus) {
+            self.active.clear();
+            self.focus = Some(focus);
+        }
+    }
+
+    /// A modifier key was tapped, with `focus` being the window with input focus.
+    pub fn tap(&mut self, keysym: u32, focus: u32) {
+        self.check_focus(focus);
+        match self.active.iter().position(|&(k, _)| k == keysym) {
+            None => self.active.push((keysym, false)),
+            Some(i) if !self.active[i].1 => self.active[i].1 = true,
+            Some(i) => {
+                self.active.remove(i);
+            }
+        }
+    }
+
+    /// A non-modifier key was tapped, returns the modifiers to hold down around it, in the
+    /// order they were tapped. Modifiers that are only latched are released.
+    pub fn key(&mut self, focus: u32) -> Vec<u32> {
+        self.check_focus(focus);
+        let held = self.active.iter().map(|&(k, _)| k).collect();
+        self.active.retain(|&(_, locked)| locked);
+        held
+    }
+}
+
+/// Auto-repeat timing of held VR keyboard keys
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyRepeat {
+    pub delay: Duration,
+    pub interval: Duration,
+}
+
+impl KeyRepeat {
+    /// X server defaults, used if the server doesn't tell us its settings
+    pub const DEFAULT: Self = Self {
+        delay: Duration::from_millis(660),
+        interval: Duration::from_millis(40),
+    };
+}
+
+#[derive(Debug, Default)]
+pub struct InputState {
+    /// Last known pointer position, as (window, x, y) in window local coordinates.
+    pub pointer: Option<(u32, f32, f32)>,
+    pub scroll: ScrollAccumulator,
+    pub clicks: ClickTracker,
+    /// Buttons currently held down, per controller
+    pub drags: HashMap<DragKey, Drag>,
+    /// Window being moved by xrdesktop's window manager. Input isn't forwarded while a window
+    /// is being grabbed, so moving a VR window doesn't turn into a drag inside the application.
+    pub grabbed: Option<u32>,
+    /// Pending focus-follows-hover activation, for the window the pointer is on
+    pub hover_timer: Option<tokio::task::JoinHandle<()>>,
+    /// Pending `[toggle-mirroring]` long press, while its button is held down
+    pub long_press_timer: Option<tokio::task::JoinHandle<()>>,
+    /// Window being scaled or dimmed, while the button of the gesture is held down on it
+    pub gesture: Option<(u32, Gesture)>,
+    /// Window of the last press, closed by the close button in VR
+    pub last_clicked: Option<u32>,
+    pub modifiers: StickyModifiers,
+    /// Repeats the held VR keyboard key
+    pub key_repeat: Option<tokio::task::JoinHandle<()>>,
+    /// Whether the VR keyboard reports key releases. Without them we can't tell when a key is
+    /// let go, so keys are only repeated once a release was seen.
+    pub key_releases: bool,
+}
+
+impl InputState {
+    /// Start a drag, returns the X button to press, or None if `button` is not mapped.
+    pub fn press(
+        &mut self,
+        mapping: &HashMap<ControllerButton, u8>,
+        key: DragKey,
+        wid: u32,
+        x: f32,
+        y: f32,
+    ) -> Option<u8> {
+        let button = *mapping.get(&key.1)?;
+        self.drags.insert(key, Drag { wid, button, x, y });
+        Some(button)
+    }
+
+    /// Finish a drag, returns the drag that should be released.
+    pub fn release(&mut self, key: DragKey) -> Option<Drag> {
+        self.drags.remove(&key)
+    }
+
+    /// Update drags with the new pointer position. Drags on windows other than `wid` are
+    /// finished and returned, they should be released at their last position.
+    pub fn motion(&mut self, wid: u32, x: f32, y: f32) -> Vec<Drag> {
+        self.pointer = Some((wid, x, y));
+        let mut finished = Vec::new();
+        self.drags.retain(|_, drag| {
+            if drag.wid == wid {
+                drag.x = x;
+                drag.y = y;
+                true
+            } else {
+                finished.push(*drag);
+                false
+            }
+        });
+        finished
+    }
+
+    /// Finish all drags, returns the drags that should be released.
+    pub fn release_all(&mut self) -> Vec<Drag> {
+        self.drags.drain().map(|(_, drag)| drag).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placement(transform: InputTransform) -> Placement {
+        Placement {
+            x: 100,
+            y: 50,
+            width: 800,
+            height: 600,
+            border_width: 0,
+            transform,
+        }
+    }
+
+    #[test]
+    fn to_root_identity() {
+        let p = placement(Default::default());
+        assert_eq!(p.to_root(0.0, 0.0), (100, 50));
+        assert_eq!(p.to_root(10.5, 20.5), (110, 70));
+    }
+
+    #[test]
+    fn to_root_scaled() {
+        // Texture at half the window's resolution
+        let p = placement(InputTransform::new((0, 0), (800, 600), (400, 300)));
+        assert_eq!(p.to_root(10.0, 20.0), (120, 90));
+        assert_eq!(p.to_root(399.0, 299.0), (898, 648));
+    }
+
+    #[test]
+    fn to_root_mip_level() {
+        // A distant window shared at mip level 2
+        let p = placement(InputTransform::new(
+            (0, 0),
+            (800, 600),
+            (800 >> 2, 600 >> 2),
+        ));
+        assert_eq!(p.to_root(50.0, 50.0), (300, 250));
+        assert_eq!(p.to_root(199.9, 149.9), (899, 649));
+    }
+
+    #[test]
+    fn to_root_cropped() {
+        // Texture only shows the window without a 10 pixel frame, and a 30 pixel title bar
+        let p = placement(InputTransform::new((10, 30), (780, 560), (780, 560)));
+        assert_eq!(p.to_root(0.0, 0.0), (110, 80));
+        assert_eq!(p.to_root(779.0, 559.0), (889, 639));
+    }
+
+    #[test]
+    fn to_root_scaled_and_cropped() {
+        let p = placement(InputTransform::new((10, 30), (780, 560), (390, 280)));
+        assert_eq!(p.to_root(0.0, 0.0), (110, 80));
+        assert_eq!(p.to_root(100.0, 100.0), (310, 280));
+        assert_eq!(p.to_root(389.5, 279.5), (889, 639));
+    }
+
+    #[test]
+    fn to_root_border() {
+        let p = Placement {
+            border_width: 2,
+            ..placement(Default::default())
+        };
+        assert_eq!(p.to_root(0.0, 0.0), (102, 52));
+        assert_eq!(p.to_root(799.0, 599.0), (901, 651));
+    }
+
+    #[test]
+    fn to_root_negative_position() {
+        // Window on a monitor left of and above the primary one
+        let p = Placement {
+            x: -1920,
+            y: -200,
+            ..placement(InputTransform::new((0, 0), (800, 600), (400, 300)))
+        };
+        assert_eq!(p.to_root(0.0, 0.0), (-1920, -200));
+        assert_eq!(p.to_root(200.0, 150.0), (-1520, 100));
+    }
+
+    #[test]
+    fn to_root_clamped() {
+        let p = Placement {
+            x: -10,
+            y: -20,
+            ..placement(Default::default())
+        };
+        assert_eq!(p.to_root(-5.0, -5.0), (-10, -20));
+        assert_eq!(p.to_root(1000.0, 1000.0), (789, 579));
+    }
+
+    const CONTROL_L: u32 = 0xffe3;
+    const SHIFT_L: u32 = 0xffe1;
+
+    #[test]
+    fn sticky_latch() {
+        let mut m = StickyModifiers::default();
+        m.tap(CONTROL_L, 1);
+        assert_eq!(m.key(1), vec![CONTROL_L]);
+        assert_eq!(m.key(1), vec![]);
+    }
+
+    #[test]
+    fn sticky_combination() {
+        let mut m = StickyModifiers::default();
+        m.tap(CONTROL_L, 1);
+        m.tap(SHIFT_L, 1);
+        assert_eq!(m.key(1), vec![CONTROL_L, SHIFT_L]);
+        assert_eq!(m.key(1), vec![]);
+    }
+
+    #[test]
+    fn sticky_lock() {
+        let mut m = StickyModifiers::default();
+        m.tap(CONTROL_L, 1);
+        m.tap(CONTROL_L, 1);
+        m.tap(SHIFT_L, 1);
+        assert_eq!(m.key(1), vec![CONTROL_L, SHIFT_L]);
+        assert_eq!(m.key(1), vec![CONTROL_L]);
+        m.tap(CONTROL_L, 1);
+        assert_eq!(m.key(1), vec![]);
+    }
+
+    #[test]
+    fn sticky_focus_change() {
+        let mut m = StickyModifiers::default();
+        m.tap(CONTROL_L, 1);
+        m.tap(CONTROL_L, 1);
+        assert_eq!(m.key(2), vec![]);
+        m.tap(SHIFT_L, 2);
+        assert_eq!(m.key(3), vec![]);
+    }
+
+    #[test]
+    fn is_modifier() {
+        assert!(StickyModifiers::is_modifier(CONTROL_L));
+        assert!(StickyModifiers::is_modifier(0xfe03));
+        assert!(!StickyModifiers::is_modifier(0x61));
+        assert!(!StickyModifiers::is_modifier(0xff0d));
+    }
+}
@@ -0,0 +1,259 @@
+//! Lookup of the keys producing a keysym on the active keyboard layout.
+//!
+//! VR keyboard input arrives as text, so it has to be turned back into key presses. Assuming a
+//! US layout scrambles the text on anything else, so we build the table from the core keyboard
+//! mapping, which the X server keeps in sync with the XKB keymap.
+use std::collections::{HashMap, HashSet};
+
+use x11rb::protocol::xproto::{Keycode, Keysym, ModMask};
+
+pub const BACKSPACE: Keysym = 0xff08;
+pub const TAB: Keysym = 0xff09;
+pub const SPACE: Keysym = 0x20;
+pub const RETURN: Keysym = 0xff0d;
+pub const ESCAPE: Keysym = 0xff1b;
+pub const DELETE: Keysym = 0xffff;
+pub const SHIFT_L: Keysym = 0xffe1;
+pub const ISO_LEVEL3_SHIFT: Keysym = 0xfe03;
+pub const MODE_SWITCH: Keysym = 0xff7e;
+
+/// Modifiers that have to be held to get a keysym out of a key.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    /// AltGr, i.e. the third and fourth shift levels.
+    pub level3: bool,
+}
+
+impl Modifiers {
+    pub const fn new(shift: bool, level3: bool) -> Self {
+        Self { shift, level3 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub keycode: Keycode,
+    pub modifiers: Modifiers,
+}
+
+/// Position in the core keysym list of each shift level of the first group. XKB puts the third
+/// and fourth level of the first group after the two levels of the second group.
+const LEVELS: [(usize, Modifiers); 4] = [
+    (0, Modifiers::new(false, false)),
+    (1, Modifiers::new(true, false)),
+    (4, Modifiers::new(false, true)),
+    (5, Modifiers::new(true, true)),
+];
+
+#[derive(Debug, Default)]
+pub struct Keymap {
+    keys: HashMap<Keysym, Key>,
+}
+
+impl Keymap {
+    /// Build the table from a `GetKeyboardMapping` reply covering keycodes starting at
+    /// `min_keycode`. Keycodes in `skip` are left out, we use those to bind keysyms ourselves.
+    pub fn new(
+        min_keycode: Keycode,
+        keysyms_per_keycode: u8,
+        keysyms: &[Keysym],
+        skip: &HashSet<Keycode>,
+    ) -> Self {
+        let mut keys = HashMap::new();
+        let per_keycode = keysyms_per_keycode.max(1) as usize;
+        // Prefer the lowest level across all keys, so e.g. '1' isn't typed as Shift+'!' key
+        // on layouts that have it on both.
+        for &(index, modifiers) in &LEVELS {
+            for (keysyms, keycode) in keysyms.chunks(per_keycode).zip(min_keycode..=u8::MAX) {
+                if skip.contains(&keycode) {
+                    continue;
+                }
+                let keysym = match level_keysym(keysyms, index) {
+                    Some(keysym) => keysym,
+                    None => continue,
+                };
+                keys.entry(keysym).or_insert(Key { keycode, modifiers });
+            }
+        }
+        Self { keys }
+    }
+
+    pub fn get(&self, keysym: Keysym) -> Option<Key> {
+        self.keys.get(&keysym).copied()
+    }
+
+    /// The key to hold for `modifiers`' shift levels.
+    pub fn modifier_keys(&self, modifiers: Modifiers) -> Vec<Keycode> {
+        let mut keycodes = Vec::new();
+        if modifiers.shift {
+            keycodes.extend(self.get(SHIFT_L).map(|k| k.keycode));
+        }
+        if modifiers.level3 {
+            keycodes.extend(
+                self.get(ISO_LEVEL3_SHIFT)
+                    .or_else(|| self.get(MODE_SWITCH))
+                    .map(|k| k.keycode),
+            );
+        }
+        keycodes
+    }
+}
+
+/// The keysym at `index` of a keycode's keysym list, filling in the shifted level of letters
+/// that only list their lowercase form, as the core protocol specifies.
+fn level_keysym(keysyms: &[Keysym], index: usize) -> Option<Keysym> {
+    match keysyms.get(index).copied() {
+        Some(x11rb::NO_SYMBOL) | None if index == 1 => match keysyms.first() {
+            Some(&base @ 0x61..=0x7a) => Some(base - 0x20),
+            _ => None,
+        },
+        Some(x11rb::NO_SYMBOL) | None => None,
+        keysym => keysym,
+    }
+}
+
+/// A key combination, like `Super+Shift+R`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    /// X modifier mask
+    pub modifiers: u16,
+    pub keysym: Keysym,
+}
+
+impl std::str::FromStr for Hotkey {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut parts: Vec<_> = s.split('+').map(str::trim).collect();
+        let key = parts.pop().unwrap_or_default();
+        let modifiers = parts.into_iter().try_fold(0, |mask, modifier| {
+            let modifier = match modifier.to_ascii_lowercase().as_str() {
+                "shift" => ModMask::SHIFT,
+                "ctrl" | "control" => ModMask::CONTROL,
+                "alt" | "mod1" => ModMask::M1,
+                "super" | "mod4" => ModMask::M4,
+                _ => return Err(format!("unknown modifier {modifier:?} in {s:?}")),
+            };
+            Ok(mask | u16::from(modifier))
+        })?;
+        let mut chars = key.chars();
+        let keysym = match (chars.next(), chars.next()) {
+            // Letters are bound to the lowercase keysym, the uppercase one needs Shift
+            (Some(ch), None) if ch.is_ascii_graphic() => ch.to_ascii_lowercase() as Keysym,
+            _ => match key.to_ascii_lowercase().as_str() {
+                "space" => SPACE,
+                "tab" => TAB,
+                "return" | "enter" => RETURN,
+                "escape" | "esc" => ESCAPE,
+                "backspace" => BACKSPACE,
+                "delete" => DELETE,
+                // F1 is 0xffbe, up to F35
+                f => match f.strip_prefix('f').and_then(|n| n.parse::<Keysym>().ok()) {
+                    Some(n @ 1..=35) => 0xffbe + n - 1,
+                    _ => return Err(format!("unknown key {key:?} in {s:?}")),
+                },
+            },
+        };
+        Ok(Self { modifiers, keysym })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: Modifiers = Modifiers::new(true, false);
+    const ALTGR: Modifiers = Modifiers::new(false, true);
+
+    fn key(keycode: Keycode, modifiers: Modifiers) -> Option<Key> {
+        Some(Key { keycode, modifiers })
+    }
+
+    /// Keycodes 8.. with 7 keysyms each, as XKB reports them.
+    fn keymap(keys: &[[Keysym; 7]]) -> Keymap {
+        Keymap::new(8, 7, &keys.concat(), &HashSet::new())
+    }
+
+    #[test]
+    fn us() {
+        let keymap = keymap(&[
+            [0x31, 0x21, 0x31, 0x21, 0, 0, 0],    // 1 !
+            [0x71, 0x51, 0x71, 0x51, 0, 0, 0],    // q Q
+            [0x61, 0x41, 0x61, 0x41, 0, 0, 0],    // a A
+            [SHIFT_L, 0, SHIFT_L, 0, 0, 0, 0],    // Shift_L
+            [ISO_LEVEL3_SHIFT, 0, 0, 0, 0, 0, 0], // AltGr
+        ]);
+        assert_eq!(keymap.get(0x31), key(8, Modifiers::default()));
+        assert_eq!(keymap.get(0x21), key(8, SHIFT));
+        assert_eq!(keymap.get(0x71), key(9, Modifiers::default()));
+        assert_eq!(keymap.get(0x41), key(10, SHIFT));
+        assert_eq!(keymap.get(0xe9), None);
+        assert_eq!(keymap.modifier_keys(SHIFT), vec![11]);
+        assert_eq!(keymap.modifier_keys(ALTGR), vec![12]);
+    }
+
+    #[test]
+    fn azerty() {
+        let keymap = keymap(&[
+            [0x26, 0x31, 0x26, 0x31, 0, 0, 0],       // & 1
+            [0xe9, 0x32, 0xe9, 0x32, 0x7e, 0, 0],    // é 2 ~
+            [0x61, 0x41, 0x61, 0x41, 0xe6, 0xc6, 0], // a A æ Æ
+            [0x71, 0x51, 0x71, 0x51, 0, 0, 0],       // q Q
+            [0x65, 0x45, 0x65, 0x45, 0x20ac, 0, 0],  // e E €
+        ]);
+        assert_eq!(keymap.get(0x31), key(8, SHIFT));
+        assert_eq!(keymap.get(0xe9), key(9, Modifiers::default()));
+        assert_eq!(keymap.get(0x7e), key(9, ALTGR));
+        assert_eq!(keymap.get(0x61), key(10, Modifiers::default()));
+        assert_eq!(keymap.get(0xc6), key(10, Modifiers::new(true, true)));
+        assert_eq!(keymap.get(0x71), key(11, Modifiers::default()));
+        assert_eq!(keymap.get(0x20ac), key(12, ALTGR));
+    }
+
+    #[test]
+    fn lowercase_only() {
+        let keymap = Keymap::new(8, 2, &[0x61, 0, 0x31, 0], &HashSet::new());
+        assert_eq!(keymap.get(0x61), key(8, Modifiers::default()));
+        assert_eq!(keymap.get(0x41), key(8, SHIFT));
+        assert_eq!(keymap.get(0x31), key(9, Modifiers::default()));
+        assert_eq!(keymap.get(0), None);
+    }
+
+    #[test]
+    fn skipped() {
+        let skip = [9].into_iter().collect();
+        let keymap = Keymap::new(8, 1, &[0x61, 0x62], &skip);
+        assert_eq!(keymap.get(0x61), key(8, Modifiers::default()));
+        assert_eq!(keymap.get(0x62), None);
+    }
+
+    #[test]
+    fn hotkeys() {
+        let hotkey = |s: &str| s.parse::<Hotkey>();
+        let mask = |m: ModMask| u16::from(m);
+        assert_eq!(
+            hotkey("Super+Shift+R"),
+            Ok(Hotkey {
+                modifiers: mask(ModMask::M4) | mask(ModMask::SHIFT),
+                keysym: 0x72,
+            })
+        );
+        assert_eq!(
+            hotkey("ctrl + F12"),
+            Ok(Hotkey {
+                modifiers: mask(ModMask::CONTROL),
+                keysym: 0xffc9,
+            })
+        );
+        assert_eq!(
+            hotkey("Escape"),
+            Ok(Hotkey {
+                modifiers: 0,
+                keysym: ESCAPE,
+            })
+        );
+        assert!(hotkey("Hyper+R").is_err());
+        assert!(hotkey("Super+").is_err());
+        assert!(hotkey("Super+F36").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Window titles shown as labels above the windows in VR, see `[labels]`. xrdesktop draws the
+//! text, like on its buttons. Labels aren't children of their window, windows have one child at
+//! most and that is for popups, so `App::labels_task` keeps moving them along.
+
+/// Width of a character relative to the label height, about what xrdesktop's font takes
+const CHAR_WIDTH: f32 = 0.45;
+
+/// `title` cut to `max_chars` characters, ending in an ellipsis if it was longer
+pub fn truncate(title: &str, max_chars: usize) -> String {
+    let title = title.trim();
+    if title.chars().count() <= max_chars {
+        return title.into();
+    }
+    let mut truncated: String = title.chars().take(max_chars.saturating_sub(1)).collect();
+    truncated.truncate(truncated.trim_end().len());
+    truncated.push('…');
+    truncated
+}
+
+/// Size of the label for `text`, in meters
+pub fn size(text: &str, height: f32) -> (f32, f32) {
+    let chars = text.chars().count().max(4) as f32;
+    (height * (CHAR_WIDTH * chars + 1.0), height)
+}
+
+/// Where the label goes for a window at `window`, without its scale, that is `window_height`
+/// meters high: centered above it, `gap` meters away, facing the same way
+pub fn transform(
+    window: &graphene::Matrix,
+    window_height: f32,
+    label_height: f32,
+    gap: f32,
+) -> graphene::Matrix {
+    let offset = window_height / 2.0 + gap + label_height / 2.0;
+    graphene::Matrix::new_translate(&graphene::Point3D::new(0.0, offset, 0.0)).multiply(window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncated() {
+        assert_eq!(truncate("vim", 10), "vim");
+        assert_eq!(truncate("  build log ", 10), "build log");
+        assert_eq!(truncate("cargo build --release", 10), "cargo bui…");
+        assert_eq!(truncate("make -j 16 all", 6), "make…");
+        assert_eq!(truncate("Überschrift", 4), "Übe…");
+    }
+
+    #[test]
+    fn above_window() {
+        let window = graphene::Matrix::new_translate(&graphene::Point3D::new(1.0, 2.0, -3.0));
+        let label = transform(&window, 1.0, 0.1, 0.05);
+        let center = label.transform_point3d(&graphene::Point3D::new(0.0, 0.0, 0.0));
+        assert!((center.x() - 1.0).abs() < 1e-5);
+        assert!((center.y() - 2.6).abs() < 1e-5);
+        assert!((center.z() + 3.0).abs() < 1e-5);
+    }
+}
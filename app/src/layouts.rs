@@ -0,0 +1,172 @@
+//! Named layouts of the windows in VR, saved with `SaveLayout` and brought back with
+//! `LoadLayout` over D-Bus, e.g. one for coding and one for watching videos. Window ids don't
+//! survive a restart, so windows are matched by class and title.
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// A saved window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Instance part of WM_CLASS, or the general part if it has none
+    pub class: String,
+    pub title: String,
+    /// Where it is in VR. For a pinned window, where it goes back to when unpinned.
+    pub transform: [f32; 16],
+    pub scale: f32,
+    pub pinned: bool,
+    pub visible: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Layout {
+    pub windows: Vec<Entry>,
+}
+
+/// A live window to match against a layout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub wid: u32,
+    pub class: String,
+    pub title: String,
+}
+
+/// Names are used as file names, so they can't leave the layouts directory
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.contains('/')
+}
+
+/// The class windows are saved by, like `window_settings` remembers them
+pub fn class<'a>(class_instance: &'a str, class_general: &'a str) -> &'a str {
+    if class_instance.is_empty() {
+        class_general
+    } else {
+        class_instance
+    }
+}
+
+fn dir() -> PathBuf {
+    glib::user_config_dir()
+        .join("picom-xrdesktop-companion")
+        .join("layouts")
+}
+
+pub fn path(name: &str) -> PathBuf {
+    dir().join(format!("{name}.json"))
+}
+
+impl Layout {
+    pub fn load(name: &str) -> Result<Self> {
+        let path = path(name);
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, name: &str) -> Result<()> {
+        let dir = dir();
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let path = path(name);
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Pair live windows with saved ones, by class and title first, then by class alone. Each
+    /// saved window goes to one live window at most. Saved windows without a match are ignored,
+    /// and so are live windows, which stay where they are.
+    pub fn assign<'a>(&'a self, candidates: &[Candidate]) -> Vec<(u32, &'a Entry)> {
+        let mut used = vec![false; self.windows.len()];
+        let mut assigned: Vec<(u32, &Entry)> = Vec::new();
+        for same_title in [true, false] {
+            for candidate in candidates {
+                if assigned.iter().any(|(wid, _)| *wid == candidate.wid) {
+                    continue;
+                }
+                let found = self.windows.iter().enumerate().find(|(i, entry)| {
+                    !used[*i]
+                        && entry.class == candidate.class
+                        && (!same_title || entry.title == candidate.title)
+                });
+                if let Some((i, entry)) = found {
+                    used[i] = true;
+                    assigned.push((candidate.wid, entry));
+                }
+            }
+        }
+        assigned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(class: &str, title: &str, x: f32) -> Entry {
+        let mut transform = [0.0; 16];
+        transform[12] = x;
+        Entry {
+            class: class.into(),
+            title: title.into(),
+            transform,
+            scale: 1.0,
+            pinned: false,
+            visible: true,
+        }
+    }
+
+    fn candidate(wid: u32, class: &str, title: &str) -> Candidate {
+        Candidate {
+            wid,
+            class: class.into(),
+            title: title.into(),
+        }
+    }
+
+    #[test]
+    fn title_before_class() {
+        let layout = Layout {
+            windows: vec![entry("xterm", "vim", 1.0), entry("xterm", "htop", 2.0)],
+        };
+        let assigned =
+            layout.assign(&[candidate(1, "xterm", "bash"), candidate(2, "xterm", "htop")]);
+        assert_eq!(assigned.len(), 2);
+        assert!(assigned.contains(&(2, &layout.windows[1])));
+        assert!(assigned.contains(&(1, &layout.windows[0])));
+    }
+
+    #[test]
+    fn unmatched_ignored() {
+        let layout = Layout {
+            windows: vec![entry("mpv", "video.mkv", 1.0), entry("firefox", "", 2.0)],
+        };
+        let assigned = layout.assign(&[
+            candidate(1, "mpv", "other.mkv"),
+            candidate(2, "mpv", "third.mkv"),
+            candidate(3, "xterm", ""),
+        ]);
+        assert_eq!(assigned, [(1, &layout.windows[0])]);
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_valid_name("coding"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name("../config"));
+        assert!(!is_valid_name(".hidden"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let layout = Layout {
+            windows: vec![entry("mpv", "video.mkv", 1.5)],
+        };
+        let saved = serde_json::to_string(&layout).unwrap();
+        assert_eq!(serde_json::from_str::<Layout>(&saved).unwrap(), layout);
+    }
+}
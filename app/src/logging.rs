@@ -0,0 +1,113 @@
+//! Log output, and `--trace-timing`: logging the spans of the render pipeline that took longer
+//! than a threshold.
+use std::{
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+
+/// Log to stderr, filtered by `RUST_LOG`. With `trace_timing`, spans of this crate that take
+/// longer than it are logged when they end, whatever the filter.
+pub fn init(trace_timing: Option<Duration>) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::new(if cfg!(debug_assertions) {
+            "app=debug"
+        } else {
+            "app=info"
+        })
+    });
+    // Slow spans are logged whatever the filter says
+    let filter = match trace_timing {
+        Some(_) => filter.add_directive("timing=info".parse().unwrap()),
+        None => filter,
+    };
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_filter(filter);
+    let slow_spans = trace_timing.map(|threshold| {
+        SlowSpans { threshold }.with_filter(
+            Targets::new().with_target(env!("CARGO_CRATE_NAME"), tracing::Level::TRACE),
+        )
+    });
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(slow_spans)
+        .init();
+}
+
+/// Fields and start time of a span, kept in its extensions
+struct Timing {
+    started: Instant,
+    fields: String,
+}
+
+struct FieldWriter<'a>(&'a mut String);
+
+impl Visit for FieldWriter<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if !self.0.is_empty() {
+            self.0.push(' ');
+        }
+        let _ = write!(self.0, "{}={:?}", field.name(), value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"))
+    }
+}
+
+/// Logs spans that were open for longer than `threshold`, with their fields.
+struct SlowSpans {
+    threshold: Duration,
+}
+
+impl<S> Layer<S> for SlowSpans
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut fields = String::new();
+        attrs.record(&mut FieldWriter(&mut fields));
+        span.extensions_mut().insert(Timing {
+            started: Instant::now(),
+            fields,
+        });
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                values.record(&mut FieldWriter(&mut timing.fields));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let (elapsed, fields) = match span.extensions_mut().remove::<Timing>() {
+            Some(timing) => (timing.started.elapsed(), timing.fields),
+            None => return,
+        };
+        if elapsed >= self.threshold {
+            tracing::info!(target: "timing", "{}{{{fields}}} took {elapsed:?}", span.name());
+        }
+    }
+}
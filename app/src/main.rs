@@ -1,8 +1,11 @@
 use ::next_gen::prelude::*;
 use std::{
     cell::RefCell,
-    collections::{hash_map::Entry, HashMap},
-    sync::{Arc, Weak},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc, Weak,
+    },
 };
 
 use anyhow::{anyhow, Context};
@@ -11,32 +14,101 @@ use futures::{StreamExt, TryStreamExt};
 use gio::prelude::*;
 use glib::{clone::Downgrade, translate::ToGlibPtr};
 use gxr::ContextExt;
-use log::*;
 use tokio::{
     sync::{Mutex, RwLock},
     task::{block_in_place, spawn_blocking, JoinHandle},
 };
+use tracing::{debug, error, info, trace, warn, Instrument};
 use x11rb::{
     connection::Connection,
     protocol::{
         composite::ConnectionExt as _,
         damage::{self, ConnectionExt as _},
+        shape::{self, ConnectionExt as _},
         xproto::{self, ConnectionExt as _},
     },
     rust_connection::RustConnection,
 };
 use xrd::{ClientExt, ClientExtExt, DesktopCursorExt, WindowExt};
 
+mod animation;
+mod benchmark;
+mod cli;
+mod config;
+mod dbus;
+mod dry_run;
 mod gl;
+mod haptics;
+mod icon;
+mod input;
+mod keymap;
+mod labels;
+mod layouts;
+mod logging;
+mod native;
+mod night_light;
 mod picom;
+mod ping;
+mod placement;
+mod priority;
+mod runtime;
+mod sd_notify;
+mod source;
+mod stats;
+mod synth;
+mod throttle;
 mod utils;
+mod vr_windows;
+mod window_map;
+mod window_settings;
+mod workspace;
 
 const PIXELS_PER_METER: f32 = 600.0;
+/// Resolution window titles are drawn at, see `labels`
+const LABEL_PIXELS_PER_METER: f32 = 450.0;
+/// ICCCM WM_STATE value of minimized windows
+const ICONIC_STATE: u32 = 3;
+/// Set in the level of a DamageNotify when more events for the same damage follow
+const DAMAGE_MORE: u8 = 0x80;
+/// Wait before trying to start xrdesktop again, doubled after each attempt up to
+/// `VR_RUNTIME_MAX_RETRY_DELAY`
+const VR_RUNTIME_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const VR_RUNTIME_MAX_RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(16);
+/// Times the render watchdog rebuilds the textures of a stalled window before resetting the GL
+/// context
+const MAX_TEXTURE_REBUILDS: u32 = 2;
+/// How often fading and gliding windows are updated, see `App::animation_task`
+const ANIMATION_INTERVAL: std::time::Duration = std::time::Duration::from_millis(11);
+/// Initial windows whose VR windows and textures are created at the same time, see
+/// `App::setup_initial_windows`
+const SETUP_CONCURRENCY: usize = 4;
+/// How often windows hidden in VR with damage waiting are checked for being shown again, see
+/// `App::visibility_task`
+const VISIBILITY_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+/// Windows that can't be asked to close are only killed if closing them is requested again
+/// within this time, see `App::close_window`
+const KILL_CONFIRM_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 type Result<T> = anyhow::Result<T>;
 
 x11rb::atom_manager! {
     pub AtomCollection: AtomCollectionCookie {
         WM_TRANSIENT_FOR,
+        _NET_WM_ICON,
+        _NET_ACTIVE_WINDOW,
+        WM_PROTOCOLS,
+        WM_DELETE_WINDOW,
+        WM_CHANGE_STATE,
+        _NET_WM_PING,
+        WM_STATE,
+        _NET_WM_STATE,
+        _NET_WM_STATE_HIDDEN,
+        _NET_WM_STATE_SKIP_TASKBAR,
+        _NET_WM_STATE_SKIP_PAGER,
+        _NET_WM_STATE_FULLSCREEN,
+        _NET_WM_DESKTOP,
+        _NET_CURRENT_DESKTOP,
+        _NET_WM_PID,
+        _NET_WM_NAME,
     }
 }
 
@@ -46,6 +118,11 @@ struct TextureSet {
     x11_texture: gl::Texture,
     remote_texture: gulkan::Texture,
     imported_texture: gl::Texture,
+    /// Format of `remote_texture` and `imported_texture`
+    format: gl::SharedFormat,
+    icon: Option<gl::Texture>,
+    /// Maps xrdesktop pointer positions on `remote_texture` back to the window
+    input_transform: input::InputTransform,
 }
 
 impl TextureSet {
@@ -53,11 +130,15 @@ impl TextureSet {
         if let Some(Self {
             x11_texture,
             x11_pixmap,
+            icon,
             ..
         }) = this
         {
             block_in_place(|| Result::Ok(x11.free_pixmap(x11_pixmap)?.check()?))?;
             gl.release_texture(x11_texture).await?;
+            if let Some(icon) = icon {
+                gl.release_texture(icon).await?;
+            }
         }
         Ok(())
     }
@@ -65,16 +146,30 @@ impl TextureSet {
         if let Some(Self {
             x11_texture,
             x11_pixmap,
+            icon,
             ..
         }) = this
         {
             x11.free_pixmap(x11_pixmap)?.check()?;
             gl.release_texture_sync(x11_texture)?;
+            if let Some(icon) = icon {
+                gl.release_texture_sync(icon)?;
+            }
         }
         Ok(())
     }
 }
 
+/// What the blit of a window depends on besides its content. Only the damage is blitted again
+/// while it stays the same, with `partial-blit`, see `App::render_win`.
+#[derive(Debug, Clone, PartialEq)]
+struct BlitInputs {
+    shape: Option<Vec<xproto::Rectangle>>,
+    border: Option<gl::Border>,
+    adjustment: gl::Adjustment,
+    filtering: gl::Filtering,
+}
+
 #[derive(Debug)]
 struct Window {
     id: xproto::Window,
@@ -85,6 +180,41 @@ struct Window {
     textures: Option<TextureSet>,
     xrd_window: Mutex<xrd::Window>,
     client_wid: u32,
+    /// Damaged since last rendered, see `App::render_task`
+    dirty: AtomicBool,
+    /// Dirty, but hidden in VR when it was to be rendered, see `App::visibility_task`
+    hidden_dirty: AtomicBool,
+    /// Bounding box of the damage reported since last rendered, in window coordinates
+    damaged: std::sync::Mutex<Option<placement::Geometry>>,
+    /// What it was last blitted with, None if the next blit has to cover all of it
+    blitted: Option<BlitInputs>,
+    /// Bounding shape, for non-rectangular windows
+    shape: Option<Vec<xproto::Rectangle>>,
+    /// Size in meters from the config, instead of the pixel size
+    size_override: Option<(f32, f32)>,
+    /// How its texture is sampled, from the config
+    filtering: gl::Filtering,
+    /// Registered in `App::stats`
+    stats: Arc<stats::Counters>,
+    /// When damage that hasn't been rendered yet arrived, see `App::watchdog_task`
+    stalled_since: std::sync::Mutex<Option<std::time::Instant>>,
+    /// Times the textures were rebuilt by the watchdog since the window was last rendered
+    recoveries: u32,
+    /// Transformation and scale from before the window was moved to the theater screen, see
+    /// `App::update_theater`
+    pre_theater: Option<([f32; 16], f32)>,
+    /// Whether the application answers pings, see `App::unresponsive_task`
+    responsiveness: std::sync::Mutex<ping::Responsiveness>,
+    /// When it was last focused or drawn, see `App::limit_windows`
+    activity: std::sync::Mutex<priority::Activity>,
+    /// How often it draws, see `App::render_task`
+    throttle: std::sync::Mutex<throttle::Throttle>,
+    /// Brightness and gamma it is drawn with, see `App::set_adjustment`
+    adjustment: gl::Adjustment,
+    /// Its texture is shared at this fraction of its size, see `App::distant_windows_task`
+    mip_level: u32,
+    /// X screen it is on, as an index into the roots of the X setup
+    screen: usize,
 
     // Dropping Window is unsafe, so we don't allow implicit dropping
     drop_bomb: DropBomb,
@@ -110,6 +240,11 @@ impl Window {
         let child: xrd::Window = from_glib_none((*(*data).child_window).xrd_window);
         parent.add_child(&child, &mut offset);
     }
+    /// Everything damaged so far is rendered, or doesn't need to be.
+    fn rendered(&mut self) {
+        *self.stalled_since.get_mut().unwrap() = None;
+        self.recoveries = 0;
+    }
     // Must be dropped with exclusive access to WindowState
     unsafe fn drop(self) -> impl std::future::Future<Output = Result<()>> {
         let Self {
@@ -134,7 +269,9 @@ impl Window {
             xrd_window.close();
             // damage will have already been freed is window is closed
             // so ignore error
-            x11.damage_destroy(damage).unwrap().ignore_error();
+            if let Ok(cookie) = x11.damage_destroy(damage) {
+                cookie.ignore_error();
+            }
             TextureSet::free(textures, &gl, &x11).await
         }
     }
@@ -161,7 +298,9 @@ impl Window {
         xrd_window.close();
         // damage will have already been freed is window is closed
         // so ignore error
-        x11.damage_destroy(damage).unwrap().ignore_error();
+        if let Ok(cookie) = x11.damage_destroy(damage) {
+            cookie.ignore_error();
+        }
         TextureSet::free_sync(textures, &gl, &x11)
     }
 }
@@ -173,9 +312,89 @@ struct Cursor {
     hotspot_y: u32,
 }
 
+/// Reasons for not rendering. Rendering is suspended while any of them is set.
+#[derive(Default, Debug)]
+struct Suspend {
+    /// Through D-Bus
+    manual: AtomicBool,
+    /// Nobody is wearing the headset
+    absent: AtomicBool,
+    /// The VR runtime went away, see `App::runtime_task`
+    runtime_lost: AtomicBool,
+    /// Mirroring is toggled off, see `App::set_mirroring`
+    hidden: AtomicBool,
+}
+
+impl Suspend {
+    fn is_suspended(&self) -> bool {
+        self.manual.load(Ordering::Acquire)
+            || self.absent.load(Ordering::Acquire)
+            || self.runtime_lost.load(Ordering::Acquire)
+            || self.hidden.load(Ordering::Acquire)
+    }
+}
+
+/// Only the active window is shown, see `App::update_focus_mode`
+#[derive(Debug, Default)]
+struct FocusMode {
+    /// The window shown large, with its transformation and scale from before
+    hero: Option<(u32, [f32; 16], f32)>,
+    /// Windows hidden for focus mode, shown again when it is switched off
+    hidden: HashSet<u32>,
+    /// Counts changes of the active window, only the last one is acted on once focus stays
+    changes: u64,
+}
+
+/// A window following the head, see `App::pin_task`
+#[derive(Debug)]
+struct Pinned {
+    /// Transformation and scale from before it was pinned, restored when it is unpinned
+    world: ([f32; 16], f32),
+    /// Transformation relative to the head, see `placement::pinned_transform`
+    offset: [f32; 16],
+    /// Being moved in VR, it doesn't follow the head meanwhile
+    grabbed: bool,
+}
+
+/// A window fading in or out, see `App::animation_task`
+struct Fading {
+    xrd_window: xrd::Window,
+    /// Its scale when fully shown
+    scale: f32,
+    fade: animation::Fade,
+    /// The window fading out, removed from `WindowState` already. Freed once it has faded out.
+    removed: Option<Window>,
+}
+
+/// The title of a window shown above it, see `App::labels_task`
+struct Label {
+    /// As shown, truncated
+    title: String,
+    xrd_window: xrd::Window,
+}
+
+/// A window gliding to a new place, see `App::animation_task`
+struct Gliding {
+    xrd_window: xrd::Window,
+    glide: animation::Glide,
+}
+
+/// A minimized window, or one dropped when the VR runtime went away, remembered so it comes back
+/// to the same place in VR.
+#[derive(Debug)]
+struct Parked {
+    /// The top level window picom reports, the key is the client window
+    wid: u32,
+    /// Transformation and reset transformation of the xrd window, if it was mirrored
+    transforms: Option<([f32; 16], [f32; 16])>,
+    /// The window manager keeps minimized windows mapped, and only sets _NET_WM_STATE_HIDDEN.
+    /// picom won't tell us when such windows are restored, we have to watch their state.
+    mapped: bool,
+}
+
 #[derive(Default, Debug)]
 struct WindowState {
-    windows: HashMap<u32, RwLock<Window>>,
+    windows: HashMap<u32, window_map::Handle<Window>>,
     client_window_to_window: HashMap<u32, u32>,
 }
 
@@ -183,16 +402,125 @@ struct App {
     gl: gl::Gl,
     dbus: zbus::Connection,
     xrd_client: Arc<Mutex<xrd::Client>>,
-    input_synth: Mutex<inputsynth::InputSynth>,
+    input_synth: synth::InputSynth,
     x11: Arc<RustConnection>,
+    /// The screen of the desktop mirror, hotkeys and GL. The default screen, unless only another
+    /// screen is mirrored.
     screen: u32,
+    /// Screens whose windows are mirrored, laid out left to right in VR in this order
+    screens: Vec<usize>,
     display: String,
     cursors: Mutex<std::collections::HashMap<u32, Cursor>>,
     atoms: AtomCollection,
     window_state: RwLock<WindowState>,
     pending_windows: Mutex<HashMap<u32, JoinHandle<()>>>,
+    config: config::Config,
+    input_state: Mutex<input::InputState>,
+    input_tx: tokio::sync::mpsc::Sender<InputEvent>,
+    input_rx: std::sync::Mutex<Option<tokio::sync::mpsc::Receiver<InputEvent>>>,
+    /// Wakes up the render task when a window is marked dirty
+    render_notify: tokio::sync::Notify,
+    /// Windows ignored through D-Bus, until they are destroyed
+    ignored_windows: Mutex<HashSet<u32>>,
+    suspend: Suspend,
+    /// Minimized windows, by client window
+    parked: Mutex<HashMap<u32, Parked>>,
+    /// Top level windows not mirrored because they skip the taskbar, by client window. Mirrored
+    /// once they drop the hint.
+    skipping: Mutex<HashMap<u32, u32>>,
+    /// Processes whose windows are never mirrored, see `vr_windows::pids`. Looked up again when
+    /// the VR runtime comes back, as it is a new process then.
+    vr_pids: std::sync::Mutex<HashSet<u32>>,
+    /// None if held VR keyboard keys don't repeat
+    key_repeat: Option<input::KeyRepeat>,
+    /// By screen, updated on RandR changes, see `handle_screen_change`
+    root_geometry: std::sync::Mutex<HashMap<usize, placement::Geometry>>,
+    /// Monitors mirrored as windows of their own, by RandR CRTC, see `sync_monitors`
+    monitors: std::sync::Mutex<HashMap<u32, placement::Geometry>>,
+    /// Layout preset, from the config until changed through D-Bus
+    preset: std::sync::Mutex<placement::Preset>,
+    /// Keycode grabbed on the root window to reset the layout, None if it isn't grabbed
+    reset_layout_key: Option<xproto::Keycode>,
+    /// Keycode grabbed to toggle focus mode, see `reset_layout_key`
+    focus_mode_key: Option<xproto::Keycode>,
+    /// Some while focus mode is on
+    focus_mode: Mutex<Option<FocusMode>>,
+    /// Windows hidden because they are on another virtual desktop, see `update_desktop_filter`
+    desktop_hidden: Mutex<HashSet<u32>>,
+    /// Windows following the head
+    pinned: Mutex<HashMap<u32, Pinned>>,
+    /// Windows fading in or out, see `animation_task`
+    fading: std::sync::Mutex<HashMap<u32, Fading>>,
+    /// Titles shown above top level windows, see `labels_task`
+    labels: std::sync::Mutex<HashMap<u32, Label>>,
+    /// Wakes `labels_task` when a label is added
+    labels_notify: tokio::sync::Notify,
+    /// Windows hidden and without textures because of `[window-limit]`, see `limit_windows`
+    demoted: std::sync::Mutex<HashSet<u32>>,
+    /// Counts the windows mirrored so far, so `[window-limit]` can tell which came first
+    map_sequence: AtomicU64,
+    /// The window with keyboard focus, as the window manager reports it, see `update_focused`
+    focused: std::sync::Mutex<Option<u32>>,
+    /// Controller vibration of the current xrdesktop client, see `connect_client_signals`
+    haptics: std::sync::Mutex<Option<Arc<haptics::Haptics>>>,
+    /// Client window that was asked to close, but can only be killed, and when, see
+    /// `close_window`
+    kill_requested: std::sync::Mutex<Option<(u32, std::time::Instant)>>,
+    /// Windows gliding to new places, see `animation_task`
+    gliding: std::sync::Mutex<HashMap<u32, Gliding>>,
+    /// Wakes `animation_task` when a window starts fading or gliding
+    animation_notify: tokio::sync::Notify,
+    /// Wakes up `pin_task` when the first window is pinned
+    pin_notify: tokio::sync::Notify,
+    /// Night light color temperature in Kelvin, see `night_light`
+    color_temperature: AtomicU32,
+    /// Mirroring the whole screen as one window, instead of each window on its own, see
+    /// `App::set_desktop_mirror`
+    desktop_mirror: AtomicBool,
+    /// Scales and brightness set in VR or through D-Bus, by window class
+    window_settings: std::sync::Mutex<window_settings::Store>,
+    /// None if we weren't started as a systemd notify service
+    notifier: Option<sd_notify::Notifier>,
+    stats: Arc<stats::Stats>,
+    /// Started with --no-hmd: windows are rendered, but their textures are never submitted
+    no_hmd: bool,
+    /// The X server went away, there is nothing left to clean up there
+    x11_lost: AtomicBool,
+    /// XFixes region the damage of windows is moved into to read it, with `partial-blit`, see
+    /// `subtract_damage`
+    damage_parts: Option<x11rb::protocol::xfixes::Region>,
+    /// Tells us which windows there are
+    source: Box<dyn source::WindowSource>,
+    /// Whether the VR runtime is there, or we are waiting for it to come back
+    runtime: std::sync::Mutex<runtime::Supervisor>,
+    /// To create a new xrdesktop client when the VR runtime comes back
+    client_mode: xrd::ClientMode,
+    /// Notified when the VR runtime activates or deactivates a device, see `presence_task`
+    devices_changed: Arc<tokio::sync::Notify>,
+    /// While mirroring is toggled off, the windows it hid, to show them again when it is toggled
+    /// back on. Windows hidden through D-Bus before aren't in there, they stay hidden.
+    hidden_windows: Mutex<Option<HashSet<u32>>>,
 }
 
+/// xrdesktop has no Vulkan context to create textures with, see `wait_for_vr_runtime`.
+/// Windows stay dirty, so their textures are created on a later render.
+#[derive(thiserror::Error, Debug)]
+#[error("the VR runtime isn't ready, can't create textures yet")]
+struct VrRuntimeNotReady;
+
+/// xrdesktop calls that failed.
+#[derive(thiserror::Error, Debug)]
+enum XrdError {
+    #[error("xrdesktop couldn't create a {width}x{height} window for {wid:#010x}")]
+    CreateWindow { wid: u32, width: u32, height: u32 },
+}
+
+/// The connection to the X server broke, e.g. because the session ended. `main` exits with
+/// `X_CONNECTION_LOST_EXIT_CODE` then.
+#[derive(thiserror::Error, Debug)]
+#[error("X connection lost: {0}")]
+struct XConnectionLost(#[from] x11rb::errors::ConnectionError);
+
 #[derive(Debug)]
 enum InputEvent {
     Move {
@@ -206,10 +534,73 @@ enum InputEvent {
         y: f32,
         button: xrd::sys::XrdInputSynthButton,
         pressed: bool,
+        controller: usize,
     },
+    /// A key of the VR keyboard. `string` is the text it types, empty for keys like modifiers
+    /// or arrows, which are identified by `keyval`.
     KeyPresses {
-        string: Vec<i8>,
+        string: String,
+        keyval: u32,
+        pressed: bool,
+    },
+    /// The key held since the last `KeyPresses` is repeated. `held` are the modifiers it was
+    /// typed with, and `focus` the window that had input focus then.
+    KeyRepeat {
+        string: String,
+        keyval: u32,
+        held: Vec<u32>,
+        focus: u32,
+    },
+    /// Scroll amounts are in wheel detents, positive values scroll down/right.
+    Scroll {
+        wid: u32,
+        dx: f64,
+        dy: f64,
+    },
+    /// xrdesktop window manager started/stopped moving a window
+    Grab {
+        wid: u32,
+        grabbed: bool,
+    },
+    /// The pointer has been on `wid` for the hover focus delay
+    HoverDwell {
+        wid: u32,
     },
+    /// The `[toggle-mirroring]` button was pressed or released, pointing at a window or not
+    ToggleButton {
+        pressed: bool,
+    },
+    /// The `[toggle-mirroring]` button was held down long enough
+    LongPress,
+}
+
+impl InputEvent {
+    fn wid(&self) -> Option<u32> {
+        match *self {
+            Self::Move { wid, .. }
+            | Self::Click { wid, .. }
+            | Self::Scroll { wid, .. }
+            | Self::Grab { wid, .. }
+            | Self::HoverDwell { wid } => Some(wid),
+            Self::KeyPresses { .. }
+            | Self::KeyRepeat { .. }
+            | Self::ToggleButton { .. }
+            | Self::LongPress => None,
+        }
+    }
+
+    /// Whether handling this presses, moves or types something on the desktop. Releases don't
+    /// count, so nothing stays held down when input is blocked in between.
+    fn synthesizes_input(&self) -> bool {
+        match *self {
+            Self::Click { pressed, .. } | Self::KeyPresses { pressed, .. } => pressed,
+            Self::Move { .. }
+            | Self::Scroll { .. }
+            | Self::KeyRepeat { .. }
+            | Self::HoverDwell { .. } => true,
+            Self::Grab { .. } | Self::ToggleButton { .. } | Self::LongPress => false,
+        }
+    }
 }
 
 impl std::fmt::Debug for App {
@@ -221,41 +612,130 @@ impl std::fmt::Debug for App {
 impl Drop for App {
     fn drop(&mut self) {
         let mut window_state = self.window_state.blocking_write();
+        self.teardown(&mut window_state);
+    }
+}
+impl App {
+    /// Free the X resources of all windows and remove them from VR, without needing the
+    /// runtime, so it can also be done after a panic, see `install_panic_hook`.
+    fn teardown(&self, window_state: &mut WindowState) {
+        let x11_lost = self.x11_lost.load(Ordering::Acquire);
+        if x11_lost {
+            info!("X connection lost, only removing windows from VR");
+        }
+        if !x11_lost {
+            let root = self.root();
+            for keycode in [self.reset_layout_key, self.focus_mode_key]
+                .into_iter()
+                .flatten()
+            {
+                if let Ok(cookie) = self.x11.ungrab_key(keycode, root, xproto::ModMask::ANY) {
+                    cookie.ignore_error();
+                }
+            }
+            // So the next run, and other programs, find them unused again
+            if let Err(e) = self.input_synth.unbind_spare_keycodes() {
+                warn!("Failed to unbind the spare keycodes: {e}");
+            }
+        }
         // Drop the Windows to defuse the drop bombs
-        for (_, w) in window_state.windows.drain() {
-            let w = w.into_inner();
+        for (wid, w) in window_state.windows.drain() {
+            let w = window_map::take_blocking(w);
             // We own window_state at this point
-            unsafe { w.drop_sync().unwrap() };
+            // Freeing X resources fails without a connection, that's expected
+            if let Err(e) = unsafe { w.drop_sync() } {
+                if !x11_lost {
+                    error!("Failed to free {wid:#010x}: {e}");
+                }
+            }
+        }
+        window_state.client_window_to_window.clear();
+        let xrd_client = self.xrd_client.blocking_lock();
+        for (_, label) in self.labels.lock().unwrap().drain() {
+            label.xrd_window.close();
+        }
+        drop(xrd_client);
+        // Windows that were still fading out
+        for (wid, fading) in self.fading.lock().unwrap().drain() {
+            if let Some(w) = fading.removed {
+                if let Err(e) = unsafe { w.drop_sync() } {
+                    if !x11_lost {
+                        error!("Failed to free {wid:#010x}: {e}");
+                    }
+                }
+            }
+        }
+        if !x11_lost {
+            let _ = self.x11.flush();
         }
     }
-}
-impl App {
-    async fn new() -> Result<Self> {
+
+    /// `teardown`, unless the windows are locked, e.g. by the task that panicked.
+    fn try_teardown(&self) {
+        match self.window_state.try_write() {
+            Ok(mut window_state) => self.teardown(&mut window_state),
+            Err(_) => error!("Windows are locked, they are left behind in VR"),
+        }
+    }
+
+    /// With `replace`, take over from a companion already running on the display, instead of
+    /// failing.
+    async fn new(config: config::Config, replace: bool, no_hmd: bool) -> Result<Self> {
         if !xrd::settings_is_schema_installed() {
-            return Err(anyhow!("xrdesktop GSettings Schema not installed"));
+            return Err(schema_not_installed_error());
         }
 
         let dbus = zbus::Connection::session().await.unwrap();
+        let display = display_id();
+        // Before touching VR, so there is only ever one set of windows there
+        dbus::acquire_name(&dbus, &dbus::service_name(&display), replace).await?;
+        if let Err(e) = dbus::request_alias(&dbus).await {
+            warn!("Failed to request {}: {e}", dbus::ALIAS_NAME);
+        }
 
         let settings = xrd::settings_get_instance().unwrap();
         let mode: xrd::ClientMode =
             unsafe { glib::translate::from_glib(settings.enum_("default-mode")) };
-        println!("{}", mode);
-
         if mode == xrd::ClientMode::Scene {
-            unimplemented!("Scene mode");
+            return Err(anyhow!(
+                "Scene mode isn't supported. Set the default mode to \"overlay\" in the xrdesktop \
+                 settings"
+            ));
         }
 
-        let client = xrd::Client::with_mode(mode);
-        let input_synth =
-            Mutex::new(inputsynth::InputSynth::new().expect("Failed to initialize inputsynth"));
-        let (x11, screen) = RustConnection::connect(None)?;
+        let client = wait_for_vr_runtime(mode, config.vr_runtime_timeout).await?;
+        if !hmd_connected(&client) {
+            if !no_hmd {
+                return Err(anyhow!(
+                    "No headset connected, windows would not show up anywhere. Connect one, or \
+                     pass --no-hmd to track windows without sending them to VR"
+                ));
+            }
+            warn!("No headset connected, windows are tracked but not sent to VR");
+        }
+        let (x11, default_screen) = RustConnection::connect(None)?;
         let x11 = Arc::new(x11);
+        let screen = config.screen.unwrap_or(default_screen);
+        let screen_count = x11.setup().roots.len();
+        if screen >= screen_count {
+            return Err(anyhow!(
+                "There is no screen {screen}, the display has {screen_count}"
+            ));
+        }
+        let mut screens: Vec<usize> = match config.screen {
+            Some(screen) => vec![screen],
+            None => (0..screen_count).collect(),
+        };
+        let input_synth = synth::InputSynth::new(x11.clone(), config.input_backend)?;
         block_in_place(|| {
-            use x11rb::protocol::xfixes::{ConnectionExt, CursorNotifyMask};
+            use x11rb::protocol::{
+                randr::{self, ConnectionExt as _},
+                xfixes::{ConnectionExt, CursorNotifyMask},
+            };
             let (damage_major, damage_minor) = x11rb::protocol::damage::X11_XML_VERSION;
             x11.damage_query_version(damage_major, damage_minor)?
                 .reply()?;
+            x11.shape_query_version()?.reply()?;
             let (xfixes_major, xfixes_minor) = x11rb::protocol::xfixes::X11_XML_VERSION;
             x11.xfixes_query_version(xfixes_major, xfixes_minor)?
                 .reply()?;
@@ -264,22 +744,172 @@ impl App {
                 CursorNotifyMask::DISPLAY_CURSOR,
             )?
             .check()?;
+            // 1.3 for GetScreenResourcesCurrent, see query_monitors
+            x11.randr_query_version(1, 3)?.reply()?;
+            for &screen in &screens {
+                let root = x11.setup().roots[screen].root;
+                // Top level windows being mapped, unmapped and configured, and the active
+                // window changing, see handle_root_property
+                select_input(
+                    &x11,
+                    root,
+                    xproto::EventMask::SUBSTRUCTURE_NOTIFY | xproto::EventMask::PROPERTY_CHANGE,
+                )?;
+                // Resolution and monitor changes, see handle_screen_change
+                x11.randr_select_input(
+                    root,
+                    randr::NotifyMask::SCREEN_CHANGE | randr::NotifyMask::CRTC_CHANGE,
+                )?
+                .check()?;
+            }
             Result::Ok(())
         })?;
         let atoms = AtomCollection::new(&*x11)?.reply()?;
+        let damage_parts = if config.partial_blit {
+            Some(block_in_place(|| {
+                use x11rb::protocol::xfixes::ConnectionExt;
+                let region = x11.generate_id()?;
+                x11.xfixes_create_region(region, &[])?.check()?;
+                Result::Ok(region)
+            })?)
+        } else {
+            None
+        };
+        let root = x11.setup().roots[screen].root;
+        // picom runs once per screen. A screen without it isn't mirrored, unless it is the only
+        // one.
+        let mut sources = Vec::new();
+        for other in std::mem::take(&mut screens) {
+            let root = x11.setup().roots[other].root;
+            let display = match other {
+                other if other == default_screen => display.clone(),
+                other => screen_display_id(other),
+            };
+            match source::connect(&config, &dbus, &x11, root, &display).await {
+                Ok((backend, source)) => {
+                    info!("Finding windows of screen {other} through {backend}");
+                    sources.push((root, source));
+                    screens.push(other);
+                }
+                Err(e) if other != screen => warn!("Not mirroring screen {other}: {e:#}"),
+                Err(e) => return Err(e),
+            }
+        }
+        let source: Box<dyn source::WindowSource> = match sources.len() {
+            1 => sources.pop().unwrap().1,
+            _ => Box::new(source::Screens::new(x11.clone(), sources)),
+        };
+        let root_geometry = block_in_place(|| {
+            screens
+                .iter()
+                .map(|&screen| {
+                    let root = x11.setup().roots[screen].root;
+                    Result::Ok((screen, (&x11.get_geometry(root)?.reply()?).into()))
+                })
+                .collect::<Result<HashMap<_, _>>>()
+        })?;
+        let (input_tx, input_rx) = tokio::sync::mpsc::channel(2);
+        let key_repeat = if config.key_repeat.enabled {
+            let server = block_in_place(|| synth::server_key_repeat(&x11))?;
+            let repeat = server.unwrap_or(input::KeyRepeat::DEFAULT);
+            let delay = config
+                .key_repeat
+                .delay
+                .map(std::time::Duration::from_millis);
+            let interval = config
+                .key_repeat
+                .rate
+                .map(|rate| std::time::Duration::from_secs(1) / rate.max(1));
+            // Only follow the server being set to not repeat if nothing was configured
+            (server.is_some() || delay.is_some() || interval.is_some()).then(|| input::KeyRepeat {
+                delay: delay.unwrap_or(repeat.delay),
+                interval: interval.unwrap_or(repeat.interval),
+            })
+        } else {
+            None
+        };
+        let grab = |hotkey: &str, setting: &str| -> Result<_> {
+            match hotkey {
+                "" => Ok(None),
+                hotkey => {
+                    let hotkey: keymap::Hotkey = hotkey
+                        .parse()
+                        .map_err(anyhow::Error::msg)
+                        .with_context(|| format!("Invalid {setting}"))?;
+                    block_in_place(|| grab_hotkey(&x11, root, hotkey))
+                }
+            }
+        };
+        let reset_layout_key = grab(&config.reset_layout_key, "reset-layout-key")?;
+        let focus_mode_key = grab(&config.focus_mode.key, "focus-mode key")?;
+
+        let notifier = sd_notify::Notifier::from_env().unwrap_or_else(|e| {
+            warn!("Failed to connect to NOTIFY_SOCKET: {e}");
+            None
+        });
+
+        let gl = gl::Gl::new(x11.clone(), screen as u32, config.filtering).await?;
+        if let Err(e) = gl.check_colors().await {
+            warn!("Windows won't look the same in VR as on the desktop: {e}");
+        }
 
         Ok(Self {
-            gl: gl::Gl::new(x11.clone(), screen as u32).await?,
+            gl,
             dbus,
             window_state: Default::default(),
             xrd_client: Arc::new(Mutex::new(client)),
             input_synth,
             screen: screen as u32,
+            screens,
             x11,
-            display: std::env::var("DISPLAY").unwrap().replace(':', "_").replace('.', "_"),
+            display,
             cursors: Default::default(),
             atoms,
             pending_windows: Default::default(),
+            // Before config is moved
+            preset: std::sync::Mutex::new(config.layout.preset),
+            color_temperature: AtomicU32::new(config.night_light.temperature),
+            desktop_mirror: AtomicBool::new(config.desktop_mirror.enabled),
+            config,
+            input_state: Default::default(),
+            input_tx,
+            input_rx: std::sync::Mutex::new(Some(input_rx)),
+            render_notify: tokio::sync::Notify::new(),
+            ignored_windows: Default::default(),
+            suspend: Default::default(),
+            parked: Default::default(),
+            skipping: Default::default(),
+            vr_pids: std::sync::Mutex::new(vr_windows::pids()),
+            key_repeat,
+            root_geometry: std::sync::Mutex::new(root_geometry),
+            monitors: Default::default(),
+            reset_layout_key,
+            focus_mode_key,
+            focus_mode: Default::default(),
+            desktop_hidden: Default::default(),
+            pinned: Default::default(),
+            fading: Default::default(),
+            gliding: Default::default(),
+            haptics: Default::default(),
+            kill_requested: Default::default(),
+            focused: Default::default(),
+            demoted: Default::default(),
+            map_sequence: AtomicU64::new(0),
+            labels: Default::default(),
+            labels_notify: tokio::sync::Notify::new(),
+            animation_notify: tokio::sync::Notify::new(),
+            pin_notify: tokio::sync::Notify::new(),
+            window_settings: std::sync::Mutex::new(window_settings::Store::load()),
+            notifier,
+            stats: Default::default(),
+            no_hmd,
+            x11_lost: AtomicBool::new(false),
+            damage_parts,
+            source,
+            runtime: Default::default(),
+            client_mode: mode,
+            devices_changed: Default::default(),
+            hidden_windows: Default::default(),
         })
     }
 
@@ -287,6 +917,10 @@ impl App {
     // current cursor (might or might not be cursor_serial) and set the cursor to that.
     async fn refresh_cursor(&self, cursor_serial: u32) -> Result<()> {
         use x11rb::protocol::xfixes;
+        // The cursor is set up again once the VR runtime is back
+        if self.suspend.runtime_lost.load(Ordering::Acquire) {
+            return Ok(());
+        }
         let xrd_client = self.xrd_client.lock().await;
         let mut cursors = self.cursors.lock().await;
         let cursor = if let Some(cursor) = cursors.get(&cursor_serial) {
@@ -313,7 +947,7 @@ impl App {
                 4i32 * cursor_image.width as i32,
             );
             let texture = {
-                let gulkan_client = xrd_client.gulkan().unwrap();
+                let gulkan_client = xrd_client.gulkan().ok_or(VrRuntimeNotReady)?;
                 let layout = xrd_client.upload_layout();
                 let texture: gulkan::Texture = unsafe {
                     glib::translate::from_glib_full(gulkan::sys::gulkan_texture_new_from_pixbuf(
@@ -339,682 +973,5169 @@ impl App {
         Ok(())
     }
 
-    async fn handle_x_events(&self, event: x11rb::protocol::Event) -> Result<()> {
-        use x11rb::protocol::xfixes;
-        use x11rb::protocol::Event;
+    /// Bounding shape of `wid`, relative to its top left corner. None if the window is not
+    /// shaped, i.e. it is a plain rectangle.
+    fn query_shape(&self, wid: u32) -> Result<Option<Vec<xproto::Rectangle>>> {
+        if !self.x11.shape_query_extents(wid)?.reply()?.bounding_shaped {
+            return Ok(None);
+        }
+        let rectangles = self
+            .x11
+            .shape_get_rectangles(wid, shape::SK::BOUNDING)?
+            .reply()?
+            .rectangles;
+        Ok(Some(rectangles))
+    }
+
+    async fn handle_x_events(self: &Arc<Self>, event: x11rb::protocol::Event) -> Result<()> {
+        use x11rb::protocol::{randr, xfixes, Event};
         match event {
-            Event::DamageNotify(damage::NotifyEvent { drawable, .. }) => {
+            // Only the reset layout and focus mode hotkeys are grabbed
+            Event::KeyPress(event) if Some(event.detail) == self.reset_layout_key => {
+                debug!("Reset layout hotkey pressed");
+                self.reset_layout().await;
+            }
+            Event::KeyPress(event) if Some(event.detail) == self.focus_mode_key => {
+                debug!("Focus mode hotkey pressed");
+                let enabled = self.focus_mode.lock().await.is_none();
+                self.set_focus_mode(enabled).await;
+            }
+            Event::DamageNotify(damage::NotifyEvent {
+                drawable,
+                damage,
+                level,
+                area,
+                ..
+            }) => {
                 let window_state = self.window_state.read().await;
-                if let Some(w) = window_state.windows.get(&drawable) {
+                let mut wid = drawable;
+                // Monitors of the desktop mirror each have their own damage of the root window
+                if drawable == self.root() {
+                    let monitors: Vec<u32> =
+                        self.monitors.lock().unwrap().keys().copied().collect();
+                    for monitor in monitors {
+                        if let Some(w) = window_state.windows.get(&monitor) {
+                            if w.read().await.damage == damage {
+                                wid = monitor;
+                            }
+                        }
+                    }
+                }
+                if let Some(w) = window_state.windows.get(&wid) {
                     // we might not be able to find the window if:
                     // we receive a damage notify from X, but have processed it;
                     // then we receive a WinUnmapped signal from picom, and we processed it;
                     // then we dequeue the damage notify from x11rb.
                     // this is not an error.
-                    let mut w = w.write().await;
-
-                    // Window could've closed between damage_notify and here, handle that case.
-                    if block_in_place(|| {
-                        Result::Ok(
-                            self.x11
-                                .damage_subtract(w.damage, x11rb::NONE, x11rb::NONE)?
-                                .check()?,
-                        )
-                    })
-                    .is_ok()
+                    let w = w.read().await;
+                    w.stats.damage_event();
+                    let now = std::time::Instant::now();
+                    w.activity.lock().unwrap().damaged = Some(now);
+                    // Once per frame, not per rectangle
+                    if let Some(limits) = self.config.throttle.limits() {
+                        if u8::from(level) & DAMAGE_MORE == 0 {
+                            w.throttle.lock().unwrap().damage(&limits, now);
+                        }
+                    }
+                    if w.responsiveness.lock().unwrap().activity(now) {
+                        info!("{drawable:#010x} is responding again");
+                        set_dimmed(&*w.xrd_window.lock().await, None);
+                    }
                     {
-                        // render_win will fail if window is closed, this is fine.
-                        let _: Result<_> = self.render_win(&mut w).await;
+                        let mut damaged = w.damaged.lock().unwrap();
+                        let area = placement::Geometry::from(area);
+                        *damaged = Some(damaged.map_or(area, |d| d.union(&area)));
+                    }
+                    w.stalled_since
+                        .lock()
+                        .unwrap()
+                        .get_or_insert_with(std::time::Instant::now);
+                    if self.suspend.is_suspended() {
+                        // Leave the damage in place, so X doesn't report more of it until we
+                        // resume.
+                        w.dirty.store(true, Ordering::Release);
+                        return Ok(());
+                    }
+
+                    // With raw rectangles X doesn't accumulate damage, there is nothing to
+                    // clear. Window could've closed between damage_notify and here, handle that
+                    // case.
+                    let raw = self.config.damage_report_level()
+                        == config::DamageReportLevel::RawRectangles;
+                    if raw || block_in_place(|| self.subtract_damage(&w)).is_ok() {
+                        // Rendering is left to the render task, so we don't wait for the GPU here
+                        w.dirty.store(true, Ordering::Release);
+                        // More rectangles of the same damage follow, render once they're in
+                        let more = u8::from(level) & DAMAGE_MORE != 0;
+                        if !more {
+                            self.render_notify.notify_one();
+                        }
+                    }
+                }
+            }
+            Event::ShapeNotify(shape::NotifyEvent {
+                shape_kind: shape::SK::BOUNDING,
+                affected_window,
+                ..
+            }) => {
+                let window_state = self.window_state.read().await;
+                if let Some(w) = window_state.windows.get(&affected_window) {
+                    let mut w = w.write().await;
+                    // Window could've closed in the meantime, keep the old shape then.
+                    if let Ok(shape) = block_in_place(|| self.query_shape(affected_window)) {
+                        w.shape = shape;
+                        w.dirty.store(true, Ordering::Release);
+                        self.render_notify.notify_one();
                     }
                 }
             }
+            Event::MappingNotify(xproto::MappingNotifyEvent {
+                request: xproto::Mapping::KEYBOARD,
+                first_keycode,
+                count,
+                ..
+            }) => {
+                self.input_synth.mapping_changed(first_keycode, count)?;
+            }
+            Event::RandrScreenChangeNotify(_)
+            | Event::RandrNotify(randr::NotifyEvent {
+                sub_code: randr::Notify::CRTC_CHANGE,
+                ..
+            }) => {
+                self.handle_screen_change().await?;
+            }
             Event::XfixesCursorNotify(xfixes::CursorNotifyEvent { cursor_serial, .. }) => {
                 self.refresh_cursor(cursor_serial).await?;
             }
+            // Answers to `_NET_WM_PING` are sent to the root window
+            Event::ClientMessage(event)
+                if self.is_root(event.window)
+                    && event.type_ == self.atoms.WM_PROTOCOLS
+                    && event.data.as_data32()[0] == self.atoms._NET_WM_PING =>
+            {
+                let [_, timestamp, client_wid, ..] = event.data.as_data32();
+                let window_state = self.window_state.read().await;
+                let w = window_state
+                    .client_window_to_window
+                    .get(&client_wid)
+                    .and_then(|wid| window_state.windows.get(wid));
+                if let Some(w) = w {
+                    let w = w.read().await;
+                    let now = std::time::Instant::now();
+                    if w.responsiveness.lock().unwrap().pong(timestamp, now) {
+                        info!("{:#010x} is responding again", w.id);
+                        set_dimmed(&*w.xrd_window.lock().await, None);
+                    }
+                }
+            }
             _ => (),
         }
         Ok(())
     }
 
-    async fn handle_input_events(&self, input_event: InputEvent) {
-        trace!("{:?}", input_event);
-        let raise_window_and_resolve_position = |wid, x, y| {
-            let geometry = block_in_place(|| {
-                let cookie1 = self
-                    .x11
-                    .configure_window(
-                        wid,
-                        &xproto::ConfigureWindowAux {
-                            stack_mode: Some(xproto::StackMode::ABOVE),
-                            ..Default::default()
-                        },
-                    )
-                    .unwrap();
-                let cookie2 = self.x11.get_geometry(wid).unwrap();
-                cookie1.check()?;
-                Result::Ok(cookie2.reply()?)
-            })?;
-            let x = (geometry.x as f32 + x) as _;
-            let y = (geometry.y as f32 + y) as _;
-            Result::Ok((x, y))
-        };
-
-        let input_synth = self.input_synth.lock().await;
-        let result = match input_event {
-            InputEvent::Move { x, y, wid } => {
-                raise_window_and_resolve_position(wid, x, y).and_then(|(x, y)| {
-                    // The window could have been closed, in that case we stop
-                    input_synth.move_cursor(x, y).map_err(Into::into)
-                })
-            }
+    /// Switch between mirroring the whole screen as one window, and mirroring windows on their
+    /// own. Windows go back where they were when switching back.
+    async fn set_desktop_mirror(self: &Arc<Self>, enabled: bool) -> Result<()> {
+        if self.desktop_mirror.swap(enabled, Ordering::AcqRel) == enabled {
+            return Ok(());
+        }
+        info!(
+            "Switching to mirroring {}",
+            if enabled { "the desktop" } else { "windows" }
+        );
+        self.unmap_all().await;
+        if self.suspend.runtime_lost.load(Ordering::Acquire) {
+            // Mirrored when the runtime is back, see `runtime_resumed`
+            return Ok(());
+        }
+        if enabled {
+            self.map_desktop().await
+        } else {
+            Self::setup_initial_windows(self).await
+        }
+    }
 
-            InputEvent::Click {
-                x,
-                y,
-                wid,
-                button,
-                pressed,
-            } => {
-                raise_window_and_resolve_position(wid, x, y).and_then(|(x, y)| {
-                    // The window could have been closed, in that case we stop
-                    input_synth
-                        .click(x, y, button as _, pressed)
-                        .map_err(Into::into)
-                })
+    /// Redraw the desktop mirror at the configured rate. The root window gets no damage of its
+    /// own when redirected windows on it change, so it is copied unconditionally, even with
+    /// `root_damage`.
+    async fn mirror_task(&self) {
+        let mut interval = tokio::time::interval(self.config.desktop_mirror.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if !self.desktop_mirror.load(Ordering::Acquire) {
+                continue;
             }
-            InputEvent::KeyPresses { string } => {
-                debug!("key press {:?}", string);
-                block_in_place(|| {
-                    string
-                        .into_iter()
-                        .map(|ch| {
-                            (if ch == b'\n' as _ {
-                                input_synth.ascii_char(b'\r' as _)
-                            } else {
-                                input_synth.ascii_char(ch as _)
-                            })
-                            .map_err(Into::into)
-                        })
-                        .collect()
-                })
+            let monitors: Vec<u32> = self.monitors.lock().unwrap().keys().copied().collect();
+            let window_state = self.window_state.read().await;
+            for wid in std::iter::once(self.root()).chain(monitors) {
+                if let Some(w) = window_state.windows.get(&wid) {
+                    w.read().await.dirty.store(true, Ordering::Release);
+                    self.render_notify.notify_one();
+                }
             }
-        };
-        if let Err(e) = result {
-            error!("Failed to synthesis input {}", e);
         }
     }
 
-    // A window group in xrdesktop is a linked list held together by the window's parent/child
-    // pointers. this function finds the group for `wid`, and returns the last window in the list
-    async fn find_window_group(window_state: &WindowState, wid: u32) -> Option<&RwLock<Window>> {
-        debug!("looking for group for {}", wid);
-        let window = window_state.windows.get(&wid).or_else(|| {
-            window_state
-                .client_window_to_window
-                .get(&wid)
-                .and_then(|p| {
-                    debug!("using parent {} of client window {} instead", p, wid);
-                    window_state.windows.get(p)
-                })
-        })?;
-        let parent = unsafe {
-            let mut current =
-                xrd::sys::xrd_window_get_data(window.read().await.xrd_window.lock().await.as_ptr());
-            while !(*current).child_window.is_null() {
-                debug!("{}", (*current).native as u64);
-                current = (*current).child_window;
+    /// Render the windows that were damaged since they were last rendered. Damage only marks
+    /// windows as dirty, so several damage events arriving before we get to a window are
+    /// rendered once, and handling X events never waits on the GPU. Throttled windows stay dirty
+    /// until they may be rendered again, see `throttle`, the others are rendered meanwhile.
+    async fn render_task(&self) {
+        let limits = self.config.throttle.limits();
+        // When the next throttled window may be rendered
+        let mut deferred: Option<std::time::Instant> = None;
+        loop {
+            match deferred.take() {
+                Some(deadline) => tokio::select! {
+                    _ = self.render_notify.notified() => (),
+                    _ = tokio::time::sleep_until(deadline.into()) => (),
+                },
+                None => self.render_notify.notified().await,
             }
-            current
-        };
-
-        let native: u64 = unsafe { (*parent).native } as _;
-        Some(window_state.windows.get(&(native as _)).unwrap())
-    }
-
-    pub async fn run(self: Arc<Self>) -> Result<()> {
-        Self::setup_initial_windows(&self).await?;
-        self.refresh_cursor(0).await?;
-        self.xrd_client
-            .lock()
-            .await
-            .desktop_cursor()
-            .unwrap()
-            .show();
-
-        let picom = picom::CompositorProxy::builder(&self.dbus)
-            .destination(format!("com.github.chjj.compton.{}", self.display))?
-            .build()
-            .await?;
-
-        let (tx, mut x11_rx) = tokio::sync::mpsc::channel(4);
-        let x11_clone = self.x11.clone();
-
-        // feature: never_type
-        // tokio task for receiving X events
-        let _: tokio::task::JoinHandle<Result<() /* ! */>> = spawn_blocking(move || loop {
-            let event = x11_clone.wait_for_event()?;
-            tx.blocking_send(event)?;
-            while let Some(event) = x11_clone.poll_for_event()? {
-                tx.blocking_send(event)?;
+            if self.suspend.is_suspended() {
+                continue;
             }
-        });
-        let (mut exit_rx, mut input_rx) = {
-            let xrd_client = self.xrd_client.lock().await;
-            let (input_tx, input_rx) = tokio::sync::mpsc::channel(2);
-            let tx = input_tx.clone();
-            xrd_client.connect_move_cursor_event(move |_, event| {
-                if event.ignore != 0 {
-                    return;
-                }
-                let window: xrd::Window = unsafe { glib::translate::from_glib_none(event.window) };
-                let point: graphene::Point =
-                    unsafe { glib::translate::from_glib_none(event.position) };
-                let mut native = 0u64;
-                unsafe {
-                    gobject_sys::g_object_get(
-                        window.as_ptr() as *mut _,
-                        "native\0".as_bytes().as_ptr() as *const _,
-                        &mut native,
-                        0,
-                    );
-                };
-                // If the queue is full, we drop the event
-                let _: std::result::Result<_, _> = tx.try_send(InputEvent::Move {
-                    wid: native as u32,
-                    x: point.x(),
-                    y: point.y(),
-                });
-            });
-            // if send() errors, that means run() has returned. so ignore those errors
-            let tx = input_tx.clone();
-            xrd_client.connect_click_event(move |_, event| {
-                let window: xrd::Window = unsafe { glib::translate::from_glib_none(event.window) };
-                let point: graphene::Point =
-                    unsafe { glib::translate::from_glib_none(event.position) };
-                let mut native = 0u64;
-                unsafe {
-                    gobject_sys::g_object_get(
-                        window.as_ptr() as *mut _,
-                        "native\0".as_bytes().as_ptr() as *const _,
-                        &mut native as *mut _,
-                        0,
-                    );
-                };
-                // We don't want to lose click events
-                let _ = tx.blocking_send(InputEvent::Click {
-                    wid: native as u32,
-                    x: point.x(),
-                    y: point.y(),
-                    button: event.button,
-                    pressed: event.state != 0,
-                });
-            });
-            let tx = input_tx;
-            xrd_client.connect_keyboard_press_event(move |_, event| {
-                let event: &gdk::EventKey = event.downcast_ref().unwrap();
-                let string = unsafe {
-                    std::slice::from_raw_parts(event.as_ref().string, event.length() as _)
+            // Rendered without holding WindowState, so windows can be mapped and unmapped
+            // meanwhile. Unmapping waits for the render of the window, see `window_map::remove`.
+            let wids = window_map::keys(&self.window_state, |s| &s.windows).await;
+            for wid in wids {
+                let w = match window_map::get(&self.window_state, |s| &s.windows, &wid).await {
+                    Some(w) => w,
+                    // Unmapped meanwhile
+                    None => continue,
                 };
-                let string = string.to_owned();
-                let _ = tx.blocking_send(InputEvent::KeyPresses { string });
-            });
-
-            let (tx, exit_rx) = tokio::sync::mpsc::channel(1);
-            xrd_client.connect_request_quit_event(move |_, reason| {
-                if reason.reason == gxr::sys::GXR_QUIT_SHUTDOWN {
-                    let _ = tx.blocking_send(reason.clone());
-                }
-            });
-
-            (exit_rx, input_rx)
-        };
-
-        let mut win_mapped = picom.receive_win_mapped().await?;
-        let mut win_unmapped = picom.receive_win_unmapped().await?;
-
-        info!("Existing windows mapped, entering mainloop");
-        loop {
-            tokio::select! {
-                event = x11_rx.recv() => {
-                    trace!("{:?}", event);
-                    let this = self.clone();
-                    let event = event.with_context(|| anyhow!("Xorg connection broke"))?;
-                    tokio::spawn(async move {
-                        if let Err(e) = this.handle_x_events(event).await {
-                            error!("Failed to handle X events {}", e);
-                        }
-                    });
-                }
-                new_window = win_mapped.next() => {
-                    let new_window = new_window.with_context(|| anyhow!("dbus connection broke"))?;
-                    let wid = new_window.args()?.wid;
-                    debug!("{wid:#010x}, new window");
-                    let this = self.clone();
-                    let handle = tokio::spawn(async move {
-                        if let Err(e) = this.map_win(wid).await {
-                            info!("Failed to map window {}, {}", wid, e);
-                        }
+                {
+                    let w = w.read().await;
+                    if !w.dirty.swap(false, Ordering::AcqRel) {
+                        continue;
+                    }
+                    let now = std::time::Instant::now();
+                    let ready = limits.as_ref().map_or(Ok(()), |limits| {
+                        w.throttle.lock().unwrap().ready(limits, now)
                     });
-                    // feature: map_or_insert
-                    match self.pending_windows.lock().await.entry(wid) {
-                        Entry::Occupied(_) => {
-                            panic!("Window {} already mapped", wid);
-                        }
-                        Entry::Vacant(entry) => {
-                            entry.insert(handle);
-                        }
+                    if let Err(ready) = ready {
+                        w.dirty.store(true, Ordering::Release);
+                        deferred = Some(deferred.map_or(ready, |d| d.min(ready)));
+                        continue;
                     }
                 }
-                closed_window = win_unmapped.next() => {
-                    let closed_window = closed_window.with_context(|| anyhow!("dbus connection broke"))?;
-                    let wid = closed_window.args()?.wid;
-                    debug!("{wid:#010x} closed");
-                    if let Some(handle) = self.pending_windows.lock().await.remove(&wid) {
-                        debug!("stopped map_win task for {wid:#010x}");
-                        handle.abort();
-                        // we still need to continue, depending on the timing, map_win might have
-                        // already inserted the window into window_state.
+                let mut w = w.write().await;
+                if let Err(e) = self.render_win(&mut w).await {
+                    if let Some(gl::Error::ContextLost) = e.downcast_ref() {
+                        // Every texture is gone with the context, not just this window's
+                        drop(w);
+                        error!("The GL context was lost, resetting it");
+                        self.reset_gl().await;
+                        self.render_notify.notify_one();
+                        break;
                     }
-                    let this = self.clone();
-                    let mut window_state = this.window_state.write().await;
-                    let w = window_state.windows.remove(&wid);
-                    match w {
-                        Some(w) =>  {
-                            let w = w.into_inner();
-                            window_state.client_window_to_window.remove(&w.client_wid);
-                            drop(window_state);
-
-                            // We have to remove window from window_state before handling any
-                            // further events, so we wouldn't close a window with the same wid that
-                            // is created _after_ we receive this event. That's why it is not part of
-                            // tokio::spawn.
-                            tokio::spawn(async move {
-                                let window_state = this.window_state.write().await;
-                                // window_state here is locked exclusively at this point.
-                                unsafe { w.drop().await.unwrap() };
-                                drop(window_state);
-                                debug!("{wid:#010x} dropped");
-                            });
-                        }
-                        None => {},
+                    match e.downcast_ref::<XrdError>() {
+                        Some(e) => warn!("{e}"),
+                        // render_win will fail if window is closed, this is fine.
+                        None => debug!("Failed to render {:#010x}: {e}", w.id),
                     }
                 }
-                input_event = input_rx.recv() => {
-                    let input_event = input_event.unwrap();
-                    let this = self.clone();
-                    tokio::spawn(async move { this.handle_input_events(input_event).await });
-                }
-                exit = exit_rx.recv() => {
-                    let exit = exit.with_context(|| anyhow!("exit channel broke"))?;
-                    let xrd_client = self.xrd_client.lock().await;
-                    let gxr = xrd_client.gxr_context().unwrap();
-                    gxr.acknowledge_quit();
-                    info!("Received exit request {:?}", exit);
-                    break;
+                let now = std::time::Instant::now();
+                w.throttle.get_mut().unwrap().rendered(now);
+            }
+        }
+    }
+
+    /// Render windows that were damaged while hidden in VR as soon as they are shown again, by
+    /// us or in xrdesktop, so they don't show stale content until they are damaged again
+    async fn visibility_task(&self) {
+        let mut interval = tokio::time::interval(VISIBILITY_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if self.suspend.is_suspended() {
+                continue;
+            }
+            let mut shown = false;
+            let window_state = self.window_state.read().await;
+            for w in window_state.windows.values() {
+                let w = w.read().await;
+                if !w.hidden_dirty.load(Ordering::Acquire) {
+                    continue;
+                }
+                if w.xrd_window.lock().await.is_visible() {
+                    w.hidden_dirty.store(false, Ordering::Release);
+                    shown = true;
                 }
             }
+            drop(window_state);
+            if shown {
+                self.render_notify.notify_one();
+            }
         }
-        Ok(())
     }
-    async fn refresh_texture(&self, w: &mut Window) -> Result<bool> {
-        let x11_clone = self.x11.clone();
-        let wid = w.id;
-        let win_geometry =
-            block_in_place(|| Result::Ok(x11_clone.as_ref().get_geometry(wid)?.reply()?))?;
-        if let Some((width, height)) = w
-            .textures
-            .as_ref()
-            .map(|ts| (ts.x11_texture.width(), ts.x11_texture.height()))
-        {
-            if width != win_geometry.width as u32 || height != win_geometry.height as u32 {
-                debug!("Free old textures for {}", wid);
-                TextureSet::free(w.textures.take(), &self.gl, &self.x11).await?;
+
+    /// Log which windows are throttled for drawing too often, see `throttle`
+    async fn throttle_task(&self, limits: throttle::Limits) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let now = std::time::Instant::now();
+            let mut reports = Vec::new();
+            let window_state = self.window_state.read().await;
+            for (&wid, w) in &window_state.windows {
+                let report = w.read().await.throttle.lock().unwrap().poll(&limits, now);
+                if let Some(report) = report {
+                    reports.push((wid, report));
+                }
+            }
+            drop(window_state);
+            for (wid, report) in reports {
+                let title = match self.source.window_info(wid).await {
+                    Ok(info) => info.name,
+                    // Closed meanwhile
+                    Err(_) => continue,
+                };
+                match report {
+                    throttle::Report::Throttled(rate) => info!(
+                        "{wid:#010x} ({title:?}) draws {rate} times a second, rendering it at {} \
+                         Hz",
+                        self.config.throttle.rate
+                    ),
+                    throttle::Report::Lifted => {
+                        info!("{wid:#010x} ({title:?}) is rendered at full rate again")
+                    }
+                }
             }
         }
+    }
 
-        if w.textures.is_none() {
-            let (attrs, x11_pixmap) = block_in_place(|| {
-                let attrs = self.x11.get_window_attributes(wid)?.reply()?;
-                let x11_pixmap = self.x11.generate_id()?;
-                self.x11
-                    .composite_name_window_pixmap(wid, x11_pixmap)?
-                    .check()?;
-                Result::Ok((attrs, x11_pixmap))
-            })?;
-            let x11_texture = self.gl.bind_texture(x11_pixmap, attrs.visual).await?;
+    /// Set one of the reasons to suspend rendering. When rendering resumes, windows damaged in
+    /// the meantime are rendered.
+    async fn set_suspended(&self, reason: &AtomicBool, value: bool) {
+        let was_suspended = self.suspend.is_suspended();
+        reason.store(value, Ordering::Release);
+        let suspended = self.suspend.is_suspended();
+        if was_suspended == suspended {
+            return;
+        }
+        info!("{}", if suspended { "Suspended" } else { "Resumed" });
+        if !suspended {
+            let window_state = self.window_state.read().await;
+            for w in window_state.windows.values() {
+                let w = w.read().await;
+                // Ask X to report damage again. Fails if the window is gone, that's fine.
+                let _: Result<_> = block_in_place(|| self.subtract_damage(&w));
+                w.dirty.store(true, Ordering::Release);
+                // Don't count the time spent suspended as a stall
+                *w.stalled_since.lock().unwrap() = None;
+            }
+            self.render_notify.notify_one();
+        }
+    }
 
-            let (remote_texture, fd, size) = {
-                let xrd_client = self.xrd_client.lock().await; // Need to keep this alive for gulkan_client
-                let gulkan_client = xrd_client.gulkan().unwrap();
-                let extent = ash::vk::Extent2D {
-                    width: win_geometry.width.into(),
-                    height: win_geometry.height.into(),
-                };
-                let layout = xrd_client.upload_layout();
+    /// Hide all VR windows, or show them again. While hidden, windows aren't rendered and don't get
+    /// input, and their textures are dropped. They are still tracked, and X reports their damage
+    /// again when they are shown, which creates new textures.
+    async fn set_mirroring(&self, enabled: bool) {
+        // Lock windows before hidden_windows
+        let window_state = self.window_state.read().await;
+        let mut hidden_windows = self.hidden_windows.lock().await;
+        if enabled == hidden_windows.is_none() {
+            return;
+        }
+        info!("Mirroring {}", if enabled { "enabled" } else { "disabled" });
+        let mut xrd_windows = Vec::new();
+        if enabled {
+            let shown = hidden_windows.take().unwrap_or_default();
+            for (wid, w) in &window_state.windows {
+                if shown.contains(wid) {
+                    xrd_windows.push(w.read().await.xrd_window.lock().await.clone());
+                }
+            }
+        } else {
+            // Rendering stops before the textures go away
+            self.set_suspended(&self.suspend.hidden, true).await;
+            let mut hidden = HashSet::new();
+            for (&wid, w) in &window_state.windows {
+                let mut w = w.write().await;
+                if let Err(e) = TextureSet::free(w.textures.take(), &self.gl, &self.x11).await {
+                    warn!("Failed to free the textures of {wid:#010x}: {e}");
+                }
+                let xrd_window = w.xrd_window.get_mut();
+                if xrd_window.is_visible() {
+                    hidden.insert(wid);
+                    xrd_windows.push(xrd_window.clone());
+                }
+            }
+            *hidden_windows = Some(hidden);
+        }
+        drop(window_state);
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            for xrd_window in xrd_windows {
+                if enabled {
+                    xrd_window.show();
+                } else {
+                    xrd_window.hide();
+                }
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Failed to update VR windows: {e}");
+        }
+        drop(hidden_windows);
+        if !self.suspend.runtime_lost.load(Ordering::Acquire) {
+            let xrd_client = self.xrd_client.lock().await;
+            let xrd_cursor = xrd_client.desktop_cursor().unwrap();
+            if enabled {
+                xrd_cursor.show();
+            } else {
+                xrd_cursor.hide();
+            }
+        }
+        if enabled {
+            self.set_suspended(&self.suspend.hidden, false).await;
+            // Windows shown again can be on another desktop by now
+            self.update_desktop_filter().await;
+            self.limit_windows().await;
+        }
+    }
 
-                let mut size = 0;
-                let mut fd = 0;
-                let remote_texture = unsafe {
-                    glib::translate::from_glib_full(gulkan::sys::gulkan_texture_new_export_fd(
-                        gulkan_client.as_ptr(),
-                        std::mem::transmute(extent),
-                        ash::vk::Format::R8G8B8A8_SRGB.as_raw() as _,
-                        layout,
-                        &mut size,
-                        &mut fd,
-                    ))
+    /// Rebuild the textures of windows that were damaged but not rendered for
+    /// `render-stall-timeout`, e.g. after a GPU or X stall. If that doesn't help, the GL context
+    /// is rebuilt.
+    async fn watchdog_task(&self) {
+        let timeout = std::time::Duration::from_secs(self.config.render_stall_timeout);
+        let mut interval = tokio::time::interval(timeout / 2);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            if self.suspend.is_suspended() {
+                continue;
+            }
+            let mut reset_gl = false;
+            let window_state = self.window_state.read().await;
+            for (wid, w) in &window_state.windows {
+                // The same lock render_task takes, so this never runs in the middle of a render
+                let mut w = w.write().await;
+                let stalled_for = match *w.stalled_since.get_mut().unwrap() {
+                    Some(since) if since.elapsed() >= timeout => since.elapsed(),
+                    _ => continue,
                 };
-                (remote_texture, fd, size)
-            };
-            let imported_texture = self
-                .gl
-                .import_fd(
-                    win_geometry.width.into(),
-                    win_geometry.height.into(),
-                    fd,
-                    size as _,
-                )
-                .await?;
-            w.textures = Some(TextureSet {
-                x11_texture,
-                x11_pixmap,
-                remote_texture,
-                imported_texture,
-            });
-            Ok(true)
-        } else {
-            Ok(false)
+                warn!(
+                    "{wid:#010x} was damaged but not rendered for {stalled_for:?}, rebuilding its \
+                     textures. Has textures: {}, stats: {:?}",
+                    w.textures.is_some(),
+                    w.stats.snapshot()
+                );
+                if w.recoveries >= MAX_TEXTURE_REBUILDS {
+                    reset_gl = true;
+                    break;
+                }
+                w.recoveries += 1;
+                if let Err(e) = TextureSet::free(w.textures.take(), &self.gl, &self.x11).await {
+                    warn!("Failed to free the textures of {wid:#010x}: {e}");
+                }
+                *w.stalled_since.get_mut().unwrap() = Some(std::time::Instant::now());
+                w.dirty.store(true, Ordering::Release);
+            }
+            drop(window_state);
+            if reset_gl {
+                error!(
+                    "Rendering is still stalled after rebuilding textures, resetting the GL context"
+                );
+                self.reset_gl().await;
+            }
+            self.render_notify.notify_one();
         }
     }
 
-    async fn render_win(&self, w: &mut Window) -> Result<()> {
-        if !w.xrd_window.get_mut().is_visible() {
-            return Ok(());
+    /// Replace the GL context, after rebuilding the textures of a window didn't get it rendered,
+    /// or after the context was lost. Nothing is rendered until the new context is there, or
+    /// textures would be made in the old one: windows stay locked, and WindowState too, so no
+    /// window is added meanwhile.
+    async fn reset_gl(&self) {
+        let window_state = self.window_state.read().await;
+        let mut windows = Vec::new();
+        for (&wid, w) in &window_state.windows {
+            windows.push((wid, w.write().await));
+        }
+        // All textures belong to the old context
+        for (wid, w) in &mut windows {
+            if let Err(e) = TextureSet::free(w.textures.take(), &self.gl, &self.x11).await {
+                warn!("Failed to free the textures of {wid:#010x}: {e}");
+            }
+            w.recoveries = 0;
+            *w.stalled_since.get_mut().unwrap() = Some(std::time::Instant::now());
+            w.dirty.store(true, Ordering::Release);
         }
+        if let Err(e) = self.gl.reset_context().await {
+            error!("Failed to reset the GL context: {e}");
+        }
+        drop(windows);
+        drop(window_state);
+    }
 
-        #[cfg(debug_assertions)]
-        self.gl.capture(true).await?;
+    /// Whether someone is wearing the headset. There is no proximity sensor signal in gxr, so we
+    /// go by whether the headset is connected and tracked.
+    async fn is_user_present(&self) -> bool {
+        let xrd_client = self.xrd_client.lock().await;
+        if !hmd_connected(&xrd_client) {
+            return false;
+        }
+        let gxr = xrd_client.gxr_context().unwrap();
+        unsafe {
+            let hmd = gxr::sys::GXR_DEVICE_INDEX_HMD as u32;
+            let device_manager = gxr::sys::gxr_context_get_device_manager(gxr.as_ptr());
+            let device = gxr::sys::gxr_device_manager_get(device_manager, hmd as u64);
+            // Not known to the device manager yet, assume the user is there
+            device.is_null() || gxr::sys::gxr_device_is_pose_valid(device) != 0
+        }
+    }
 
-        let refreshed = self.refresh_texture(w).await?;
-        let textures = w.textures.as_ref().unwrap();
-        self.gl
-            .blit(&textures.x11_texture, &textures.imported_texture)
-            .await?;
+    /// Whether VR input is ours. The runtime takes it while its dashboard or another scene
+    /// application has the focus, then clicks would go to the desktop behind it.
+    async fn is_input_available(&self) -> bool {
+        let xrd_client = self.xrd_client.lock().await;
+        xrd_client
+            .gxr_context()
+            .map_or(true, |gxr| gxr.is_input_available())
+    }
 
-        #[cfg(debug_assertions)]
-        self.gl.capture(false).await?;
+    /// Suspend rendering and block input while nobody is wearing the headset, as configured, and
+    /// block input while the runtime has it. gxr has no signal for either, so it is checked every
+    /// second, and right away when the VR runtime connects or disconnects a device.
+    async fn presence_task(&self) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = self.devices_changed.notified() => (),
+            }
+            let absent = !self.is_user_present().await;
+            if self.config.suspend_when_absent
+                && absent != self.suspend.absent.load(Ordering::Acquire)
+            {
+                self.set_suspended(&self.suspend.absent, absent).await;
+            }
+            // Controllers lying around shouldn't click or type anything, neither should clicks
+            // meant for the dashboard
+            let unavailable = !self.is_input_available().await;
+            let blocked = (self.config.block_input_when_absent && absent) || unavailable;
+            if blocked != self.stats.input_blocked() {
+                if unavailable {
+                    info!("The VR runtime has the input, e.g. for its dashboard, ignoring it");
+                } else if blocked {
+                    info!("Nobody is wearing the headset, ignoring VR input");
+                } else {
+                    info!("Accepting VR input again");
+                }
+                self.stats.set_input_blocked(blocked);
+            }
+        }
+    }
 
-        let xrd_window = w.xrd_window.get_mut();
-        if refreshed {
-            xrd_window.set_and_submit_texture(&textures.remote_texture);
+    /// Ping windows that haven't drawn anything for a while, and dim the ones that don't answer,
+    /// like window managers do for hung applications.
+    async fn unresponsive_task(&self) {
+        let config = &self.config.unresponsive;
+        let timeouts = ping::Timeouts {
+            idle: std::time::Duration::from_secs(config.idle),
+            answer: std::time::Duration::from_secs(config.timeout),
+        };
+        let start = std::time::Instant::now();
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let now = std::time::Instant::now();
+            // Only has to differ between pings of the same window
+            let timestamp = now.duration_since(start).as_millis() as u32;
+            let window_state = self.window_state.read().await;
+            for w in window_state.windows.values() {
+                let w = w.read().await;
+                // The desktop mirror is no application
+                if self.desktop_region(w.id).is_some() {
+                    continue;
+                }
+                let action = w
+                    .responsiveness
+                    .lock()
+                    .unwrap()
+                    .poll(&timeouts, timestamp, now);
+                match action {
+                    Some(ping::Action::Ping(timestamp)) => {
+                        let sent = block_in_place(|| self.ping(w.client_wid, timestamp));
+                        if !sent.unwrap_or(false) {
+                            // Without an answer to wait for, count it as answered
+                            w.responsiveness.lock().unwrap().activity(now);
+                        }
+                    }
+                    Some(ping::Action::Unresponsive) => {
+                        info!("{:#010x} is not responding", w.id);
+                        set_dimmed(&*w.xrd_window.lock().await, Some(config.brightness));
+                    }
+                    None => (),
+                }
+            }
+        }
+    }
+
+    /// Send `_NET_WM_PING` to `client_wid`. Returns false if it doesn't support it.
+    fn ping(&self, client_wid: u32, timestamp: u32) -> Result<bool> {
+        if !self.supports_protocol(client_wid, self.atoms._NET_WM_PING)? {
+            return Ok(false);
+        }
+        let event = xproto::ClientMessageEvent::new(
+            32,
+            client_wid,
+            self.atoms.WM_PROTOCOLS,
+            [self.atoms._NET_WM_PING, timestamp, client_wid, 0, 0],
+        );
+        self.x11
+            .send_event(false, client_wid, xproto::EventMask::NO_EVENT, event)?
+            .check()?;
+        Ok(true)
+    }
+
+    /// Whether `protocol` is in the `WM_PROTOCOLS` of `client_wid`
+    fn supports_protocol(&self, client_wid: u32, protocol: xproto::Atom) -> Result<bool> {
+        let protocols = self
+            .x11
+            .get_property(
+                false,
+                client_wid,
+                self.atoms.WM_PROTOCOLS,
+                xproto::AtomEnum::ATOM,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(protocols
+            .value32()
+            .map_or(false, |mut p| p.any(|a| a == protocol)))
+    }
+
+    /// Connect to the VR runtime again after it went away, e.g. because SteamVR was restarted.
+    /// The signals of the new client are connected to `exit_tx` and the input channel, like the
+    /// first client's in `run`.
+    async fn runtime_task(
+        self: &Arc<Self>,
+        exit_tx: tokio::sync::mpsc::Sender<gxr::sys::GxrQuitEvent>,
+        control_tx: tokio::sync::mpsc::Sender<dbus::Request>,
+    ) {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let mut reconnector = Reconnector {
+                mode: self.client_mode,
+                client: None,
+            };
+            let transition = block_in_place(|| {
+                let mut runtime = self.runtime.lock().unwrap();
+                runtime.poll(&mut reconnector, std::time::Instant::now())
+            });
+            if let (Some(runtime::Transition::Resume), Some(client)) =
+                (transition, reconnector.client)
+            {
+                let result = self
+                    .runtime_resumed(client, exit_tx.clone(), control_tx.clone())
+                    .await;
+                if let Err(e) = result {
+                    error!("Failed to set up the windows again: {e}");
+                }
+            }
+        }
+    }
+
+    /// The VR runtime went away, and its windows with it. Remember where our windows were, and
+    /// stop mirroring them until it is back. X windows are still followed meanwhile.
+    async fn runtime_lost(self: &Arc<Self>) {
+        warn!("The VR runtime went away, waiting for it to come back");
+        self.set_suspended(&self.suspend.runtime_lost, true).await;
+        self.unmap_all().await;
+    }
+
+    /// Stop mirroring all windows, remembering where they were to put them back there when they
+    /// are mirrored again.
+    async fn unmap_all(self: &Arc<Self>) {
+        let wids: HashSet<u32> = {
+            let window_state = self.window_state.read().await;
+            let pending_windows = self.pending_windows.lock().await;
+            window_state
+                .windows
+                .keys()
+                .chain(pending_windows.keys())
+                .copied()
+                .collect()
+        };
+        for wid in wids {
+            // The desktop mirror is placed the same way every time
+            if self.desktop_region(wid).is_none() {
+                let window_state = self.window_state.read().await;
+                if let Some(w) = window_state.windows.get(&wid) {
+                    self.park(&*w.read().await, false).await;
+                }
+            }
+            self.unmap_win(wid).await;
+        }
+    }
+
+    /// Set up `client`, connected to the VR runtime that came back, like the first one in `run`,
+    /// and mirror the windows again, where they were.
+    async fn runtime_resumed(
+        self: &Arc<Self>,
+        client: xrd::Client,
+        exit_tx: tokio::sync::mpsc::Sender<gxr::sys::GxrQuitEvent>,
+        control_tx: tokio::sync::mpsc::Sender<dbus::Request>,
+    ) -> Result<()> {
+        info!("The VR runtime is back");
+        *self.vr_pids.lock().unwrap() = vr_windows::pids();
+        let input_tx = self.input_tx.clone();
+        let devices_changed = self.devices_changed.clone();
+        let client_clone = client.clone();
+        let haptics_config = self.config.haptics.clone();
+        let toggle_button = self.config.toggle_mirroring.button;
+        let haptics = utils::invoke(&glib::MainContext::default(), move || {
+            let haptics = haptics::Haptics::new(&client_clone, &haptics_config).map(Arc::new);
+            connect_client_signals(
+                &client_clone,
+                input_tx,
+                exit_tx,
+                devices_changed,
+                haptics.clone(),
+                toggle_button,
+            );
+            haptics
+        })
+        .await?;
+        *self.haptics.lock().unwrap() = haptics;
+        if self.config.close_button {
+            add_close_button(&client, control_tx);
+        }
+        // Windows share the client through `xrd_client`, so they use the new one from now on
+        *self.xrd_client.lock().await = client;
+        // Cursor textures belonged to the old client
+        self.cursors.lock().await.clear();
+        // The runtime may be on another GPU now
+        self.gl.forget_formats();
+        self.set_suspended(&self.suspend.runtime_lost, false).await;
+        if self.desktop_mirror.load(Ordering::Acquire) {
+            self.map_desktop().await?;
         } else {
-            xrd_window.submit_texture();
+            Self::setup_initial_windows(self).await?;
+        }
+        self.refresh_cursor(0).await?;
+        // Stays hidden while mirroring is toggled off
+        if !self.suspend.hidden.load(Ordering::Acquire) {
+            self.xrd_client
+                .lock()
+                .await
+                .desktop_cursor()
+                .unwrap()
+                .show();
         }
         Ok(())
     }
 
-    async fn map_win_impl(&self, wid: u32) -> Result<()> {
-        let picom_service = format!("com.github.chjj.compton.{}", self.display);
-        let proxy = picom::WindowProxy::builder(&self.dbus)
-            .destination(picom_service)?
-            .path(format!("{}/{}/{}", PICOM_OBJECT_PATH, "windows", wid))
-            .map(|pb| pb.cache_properties(zbus::CacheProperties::No))?
-            .build()
-            .await?;
-        debug!("Dbus connected {}", wid);
-        if !proxy.mapped().await? {
-            return Ok(());
+    /// Log what was rendered every `stats-interval` seconds, and which window rendered the most.
+    async fn stats_task(&self) {
+        let period = std::time::Duration::from_secs(self.config.stats_interval);
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut previous_total = self.stats.total();
+        let mut previous_windows = self.stats.windows();
+        loop {
+            interval.tick().await;
+            let total = self.stats.total();
+            let windows = self.stats.windows();
+            let delta = total.since(&previous_total);
+            let seconds = period.as_secs_f64();
+            info!(
+                "Stats: {:.1} damage events/s, {:.1} submits/s, {} texture allocations, \
+                 {}us average render time",
+                delta.damage_events as f64 / seconds,
+                delta.submits as f64 / seconds,
+                delta.texture_allocations,
+                delta.average_render_time_us(),
+            );
+            let busiest = windows
+                .iter()
+                .map(|(wid, w)| {
+                    let previous = previous_windows.get(wid).copied().unwrap_or_default();
+                    (*wid, w.since(&previous))
+                })
+                .max_by_key(|(_, delta)| delta.submits);
+            if let Some((wid, delta)) = busiest.filter(|(_, delta)| delta.submits > 0) {
+                info!(
+                    "Stats: {wid:#010x} rendered most, {:.1} submits/s",
+                    delta.submits as f64 / seconds
+                );
+            }
+            previous_total = total;
+            previous_windows = windows;
         }
-        let ty = proxy.type_().await?;
-        debug!("window {} is {}", wid, ty);
-        if ty != "normal"
-            && ty != "menu"
-            && ty != "popup_menu"
-            && ty != "dropdown_menu"
-            && ty != "utility"
-        {
-            return Ok(());
+    }
+
+    /// Input transforms of `wids`, windows without textures yet are left out.
+    async fn input_transforms(
+        &self,
+        wids: impl Iterator<Item = u32>,
+    ) -> HashMap<u32, input::InputTransform> {
+        let window_state = self.window_state.read().await;
+        let mut transforms = HashMap::new();
+        for wid in wids {
+            if let Some(w) = window_state.windows.get(&wid) {
+                if let Some(textures) = &w.read().await.textures {
+                    transforms.insert(wid, textures.input_transform);
+                }
+            }
         }
-        let window_name = proxy.name().await?;
-        let client_wid = proxy.client_win().await?;
-        let transient_for = block_in_place(|| {
-            Result::Ok(
-                self.x11
-                    .get_property(
-                        false,
-                        wid,
-                        self.atoms.WM_TRANSIENT_FOR,
-                        xproto::AtomEnum::WINDOW,
-                        0,
-                        1,
-                    )?
-                    .reply()?,
-            )
-        })?
-        .value32()
-        .and_then(|mut w| w.next());
-        debug!("transient for of {} is {:?}", wid, transient_for);
-        // TODO: cache root geometry
-        let root_win = self.x11.setup().roots[self.screen as usize].root;
-        let (root_geometry, win_geometry) = block_in_place(|| {
-            Result::Ok((
-                self.x11.get_geometry(root_win)?.reply()?,
-                self.x11.get_geometry(wid)?.reply()?,
-            ))
-        })?;
-        if win_geometry.x <= -(win_geometry.width as i16)
-            || win_geometry.y <= -(win_geometry.height as i16)
-            || win_geometry.x >= root_geometry.width as _
-            || win_geometry.y >= root_geometry.height as _
-        {
-            // If the window is entirely outside of the screen, hide it
-            // Firefox does this and has a 1x1 window outside the screen
-            return Ok(());
+        transforms
+    }
+
+    fn root(&self) -> xproto::Window {
+        self.x11.setup().roots[self.screen as usize].root
+    }
+
+    fn root_of(&self, screen: usize) -> xproto::Window {
+        self.x11.setup().roots[screen].root
+    }
+
+    /// Whether `window` is the root window of a mirrored screen
+    fn is_root(&self, window: xproto::Window) -> bool {
+        self.screens
+            .iter()
+            .any(|&screen| self.root_of(screen) == window)
+    }
+
+    /// The screen `wid` is on
+    fn screen_of(&self, wid: u32) -> Result<usize> {
+        let root = block_in_place(|| Result::Ok(self.x11.get_geometry(wid)?.reply()?.root))?;
+        self.x11
+            .setup()
+            .roots
+            .iter()
+            .position(|screen| screen.root == root)
+            .ok_or_else(|| anyhow!("{wid:#010x} has an unknown root {root:#010x}"))
+    }
+
+    fn root_geometry(&self, screen: usize) -> placement::Geometry {
+        self.root_geometry.lock().unwrap()[&screen]
+    }
+
+    /// `win`, a top level window on `screen`, and the geometry to place it relative to, with
+    /// the mirrored screens side by side
+    fn layout_geometry(
+        &self,
+        screen: usize,
+        win: &placement::Geometry,
+    ) -> (placement::Geometry, placement::Geometry) {
+        let root_geometry = self.root_geometry.lock().unwrap();
+        let roots: Vec<_> = self.screens.iter().map(|s| root_geometry[s]).collect();
+        let index = self.screens.iter().position(|&s| s == screen).unwrap_or(0);
+        placement::side_by_side(&roots, index, win)
+    }
+
+    /// The part of the screen a window of the desktop mirror shows: all of it for the root
+    /// window, or one monitor. None for any other window.
+    fn desktop_region(&self, wid: u32) -> Option<placement::Geometry> {
+        if wid == self.root() {
+            return Some(self.root_geometry(self.screen as usize));
         }
+        self.monitors.lock().unwrap().get(&wid).copied()
+    }
 
-        let xrd_window = {
-            let xrd_client = self.xrd_client.lock().await;
-            let xrd_window = xrd::Window::new_from_pixels(
-                &*xrd_client,
-                &window_name,
-                win_geometry.width.into(),
-                win_geometry.height.into(),
-                PIXELS_PER_METER,
-            )
-            .with_context(|| anyhow::anyhow!("failed to create xrdWindow"))?;
-            unsafe {
-                gobject_sys::g_object_set(
-                    xrd_window.as_object_ref().to_glib_none().0,
-                    "native\0".as_bytes().as_ptr() as *const _,
-                    wid as *const std::ffi::c_void,
-                    0,
-                );
-                xrd::sys::xrd_client_add_window(
-                    xrd_client.as_ptr(),
-                    xrd_window.as_ptr(),
-                    true as _,
-                    std::mem::transmute(wid as usize),
+    /// Geometry of `wid` as `GetGeometry` reports it, also for monitors of the desktop mirror,
+    /// which are no windows X knows about.
+    fn window_geometry(&self, wid: u32) -> Result<xproto::GetGeometryReply> {
+        let monitor = self.monitors.lock().unwrap().get(&wid).copied();
+        match monitor {
+            Some(monitor) => {
+                let screen = &self.x11.setup().roots[self.screen as usize];
+                Ok(xproto::GetGeometryReply {
+                    depth: screen.root_depth,
+                    sequence: 0,
+                    length: 0,
+                    root: screen.root,
+                    x: monitor.x,
+                    y: monitor.y,
+                    width: monitor.width,
+                    height: monitor.height,
+                    border_width: 0,
+                })
+            }
+            None => block_in_place(|| Ok(self.x11.get_geometry(wid)?.reply()?)),
+        }
+    }
+
+    /// Active monitors, by CRTC, with the name of their first output
+    fn query_monitors(&self) -> Result<HashMap<u32, (String, placement::Geometry)>> {
+        let resources = self
+            .x11
+            .randr_get_screen_resources_current(self.root())?
+            .reply()?;
+        let mut monitors = HashMap::new();
+        for crtc in resources.crtcs {
+            let info = self
+                .x11
+                .randr_get_crtc_info(crtc, resources.config_timestamp)?
+                .reply()?;
+            // Disabled CRTCs have no mode
+            if info.mode == x11rb::NONE || info.width == 0 || info.height == 0 {
+                continue;
+            }
+            let name = match info.outputs.first() {
+                Some(&output) => String::from_utf8_lossy(
+                    &self
+                        .x11
+                        .randr_get_output_info(output, resources.config_timestamp)?
+                        .reply()?
+                        .name,
                 )
+                .into_owned(),
+                None => format!("Monitor {crtc:#x}"),
             };
-            xrd_window
-        };
-        debug!("window created {}", wid);
+            let geometry = placement::Geometry {
+                x: info.x,
+                y: info.y,
+                width: info.width,
+                height: info.height,
+            };
+            monitors.insert(crtc, (name, geometry));
+        }
+        Ok(monitors)
+    }
 
-        let (window_center_x, window_center_y) = (
-            win_geometry.x + win_geometry.width as i16 / 2,
-            win_geometry.y + win_geometry.height as i16 / 2,
-        );
+    /// Mirror each monitor as a VR window of its own, placed like the monitors are arranged.
+    /// Called again when monitors change, adding windows for new monitors and removing the ones
+    /// of monitors that were unplugged or turned off.
+    async fn sync_monitors(self: &Arc<Self>) -> Result<()> {
+        let current = block_in_place(|| self.query_monitors())?;
+        let old = self.monitors.lock().unwrap().clone();
+        for (crtc, geometry) in &old {
+            if current.get(crtc).map(|(_, g)| g) != Some(geometry) {
+                info!("Monitor {crtc:#x} changed or is gone");
+                self.unmap_win(*crtc).await;
+            }
+        }
+        for (crtc, (name, geometry)) in current {
+            if old.get(&crtc) == Some(&geometry) {
+                continue;
+            }
+            self.monitors.lock().unwrap().insert(crtc, geometry);
+            if let Err(e) = self.map_plane(crtc, &name, geometry).await {
+                self.monitors.lock().unwrap().remove(&crtc);
+                return Err(e);
+            }
+            info!(
+                "Mirroring monitor {name}, {}x{}+{}+{}",
+                geometry.width, geometry.height, geometry.x, geometry.y
+            );
+        }
+        Ok(())
+    }
 
+    /// A screen was resized, or monitors were added or removed. Update the root geometries and
+    /// place top level windows again, relative to the new screens.
+    async fn handle_screen_change(self: &Arc<Self>) -> Result<()> {
+        let mut changed = false;
+        for &screen in &self.screens {
+            let root = self.root_of(screen);
+            let root_geometry: placement::Geometry =
+                (&block_in_place(|| Result::Ok(self.x11.get_geometry(root)?.reply()?))?).into();
+            let old = self
+                .root_geometry
+                .lock()
+                .unwrap()
+                .insert(screen, root_geometry);
+            if let Some(old) = old.filter(|old| *old != root_geometry) {
+                info!(
+                    "Screen {screen} changed from {}x{} to {}x{}",
+                    old.width, old.height, root_geometry.width, root_geometry.height
+                );
+                changed = true;
+            }
+        }
+        if self.config.desktop_mirror.per_monitor
+            && self.desktop_mirror.load(Ordering::Acquire)
+            && !self.suspend.runtime_lost.load(Ordering::Acquire)
         {
-            // Lock windows before xrd_client, because that's the order we used in render_win ->
-            // refresh_texture.
-            let window_state = self.window_state.read().await;
-            let xrd_client = self.xrd_client.lock().await;
-            let parent = if ty.contains("menu") || ty == "utility" {
-                if let Some(leader) = transient_for {
-                    Self::find_window_group(&window_state, leader).await
-                } else {
-                    let hovered = xrd_client.synth_hovered();
-                    debug!("no leader, hovered is {:?}", hovered);
-                    hovered.map(|hovered| {
-                        let mut native = 0u64;
-                        unsafe {
-                            gobject_sys::g_object_get(
-                                hovered.as_ptr() as *mut _,
-                                "native\0".as_bytes().as_ptr() as *const _,
-                                &mut native as *mut _,
-                                0,
-                            );
-                        }
-                        debug!("hovered is native {}", native);
-                        window_state.windows.get(&(native as _)).unwrap()
-                    })
-                }
-            } else {
-                None
+            self.sync_monitors().await?;
+        }
+        // Windows now outside of the screen stay mirrored, their contents are still there. Only
+        // windows mapped outside of it aren't mirrored, see `decide`.
+        if changed && self.config.layout.relayout_on_screen_change {
+            self.relayout().await;
+        }
+        Ok(())
+    }
+
+    /// Place top level windows again, where they would be placed first with the current screen
+    /// size and layout preset. Where they were moved in VR is forgotten. They glide there, see
+    /// `start_glide`.
+    async fn relayout(&self) {
+        let preset = self.preset();
+        let window_state = self.window_state.read().await;
+        for (index, w) in window_state.windows.values().enumerate() {
+            let w = w.read().await;
+            let xrd_window = w.xrd_window.lock().await;
+            // Children are placed relative to their parent, they move with it
+            let data = unsafe { xrd::sys::xrd_window_get_data(xrd_window.as_ptr()) };
+            if unsafe { !(*data).parent_window.is_null() } {
+                continue;
+            }
+            // The window could be closing, it's removed soon then
+            let win_geometry = match self.window_geometry(w.id) {
+                Ok(geometry) => placement::Geometry::from(&geometry),
+                Err(_) => continue,
             };
-            drop(xrd_client);
-            if let Some(parent) = parent {
-                let parent = parent.read().await;
-                let parent_geometry =
-                    block_in_place(|| Result::Ok(self.x11.get_geometry(parent.id)?.reply()?))?;
-                let (parent_center_x, parent_center_y) = (
-                    parent_geometry.x + parent_geometry.width as i16 / 2,
-                    parent_geometry.y + parent_geometry.height as i16 / 2,
-                );
-                let mut offset = graphene::Point::new(
-                    (window_center_x - parent_center_x) as _,
-                    -(window_center_y - parent_center_y) as _,
+            let (win_geometry, layout) = self.layout_geometry(w.screen, &win_geometry);
+            let mut transform = placement::transform(
+                &win_geometry,
+                &layout,
+                index,
+                PIXELS_PER_METER,
+                self.config.layout.depth,
+                self.config.layout.z_spacing,
+                preset,
+            );
+            xrd_window.set_reset_transformation(&mut transform);
+            if self.glides() {
+                let mut from = graphene::Matrix::new_identity();
+                xrd_window.is_transformation(&mut from);
+                self.start_glide(
+                    w.id,
+                    xrd_window.clone(),
+                    from.to_float(),
+                    transform.to_float(),
                 );
-                parent
-                    .xrd_window
-                    .lock()
-                    .await
-                    .add_child(&xrd_window, &mut offset);
             } else {
-                let point = graphene::Point3D::new(
-                    (window_center_x - root_geometry.width as i16 / 2) as f32 / PIXELS_PER_METER,
-                    -(window_center_y - root_geometry.height as i16 * 3 / 4) as f32
-                        / PIXELS_PER_METER,
-                    window_state.windows.len() as f32 / 3.0 - 8.0,
-                );
-                let mut transform = graphene::Matrix::new_translate(&point);
                 xrd_window.set_transformation(&mut transform);
-                xrd_window.set_reset_transformation(&mut transform);
             }
         }
-        debug!("position set");
+    }
+
+    /// Whether windows glide to new places instead of jumping there
+    fn glides(&self) -> bool {
+        self.config.layout.glide > 0 && !self.suspend.is_suspended()
+    }
+
+    fn preset(&self) -> placement::Preset {
+        *self.preset.lock().unwrap()
+    }
+
+    /// Ask the window manager to focus and raise `wid`, if it isn't the active window already.
+    /// Without this the WM never sees the clicks we synthesize, so keyboard focus doesn't follow
+    /// clicks in VR.
+    async fn activate_window(&self, wid: u32) -> Result<()> {
+        if self.desktop_region(wid).is_some() {
+            // Desktop mirror, the window manager sees the click on the actual window
+            return Ok(());
+        }
+        let client_wid = match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => w.read().await.client_wid,
+            None => return Ok(()),
+        };
+        block_in_place(|| self.activate_client_window(client_wid))
+    }
+
+    /// The client window the window manager of the screen of `root` says is active
+    fn active_window(&self, root: xproto::Window) -> Result<Option<u32>> {
+        Ok(self
+            .x11
+            .get_property(
+                false,
+                root,
+                self.atoms._NET_ACTIVE_WINDOW,
+                xproto::AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()?
+            .value32()
+            .and_then(|mut w| w.next())
+            .filter(|&wid| wid != x11rb::NONE))
+    }
+
+    fn activate_client_window(&self, client_wid: u32) -> Result<()> {
+        // Each screen has a window manager of its own
+        let root = self.x11.get_geometry(client_wid)?.reply()?.root;
+        let active = self.active_window(root)?;
+        if active == Some(client_wid) {
+            return Ok(());
+        }
+        debug!("activating {client_wid:#010x}");
+        // Source indication 2: the request comes from the user, through a pager-like tool.
+        // Pagers may use CURRENT_TIME, WMs don't apply focus stealing prevention to them.
+        let event = xproto::ClientMessageEvent::new(
+            32,
+            client_wid,
+            self.atoms._NET_ACTIVE_WINDOW,
+            [2, x11rb::CURRENT_TIME, active.unwrap_or(x11rb::NONE), 0, 0],
+        );
+        self.x11
+            .send_event(
+                false,
+                root,
+                xproto::EventMask::SUBSTRUCTURE_REDIRECT | xproto::EventMask::SUBSTRUCTURE_NOTIFY,
+                event,
+            )?
+            .check()?;
+        Ok(())
+    }
+
+    /// Type a key of the VR keyboard: its text if it has any, keys without text, or with a
+    /// control character like Return, by their keysym
+    fn type_key(&self, string: String, keyval: u32, held: Vec<u32>) -> Result<()> {
+        if synth::is_text(&string) {
+            self.input_synth.text(string, held)
+        } else {
+            self.input_synth.key(keyval, held)
+        }
+    }
+
+    fn key_press(
+        &self,
+        input_state: &mut input::InputState,
+        string: String,
+        keyval: u32,
+    ) -> Result<()> {
+        // Pressing another key stops the repeat, like on a physical keyboard
+        if let Some(repeat) = input_state.key_repeat.take() {
+            repeat.abort();
+        }
+        let focus = block_in_place(|| Result::Ok(self.x11.get_input_focus()?.reply()?.focus))?;
+        if input::StickyModifiers::is_modifier(keyval) {
+            // Modifiers latch instead of being typed, and don't repeat
+            input_state.modifiers.tap(keyval, focus);
+            return Ok(());
+        }
+        let held = input_state.modifiers.key(focus);
+        self.type_key(string.clone(), keyval, held.clone())?;
+
+        if let (Some(repeat), true) = (self.key_repeat, input_state.key_releases) {
+            let tx = self.input_tx.clone();
+            input_state.key_repeat = Some(tokio::spawn(async move {
+                tokio::time::sleep(repeat.delay).await;
+                loop {
+                    let event = InputEvent::KeyRepeat {
+                        string: string.clone(),
+                        keyval,
+                        held: held.clone(),
+                        focus,
+                    };
+                    if tx.send(event).await.is_err() {
+                        break;
+                    }
+                    tokio::time::sleep(repeat.interval).await;
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    async fn handle_input_events(&self, input_event: InputEvent) {
+        trace!("{:?}", input_event);
+        if input_event.synthesizes_input() && self.suspend.hidden.load(Ordering::Acquire) {
+            trace!("Mirroring is toggled off, dropped");
+            return;
+        }
+        if input_event.synthesizes_input() && self.stats.input_blocked() {
+            trace!("Nobody is wearing the headset, dropped");
+            self.stats.blocked_input_event();
+            return;
+        }
+        let mut input_state = self.input_state.lock().await;
+        // Windows this event can send input to: its own, and the ones with buttons held down
+        let transforms = self
+            .input_transforms(
+                input_event
+                    .wid()
+                    .into_iter()
+                    .chain(input_state.drags.values().map(|drag| drag.wid)),
+            )
+            .await;
+        let raise_window_and_resolve_position = |wid: u32, x: f32, y: f32| {
+            let geometry = if self.desktop_region(wid).is_some() {
+                // The desktop mirror can't be raised
+                self.window_geometry(wid)?
+            } else {
+                block_in_place(|| {
+                    let cookie1 = self
+                        .x11
+                        .configure_window(
+                            wid,
+                            &xproto::ConfigureWindowAux {
+                                stack_mode: Some(xproto::StackMode::ABOVE),
+                                ..Default::default()
+                            },
+                        )
+                        .unwrap();
+                    let cookie2 = self.x11.get_geometry(wid).unwrap();
+                    cookie1.check()?;
+                    Result::Ok(cookie2.reply()?)
+                })?
+            };
+            let transform = transforms.get(&wid).copied().unwrap_or_default();
+            let (x, y) = input::Placement::new(&geometry, transform).to_root(x, y);
+            Result::Ok((geometry.root, x as _, y as _))
+        };
+        let input_synth = &self.input_synth;
+        let release = |drag: input::Drag| {
+            raise_window_and_resolve_position(drag.wid, drag.x, drag.y)
+                .and_then(|(root, x, y)| input_synth.click(root, x, y, drag.button as _, false))
+        };
+
+        if let InputEvent::ToggleButton { pressed } = input_event {
+            if let Some(timer) = input_state.long_press_timer.take() {
+                timer.abort();
+            }
+            if pressed {
+                let tx = self.input_tx.clone();
+                let hold = self.config.toggle_mirroring.hold();
+                input_state.long_press_timer = Some(tokio::spawn(async move {
+                    tokio::time::sleep(hold).await;
+                    let _ = tx.send(InputEvent::LongPress).await;
+                }));
+            }
+        }
+
+        let hover_focus = &self.config.hover_focus;
+        let activate = match input_event {
+            InputEvent::Click { pressed: true, .. } => {
+                self.config.activate_on_click && input_state.grabbed.is_none()
+            }
+            // Pointer is still on the window the dwell timer was started for
+            InputEvent::HoverDwell { wid } => {
+                input_state.pointer.map(|(pointer_wid, ..)| pointer_wid) == Some(wid)
+            }
+            InputEvent::Move { wid, .. }
+                if hover_focus.enabled
+                    && input_state.grabbed.is_none()
+                    && input_state.pointer.map(|(pointer_wid, ..)| pointer_wid) != Some(wid) =>
+            {
+                // Hover target changed, restart the dwell timer
+                if let Some(timer) = input_state.hover_timer.take() {
+                    timer.abort();
+                }
+                let tx = self.input_tx.clone();
+                let delay = hover_focus.delay();
+                input_state.hover_timer = Some(tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = tx.send(InputEvent::HoverDwell { wid }).await;
+                }));
+                false
+            }
+            _ => false,
+        };
+        if activate {
+            let wid = input_event.wid().unwrap();
+            if let Err(e) = self.activate_window(wid).await {
+                debug!("Failed to activate {wid:#010x}: {e}");
+            }
+        }
+
+        let now = std::time::Instant::now();
+        let double_click = &self.config.double_click;
+        let result = match input_event {
+            // Handled above
+            InputEvent::HoverDwell { .. } | InputEvent::ToggleButton { .. } => Ok(()),
+            InputEvent::LongPress => {
+                input_state.long_press_timer = None;
+                let hidden = self.suspend.hidden.load(Ordering::Acquire);
+                self.set_mirroring(hidden).await;
+                Ok(())
+            }
+            InputEvent::Click {
+                wid,
+                button,
+                pressed,
+                ..
+            } if self.config.pin.button.is_some()
+                && input::ControllerButton::from_xrd(button) == self.config.pin.button =>
+            {
+                if pressed {
+                    self.set_pinned(wid, None).await;
+                }
+                Ok(())
+            }
+            InputEvent::Click {
+                wid,
+                button,
+                pressed,
+                ..
+            } if self.gesture(button).is_some() => {
+                if pressed {
+                    input_state.gesture = Some((wid, self.gesture(button).unwrap()));
+                } else if let Some((wid, gesture)) = input_state.gesture.take() {
+                    self.remember_gesture(wid, gesture).await;
+                }
+                Ok(())
+            }
+            InputEvent::Scroll { wid, dy, .. }
+                if input_state.gesture.map(|(gesture_wid, _)| gesture_wid) == Some(wid) =>
+            {
+                match input_state.gesture.unwrap().1 {
+                    // xrdesktop reports pointer positions in window pixels whatever the scale, so
+                    // clicks keep landing where they should on a scaled window
+                    input::Gesture::Scale => {
+                        let config = self.config.scale_gesture.clone();
+                        self.with_xrd_window(wid, move |w| {
+                            w.set_scale(window_settings::adjust_scale(w.scale(), dy, &config))
+                        })
+                        .await;
+                    }
+                    input::Gesture::Brightness => {
+                        let config = &self.config.brightness_gesture;
+                        self.set_adjustment(wid, |a| {
+                            a.brightness =
+                                window_settings::adjust_brightness(a.brightness, dy, config)
+                        })
+                        .await;
+                    }
+                }
+                Ok(())
+            }
+            InputEvent::Move { .. } | InputEvent::Click { pressed: true, .. }
+                if input_state.grabbed.is_some() =>
+            {
+                // Window is being moved in VR, don't leak that into the application
+                Ok(())
+            }
+            InputEvent::Move { x, y, wid } => {
+                // Don't let jitter separate the clicks of a double click
+                let (x, y) = input_state.clicks.snap(wid, x, y, now, double_click);
+                // Pointer left the window mid-drag, finish the drag on that window
+                input_state
+                    .motion(wid, x, y)
+                    .into_iter()
+                    .try_for_each(&release)
+                    .and_then(|_| {
+                        raise_window_and_resolve_position(wid, x, y).and_then(|(root, x, y)| {
+                            // The window could have been closed, in that case we stop
+                            input_synth.move_to(root, x, y)
+                        })
+                    })
+            }
+
+            InputEvent::Click {
+                x,
+                y,
+                wid,
+                button,
+                pressed,
+                controller,
+            } => match input::ControllerButton::from_xrd(button) {
+                Some(button) if pressed => {
+                    let key = (controller, button);
+                    input_state.last_clicked = Some(wid);
+                    // A press without a release, finish the previous drag first
+                    let result = input_state.release(key).map_or(Ok(()), &release);
+                    let (x, y, count) =
+                        input_state
+                            .clicks
+                            .press(wid, button, x, y, now, double_click);
+                    if count > 1 {
+                        debug!("click #{count} on {wid:#010x}");
+                    }
+                    match input_state.press(&self.config.buttons, key, wid, x, y) {
+                        Some(x_button) => result.and_then(|_| {
+                            raise_window_and_resolve_position(wid, x, y).and_then(|(root, x, y)| {
+                                // The window could have been closed, in that case we stop
+                                input_synth.click(root, x, y, x_button as _, true)
+                            })
+                        }),
+                        // Unmapped button
+                        None => result,
+                    }
+                }
+                Some(button) => match input_state.release((controller, button)) {
+                    // Release on the window the button was pressed on, clamped to that window
+                    Some(drag) if drag.wid == wid => {
+                        let (x, y) = input_state.clicks.snap(wid, x, y, now, double_click);
+                        release(input::Drag { x, y, ..drag })
+                    }
+                    Some(drag) => release(drag),
+                    None => Ok(()),
+                },
+                None => Ok(()),
+            },
+            InputEvent::KeyPresses { pressed: false, .. } => {
+                input_state.key_releases = true;
+                if let Some(repeat) = input_state.key_repeat.take() {
+                    repeat.abort();
+                }
+                Ok(())
+            }
+            InputEvent::KeyPresses { string, keyval, .. } => {
+                debug!("key press {:?} {:#x}", string, keyval);
+                self.key_press(&mut input_state, string, keyval)
+            }
+            InputEvent::KeyRepeat {
+                string,
+                keyval,
+                held,
+                focus,
+            } => block_in_place(|| {
+                if self.x11.get_input_focus()?.reply()?.focus != focus {
+                    if let Some(repeat) = input_state.key_repeat.take() {
+                        repeat.abort();
+                    }
+                    return Ok(());
+                }
+                self.type_key(string, keyval, held)
+            }),
+            InputEvent::Grab { wid, grabbed } => {
+                self.pin_grabbed(wid, grabbed).await;
+                if grabbed {
+                    // The user takes over from a glide
+                    self.gliding.lock().unwrap().remove(&wid);
+                    input_state.grabbed = Some(wid);
+                    input_state.release_all().into_iter().try_for_each(&release)
+                } else {
+                    if input_state.grabbed == Some(wid) {
+                        input_state.grabbed = None;
+                    }
+                    Ok(())
+                }
+            }
+            InputEvent::Scroll { wid, dx, dy } => {
+                let scroll_config = &self.config.scroll;
+                let sign = if scroll_config.invert { -1.0 } else { 1.0 };
+                let (steps_x, steps_y) =
+                    input_state
+                        .scroll
+                        .accumulate(dx * sign, dy * sign, scroll_config.threshold);
+                match input_state.pointer {
+                    // Scroll where the pointer is, if it is still on the scrolled window
+                    Some((pointer_wid, x, y))
+                        if pointer_wid == wid && (steps_x, steps_y) != (0, 0) =>
+                    {
+                        raise_window_and_resolve_position(wid, x, y).and_then(|(root, x, y)| {
+                            input_synth.move_to(root, x, y)?;
+                            [(steps_x, false), (steps_y, true)]
+                                .into_iter()
+                                .flat_map(|(steps, vertical)| {
+                                    let button = input::scroll_button(steps, vertical);
+                                    std::iter::repeat(button).take(steps.unsigned_abs() as _)
+                                })
+                                .try_for_each(|button| {
+                                    input_synth.click(root, x, y, button as _, true)?;
+                                    input_synth.click(root, x, y, button as _, false)
+                                })
+                        })
+                    }
+                    _ => Ok(()),
+                }
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to synthesis input {}", e);
+        }
+    }
+
+    // A window group in xrdesktop is a linked list held together by the window's parent/child
+    // pointers. this function finds the group for `wid`, and returns the last window in the list
+    async fn find_window_group(
+        window_state: &WindowState,
+        wid: u32,
+    ) -> Option<&window_map::Handle<Window>> {
+        debug!("looking for group for {}", wid);
+        let window = window_state.windows.get(&wid).or_else(|| {
+            window_state
+                .client_window_to_window
+                .get(&wid)
+                .and_then(|p| {
+                    debug!("using parent {} of client window {} instead", p, wid);
+                    window_state.windows.get(p)
+                })
+        })?;
+        let parent = unsafe {
+            let mut current =
+                xrd::sys::xrd_window_get_data(window.read().await.xrd_window.lock().await.as_ptr());
+            while !(*current).child_window.is_null() {
+                debug!("{}", native::to_wid((*current).native));
+                current = (*current).child_window;
+            }
+            current
+        };
+
+        let native = native::to_wid(unsafe { (*parent).native });
+        Some(window_state.windows.get(&native).unwrap())
+    }
+
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        if self.desktop_mirror.load(Ordering::Acquire) {
+            self.map_desktop().await?;
+        } else {
+            Self::setup_initial_windows(&self).await?;
+        }
+        self.refresh_cursor(0).await?;
+        self.xrd_client
+            .lock()
+            .await
+            .desktop_cursor()
+            .unwrap()
+            .show();
+
+        let (tx, mut x11_rx) = tokio::sync::mpsc::channel(4);
+        let x11_clone = self.x11.clone();
+
+        // feature: never_type
+        // tokio task for receiving X events
+        // Connection errors are passed on, so they can be told apart from the channel closing
+        let _: tokio::task::JoinHandle<Result<() /* ! */>> = spawn_blocking(move || loop {
+            let event = match x11_clone.wait_for_event() {
+                Ok(event) => event,
+                Err(e) => {
+                    tx.blocking_send(Err(e))?;
+                    return Ok(());
+                }
+            };
+            tx.blocking_send(Ok(event))?;
+            loop {
+                match x11_clone.poll_for_event() {
+                    Ok(Some(event)) => tx.blocking_send(Ok(event))?,
+                    Ok(None) => break,
+                    Err(e) => {
+                        tx.blocking_send(Err(e))?;
+                        return Ok(());
+                    }
+                }
+            }
+        });
+        // Don't hold the lock while waiting for the mainloop, it locks xrd_client itself before
+        // dispatching
+        let xrd_client = self.xrd_client.lock().await.clone();
+        let input_tx = self.input_tx.clone();
+        let mut input_rx = self.input_rx.lock().unwrap().take().unwrap();
+        let (exit_tx, mut exit_rx) = tokio::sync::mpsc::channel(1);
+        {
+            let exit_tx = exit_tx.clone();
+            let devices_changed = self.devices_changed.clone();
+            let haptics_config = self.config.haptics.clone();
+            let toggle_button = self.config.toggle_mirroring.button;
+            let haptics = utils::invoke(&glib::MainContext::default(), move || {
+                let haptics = haptics::Haptics::new(&xrd_client, &haptics_config).map(Arc::new);
+                connect_client_signals(
+                    &xrd_client,
+                    input_tx,
+                    exit_tx,
+                    devices_changed,
+                    haptics.clone(),
+                    toggle_button,
+                );
+                haptics
+            })
+            .await?;
+            *self.haptics.lock().unwrap() = haptics;
+        }
+
+        let mut window_events = self.source.events().await?;
+
+        let (control_tx, mut control_rx) = tokio::sync::mpsc::channel(4);
+        let input_backend = self.input_synth.backend();
+        self.dbus
+            .object_server()
+            .at(
+                dbus::OBJECT_PATH,
+                dbus::Control::new(control_tx.clone(), input_backend, self.stats.clone()),
+            )
+            .await?;
+        let mut name_lost = zbus::fdo::DBusProxy::new(&self.dbus)
+            .await?
+            .receive_name_lost()
+            .await?;
+        if self.config.close_button {
+            let xrd_client = self.xrd_client.lock().await;
+            add_close_button(&xrd_client, control_tx.clone());
+        }
+
+        // Input events are handled in order, so a release is never processed before its press.
+        let this = self.clone();
+        let input_task = tokio::spawn(async move {
+            while let Some(input_event) = input_rx.recv().await {
+                this.handle_input_events(input_event).await;
+            }
+        });
+
+        let this = self.clone();
+        let render_task = tokio::spawn(async move { this.render_task().await });
+        let this = self.clone();
+        let mirror_task = tokio::spawn(async move { this.mirror_task().await });
+        let this = self.clone();
+        let presence_task = tokio::spawn(async move {
+            // Without a headset nobody is ever present, and there is no dashboard
+            if !this.no_hmd {
+                this.presence_task().await
+            }
+        });
+        let this = self.clone();
+        let watchdog_task = tokio::spawn(async move {
+            if this.config.render_stall_timeout > 0 {
+                this.watchdog_task().await
+            }
+        });
+        let this = self.clone();
+        let stats_task = tokio::spawn(async move {
+            if this.config.stats_interval > 0 {
+                this.stats_task().await
+            }
+        });
+        let this = self.clone();
+        let unresponsive_task = tokio::spawn(async move {
+            if this.config.unresponsive.enabled {
+                this.unresponsive_task().await
+            }
+        });
+        let this = self.clone();
+        let visibility_task = tokio::spawn(async move { this.visibility_task().await });
+        let this = self.clone();
+        let throttle_task = tokio::spawn(async move {
+            if let Some(limits) = this.config.throttle.limits() {
+                this.throttle_task(limits).await
+            }
+        });
+        let this = self.clone();
+        let pin_task = tokio::spawn(async move { this.pin_task().await });
+        let this = self.clone();
+        let animation_task = tokio::spawn(async move { this.animation_task().await });
+        let this = self.clone();
+        let window_limit_task = tokio::spawn(async move {
+            if this.config.window_limit.max_windows > 0 {
+                this.window_limit_task().await
+            }
+        });
+        let this = self.clone();
+        let labels_task = tokio::spawn(async move {
+            if this.config.labels.enabled {
+                this.labels_task().await
+            }
+        });
+        let this = self.clone();
+        let distant_windows_task = tokio::spawn(async move {
+            if this.config.distant_windows.enabled {
+                this.distant_windows_task().await
+            }
+        });
+        let this = self.clone();
+        let night_light_task = tokio::spawn(async move {
+            if this.config.night_light.follow_gnome {
+                if let Err(e) = this.night_light_task().await {
+                    warn!("Not following GNOME's night light: {e}");
+                }
+            }
+        });
+        let this = self.clone();
+        let control_tx_clone = control_tx.clone();
+        let runtime_task = tokio::spawn(async move {
+            if this.config.reconnect_runtime {
+                this.runtime_task(exit_tx, control_tx_clone).await
+            }
+        });
+
+        self.update_focused(self.root()).await;
+        info!("Existing windows mapped, entering mainloop");
+        if let Some(notifier) = &self.notifier {
+            self.update_status().await;
+            notifier.ready();
+        }
+        let watchdog_interval = self.notifier.as_ref().and_then(|n| n.watchdog_interval());
+        let mut watchdog = tokio::time::interval(
+            watchdog_interval.unwrap_or(std::time::Duration::from_secs(3600)),
+        );
+        loop {
+            tokio::select! {
+                event = x11_rx.recv() => {
+                    trace!("{:?}", event);
+                    let this = self.clone();
+                    let event = match event.with_context(|| anyhow!("X event channel broke"))? {
+                        Ok(event) => event,
+                        Err(e) => {
+                            error!("X connection lost: {e}");
+                            self.x11_lost.store(true, Ordering::Release);
+                            return Err(XConnectionLost(e).into());
+                        }
+                    };
+                    self.source.x_event(&event);
+                    // Handled in order with picom's map/unmap signals
+                    let span = tracing::debug_span!("event_loop", source = "x11");
+                    match &event {
+                        x11rb::protocol::Event::ReparentNotify(event) => {
+                            self.handle_reparent(event).instrument(span.clone()).await
+                        }
+                        x11rb::protocol::Event::PropertyNotify(event)
+                            if self.is_root(event.window) =>
+                        {
+                            self.handle_root_property(event).instrument(span.clone()).await
+                        }
+                        x11rb::protocol::Event::PropertyNotify(event) => {
+                            self.handle_state_change(event).instrument(span.clone()).await
+                        }
+                        _ => (),
+                    }
+                    tokio::spawn(async move {
+                        if let Err(e) = this.handle_x_events(event).await {
+                            error!("Failed to handle X events {}", e);
+                        }
+                    }.instrument(span));
+                }
+                window_event = window_events.next() => {
+                    let window_event = window_event.with_context(|| anyhow!("window source broke"))??;
+                    let span = tracing::debug_span!("event_loop", source = "windows");
+                    self.handle_window_event(window_event).instrument(span).await;
+                }
+                request = control_rx.recv() => {
+                    let request = request.with_context(|| anyhow!("control channel broke"))?;
+                    self.handle_control_request(request)
+                        .instrument(tracing::debug_span!("event_loop", source = "dbus"))
+                        .await;
+                }
+                name_lost = name_lost.next() => {
+                    let name_lost = name_lost.with_context(|| anyhow!("dbus connection broke"))?;
+                    if name_lost.args()?.name.as_str() == dbus::service_name(&self.display) {
+                        // Dropping App removes our windows from VR
+                        info!("Replaced by another companion, exiting");
+                        break;
+                    }
+                }
+                _ = watchdog.tick(), if watchdog_interval.is_some() => {
+                    if let Some(notifier) = &self.notifier {
+                        notifier.watchdog();
+                    }
+                }
+                _ = PANICKED.notified() => {
+                    return Err(anyhow!("A task panicked, shutting down"));
+                }
+                exit = exit_rx.recv() => {
+                    let exit = exit.with_context(|| anyhow!("exit channel broke"))?;
+                    let xrd_client = self.xrd_client.lock().await;
+                    let gxr = xrd_client.gxr_context().unwrap();
+                    gxr.acknowledge_quit();
+                    drop(xrd_client);
+                    info!("Received exit request {:?}", exit);
+                    if !self.config.reconnect_runtime {
+                        break;
+                    }
+                    let transition = self.runtime.lock().unwrap().lost(std::time::Instant::now());
+                    if transition == Some(runtime::Transition::Suspend) {
+                        self.runtime_lost().await;
+                    }
+                }
+            }
+        }
+        input_task.abort();
+        render_task.abort();
+        presence_task.abort();
+        mirror_task.abort();
+        stats_task.abort();
+        watchdog_task.abort();
+        runtime_task.abort();
+        unresponsive_task.abort();
+        throttle_task.abort();
+        visibility_task.abort();
+        pin_task.abort();
+        animation_task.abort();
+        labels_task.abort();
+        window_limit_task.abort();
+        distant_windows_task.abort();
+        night_light_task.abort();
+        Ok(())
+    }
+    // Read `_NET_WM_ICON` of `w`, and upload the best fitting icon. Returns None if the window
+    // doesn't have an icon.
+    async fn load_icon(&self, w: &Window) -> Result<Option<gl::Texture>> {
+        let reply = block_in_place(|| {
+            Result::Ok(
+                self.x11
+                    .get_property(
+                        false,
+                        w.client_wid,
+                        self.atoms._NET_WM_ICON,
+                        xproto::AtomEnum::CARDINAL,
+                        0,
+                        u32::MAX,
+                    )?
+                    .reply()?,
+            )
+        })?;
+        let data: Vec<u32> = match reply.value32() {
+            Some(data) => data.collect(),
+            None => return Ok(None),
+        };
+        let icons = icon::parse(&data);
+        let icon = match icon::pick(&icons, icon::ICON_SIZE) {
+            Some(icon) => icon,
+            None => return Ok(None),
+        };
+        debug!(
+            "using {}x{} icon for {:#010x}",
+            icon.width, icon.height, w.id
+        );
+        Ok(Some(
+            self.gl
+                .upload_texture(icon.width, icon.height, icon.to_rgba())
+                .await?,
+        ))
+    }
+
+    /// The icon drawn onto `w`, if icons are enabled and it is big enough for one at `width` x
+    /// `height`
+    async fn window_icon(&self, w: &Window, width: u32, height: u32) -> Option<gl::Texture> {
+        if !self.config.window_icons || width < icon::ICON_SIZE * 2 || height < icon::ICON_SIZE * 2
+        {
+            return None;
+        }
+        // Not having an icon is not an error
+        self.load_icon(w).await.unwrap_or_else(|e| {
+            debug!("Failed to load icon for {:#010x}: {e}", w.id);
+            None
+        })
+    }
+
+    /// Load the icon of the window of `client_wid` again after the application changed it
+    async fn reload_icon(&self, client_wid: u32) -> Result<()> {
+        let window_state = self.window_state.read().await;
+        let w = match window_state
+            .client_window_to_window
+            .get(&client_wid)
+            .and_then(|wid| window_state.windows.get(wid))
+        {
+            Some(w) => w,
+            None => return Ok(()),
+        };
+        let mut w = w.write().await;
+        let (width, height) = match &w.textures {
+            Some(textures) => (textures.x11_texture.width(), textures.x11_texture.height()),
+            // Loaded with the textures
+            None => return Ok(()),
+        };
+        let icon = self.window_icon(&w, width, height).await;
+        // Drawn over the window, which is blitted again as a whole for it
+        w.blitted = None;
+        if let Some(old) = std::mem::replace(&mut w.textures.as_mut().unwrap().icon, icon) {
+            self.gl.release_texture(old).await?;
+        }
+        debug!("reloaded the icon of {:#010x}", w.id);
+        w.dirty.store(true, Ordering::Release);
+        self.render_notify.notify_one();
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(wid = %format_args!("{:#010x}", w.id), width, height)
+    )]
+    async fn refresh_texture(&self, w: &mut Window) -> Result<bool> {
+        let wid = w.id;
+        let win_geometry = self.window_geometry(wid)?;
+        tracing::Span::current()
+            .record("width", win_geometry.width)
+            .record("height", win_geometry.height);
+        let shared_size = (
+            (u32::from(win_geometry.width) >> w.mip_level).max(1),
+            (u32::from(win_geometry.height) >> w.mip_level).max(1),
+        );
+        if let Some((width, height, shared, format)) = w.textures.as_ref().map(|ts| {
+            (
+                ts.x11_texture.width(),
+                ts.x11_texture.height(),
+                (ts.imported_texture.width(), ts.imported_texture.height()),
+                ts.format,
+            )
+        }) {
+            // Formats found not to work after the textures were made are replaced too
+            if width != win_geometry.width as u32
+                || height != win_geometry.height as u32
+                || shared != shared_size
+                || self.gl.format_support(format) == Some(false)
+            {
+                debug!("Free old textures for {}", wid);
+                TextureSet::free(w.textures.take(), &self.gl, &self.x11).await?;
+            }
+        }
+
+        if w.textures.is_none() && self.xrd_client.lock().await.gulkan().is_none() {
+            // Checked before making any textures, so there are none to clean up
+            w.dirty.store(true, Ordering::Release);
+            return Err(VrRuntimeNotReady.into());
+        }
+        if w.textures.is_none() {
+            let desktop = self.desktop_region(wid).is_some();
+            let (attrs, x11_pixmap) = block_in_place(|| {
+                // Monitors of the desktop mirror show part of the root window
+                let window = if desktop { self.root() } else { wid };
+                let attrs = self.x11.get_window_attributes(window)?.reply()?;
+                let x11_pixmap = self.x11.generate_id()?;
+                if desktop {
+                    // The root window isn't redirected, so it has no pixmap to name. Make one
+                    // to copy the screen into, see `copy_root`.
+                    self.x11
+                        .create_pixmap(
+                            win_geometry.depth,
+                            x11_pixmap,
+                            window,
+                            win_geometry.width,
+                            win_geometry.height,
+                        )?
+                        .check()?;
+                } else {
+                    self.x11
+                        .composite_name_window_pixmap(wid, x11_pixmap)?
+                        .check()?;
+                }
+                Result::Ok((attrs, x11_pixmap))
+            })?;
+            let x11_texture = self.gl.bind_texture(x11_pixmap, attrs.visual).await?;
+
+            let format = self.shared_format(win_geometry.depth).await?;
+            debug!("Sharing {} as {}", wid, format);
+            let (remote_texture, imported_texture) = self
+                .share_texture(shared_size.0, shared_size.1, format)
+                .await?;
+            let icon = self
+                .window_icon(w, win_geometry.width.into(), win_geometry.height.into())
+                .await;
+            w.textures = Some(TextureSet {
+                x11_texture,
+                x11_pixmap,
+                remote_texture,
+                imported_texture,
+                format,
+                icon,
+                // The texture is the whole window, smaller if it is far away. xrdesktop reports
+                // positions in pixels of the shared texture, so they are scaled back up by about
+                // `1 << mip_level` here.
+                input_transform: input::InputTransform::new(
+                    (0, 0),
+                    (win_geometry.width.into(), win_geometry.height.into()),
+                    shared_size,
+                ),
+            });
+            w.stats.texture_allocation();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// The format to share windows of visual depth `depth` with xrdesktop in: the best one of
+    /// `gl::SharedFormat::candidates` that can be exported from Vulkan and imported into GL, or
+    /// the one forced in the config. Formats are tried once, with a small texture, and the
+    /// result is remembered by `self.gl`.
+    async fn shared_format(&self, depth: u8) -> Result<gl::SharedFormat> {
+        if let Some(format) = self.config.force_format {
+            return Ok(format);
+        }
+        let candidates = gl::SharedFormat::candidates(depth);
+        for &format in candidates {
+            let supported = match self.gl.format_support(format) {
+                Some(supported) => supported,
+                None => {
+                    let supported = match self.share_texture(1, 1, format).await {
+                        Ok((_, imported_texture)) => {
+                            self.gl.release_texture(imported_texture).await?;
+                            true
+                        }
+                        Err(e) if e.is::<VrRuntimeNotReady>() => return Err(e),
+                        Err(e) => {
+                            warn!("Can't share {} textures with VR: {:#}", format, e);
+                            false
+                        }
+                    };
+                    self.gl.set_format_support(format, supported);
+                    supported
+                }
+            };
+            if supported {
+                return Ok(format);
+            }
+        }
+        // Nothing worked, let the error come from the real texture
+        Ok(candidates[0])
+    }
+
+    /// Make a texture in Vulkan to hand to xrdesktop, and import it into GL to blit windows into.
+    async fn share_texture(
+        &self,
+        width: u32,
+        height: u32,
+        format: gl::SharedFormat,
+    ) -> Result<(gulkan::Texture, gl::Texture)> {
+        let vk_format = match format {
+            gl::SharedFormat::Srgba8 => ash::vk::Format::R8G8B8A8_SRGB,
+            gl::SharedFormat::Rgba8 => ash::vk::Format::R8G8B8A8_UNORM,
+            gl::SharedFormat::Rgb10A2 => ash::vk::Format::A2B10G10R10_UNORM_PACK32,
+        };
+        let (remote_texture, fd, size) = {
+            let xrd_client = self.xrd_client.lock().await; // Need to keep this alive for gulkan_client
+            let gulkan_client = xrd_client.gulkan().ok_or(VrRuntimeNotReady)?;
+            let extent = ash::vk::Extent2D { width, height };
+            let layout = xrd_client.upload_layout();
+
+            let mut size = 0;
+            let mut fd = 0;
+            let remote_texture = unsafe {
+                gulkan::sys::gulkan_texture_new_export_fd(
+                    gulkan_client.as_ptr(),
+                    std::mem::transmute(extent),
+                    vk_format.as_raw() as _,
+                    layout,
+                    &mut size,
+                    &mut fd,
+                )
+            };
+            if remote_texture.is_null() {
+                return Err(anyhow!("gulkan can't export a {vk_format:?} texture"));
+            }
+            let remote_texture: gulkan::Texture =
+                unsafe { glib::translate::from_glib_full(remote_texture) };
+            (remote_texture, fd, size)
+        };
+        let imported_texture = self
+            .gl
+            .import_fd(width, height, fd, size as _, format)
+            .await?;
+        Ok((remote_texture, imported_texture))
+    }
+
+    /// Copy `region` of what is on screen into the desktop mirror's pixmap. With
+    /// `IncludeInferiors` the server draws the contents of redirected windows too.
+    fn copy_root(&self, textures: &TextureSet, region: placement::Geometry) -> Result<()> {
+        let root = self.root();
+        let gc = self.x11.generate_id()?;
+        self.x11
+            .create_gc(
+                gc,
+                textures.x11_pixmap,
+                &xproto::CreateGCAux::new()
+                    .subwindow_mode(xproto::SubwindowMode::INCLUDE_INFERIORS),
+            )?
+            .check()?;
+        let result = self.x11.copy_area(
+            root,
+            textures.x11_pixmap,
+            gc,
+            region.x,
+            region.y,
+            0,
+            0,
+            textures.x11_texture.width() as _,
+            textures.x11_texture.height() as _,
+        );
+        self.x11.free_gc(gc)?;
+        result?.check()?;
+        Ok(())
+    }
+
+    /// Clear the damage of `w`, so X reports more of it. With `partial-blit`, all of it is added
+    /// to `w.damaged` first, as the events may not have covered what was damaged since.
+    fn subtract_damage(&self, w: &Window) -> Result<()> {
+        use x11rb::protocol::xfixes::ConnectionExt;
+        let parts = match self.damage_parts {
+            Some(parts) => parts,
+            None => {
+                self.x11
+                    .damage_subtract(w.damage, x11rb::NONE, x11rb::NONE)?
+                    .check()?;
+                return Ok(());
+            }
+        };
+        self.x11
+            .damage_subtract(w.damage, x11rb::NONE, parts)?
+            .check()?;
+        let extents = self.x11.xfixes_fetch_region(parts)?.reply()?.extents;
+        if extents.width > 0 && extents.height > 0 {
+            let area = placement::Geometry::from(extents);
+            let mut damaged = w.damaged.lock().unwrap();
+            *damaged = Some(damaged.map_or(area, |d| d.union(&area)));
+        }
+        Ok(())
+    }
+
+    /// Size `xrd_window` for a `width` x `height` pixels texture, keeping its aspect ratio.
+    fn set_physical_size(
+        &self,
+        xrd_window: &xrd::Window,
+        width: u32,
+        height: u32,
+        size_override: Option<(f32, f32)>,
+    ) {
+        let (width, height) = placement::physical_size(
+            width,
+            height,
+            PIXELS_PER_METER,
+            size_override,
+            self.config.window_size.fit,
+        );
+        xrd_window.set_initial_width_meters(width);
+        xrd_window.set_initial_height_meters(height);
+    }
+
+    #[tracing::instrument(
+        skip_all,
+        fields(wid = %format_args!("{:#010x}", w.id), width, height)
+    )]
+    async fn render_win(&self, w: &mut Window) -> Result<()> {
+        if !w.xrd_window.get_mut().is_visible() || self.demoted.lock().unwrap().contains(&w.id) {
+            // Hidden on purpose, not stalled. The damage is rendered once it is shown again.
+            w.rendered();
+            w.stats.hidden_skip();
+            w.dirty.store(true, Ordering::Release);
+            w.hidden_dirty.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        let start = std::time::Instant::now();
+        #[cfg(debug_assertions)]
+        self.gl.capture(true).await?;
+
+        let damaged = w.damaged.get_mut().unwrap().take();
+        if let Some(damaged) = damaged {
+            trace!("{:#010x} damaged {:?}", w.id, damaged);
+        }
+        // Forgotten until the blit succeeds, as the damage taken above is lost if anything fails
+        let blitted = w.blitted.take();
+        let refreshed = self.refresh_texture(w).await?;
+        let inputs = BlitInputs {
+            shape: w.shape.clone(),
+            border: self.border(w),
+            adjustment: gl::Adjustment {
+                color: night_light::matrix(self.color_temperature.load(Ordering::Relaxed)),
+                ..w.adjustment
+            },
+            filtering: w.filtering,
+        };
+        let desktop_region = self.desktop_region(w.id);
+        // Only the damage, unless anything else changed since the last blit. The desktop mirror
+        // is copied from the root window as a whole anyway.
+        let region = match damaged {
+            Some(damaged)
+                if self.config.partial_blit
+                    && !refreshed
+                    && desktop_region.is_none()
+                    && blitted.as_ref() == Some(&inputs) =>
+            {
+                Some(xproto::Rectangle {
+                    x: damaged.x,
+                    y: damaged.y,
+                    width: damaged.width,
+                    height: damaged.height,
+                })
+            }
+            _ => None,
+        };
+        let textures = w.textures.as_ref().unwrap();
+        tracing::Span::current()
+            .record("width", textures.x11_texture.width())
+            .record("height", textures.x11_texture.height());
+        if let Some(region) = desktop_region {
+            block_in_place(|| self.copy_root(textures, region))?;
+        }
+        self.gl
+            .blit(
+                &textures.x11_texture,
+                &textures.imported_texture,
+                region,
+                textures.icon.as_ref().map(|icon| {
+                    let (width, height) = icon::fit(icon.width(), icon.height(), icon::ICON_SIZE);
+                    (icon, width, height)
+                }),
+                inputs.shape.as_deref(),
+                inputs.border,
+                inputs.adjustment,
+                inputs.filtering,
+            )
+            .instrument(tracing::debug_span!("blit"))
+            .await?;
+        w.blitted = Some(inputs);
+        w.stats.blit();
+
+        #[cfg(debug_assertions)]
+        self.gl.capture(false).await?;
+
+        if self.no_hmd {
+            w.stats.render_time(start.elapsed());
+            w.rendered();
+            return Ok(());
+        }
+        let _submit = tracing::debug_span!("submit_texture").entered();
+        let xrd_window = w.xrd_window.get_mut();
+        if refreshed {
+            self.set_physical_size(
+                xrd_window,
+                textures.x11_texture.width(),
+                textures.x11_texture.height(),
+                w.size_override,
+            );
+            xrd_window.set_and_submit_texture(&textures.remote_texture);
+        } else {
+            xrd_window.submit_texture();
+        }
+        w.stats.submit();
+        w.stats.render_time(start.elapsed());
+        w.rendered();
+        Ok(())
+    }
+
+    async fn map_win_impl(&self, wid: u32) -> Result<()> {
+        match self.prepare_map(wid).await? {
+            Some(mapping) => self.add_window(mapping, None).await,
+            None => Ok(()),
+        }
+    }
+
+    /// What picom and X know about `wid`, to decide whether and where to mirror it, see
+    /// `add_window`. None if there is nothing to do after all.
+    #[tracing::instrument(skip_all, fields(wid = %format_args!("{:#010x}", wid)))]
+    async fn prepare_map(&self, wid: u32) -> Result<Option<Mapping>> {
+        // Added with all the others once the VR runtime is back, see `runtime_resumed`
+        if self.suspend.runtime_lost.load(Ordering::Acquire) {
+            debug!("{wid:#010x} mapped while the VR runtime is gone");
+            return Ok(None);
+        }
+        // Mapped again while fading out, the old window goes right away
+        self.stop_fade_out(wid).await;
+        let info = self.source.window_info(wid).await?;
+        if self.ignored_windows.lock().await.contains(&wid) {
+            debug!("{wid:#010x} is ignored");
+            return Ok(None);
+        }
+        let screen = self.screen_of(wid)?;
+        let root_geometry = self.root_geometry(screen);
+        let vr_pids = self.vr_pids.lock().unwrap().clone();
+        let decision = block_in_place(|| {
+            decide(
+                &self.x11,
+                &self.atoms,
+                &self.config,
+                wid,
+                &info,
+                &root_geometry,
+                &vr_pids,
+            )
+        })?;
+        Ok(Some(Mapping {
+            wid,
+            screen,
+            info,
+            decision,
+        }))
+    }
+
+    /// Mirror the window of `mapping`, or watch it until it is to be mirrored. `slot` is its place
+    /// in the layout, instead of the next one, see `setup_initial_windows`.
+    #[tracing::instrument(
+        skip_all,
+        fields(wid = %format_args!("{:#010x}", mapping.wid), width, height)
+    )]
+    async fn add_window(&self, mapping: Mapping, slot: Option<usize>) -> Result<()> {
+        let Mapping {
+            wid,
+            screen,
+            info,
+            decision,
+        } = mapping;
+        let source::WindowInfo {
+            ty,
+            name: window_name,
+            client_win: client_wid,
+            class_instance,
+            class_general,
+            ..
+        } = info;
+        debug!("window {} is {}", wid, ty);
+        let win_geometry = match decision {
+            Decision::Mirror(geometry) => geometry,
+            Decision::Minimized => {
+                // Mapped but minimized, the window manager only set _NET_WM_STATE_HIDDEN. Watch
+                // the client so we notice when it is restored.
+                debug!("{wid:#010x} is minimized");
+                block_in_place(|| {
+                    select_input(&self.x11, client_wid, xproto::EventMask::PROPERTY_CHANGE)
+                })?;
+                self.parked
+                    .lock()
+                    .await
+                    .entry(client_wid)
+                    .or_insert(Parked {
+                        wid,
+                        transforms: None,
+                        mapped: true,
+                    });
+                return Ok(());
+            }
+            Decision::SkipTaskbar => {
+                // Watched like minimized windows, as it can drop the hint later
+                debug!("{wid:#010x} ({window_name}) skips the taskbar");
+                block_in_place(|| {
+                    select_input(&self.x11, client_wid, xproto::EventMask::PROPERTY_CHANGE)
+                })?;
+                self.skipping.lock().await.insert(client_wid, wid);
+                return Ok(());
+            }
+            decision => {
+                debug!(
+                    "{wid:#010x} ({window_name}, {class_instance}.{class_general}) isn't \
+                     mirrored: {decision}"
+                );
+                return Ok(());
+            }
+        };
+        let size_override = self.config.window_size.get(&class_instance, &class_general);
+        let settings = self
+            .window_settings
+            .lock()
+            .unwrap()
+            .get(&class_instance, &class_general);
+        let transient_for = block_in_place(|| {
+            Result::Ok(
+                self.x11
+                    .get_property(
+                        false,
+                        wid,
+                        self.atoms.WM_TRANSIENT_FOR,
+                        xproto::AtomEnum::WINDOW,
+                        0,
+                        1,
+                    )?
+                    .reply()?,
+            )
+        })?
+        .value32()
+        .and_then(|mut w| w.next());
+        debug!("transient for of {} is {:?}", wid, transient_for);
+        tracing::Span::current()
+            .record("width", win_geometry.width)
+            .record("height", win_geometry.height);
+
+        let xrd_window = self
+            .create_xrd_window(
+                wid,
+                &window_name,
+                win_geometry.width.into(),
+                win_geometry.height.into(),
+                size_override,
+            )
+            .await?;
+        debug!("window created {}", wid);
+
+        {
+            // Lock windows before xrd_client, because that's the order we used in render_win ->
+            // refresh_texture.
+            let window_state = self.window_state.read().await;
+            let xrd_client = self.xrd_client.lock().await;
+            let parent = if ty.contains("menu") || ty == "utility" {
+                if let Some(leader) = transient_for {
+                    Self::find_window_group(&window_state, leader).await
+                } else {
+                    let hovered = xrd_client.synth_hovered();
+                    debug!("no leader, hovered is {:?}", hovered);
+                    hovered.and_then(|hovered| {
+                        let native = native::wid(&hovered);
+                        debug!("hovered is native {}", native);
+                        // Labels and close buttons aren't mirrored windows, no parent then
+                        window_state.windows.get(&native)
+                    })
+                }
+            } else {
+                None
+            };
+            drop(xrd_client);
+            if let Some(parent) = parent {
+                let parent = parent.read().await;
+                let parent_geometry =
+                    block_in_place(|| Result::Ok(self.x11.get_geometry(parent.id)?.reply()?))?;
+                let (x, y) = placement::child_offset(&win_geometry, &(&parent_geometry).into());
+                let mut offset = graphene::Point::new(x, y);
+                parent
+                    .xrd_window
+                    .lock()
+                    .await
+                    .add_child(&xrd_window, &mut offset);
+            } else {
+                let (win_geometry, layout) = self.layout_geometry(screen, &win_geometry);
+                let mut transform = placement::transform(
+                    &win_geometry,
+                    &layout,
+                    slot.unwrap_or(window_state.windows.len()),
+                    PIXELS_PER_METER,
+                    self.config.layout.depth,
+                    self.config.layout.z_spacing,
+                    self.preset(),
+                );
+                xrd_window.set_transformation(&mut transform);
+                xrd_window.set_reset_transformation(&mut transform);
+            }
+        }
+        if let Some(Parked {
+            transforms: Some((transform, reset_transform)),
+            ..
+        }) = self.parked.lock().await.remove(&client_wid)
+        {
+            // Restored from minimized, put it back where it was
+            xrd_window.set_transformation(&mut graphene::Matrix::from_float(transform));
+            xrd_window.set_reset_transformation(&mut graphene::Matrix::from_float(reset_transform));
+        }
+        if let Some(scale) = settings.scale {
+            debug!("{wid:#010x} scaled to {scale}, as set before");
+            xrd_window.set_scale(scale);
+        }
+        debug!("position set");
+        // Starts out small and dark, and grows in once it is mapped, see `animation_task`
+        let fade_in = (self.config.fade.enabled && !self.suspend.is_suspended()).then(|| {
+            let scale = xrd_window.scale();
+            xrd_window.set_scale(scale * animation::scale(0.0));
+            set_dimmed(&xrd_window, Some(0.0));
+            (xrd_window.clone(), scale)
+        });
+
+        let damage = self.x11.generate_id()?;
+        let damage_report_level = self.config.damage_report_level().to_x11();
+        let x11_clone = self.x11.clone();
+        let window = {
+            let mut window_state = self.window_state.write().await;
+            let win_attrs = block_in_place(move || {
+                x11_clone
+                    .damage_create(damage, wid, damage_report_level)?
+                    .check()?;
+                // To be notified when either window is reparented, see handle_reparent, and when
+                // the client is minimized, see handle_state_change
+                let client_events =
+                    xproto::EventMask::STRUCTURE_NOTIFY | xproto::EventMask::PROPERTY_CHANGE;
+                if client_wid != wid && client_wid != x11rb::NONE {
+                    select_input(&x11_clone, wid, xproto::EventMask::STRUCTURE_NOTIFY)?;
+                    // The client window can be gone already, we notice through the frame
+                    if let Err(e) = select_input(&x11_clone, client_wid, client_events) {
+                        debug!("Failed to select events on {client_wid:#010x}: {e}");
+                    }
+                } else {
+                    select_input(&x11_clone, wid, client_events)?;
+                }
+                x11_clone.shape_select_input(wid, true)?.check()?;
+                Result::Ok(x11_clone.get_window_attributes(wid)?.reply()?)
+            })?;
+
+            // If we receive map -> unmap -> map event of the same window in quick
+            // succession, the unmap event could be processed before the first map event (the
+            // second map would never be processed before unmap OTOH). In that case that unmap
+            // event will fail to remove any window and we have duplicated window. so:
+            //
+            // if we are unmapped currently, we give up adding this window. this is to prevent an
+            // unmapped window from staying visible.
+            //
+            // if we are mapped, then we either didn't receive a unmap event, which is fine.
+            // Otherwise, if we are the first map event, the window we are going to add here will
+            // be replaced later by the second map event; if we are the second map event, we will
+            // replace the existing window.
+            if win_attrs.map_state != xproto::MapState::VIEWABLE {
+                debug!("Window {wid:#010x} not viewable, giving up");
+                return Ok(());
+            }
+
+            let shape = block_in_place(|| self.query_shape(wid))?;
+            let xrd_window = Mutex::new(xrd_window);
+            let window = Window {
+                id: wid,
+                gl: self.gl.clone(),
+                damage,
+                x11: self.x11.clone(),
+                xrd: self.xrd_client.clone(),
+                textures: None,
+                xrd_window,
+                client_wid,
+                dirty: AtomicBool::new(false),
+                hidden_dirty: AtomicBool::new(false),
+                stalled_since: Default::default(),
+                recoveries: 0,
+                mip_level: 0,
+                screen,
+                adjustment: settings.adjustment(),
+                pre_theater: None,
+                responsiveness: std::sync::Mutex::new(ping::Responsiveness::new(
+                    std::time::Instant::now(),
+                )),
+                activity: std::sync::Mutex::new(priority::Activity::new(
+                    std::time::Instant::now(),
+                    self.map_sequence.fetch_add(1, Ordering::Relaxed),
+                )),
+                throttle: std::sync::Mutex::new(throttle::Throttle::new(std::time::Instant::now())),
+                damaged: Default::default(),
+                blitted: None,
+                shape,
+                size_override,
+                filtering: self.config.filtering(&class_instance, &class_general),
+                stats: self.stats.register(wid),
+                drop_bomb: DropBomb::new("Window dropped unsafely"),
+            };
+            let parent_wid = window_state.client_window_to_window.insert(client_wid, wid);
+            if let Some(parent_wid) = parent_wid {
+                if parent_wid != wid {
+                    error!(
+                        "Replaced parent of {client_wid:#010x}: was {parent_wid:#010x}, now to {wid:#010x}"
+                    );
+                    debug_assert!(false);
+                }
+            }
+            debug!("inserting {}", wid);
+            let mut entry = window_state.windows.entry(wid);
+            let window = match entry {
+                Entry::Vacant(entry) => entry.insert(window_map::handle(window)),
+                Entry::Occupied(ref mut entry) => {
+                    let old = entry.insert(window_map::handle(window));
+                    // window_state is exclusively locked at this point, using the sync version
+                    // so it couldn't be cancelled.
+                    let old = block_in_place(|| window_map::take_blocking(old));
+                    if let Err(e) = unsafe { block_in_place(|| old.drop_sync()) } {
+                        error!("Failed to free old window entry for {wid:#010x}: {e}");
+                    }
+                    error!("Replaced old window entry for {wid:#010x}");
+                    debug_assert!(false);
+                    entry.get_mut()
+                }
+            };
+            window.clone()
+        };
+        // Rendered without holding WindowState, like in render_task
+        let mut window = window.write().await;
+        // Rendered when rendering resumes, see `set_suspended`
+        if self.suspend.is_suspended() {
+            window.dirty.store(true, Ordering::Release);
+        } else {
+            self.render_win(&mut window).await?;
+        }
+        let xrd_window = window.xrd_window.get_mut().clone();
+        drop(window);
+        // Mirroring is toggled off, the window shows up when it is toggled back on. Toggled off
+        // since the window was added, set_mirroring hid it already, hiding it again is harmless.
+        let mut hidden_windows = self.hidden_windows.lock().await;
+        if let Some(hidden_windows) = hidden_windows.as_mut() {
+            hidden_windows.insert(wid);
+            let result = utils::invoke(&glib::MainContext::default(), move || xrd_window.hide());
+            if let Err(e) = result.await {
+                error!("Failed to hide VR window of {wid:#010x}: {e}");
+            }
+        }
+        drop(hidden_windows);
+        if let Some((xrd_window, scale)) = fade_in {
+            let fade = animation::Fade::new(
+                0.0,
+                1.0,
+                std::time::Instant::now(),
+                self.config.fade.duration(),
+            );
+            self.start_fade(wid, xrd_window, scale, fade, None);
+        }
+        info!("Added new window {:#010x}", wid);
+        self.update_theater(wid).await;
+        self.update_label(wid).await;
+        self.limit_windows().await;
+        self.update_desktop_filter().await;
+        self.windows_changed().await;
+        //remove ourself from pending_windows
+        Ok(())
+    }
+
+    /// Create the VR window for `wid`, and forward its input events.
+    async fn create_xrd_window(
+        &self,
+        wid: u32,
+        window_name: &str,
+        width: u32,
+        height: u32,
+        size_override: Option<(f32, f32)>,
+    ) -> Result<xrd::Window> {
+        let xrd_client = self.xrd_client.lock().await;
+        let xrd_window = xrd::Window::new_from_pixels(
+            &*xrd_client,
+            window_name,
+            width,
+            height,
+            PIXELS_PER_METER,
+        )
+        .ok_or(XrdError::CreateWindow { wid, width, height })?;
+        self.set_physical_size(&xrd_window, width, height, size_override);
+        native::add_window(&xrd_client, &xrd_window, wid);
+        let tx = self.input_tx.clone();
+        xrd_window.connect_scroll_event(move |_, event| {
+            use gdk::ScrollDirection;
+            let delta = event.scroll_deltas().or_else(|| {
+                Some(match event.scroll_direction()? {
+                    ScrollDirection::Up => (0.0, -1.0),
+                    ScrollDirection::Down => (0.0, 1.0),
+                    ScrollDirection::Left => (-1.0, 0.0),
+                    ScrollDirection::Right => (1.0, 0.0),
+                    _ => return None,
+                })
+            });
+            if let Some((dx, dy)) = delta {
+                // If the queue is full, we drop the event
+                let _: std::result::Result<_, _> = tx.try_send(InputEvent::Scroll { wid, dx, dy });
+            }
+        });
+        let tx = self.input_tx.clone();
+        xrd_window.connect_grab_start_event(move |_, _| {
+            let _ = tx.blocking_send(InputEvent::Grab { wid, grabbed: true });
+        });
+        let tx = self.input_tx.clone();
+        xrd_window.connect_release_event(move |_, _| {
+            let _ = tx.blocking_send(InputEvent::Grab {
+                wid,
+                grabbed: false,
+            });
+        });
+        if let Some(haptics) = &*self.haptics.lock().unwrap() {
+            haptics.connect_hover(&xrd_window);
+        }
+        Ok(xrd_window)
+    }
+
+    /// Desktop mirror mode: a single VR window showing the whole screen, or one per monitor,
+    /// standing in for the windows picom would report.
+    async fn map_desktop(self: &Arc<Self>) -> Result<()> {
+        if self.config.desktop_mirror.per_monitor {
+            return self.sync_monitors().await;
+        }
+        let root_geometry = self.root_geometry(self.screen as usize);
+        self.map_plane(self.root(), "Desktop", root_geometry)
+            .await?;
+        info!("Mirroring the desktop");
+        Ok(())
+    }
+
+    /// Map a window of the desktop mirror, `wid`, showing `region` of the screen. Placed in VR
+    /// where that region is on the screen.
+    async fn map_plane(&self, wid: u32, name: &str, region: placement::Geometry) -> Result<()> {
+        let root = self.root();
+        let xrd_window = self
+            .create_xrd_window(wid, name, region.width.into(), region.height.into(), None)
+            .await?;
+        let (placed, layout) = self.layout_geometry(self.screen as usize, &region);
+        let mut transform = placement::transform(
+            &placed,
+            &layout,
+            0,
+            PIXELS_PER_METER,
+            self.config.layout.depth,
+            self.config.layout.z_spacing,
+            self.preset(),
+        );
+        xrd_window.set_transformation(&mut transform);
+        xrd_window.set_reset_transformation(&mut transform);
+
+        // Drawing on the root window itself is reported like damage to any other window, and
+        // renders the mirror between the refreshes of `mirror_task`
+        let damage = if self.config.desktop_mirror.root_damage {
+            let damage = self.x11.generate_id()?;
+            let damage_report_level = self.config.damage_report_level().to_x11();
+            block_in_place(|| {
+                self.x11
+                    .damage_create(damage, root, damage_report_level)?
+                    .check()
+            })?;
+            damage
+        } else {
+            x11rb::NONE
+        };
+
+        let mut window_state = self.window_state.write().await;
+        let window = Window {
+            id: wid,
+            gl: self.gl.clone(),
+            damage,
+            x11: self.x11.clone(),
+            xrd: self.xrd_client.clone(),
+            textures: None,
+            xrd_window: Mutex::new(xrd_window),
+            client_wid: wid,
+            dirty: AtomicBool::new(false),
+            hidden_dirty: AtomicBool::new(false),
+            stalled_since: Default::default(),
+            recoveries: 0,
+            mip_level: 0,
+            screen: self.screen as usize,
+            adjustment: Default::default(),
+            pre_theater: None,
+            responsiveness: std::sync::Mutex::new(ping::Responsiveness::new(
+                std::time::Instant::now(),
+            )),
+            activity: std::sync::Mutex::new(priority::Activity::new(
+                std::time::Instant::now(),
+                self.map_sequence.fetch_add(1, Ordering::Relaxed),
+            )),
+            throttle: std::sync::Mutex::new(throttle::Throttle::new(std::time::Instant::now())),
+            damaged: Default::default(),
+            blitted: None,
+            shape: None,
+            size_override: None,
+            filtering: self.config.filtering,
+            stats: self.stats.register(wid),
+            drop_bomb: DropBomb::new("Window dropped unsafely"),
+        };
+        window_state.client_window_to_window.insert(wid, wid);
+        let window = window_state
+            .windows
+            .entry(wid)
+            .or_insert(window_map::handle(window))
+            .clone();
+        // Rendered without holding WindowState, see `add_window`
+        drop(window_state);
+        let mut window = window.write().await;
+        if self.suspend.is_suspended() {
+            window.dirty.store(true, Ordering::Release);
+        } else {
+            self.render_win(&mut window).await?;
+        }
+        let xrd_window = window.xrd_window.get_mut().clone();
+        drop(window);
+        let mut hidden_windows = self.hidden_windows.lock().await;
+        // Mirroring is toggled off, see `add_window`
+        if let Some(hidden_windows) = hidden_windows.as_mut() {
+            hidden_windows.insert(wid);
+            utils::invoke(&glib::MainContext::default(), move || xrd_window.hide()).await?;
+        }
+        drop(hidden_windows);
+        self.windows_changed().await;
+        Ok(())
+    }
+
+    async fn handle_window_event(self: &Arc<Self>, event: source::WindowEvent) {
+        // Windows are not mirrored on their own in desktop mirror mode
+        let mirror_windows = !self.desktop_mirror.load(Ordering::Acquire);
+        match event {
+            source::WindowEvent::Mapped(wid) if mirror_windows => {
+                debug!("{wid:#010x}, new window");
+                self.spawn_map_win(wid).await;
+            }
+            source::WindowEvent::Unmapped(wid) if mirror_windows => {
+                debug!("{wid:#010x} closed");
+                // Closed or minimized, remember where it was in the latter case
+                if let Err(e) = self.park_if_minimized(wid, false).await {
+                    debug!("Failed to check if {wid:#010x} is minimized: {e}");
+                }
+                self.skipping.lock().await.retain(|_, w| *w != wid);
+                self.unmap_win(wid).await;
+            }
+            source::WindowEvent::Destroyed(wid) => {
+                // The id could be reused by a new window
+                self.ignored_windows.lock().await.remove(&wid);
+                self.parked
+                    .lock()
+                    .await
+                    .retain(|_, parked| parked.wid != wid);
+                self.skipping.lock().await.retain(|_, w| *w != wid);
+            }
+            source::WindowEvent::Mapped(_) | source::WindowEvent::Unmapped(_) => (),
+        }
+    }
+
+    async fn spawn_map_win(self: &Arc<Self>, wid: u32) {
+        let this = self.clone();
+        let handle = tokio::spawn(async move {
+            if let Err(e) = this.map_win(wid).await {
+                info!("Failed to map window {}, {}", wid, e);
+            }
+        });
+        // feature: map_or_insert
+        match self.pending_windows.lock().await.entry(wid) {
+            Entry::Occupied(_) => {
+                panic!("Window {} already mapped", wid);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(handle);
+            }
+        }
+    }
+
+    /// If the mirrored window `wid` is minimized, remember its place in VR. Returns whether it
+    /// is minimized.
+    async fn park_if_minimized(&self, wid: u32, mapped: bool) -> Result<bool> {
+        let window_state = self.window_state.read().await;
+        let w = match window_state.windows.get(&wid) {
+            Some(w) => w.read().await,
+            None => return Ok(false),
+        };
+        if !block_in_place(|| is_minimized(&self.x11, &self.atoms, w.client_wid))? {
+            return Ok(false);
+        }
+        self.park(&w, mapped).await;
+        Ok(true)
+    }
+
+    /// Remember the place of `w` in VR, to put it back there when it is mirrored again.
+    async fn park(&self, w: &Window, mapped: bool) {
+        let wid = w.id;
+        let xrd_window = w.xrd_window.lock().await;
+        let mut transform = graphene::Matrix::new_identity();
+        let mut reset_transform = graphene::Matrix::new_identity();
+        xrd_window.is_transformation(&mut transform);
+        xrd_window.reset_transformation(&mut reset_transform);
+        debug!("parking {wid:#010x}");
+        // Restored from where it was before the theater, which puts it back there if it is still
+        // fullscreen
+        let transform = match w.pre_theater {
+            Some((transform, _)) => transform,
+            None => transform.to_float(),
+        };
+        self.parked.lock().await.insert(
+            w.client_wid,
+            Parked {
+                wid,
+                transforms: Some((transform, reset_transform.to_float())),
+                mapped,
+            },
+        );
+    }
+
+    fn is_fullscreen(&self, client_wid: u32) -> Result<bool> {
+        let net_wm_state = self
+            .x11
+            .get_property(
+                false,
+                client_wid,
+                self.atoms._NET_WM_STATE,
+                xproto::AtomEnum::ATOM,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(net_wm_state.value32().map_or(false, |mut s| {
+            s.any(|a| a == self.atoms._NET_WM_STATE_FULLSCREEN)
+        }))
+    }
+
+    /// With `[theater]` enabled, move `wid` to the theater screen when it goes fullscreen, and
+    /// back to where it was when it leaves fullscreen.
+    async fn update_theater(&self, wid: u32) {
+        if !self.config.theater.enabled {
+            return;
+        }
+        let (client_wid, xrd_window, in_theater) =
+            match self.window_state.read().await.windows.get(&wid) {
+                Some(w) => {
+                    let w = w.read().await;
+                    let xrd_window = w.xrd_window.lock().await.clone();
+                    (w.client_wid, xrd_window, w.pre_theater.is_some())
+                }
+                None => return,
+            };
+        let fullscreen = match block_in_place(|| self.is_fullscreen(client_wid)) {
+            Ok(fullscreen) => fullscreen,
+            Err(e) => {
+                debug!("Failed to check if {wid:#010x} is fullscreen: {e}");
+                return;
+            }
+        };
+        // The window locks can't be held while waiting for the glib thread, see utils::invoke
+        let context = glib::MainContext::default();
+        if fullscreen && !in_theater {
+            debug!("{wid:#010x} went fullscreen, moving it to the theater");
+            let theater = self.config.theater.clone();
+            let saved = utils::invoke(&context, move || {
+                let mut transform = graphene::Matrix::new_identity();
+                xrd_window.is_transformation(&mut transform);
+                let scale = xrd_window.scale();
+                xrd_window.set_transformation(&mut placement::theater_transform(theater.depth));
+                xrd_window.set_scale(placement::theater_scale(
+                    xrd_window.initial_width_meters(),
+                    theater.width,
+                ));
+                (transform.to_float(), scale)
+            })
+            .await;
+            match (saved, self.window_state.read().await.windows.get(&wid)) {
+                (Ok(saved), Some(w)) => w.write().await.pre_theater = Some(saved),
+                (Err(e), _) => error!("Failed to move {wid:#010x} to the theater: {e}"),
+                (Ok(_), None) => (),
+            }
+        } else if !fullscreen && in_theater {
+            debug!("{wid:#010x} left fullscreen, moving it back");
+            let saved = match self.window_state.read().await.windows.get(&wid) {
+                Some(w) => w.write().await.pre_theater.take(),
+                None => None,
+            };
+            if let Some((transform, scale)) = saved {
+                let result = utils::invoke(&context, move || {
+                    xrd_window.set_transformation(&mut graphene::Matrix::from_float(transform));
+                    xrd_window.set_scale(scale);
+                })
+                .await;
+                if let Err(e) = result {
+                    error!("Failed to move {wid:#010x} back from the theater: {e}");
+                }
+            }
+        }
+    }
+
+    /// WM_STATE, _NET_WM_STATE or _NET_WM_DESKTOP of a client window changed. Window managers that
+    /// keep minimized windows mapped don't generate map/unmap signals from picom, so we handle
+    /// minimizing and restoring them here.
+    async fn handle_state_change(self: &Arc<Self>, event: &xproto::PropertyNotifyEvent) {
+        // Moved to another virtual desktop
+        if event.atom == self.atoms._NET_WM_DESKTOP {
+            self.update_desktop_filter().await;
+            return;
+        }
+        if event.atom == u32::from(xproto::AtomEnum::WM_CLASS) {
+            self.recheck_vr_window(event.window).await;
+            return;
+        }
+        if event.atom == self.atoms._NET_WM_ICON {
+            if let Err(e) = self.reload_icon(event.window).await {
+                debug!("Failed to reload the icon of {:#010x}: {e}", event.window);
+            }
+            return;
+        }
+        if event.atom == self.atoms._NET_WM_NAME
+            || event.atom == u32::from(xproto::AtomEnum::WM_NAME)
+        {
+            let wid = self.toplevel(event.window).await;
+            self.update_label(wid).await;
+            return;
+        }
+        if event.atom != self.atoms.WM_STATE && event.atom != self.atoms._NET_WM_STATE {
+            return;
+        }
+        let client_wid = event.window;
+        let mirrored = self
+            .window_state
+            .read()
+            .await
+            .client_window_to_window
+            .get(&client_wid)
+            .copied();
+        if let Some(wid) = mirrored {
+            if event.atom == self.atoms._NET_WM_STATE && self.skips_taskbar(wid).await {
+                debug!("{wid:#010x} now skips the taskbar");
+                self.skipping.lock().await.insert(client_wid, wid);
+                self.unmap_win(wid).await;
+                return;
+            }
+            match self.park_if_minimized(wid, true).await {
+                Ok(true) => self.unmap_win(wid).await,
+                Ok(false) if event.atom == self.atoms._NET_WM_STATE => {
+                    self.update_theater(wid).await
+                }
+                Ok(false) => (),
+                Err(e) => debug!("Failed to check if {wid:#010x} is minimized: {e}"),
+            }
+            return;
+        }
+        let skipping = self.skipping.lock().await.get(&client_wid).copied();
+        if let Some(wid) = skipping {
+            if event.atom == self.atoms._NET_WM_STATE && !self.skips_taskbar(wid).await {
+                debug!("{wid:#010x} no longer skips the taskbar");
+                self.skipping.lock().await.remove(&client_wid);
+                if !self.pending_windows.lock().await.contains_key(&wid) {
+                    self.spawn_map_win(wid).await;
+                }
+            }
+            return;
+        }
+        let wid = match self.parked.lock().await.get(&client_wid) {
+            Some(Parked {
+                wid, mapped: true, ..
+            }) => *wid,
+            _ => return,
+        };
+        if let Ok(false) = block_in_place(|| is_minimized(&self.x11, &self.atoms, client_wid)) {
+            if !self.pending_windows.lock().await.contains_key(&wid) {
+                debug!("{wid:#010x} restored");
+                self.spawn_map_win(wid).await;
+            }
+        }
+    }
+
+    /// Stop mirroring the window of `client_wid` if its new class makes it a VR window
+    async fn recheck_vr_window(self: &Arc<Self>, client_wid: u32) {
+        let mirrored = self
+            .window_state
+            .read()
+            .await
+            .client_window_to_window
+            .get(&client_wid)
+            .copied();
+        let wid = match mirrored {
+            Some(wid) => wid,
+            None => return,
+        };
+        let info = match self.source.window_info(wid).await {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("Failed to get info of {wid:#010x}: {e}");
+                return;
+            }
+        };
+        let vr_pids = self.vr_pids.lock().unwrap().clone();
+        let vr_window =
+            block_in_place(|| is_vr_window(&self.x11, &self.atoms, &self.config, &info, &vr_pids));
+        match vr_window {
+            Ok(true) => {
+                info!("{wid:#010x} turned out to belong to VR, no longer mirrored");
+                self.unmap_win(wid).await;
+            }
+            Ok(false) => (),
+            Err(e) => debug!("Failed to check if {wid:#010x} belongs to VR: {e}"),
+        }
+    }
+
+    /// `skips_taskbar` for the top level window `wid`, false if that can't be told, e.g. as it
+    /// is gone.
+    async fn skips_taskbar(&self, wid: u32) -> bool {
+        let info = match self.source.window_info(wid).await {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("Failed to get info of {wid:#010x}: {e}");
+                return false;
+            }
+        };
+        block_in_place(|| skips_taskbar(&self.x11, &self.atoms, &self.config, &info))
+            .unwrap_or_else(|e| {
+                debug!("Failed to check if {wid:#010x} skips the taskbar: {e}");
+                false
+            })
+    }
+
+    /// Ask the window manager to minimize the window `id` (a mirrored window or its client
+    /// window). It is hidden from VR once the window manager has done so.
+    async fn minimize_window(&self, id: u32) -> Result<()> {
+        let client_wid = match self.window_state.read().await.windows.get(&id) {
+            Some(w) => w.read().await.client_wid,
+            None => id,
+        };
+        debug!("minimizing {client_wid:#010x}");
+        block_in_place(|| {
+            let root = self.x11.get_geometry(client_wid)?.reply()?.root;
+            let event = xproto::ClientMessageEvent::new(
+                32,
+                client_wid,
+                self.atoms.WM_CHANGE_STATE,
+                [ICONIC_STATE, 0, 0, 0, 0],
+            );
+            self.x11
+                .send_event(
+                    false,
+                    root,
+                    xproto::EventMask::SUBSTRUCTURE_REDIRECT
+                        | xproto::EventMask::SUBSTRUCTURE_NOTIFY,
+                    event,
+                )?
+                .check()?;
+            Result::Ok(())
+        })
+    }
+
+    /// Restore a window minimized with `minimize_window`, or from the desktop. `id` can be the
+    /// top level or the client window.
+    async fn restore_window(&self, id: u32) -> Result<()> {
+        let client_wid = self
+            .parked
+            .lock()
+            .await
+            .iter()
+            .find(|(client_wid, parked)| **client_wid == id || parked.wid == id)
+            .map_or(id, |(client_wid, _)| *client_wid);
+        debug!("restoring {client_wid:#010x}");
+        block_in_place(|| {
+            // ICCCM way of leaving IconicState, then the EWMH one which also clears
+            // _NET_WM_STATE_HIDDEN
+            self.x11.map_window(client_wid)?.check()?;
+            self.activate_client_window(client_wid)
+        })
+    }
+
+    /// Animate windows fading in and out, and gliding to new places. Windows that have faded out
+    /// are freed here. While rendering is suspended, e.g. when the VR runtime is gone, animations
+    /// finish right away.
+    async fn animation_task(&self) {
+        let mut interval = tokio::time::interval(ANIMATION_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            if self.fading.lock().unwrap().is_empty() && self.gliding.lock().unwrap().is_empty() {
+                self.animation_notify.notified().await;
+            }
+            interval.tick().await;
+            let now = std::time::Instant::now();
+            let finish = self.suspend.is_suspended();
+            let mut steps = Vec::new();
+            let mut faded_out = Vec::new();
+            self.fading.lock().unwrap().retain(|&wid, fading| {
+                let done = finish || fading.fade.is_done(now);
+                let value = if done {
+                    fading.fade.target()
+                } else {
+                    fading.fade.value(now)
+                };
+                match fading.removed.take() {
+                    Some(w) if done => faded_out.push((wid, w)),
+                    removed => {
+                        fading.removed = removed;
+                        let scale = fading.scale * animation::scale(value);
+                        steps.push((fading.xrd_window.clone(), scale, value));
+                    }
+                }
+                !done
+            });
+            let mut glides = Vec::new();
+            self.gliding.lock().unwrap().retain(|_, gliding| {
+                let done = finish || gliding.glide.is_done(now);
+                let transform = if done {
+                    gliding.glide.target()
+                } else {
+                    gliding.glide.transform(now)
+                };
+                glides.push((gliding.xrd_window.clone(), transform.to_float()));
+                !done
+            });
+            let result = utils::invoke(&glib::MainContext::default(), move || {
+                for (xrd_window, scale, value) in &steps {
+                    xrd_window.set_scale(*scale);
+                    set_dimmed(xrd_window, (*value < 1.0).then_some(*value));
+                }
+                for (xrd_window, transform) in &glides {
+                    xrd_window.set_transformation(&mut graphene::Matrix::from_float(*transform));
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                error!("Failed to animate windows: {e}");
+            }
+            for (wid, w) in faded_out {
+                self.free_faded(wid, w).await;
+            }
+        }
+    }
+
+    /// Animate `xrd_window` of `wid` with `fade`, see `animation_task`. `scale` is its scale when
+    /// fully shown. `removed` is freed once it has faded out.
+    fn start_fade(
+        &self,
+        wid: u32,
+        xrd_window: xrd::Window,
+        scale: f32,
+        fade: animation::Fade,
+        removed: Option<Window>,
+    ) {
+        let fading = Fading {
+            xrd_window,
+            scale,
+            fade,
+            removed,
+        };
+        let old = self.fading.lock().unwrap().insert(wid, fading);
+        // Callers stop fading out first
+        debug_assert!(old.map_or(true, |old| old.removed.is_none()));
+        self.animation_notify.notify_one();
+    }
+
+    /// Shrink and darken `w`, removed from `WindowState` already, then free it. A window that
+    /// was still fading in fades out from where it got.
+    async fn fade_out(&self, wid: u32, w: Window) {
+        self.stop_fade_out(wid).await;
+        let now = std::time::Instant::now();
+        let fading_in = self.fading.lock().unwrap().remove(&wid);
+        let xrd_window = w.xrd_window.lock().await.clone();
+        let (from, scale) = match fading_in {
+            Some(fading) => (fading.fade.value(now), fading.scale),
+            None => {
+                let xrd_window = xrd_window.clone();
+                let scale =
+                    utils::invoke(&glib::MainContext::default(), move || xrd_window.scale()).await;
+                match scale {
+                    Ok(scale) => (1.0, scale),
+                    Err(e) => {
+                        error!("Failed to get the scale of {wid:#010x}: {e}");
+                        self.free_faded(wid, w).await;
+                        return;
+                    }
+                }
+            }
+        };
+        let fade = animation::Fade::new(from, 0.0, now, self.config.fade.duration());
+        self.start_fade(wid, xrd_window, scale, fade, Some(w));
+    }
+
+    /// Free the window of `wid` fading out right away, if there is one
+    async fn stop_fade_out(&self, wid: u32) {
+        let fading = {
+            let mut fading = self.fading.lock().unwrap();
+            match fading.get(&wid) {
+                Some(f) if f.removed.is_some() => fading.remove(&wid),
+                _ => None,
+            }
+        };
+        if let Some(w) = fading.and_then(|f| f.removed) {
+            self.free_faded(wid, w).await;
+        }
+    }
+
+    /// Move `xrd_window` of `wid` from the `from` to the `to` transformation over
+    /// `layout.glide`, see `animation_task`. A glide already going on is replaced.
+    fn start_glide(&self, wid: u32, xrd_window: xrd::Window, from: [f32; 16], to: [f32; 16]) {
+        let now = std::time::Instant::now();
+        let glide = animation::Glide::new(from, to, now, self.config.layout.glide());
+        let gliding = Gliding { xrd_window, glide };
+        self.gliding.lock().unwrap().insert(wid, gliding);
+        self.animation_notify.notify_one();
+    }
+
+    async fn free_faded(&self, wid: u32, w: Window) {
+        // Dropping needs exclusive access to WindowState, for windows linked to others
+        let _window_state = self.window_state.write().await;
+        if let Err(e) = unsafe { w.drop() }.await {
+            error!("Failed to free {wid:#010x}: {e}");
+        }
+    }
+
+    /// Stop mirroring `wid`, whether it is still being mapped or already mapped.
+    async fn unmap_win(self: &Arc<Self>, wid: u32) {
+        if let Some(handle) = self.pending_windows.lock().await.remove(&wid) {
+            debug!("stopped map_win task for {wid:#010x}");
+            handle.abort();
+            // we still need to continue, depending on the timing, map_win might have
+            // already inserted the window into window_state.
+        }
+        self.pinned.lock().await.remove(&wid);
+        self.monitors.lock().unwrap().remove(&wid);
+        self.gliding.lock().unwrap().remove(&wid);
+        self.demoted.lock().unwrap().remove(&wid);
+        let label = self.labels.lock().unwrap().remove(&wid);
+        if let Some(label) = label {
+            self.close_label(label.xrd_window).await;
+        }
+        // A render holding the window may be waiting for window_state, so it isn't held while
+        // waiting for the render
+        let w = window_map::remove(&self.window_state, |s| s.windows.remove(&wid)).await;
+        if let Some(w) = w {
+            let mut window_state = self.window_state.write().await;
+            // Unless another window took over the client window meanwhile
+            if window_state.client_window_to_window.get(&w.client_wid) == Some(&wid) {
+                window_state.client_window_to_window.remove(&w.client_wid);
+            }
+            self.stats.unregister(wid);
+            // We have to remove window from window_state before handling any
+            // further events, so we wouldn't close a window with the same wid that
+            // is created _after_ we receive this event. Freeing it right away too, instead of in
+            // a spawned task, so nothing is left behind when we shut down.
+            // window_state here is locked exclusively at this point.
+            if self.config.fade.enabled && !self.suspend.is_suspended() {
+                drop(window_state);
+                // Freed once it has faded out
+                self.fade_out(wid, w).await;
+            } else {
+                if let Err(e) = unsafe { w.drop() }.await {
+                    error!("Failed to free {wid:#010x}: {e}");
+                }
+                drop(window_state);
+            }
+            debug!("{wid:#010x} dropped");
+            self.windows_changed().await;
+        }
+    }
+
+    async fn handle_control_request(self: &Arc<Self>, request: dbus::Request) {
+        debug!("D-Bus request {request:?}");
+        match request {
+            dbus::Request::IgnoreWindow(id) => {
+                let wid = self.toplevel(id).await;
+                info!("Ignoring {wid:#010x}");
+                self.ignored_windows.lock().await.insert(wid);
+                self.unmap_win(wid).await;
+            }
+            dbus::Request::CloseWindow(id) => {
+                let id = match id {
+                    Some(id) => id,
+                    None => match self.input_state.lock().await.last_clicked {
+                        Some(wid) => wid,
+                        None => return,
+                    },
+                };
+                if let Err(e) = self.close_window(id).await {
+                    error!("Failed to close {id:#010x}: {e}");
+                }
+            }
+            dbus::Request::MinimizeWindow(id) => {
+                if let Err(e) = self.minimize_window(id).await {
+                    error!("Failed to minimize {id:#010x}: {e}");
+                }
+            }
+            dbus::Request::RestoreWindow(id) => {
+                if let Err(e) = self.restore_window(id).await {
+                    error!("Failed to restore {id:#010x}: {e}");
+                }
+            }
+            dbus::Request::Suspend => self.set_suspended(&self.suspend.manual, true).await,
+            dbus::Request::Resume => self.set_suspended(&self.suspend.manual, false).await,
+            dbus::Request::ListWindows(reply) => {
+                let mut windows = Vec::new();
+                let window_state = self.window_state.read().await;
+                for (&wid, w) in window_state.windows.iter() {
+                    windows.push((wid, w.read().await.xrd_window.lock().await.clone()));
+                }
+                drop(window_state);
+                let titles = utils::invoke(&glib::MainContext::default(), move || {
+                    windows
+                        .into_iter()
+                        .map(|(wid, w)| (wid, w.title().map(Into::into).unwrap_or_default()))
+                        .collect()
+                })
+                .await;
+                match titles {
+                    Ok(titles) => {
+                        let _ = reply.send(titles);
+                    }
+                    Err(e) => error!("Failed to list windows: {e}"),
+                }
+            }
+            dbus::Request::HideWindow(id) => {
+                let wid = self.toplevel(id).await;
+                self.with_xrd_window(wid, |w| w.hide()).await;
+            }
+            dbus::Request::ShowWindow(id) => {
+                let wid = self.toplevel(id).await;
+                self.promote(wid).await;
+                self.with_xrd_window(wid, |w| w.show()).await;
+                // Hidden windows aren't rendered, catch up with what changed meanwhile
+                if let Some(w) = self.window_state.read().await.windows.get(&wid) {
+                    w.read().await.dirty.store(true, Ordering::Release);
+                    self.render_notify.notify_one();
+                }
+            }
+            dbus::Request::SetScale(id, scale) => {
+                let wid = self.toplevel(id).await;
+                self.with_xrd_window(wid, move |w| w.set_scale(scale)).await;
+            }
+            dbus::Request::ResetLayout => self.reset_layout().await,
+            dbus::Request::SetDesktopMirror(enabled) => {
+                let enabled = enabled.unwrap_or(!self.desktop_mirror.load(Ordering::Acquire));
+                if let Err(e) = self.set_desktop_mirror(enabled).await {
+                    error!("Failed to switch mirroring modes: {e:#}");
+                }
+            }
+            dbus::Request::SetFocusMode(enabled) => {
+                let enabled = match enabled {
+                    Some(enabled) => enabled,
+                    None => self.focus_mode.lock().await.is_none(),
+                };
+                self.set_focus_mode(enabled).await;
+            }
+            dbus::Request::SetBrightness(id, brightness) => {
+                let wid = self.toplevel(id).await;
+                self.set_adjustment(wid, |a| a.brightness = brightness)
+                    .await;
+                self.remember(wid, |s| s.brightness = Some(brightness))
+                    .await;
+            }
+            dbus::Request::SetGamma(id, gamma) => {
+                let wid = self.toplevel(id).await;
+                self.set_adjustment(wid, |a| a.gamma = gamma).await;
+                self.remember(wid, |s| s.gamma = Some(gamma)).await;
+            }
+            dbus::Request::SetColorTemperature(kelvin) => self.set_color_temperature(kelvin).await,
+            dbus::Request::SetPinned(id, pinned) => {
+                let wid = self.toplevel(id).await;
+                self.set_pinned(wid, pinned).await;
+            }
+            dbus::Request::SetPreset(preset) => {
+                info!("Switching to the {preset:?} layout");
+                *self.preset.lock().unwrap() = preset;
+                self.relayout().await;
+            }
+            dbus::Request::SetMirroring(enabled) => {
+                let enabled =
+                    enabled.unwrap_or_else(|| self.suspend.hidden.load(Ordering::Acquire));
+                self.set_mirroring(enabled).await;
+            }
+            dbus::Request::SaveLayout(name, reply) => {
+                let result = self.save_layout(&name).await;
+                if let Err(e) = &result {
+                    error!("Failed to save the layout {name:?}: {e:#}");
+                }
+                let _ = reply.send(result.map_err(|e| format!("{e:#}")));
+            }
+            dbus::Request::LoadLayout(name, reply) => {
+                let result = self.load_layout(&name).await;
+                if let Err(e) = &result {
+                    error!("Failed to load the layout {name:?}: {e:#}");
+                }
+                let _ = reply.send(result.map_err(|e| format!("{e:#}")));
+            }
+            dbus::Request::ExportWindow(id, reply) => {
+                let wid = self.toplevel(id).await;
+                let _ = reply.send(self.export_window(wid).await.map_err(|e| e.to_string()));
+            }
+        }
+    }
+
+    /// Mirrored windows with their class and title, to save or load a layout, see `layouts`.
+    /// Windows that are closing are left out.
+    async fn layout_candidates(&self) -> Vec<(layouts::Candidate, xrd::Window)> {
+        let mut windows = Vec::new();
+        let window_state = self.window_state.read().await;
+        for (&wid, w) in &window_state.windows {
+            windows.push((wid, w.read().await.xrd_window.lock().await.clone()));
+        }
+        drop(window_state);
+        let mut candidates = Vec::new();
+        for (wid, xrd_window) in windows {
+            if let Ok(info) = self.source.window_info(wid).await {
+                let candidate = layouts::Candidate {
+                    wid,
+                    class: layouts::class(&info.class_instance, &info.class_general).into(),
+                    title: info.name,
+                };
+                candidates.push((candidate, xrd_window));
+            }
+        }
+        candidates.sort_by_key(|(candidate, _)| candidate.wid);
+        candidates
+    }
+
+    /// Save where windows are in VR as the layout `name`. Pinned windows are saved with where
+    /// they go back to when unpinned.
+    async fn save_layout(&self, name: &str) -> Result<()> {
+        let candidates = self.layout_candidates().await;
+        let pinned: HashMap<u32, ([f32; 16], f32)> = self
+            .pinned
+            .lock()
+            .await
+            .iter()
+            .map(|(&wid, pinned)| (wid, pinned.world))
+            .collect();
+        let layout = utils::invoke(&glib::MainContext::default(), move || {
+            let windows = candidates
+                .into_iter()
+                .map(|(candidate, xrd_window)| {
+                    let (transform, scale) = match pinned.get(&candidate.wid) {
+                        Some(&world) => world,
+                        None => {
+                            let mut transform = graphene::Matrix::new_identity();
+                            xrd_window.is_transformation(&mut transform);
+                            (transform.to_float(), xrd_window.scale())
+                        }
+                    };
+                    layouts::Entry {
+                        pinned: pinned.contains_key(&candidate.wid),
+                        class: candidate.class,
+                        title: candidate.title,
+                        transform,
+                        scale,
+                        visible: xrd_window.is_visible(),
+                    }
+                })
+                .collect();
+            layouts::Layout { windows }
+        })
+        .await?;
+        layout.save(name)?;
+        info!(
+            "Saved {} windows as {}",
+            layout.windows.len(),
+            layouts::path(name).display()
+        );
+        Ok(())
+    }
+
+    /// Move windows to where the layout `name` has them. They glide there, see `start_glide`,
+    /// except those that are pinned again, which follow the head right away.
+    async fn load_layout(&self, name: &str) -> Result<()> {
+        let layout = layouts::Layout::load(name)?;
+        let candidates = self.layout_candidates().await;
+        let (candidates, xrd_windows): (Vec<_>, HashMap<_, _>) = candidates
+            .into_iter()
+            .map(|(candidate, xrd_window)| {
+                let wid = candidate.wid;
+                (candidate, (wid, xrd_window))
+            })
+            .unzip();
+        let assigned = layout.assign(&candidates);
+        info!("Loading the layout {name:?} for {} windows", assigned.len());
+        let glide = self.glides();
+        for (wid, entry) in assigned {
+            let xrd_window = match xrd_windows.get(&wid) {
+                Some(xrd_window) => xrd_window.clone(),
+                None => continue,
+            };
+            // Pinned windows are pinned again from their saved place, with the current head
+            self.set_pinned(wid, Some(false)).await;
+            let jump = !glide || entry.pinned;
+            let (to, scale, visible) = (entry.transform, entry.scale, entry.visible);
+            let moved = xrd_window.clone();
+            let from = utils::invoke(&glib::MainContext::default(), move || {
+                let mut from = graphene::Matrix::new_identity();
+                moved.is_transformation(&mut from);
+                if jump {
+                    moved.set_transformation(&mut graphene::Matrix::from_float(to));
+                }
+                moved.set_scale(scale);
+                if visible {
+                    moved.show();
+                } else {
+                    moved.hide();
+                }
+                from.to_float()
+            })
+            .await?;
+            if entry.pinned {
+                self.set_pinned(wid, Some(true)).await;
+            } else if !jump {
+                self.start_glide(wid, xrd_window, from, to);
+            }
+            // Hidden windows aren't rendered, catch up with what changed meanwhile
+            if let Some(w) = self.window_state.read().await.windows.get(&wid) {
+                w.read().await.dirty.store(true, Ordering::Release);
+                self.render_notify.notify_one();
+            }
+        }
+        Ok(())
+    }
+
+    /// Export the pixmap picom currently uses for `wid`, see `gl::DmaBuf`.
+    async fn export_window(&self, wid: u32) -> Result<gl::DmaBuf> {
+        let window_state = self.window_state.read().await;
+        let w = window_state
+            .windows
+            .get(&wid)
+            .ok_or_else(|| anyhow!("{wid:#010x} isn't mirrored"))?
+            .read()
+            .await;
+        let textures = w
+            .textures
+            .as_ref()
+            .ok_or_else(|| anyhow!("{wid:#010x} has no contents yet"))?;
+        Ok(self.gl.export_dmabuf(&textures.x11_texture).await?)
+    }
+
+    /// A property of the root window changed. With `current-desktop-only`, the windows of the
+    /// new virtual desktop are shown. In focus mode, the active window is shown once focus stayed
+    /// on it for `[focus-mode] debounce`.
+    async fn handle_root_property(self: &Arc<Self>, event: &xproto::PropertyNotifyEvent) {
+        if event.atom == self.atoms._NET_CURRENT_DESKTOP {
+            self.update_desktop_filter().await;
+            return;
+        }
+        if event.atom != self.atoms._NET_ACTIVE_WINDOW {
+            return;
+        }
+        self.update_focused(event.window).await;
+        let change = match self.focus_mode.lock().await.as_mut() {
+            Some(focus_mode) => {
+                focus_mode.changes += 1;
+                focus_mode.changes
+            }
+            None => return,
+        };
+        let this = self.clone();
+        let debounce = self.config.focus_mode.debounce();
+        tokio::spawn(async move {
+            tokio::time::sleep(debounce).await;
+            // Focus moved on meanwhile, the later change does the update
+            let changes = this.focus_mode.lock().await.as_ref().map(|f| f.changes);
+            if changes == Some(change) {
+                this.update_focus_mode().await;
+            }
+        });
+    }
+
+    /// Remember the window with keyboard focus on `root`, and draw the windows that got or lost
+    /// it again, with or without the focus highlight. With `[window-limit]`, the focused window
+    /// is mirrored again if it wasn't.
+    async fn update_focused(&self, root: xproto::Window) {
+        if !self.config.focus_highlight.enabled && self.config.window_limit.max_windows == 0 {
+            return;
+        }
+        let active = match block_in_place(|| self.active_window(root)) {
+            Ok(active) => active,
+            Err(e) => {
+                warn!("Failed to get the active window: {e}");
+                return;
+            }
+        };
+        let previous = std::mem::replace(&mut *self.focused.lock().unwrap(), active);
+        if previous == active {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let window_state = self.window_state.read().await;
+        for w in window_state.windows.values() {
+            let w = w.read().await;
+            if active.map_or(false, |id| id == w.id || id == w.client_wid) {
+                w.activity.lock().unwrap().focused = Some(now);
+            }
+            if [previous, active]
+                .into_iter()
+                .flatten()
+                .any(|id| id == w.id || id == w.client_wid)
+            {
+                w.dirty.store(true, Ordering::Release);
+            }
+        }
+        drop(window_state);
+        self.render_notify.notify_one();
+        self.limit_windows().await;
+    }
+
+    /// With `[window-limit]`, hide the windows that rank below `max-windows` and free their
+    /// textures, and show the ones that rank high enough again, see `priority`. Windows hidden for
+    /// other reasons are left alone.
+    async fn limit_windows(&self) {
+        let max = self.config.window_limit.max_windows;
+        if max == 0 || self.suspend.hidden.load(Ordering::Acquire) {
+            return;
+        }
+        let pinned: HashSet<u32> = self.pinned.lock().await.keys().copied().collect();
+        let window_state = self.window_state.read().await;
+        let mut candidates = Vec::new();
+        for (&wid, w) in &window_state.windows {
+            // Children are shown and hidden with their parent
+            let w = w.read().await;
+            let xrd_window = w.xrd_window.lock().await.clone();
+            let data = unsafe { xrd::sys::xrd_window_get_data(xrd_window.as_ptr()) };
+            if unsafe { !(*data).parent_window.is_null() } || self.desktop_region(wid).is_some() {
+                continue;
+            }
+            candidates.push(priority::Candidate {
+                wid,
+                pinned: pinned.contains(&wid),
+                activity: *w.activity.lock().unwrap(),
+            });
+        }
+        let live = priority::live(&candidates, max);
+        let mut hide = Vec::new();
+        let mut show = Vec::new();
+        for candidate in candidates {
+            let wid = candidate.wid;
+            let w = match window_state.windows.get(&wid) {
+                Some(w) => w,
+                None => continue,
+            };
+            let demoted = self.demoted.lock().unwrap().contains(&wid);
+            if live.contains(&wid) && demoted {
+                self.demoted.lock().unwrap().remove(&wid);
+                let w = w.read().await;
+                show.push(w.xrd_window.lock().await.clone());
+                // X reports no damage for what changed meanwhile
+                w.dirty.store(true, Ordering::Release);
+            } else if !live.contains(&wid) && !demoted {
+                let mut w = w.write().await;
+                if !w.xrd_window.get_mut().is_visible() {
+                    continue;
+                }
+                // Not rendered from here on, see render_win
+                self.demoted.lock().unwrap().insert(wid);
+                if let Err(e) = TextureSet::free(w.textures.take(), &self.gl, &self.x11).await {
+                    warn!("Failed to free the textures of {wid:#010x}: {e}");
+                }
+                hide.push(w.xrd_window.get_mut().clone());
+            }
+        }
+        drop(window_state);
+        if hide.is_empty() && show.is_empty() {
+            return;
+        }
+        debug!(
+            "Window limit hides {} windows and shows {} again",
+            hide.len(),
+            show.len()
+        );
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            for xrd_window in hide {
+                xrd_window.hide();
+            }
+            for xrd_window in show {
+                xrd_window.show();
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Failed to update VR windows: {e}");
+        }
+        self.render_notify.notify_one();
+    }
+
+    /// Rank windows again every `[window-limit] interval`, as they draw, see `limit_windows`
+    async fn window_limit_task(&self) {
+        let mut interval = tokio::time::interval(self.config.window_limit.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            self.limit_windows().await;
+        }
+    }
+
+    /// Mirror `wid` again if `[window-limit]` hid it, as if it was focused
+    async fn promote(&self, wid: u32) {
+        if let Some(w) = self.window_state.read().await.windows.get(&wid) {
+            w.read().await.activity.lock().unwrap().focused = Some(std::time::Instant::now());
+        }
+        self.limit_windows().await;
+    }
+
+    /// The frame drawn around `w`: the focus highlight if it has keyboard focus, which the window
+    /// manager reports for its client window. It is drawn inside the window, so where clicks go
+    /// doesn't change.
+    fn border(&self, w: &Window) -> Option<gl::Border> {
+        let focused = *self.focused.lock().unwrap();
+        if focused.map_or(false, |focused| focused == w.id || focused == w.client_wid) {
+            if let Some(highlight) = self.config.focus_highlight.to_gl() {
+                return Some(highlight);
+            }
+        }
+        self.config.border.to_gl()
+    }
+
+    /// With `current-desktop-only`, hide top level windows that aren't on the current virtual
+    /// desktop, and show the ones that are again. Windows hidden for other reasons, e.g. through
+    /// D-Bus, are left alone.
+    async fn update_desktop_filter(&self) {
+        // Windows are hidden anyway, this runs again when mirroring is toggled back on
+        if !self.config.current_desktop_only || self.suspend.hidden.load(Ordering::Acquire) {
+            return;
+        }
+        let mut desktop_hidden = self.desktop_hidden.lock().await;
+        let mut windows = Vec::new();
+        let window_state = self.window_state.read().await;
+        for (&wid, w) in &window_state.windows {
+            let w = w.read().await;
+            let xrd_window = w.xrd_window.lock().await.clone();
+            // Children are shown and hidden with their parent
+            let data = unsafe { xrd::sys::xrd_window_get_data(xrd_window.as_ptr()) };
+            if unsafe { (*data).parent_window.is_null() } {
+                let client_wid = match w.client_wid {
+                    x11rb::NONE => wid,
+                    client_wid => client_wid,
+                };
+                windows.push((wid, client_wid, w.screen, xrd_window));
+            }
+        }
+        drop(window_state);
+        let desktops = block_in_place(|| {
+            let get_desktop = |window, atom| {
+                self.x11
+                    .get_property(false, window, atom, xproto::AtomEnum::CARDINAL, 0, 1)
+            };
+            // All requests first, so switching desktops takes one round trip. Each screen has
+            // desktops of its own.
+            let current = self
+                .screens
+                .iter()
+                .map(|&screen| {
+                    let root = self.root_of(screen);
+                    Ok((screen, get_desktop(root, self.atoms._NET_CURRENT_DESKTOP)?))
+                })
+                .collect::<Result<Vec<_>, x11rb::errors::ConnectionError>>()?;
+            let cookies = windows
+                .iter()
+                .map(|&(_, client_wid, ..)| get_desktop(client_wid, self.atoms._NET_WM_DESKTOP))
+                .collect::<Result<Vec<_>, _>>()?;
+            // Windows can be gone already, they are shown until they are removed
+            let desktop = |cookie: x11rb::cookie::Cookie<_, xproto::GetPropertyReply>| {
+                cookie.reply().ok().and_then(|r| r.value32()?.next())
+            };
+            Result::Ok((
+                current
+                    .into_iter()
+                    .map(|(screen, cookie)| (screen, desktop(cookie)))
+                    .collect::<HashMap<_, _>>(),
+                cookies.into_iter().map(desktop).collect::<Vec<_>>(),
+            ))
+        });
+        let (current, desktops) = match desktops {
+            Ok(desktops) => desktops,
+            Err(e) => {
+                warn!("Failed to get the desktops of windows: {e}");
+                return;
+            }
+        };
+        let mut hide = Vec::new();
+        let mut show = Vec::new();
+        for ((wid, _, screen, xrd_window), desktop) in windows.into_iter().zip(desktops) {
+            let current = current.get(&screen).copied().flatten();
+            if workspace::is_shown(desktop, current) {
+                if desktop_hidden.remove(&wid) {
+                    show.push((wid, xrd_window));
+                }
+            } else if !desktop_hidden.contains(&wid) && xrd_window.is_visible() {
+                desktop_hidden.insert(wid);
+                hide.push(xrd_window);
+            }
+        }
+        if hide.is_empty() && show.is_empty() {
+            return;
+        }
+        debug!(
+            "Desktops {current:?}: hiding {} windows, showing {}",
+            hide.len(),
+            show.len()
+        );
+        let shown: Vec<_> = show.iter().map(|(wid, _)| *wid).collect();
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            hide.iter().for_each(|xrd_window| xrd_window.hide());
+            show.iter().for_each(|(_, xrd_window)| xrd_window.show());
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Failed to switch desktops in VR: {e}");
+        }
+        // Hidden windows aren't rendered, catch up with what changed meanwhile
+        let window_state = self.window_state.read().await;
+        for wid in shown {
+            if let Some(w) = window_state.windows.get(&wid) {
+                w.read().await.dirty.store(true, Ordering::Release);
+            }
+        }
+        self.render_notify.notify_one();
+    }
+
+    /// Which gesture holding `button` starts, if any
+    fn gesture(&self, button: xrd::sys::XrdInputSynthButton) -> Option<input::Gesture> {
+        let button = input::ControllerButton::from_xrd(button)?;
+        if self.config.scale_gesture.button == Some(button) {
+            Some(input::Gesture::Scale)
+        } else if self.config.brightness_gesture.button == Some(button) {
+            Some(input::Gesture::Brightness)
+        } else {
+            None
+        }
+    }
+
+    /// Remember what a gesture on `wid` changed for its class, once its button is let go
+    async fn remember_gesture(&self, wid: u32, gesture: input::Gesture) {
+        match gesture {
+            input::Gesture::Scale => {
+                let xrd_window = match self.window_state.read().await.windows.get(&wid) {
+                    Some(w) => w.read().await.xrd_window.lock().await.clone(),
+                    None => return,
+                };
+                let scale =
+                    utils::invoke(&glib::MainContext::default(), move || xrd_window.scale()).await;
+                match scale {
+                    Ok(scale) => self.remember(wid, |s| s.scale = Some(scale)).await,
+                    Err(e) => error!("Failed to get the scale of {wid:#010x}: {e}"),
+                }
+            }
+            input::Gesture::Brightness => {
+                let brightness = match self.window_state.read().await.windows.get(&wid) {
+                    Some(w) => w.read().await.adjustment.brightness,
+                    None => return,
+                };
+                self.remember(wid, |s| s.brightness = Some(brightness))
+                    .await;
+            }
+        }
+    }
+
+    /// Change the settings remembered for the class of `wid`, see `window_settings`
+    async fn remember(&self, wid: u32, f: impl FnOnce(&mut window_settings::Settings)) {
+        let info = match self.source.window_info(wid).await {
+            Ok(info) => info,
+            Err(e) => {
+                debug!("Failed to get info of {wid:#010x}: {e}");
+                return;
+            }
+        };
+        let result = self.window_settings.lock().unwrap().update(
+            &info.class_instance,
+            &info.class_general,
+            f,
+        );
+        if let Err(e) = result {
+            warn!("Failed to save the settings of {wid:#010x}: {e:#}");
+        }
+    }
+
+    /// Change the brightness or gamma `wid` is drawn with. It is drawn again right away, also
+    /// if its contents didn't change.
+    async fn set_adjustment(&self, wid: u32, f: impl FnOnce(&mut gl::Adjustment)) {
+        match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => {
+                let mut w = w.write().await;
+                f(&mut w.adjustment);
+                debug!("{wid:#010x} adjusted to {:?}", w.adjustment);
+                w.dirty.store(true, Ordering::Release);
+                self.render_notify.notify_one();
+            }
+            None => warn!("{wid:#010x} is not mirrored"),
+        }
+    }
+
+    /// Tint all windows to `kelvin`. Only the shader changes, so they are drawn again as they are.
+    async fn set_color_temperature(&self, kelvin: u32) {
+        if self.color_temperature.swap(kelvin, Ordering::Relaxed) == kelvin {
+            return;
+        }
+        info!("Color temperature set to {kelvin} K");
+        for w in self.window_state.read().await.windows.values() {
+            w.read().await.dirty.store(true, Ordering::Release);
+        }
+        self.render_notify.notify_one();
+    }
+
+    /// Tint windows like GNOME's night light, as long as it is running
+    async fn night_light_task(&self) -> Result<()> {
+        let proxy = night_light::GnomeColorProxy::new(&self.dbus).await?;
+        let mut changes = proxy.receive_temperature_changed().await;
+        self.set_color_temperature(proxy.temperature().await?).await;
+        while let Some(change) = changes.next().await {
+            match change.get().await {
+                Ok(kelvin) => self.set_color_temperature(kelvin).await,
+                Err(e) => debug!("Failed to get GNOME's color temperature: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Pose of the headset, None without one or while it isn't tracked
+    async fn head_pose(&self) -> Option<graphene::Matrix> {
+        use glib::translate::ToGlibPtrMut;
+        let xrd_client = self.xrd_client.lock().await;
+        if self.no_hmd || !hmd_connected(&xrd_client) {
+            return None;
+        }
+        let gxr = xrd_client.gxr_context()?;
+        let mut pose = graphene::Matrix::new_identity();
+        let valid = unsafe {
+            gxr::sys::gxr_context_get_head_pose(gxr.as_ptr(), pose.to_glib_none_mut().0) != 0
+        };
+        valid.then_some(pose)
+    }
+
+    /// Pin `wid` to the head, or put it back where it was before. Toggles if `pinned` is None.
+    async fn set_pinned(&self, wid: u32, pinned: Option<bool>) {
+        let xrd_window = match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => w.read().await.xrd_window.lock().await.clone(),
+            None => {
+                warn!("{wid:#010x} is not mirrored");
+                return;
+            }
+        };
+        let was_pinned = self.pinned.lock().await.contains_key(&wid);
+        if pinned.unwrap_or(!was_pinned) == was_pinned {
+            return;
+        }
+        // The locks can't be held while waiting for the glib thread, see utils::invoke
+        let context = glib::MainContext::default();
+        if was_pinned {
+            let (transform, scale) = match self.pinned.lock().await.remove(&wid) {
+                Some(pinned) => pinned.world,
+                None => return,
+            };
+            info!("Unpinning {wid:#010x}");
+            let result = utils::invoke(&context, move || {
+                xrd_window.set_transformation(&mut graphene::Matrix::from_float(transform));
+                xrd_window.set_scale(scale);
+            })
+            .await;
+            if let Err(e) = result {
+                error!("Failed to unpin {wid:#010x}: {e}");
+            }
+            return;
+        }
+        let head = match self.head_pose().await {
+            Some(head) => head,
+            None => {
+                warn!("Can't pin {wid:#010x}, the headset isn't tracked");
+                return;
+            }
+        };
+        info!("Pinning {wid:#010x} to the head");
+        let [x, y, z] = self.config.pin.offset;
+        let offset = graphene::Matrix::new_translate(&graphene::Point3D::new(x, y, z)).to_float();
+        let scale = self.config.pin.scale;
+        let head = head.to_float();
+        let world = utils::invoke(&context, move || {
+            let mut transform = graphene::Matrix::new_identity();
+            xrd_window.is_transformation(&mut transform);
+            let world = (transform.to_float(), xrd_window.scale());
+            xrd_window.set_transformation(&mut placement::pinned_transform(
+                &graphene::Matrix::from_float(head),
+                &graphene::Matrix::from_float(offset),
+            ));
+            xrd_window.set_scale(scale);
+            world
+        })
+        .await;
+        match world {
+            Ok(world) => {
+                self.pinned.lock().await.insert(
+                    wid,
+                    Pinned {
+                        world,
+                        offset,
+                        grabbed: false,
+                    },
+                );
+                self.pin_notify.notify_one();
+            }
+            Err(e) => error!("Failed to pin {wid:#010x}: {e}"),
+        }
+    }
+
+    /// A pinned window stops following the head while it is moved in VR, and is pinned where it
+    /// is left.
+    async fn pin_grabbed(&self, wid: u32, grabbed: bool) {
+        match self.pinned.lock().await.get_mut(&wid) {
+            Some(pinned) => pinned.grabbed = grabbed,
+            None => return,
+        }
+        if grabbed {
+            return;
+        }
+        let head = match self.head_pose().await {
+            Some(head) => head.to_float(),
+            None => return,
+        };
+        let xrd_window = match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => w.read().await.xrd_window.lock().await.clone(),
+            None => return,
+        };
+        let offset = utils::invoke(&glib::MainContext::default(), move || {
+            let mut transform = graphene::Matrix::new_identity();
+            xrd_window.is_transformation(&mut transform);
+            placement::pin_offset(&transform, &graphene::Matrix::from_float(head)).to_float()
+        })
+        .await;
+        match (offset, self.pinned.lock().await.get_mut(&wid)) {
+            (Ok(offset), Some(pinned)) => pinned.offset = offset,
+            (Err(e), _) => error!("Failed to pin {wid:#010x} again: {e}"),
+            (Ok(_), None) => (),
+        }
+    }
+
+    /// Move pinned windows after the head. Sleeps while nothing is pinned.
+    async fn pin_task(&self) {
+        let mut interval = tokio::time::interval(self.config.pin.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            let pinned: Vec<_> = self
+                .pinned
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, pinned)| !pinned.grabbed)
+                .map(|(&wid, pinned)| (wid, pinned.offset))
+                .collect();
+            if pinned.is_empty() {
+                self.pin_notify.notified().await;
+                continue;
+            }
+            interval.tick().await;
+            let head = match self.head_pose().await {
+                Some(head) => head.to_float(),
+                None => continue,
+            };
+            let mut windows = Vec::with_capacity(pinned.len());
+            let window_state = self.window_state.read().await;
+            for (wid, offset) in pinned {
+                if let Some(w) = window_state.windows.get(&wid) {
+                    windows.push((w.read().await.xrd_window.lock().await.clone(), offset));
+                }
+            }
+            drop(window_state);
+            // All in one go, so pinned windows move together
+            let result = utils::invoke(&glib::MainContext::default(), move || {
+                let head = graphene::Matrix::from_float(head);
+                for (xrd_window, offset) in windows {
+                    let offset = graphene::Matrix::from_float(offset);
+                    xrd_window.set_transformation(&mut placement::pinned_transform(&head, &offset));
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                debug!("Failed to move pinned windows: {e}");
+            }
+        }
+    }
+
+    /// Show the title of `wid` above it, or show the new one if it changed. Children, like
+    /// menus, and the desktop mirror have no label.
+    async fn update_label(&self, wid: u32) {
+        if !self.config.labels.enabled || self.desktop_region(wid).is_some() {
+            return;
+        }
+        let xrd_window = match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => w.read().await.xrd_window.lock().await.clone(),
+            None => return,
+        };
+        let data = unsafe { xrd::sys::xrd_window_get_data(xrd_window.as_ptr()) };
+        if unsafe { !(*data).parent_window.is_null() } {
+            return;
+        }
+        let title = match self.source.window_info(wid).await {
+            Ok(info) => labels::truncate(&info.name, self.config.labels.max_chars),
+            Err(e) => {
+                debug!("Failed to get the title of {wid:#010x}: {e}");
+                return;
+            }
+        };
+        // Drawing the text again is only worth it if it changed
+        let old = self.labels.lock().unwrap().remove(&wid);
+        if let Some(label) = old {
+            if label.title == title {
+                self.labels.lock().unwrap().insert(wid, label);
+                return;
+            }
+            self.close_label(label.xrd_window).await;
+        }
+        if title.is_empty() {
+            return;
+        }
+        let (width, height) = labels::size(&title, self.config.labels.height);
+        let label = self.xrd_client.lock().await.button_new_from_text(
+            width,
+            height,
+            LABEL_PIXELS_PER_METER,
+            &[&title],
+        );
+        let xrd_window = match label {
+            Some(xrd_window) => xrd_window,
+            None => {
+                warn!("Failed to create the label of {wid:#010x}");
+                return;
+            }
+        };
+        // Unmapped meanwhile
+        if !self.window_state.read().await.windows.contains_key(&wid) {
+            self.close_label(xrd_window).await;
+            return;
+        }
+        self.labels
+            .lock()
+            .unwrap()
+            .insert(wid, Label { title, xrd_window });
+        self.labels_notify.notify_one();
+    }
+
+    /// Labels are xrdesktop windows like the mirrored ones, so they are closed under the client
+    /// lock too
+    async fn close_label(&self, xrd_window: xrd::Window) {
+        let _xrd_client = self.xrd_client.lock().await;
+        xrd_window.close();
+    }
+
+    /// Keep labels above their windows, also while the windows are moved or resized. Labels of
+    /// hidden windows are hidden too.
+    async fn labels_task(&self) {
+        let mut interval = tokio::time::interval(ANIMATION_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            let shown: Vec<_> = self
+                .labels
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&wid, label)| (wid, label.xrd_window.clone()))
+                .collect();
+            if shown.is_empty() {
+                self.labels_notify.notified().await;
+                continue;
+            }
+            interval.tick().await;
+            let mut windows = Vec::with_capacity(shown.len());
+            let window_state = self.window_state.read().await;
+            for (wid, label) in shown {
+                if let Some(w) = window_state.windows.get(&wid) {
+                    windows.push((label, w.read().await.xrd_window.lock().await.clone()));
+                }
+            }
+            drop(window_state);
+            let config = self.config.labels.clone();
+            let result = utils::invoke(&glib::MainContext::default(), move || {
+                for (label, xrd_window) in windows {
+                    if !xrd_window.is_visible() {
+                        label.hide();
+                        continue;
+                    }
+                    let mut transform = graphene::Matrix::new_identity();
+                    xrd_window.is_transformation_no_scale(&mut transform);
+                    let mut transform = labels::transform(
+                        &transform,
+                        xrd_window.current_height_meters(),
+                        config.height,
+                        config.gap,
+                    );
+                    label.set_transformation(&mut transform);
+                    if !label.is_visible() {
+                        label.show();
+                    }
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                debug!("Failed to move labels: {e}");
+            }
+        }
+    }
+
+    /// Share the textures of windows that are far away, or scaled down, at lower resolutions, so
+    /// they don't alias, see `placement::mip_level`.
+    async fn distant_windows_task(&self) {
+        let config = &self.config.distant_windows;
+        let mut interval = tokio::time::interval(config.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let origin = graphene::Point3D::new(0.0, 0.0, 0.0);
+        loop {
+            interval.tick().await;
+            let eye = match self.head_pose().await {
+                Some(head) => head.transform_point3d(&origin),
+                None => continue,
+            };
+            let mut windows = Vec::new();
+            let window_state = self.window_state.read().await;
+            for (&wid, w) in &window_state.windows {
+                let w = w.read().await;
+                if let Some(textures) = &w.textures {
+                    let (width, height) =
+                        (textures.x11_texture.width(), textures.x11_texture.height());
+                    let xrd_window = w.xrd_window.lock().await.clone();
+                    windows.push((wid, xrd_window, width, height, w.size_override));
+                }
+            }
+            drop(window_state);
+            let xrd_windows: Vec<_> = windows.iter().map(|(_, w, ..)| w.clone()).collect();
+            let placed = utils::invoke(&glib::MainContext::default(), move || {
+                xrd_windows
+                    .iter()
+                    .map(|xrd_window| {
+                        let mut transform = graphene::Matrix::new_identity();
+                        xrd_window.is_transformation(&mut transform);
+                        (transform.to_float(), xrd_window.scale())
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await;
+            let placed = match placed {
+                Ok(placed) => placed,
+                Err(e) => {
+                    debug!("Failed to get window positions: {e}");
+                    continue;
+                }
+            };
+            let window_state = self.window_state.read().await;
+            for ((wid, _, width, height, size_override), (transform, scale)) in
+                windows.into_iter().zip(placed)
+            {
+                if let Some(w) = window_state.windows.get(&wid) {
+                    let center = graphene::Matrix::from_float(transform).transform_point3d(&origin);
+                    let (width_meters, _) = placement::physical_size(
+                        width,
+                        height,
+                        PIXELS_PER_METER,
+                        size_override,
+                        self.config.window_size.fit,
+                    );
+                    let mut w = w.write().await;
+                    let level = placement::mip_level(
+                        width,
+                        width_meters * scale,
+                        center.distance(&eye).0,
+                        config.pixels_per_degree,
+                        w.mip_level,
+                        config.max_level,
+                    );
+                    if level != w.mip_level {
+                        debug!("Sharing {wid:#010x} at mip level {level}");
+                        w.mip_level = level;
+                        w.dirty.store(true, Ordering::Release);
+                        self.render_notify.notify_one();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Switch focus mode on, showing only the active window, or back off, showing all windows
+    /// where they were.
+    async fn set_focus_mode(&self, enabled: bool) {
+        let mut focus_mode = self.focus_mode.lock().await;
+        if enabled == focus_mode.is_some() {
+            return;
+        }
+        info!("Focus mode {}", if enabled { "on" } else { "off" });
+        let FocusMode { hero, hidden, .. } = match focus_mode.take() {
+            Some(previous) => previous,
+            None => {
+                *focus_mode = Some(FocusMode::default());
+                drop(focus_mode);
+                self.update_focus_mode().await;
+                return;
+            }
+        };
+        let mut xrd_windows = Vec::new();
+        let window_state = self.window_state.read().await;
+        for (&wid, w) in &window_state.windows {
+            if hidden.contains(&wid) || hero.map(|(hero, ..)| hero) == Some(wid) {
+                xrd_windows.push((wid, w.read().await.xrd_window.lock().await.clone()));
+            }
+        }
+        drop(window_state);
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            for (wid, xrd_window) in xrd_windows {
+                match hero {
+                    Some((hero, transform, scale)) if hero == wid => {
+                        xrd_window.set_transformation(&mut graphene::Matrix::from_float(transform));
+                        xrd_window.set_scale(scale);
+                    }
+                    _ => xrd_window.show(),
+                }
+            }
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Failed to leave focus mode: {e}");
+        }
+    }
+
+    /// Move the active window in front of the user, and hide all other top level windows. Nothing
+    /// changes if the active window isn't mirrored, e.g. when the desktop got focus.
+    async fn update_focus_mode(&self) {
+        let mut focus_mode = self.focus_mode.lock().await;
+        let focus_mode = match focus_mode.as_mut() {
+            Some(focus_mode) => focus_mode,
+            None => return,
+        };
+        let active = match block_in_place(|| self.active_window(self.root())) {
+            Ok(Some(active)) => self.toplevel(active).await,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Failed to get the active window: {e}");
+                return;
+            }
+        };
+        if focus_mode.hero.map(|(hero, ..)| hero) == Some(active) {
+            return;
+        }
+        let mut xrd_windows = Vec::new();
+        let window_state = self.window_state.read().await;
+        if !window_state.windows.contains_key(&active) {
+            return;
+        }
+        for (&wid, w) in &window_state.windows {
+            let xrd_window = w.read().await.xrd_window.lock().await.clone();
+            // Children move with their parent, and are shown and hidden with it
+            let data = unsafe { xrd::sys::xrd_window_get_data(xrd_window.as_ptr()) };
+            if unsafe { (*data).parent_window.is_null() } {
+                xrd_windows.push((wid, xrd_window));
+            }
+        }
+        drop(window_state);
+        debug!("Focus mode showing {active:#010x}");
+        let previous = focus_mode.hero.take();
+        let mut hidden = std::mem::take(&mut focus_mode.hidden);
+        let config = self.config.focus_mode.clone();
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            let mut hero = None;
+            for (wid, xrd_window) in xrd_windows {
+                // Back to its place, hidden below with the others
+                if let Some((previous, transform, scale)) = previous {
+                    if previous == wid {
+                        xrd_window.set_transformation(&mut graphene::Matrix::from_float(transform));
+                        xrd_window.set_scale(scale);
+                    }
+                }
+                if wid == active {
+                    if hidden.remove(&wid) {
+                        xrd_window.show();
+                    }
+                    let mut transform = graphene::Matrix::new_identity();
+                    xrd_window.is_transformation(&mut transform);
+                    hero = Some((wid, transform.to_float(), xrd_window.scale()));
+                    xrd_window.set_transformation(&mut placement::theater_transform(config.depth));
+                    xrd_window.set_scale(placement::theater_scale(
+                        xrd_window.initial_width_meters(),
+                        config.width,
+                    ));
+                } else if xrd_window.is_visible() {
+                    xrd_window.hide();
+                    hidden.insert(wid);
+                }
+            }
+            (hero, hidden)
+        })
+        .await;
+        match result {
+            Ok((hero, hidden)) => {
+                focus_mode.hero = hero;
+                focus_mode.hidden = hidden;
+            }
+            Err(e) => error!("Failed to update focus mode: {e}"),
+        }
+    }
+
+    /// Move all windows back to their reset transformation, where they were first placed. They
+    /// glide there, see `start_glide`.
+    async fn reset_layout(&self) {
+        let mut xrd_windows = Vec::new();
+        let window_state = self.window_state.read().await;
+        for (&wid, w) in &window_state.windows {
+            xrd_windows.push((wid, w.read().await.xrd_window.lock().await.clone()));
+        }
+        drop(window_state);
+        let glide = self.glides();
+        let result = utils::invoke(&glib::MainContext::default(), move || {
+            let mut transforms = Vec::new();
+            for (wid, xrd_window) in xrd_windows {
+                let mut transform = graphene::Matrix::new_identity();
+                xrd_window.reset_transformation(&mut transform);
+                if glide {
+                    let mut from = graphene::Matrix::new_identity();
+                    xrd_window.is_transformation(&mut from);
+                    transforms.push((wid, xrd_window, from.to_float(), transform.to_float()));
+                } else {
+                    xrd_window.set_transformation(&mut transform);
+                }
+            }
+            transforms
+        })
+        .await;
+        match result {
+            Ok(transforms) => {
+                for (wid, xrd_window, from, to) in transforms {
+                    self.start_glide(wid, xrd_window, from, to);
+                }
+            }
+            Err(e) => error!("Failed to reset the layout: {e}"),
+        }
+    }
 
-        let damage = self.x11.generate_id()?;
-        let x11_clone = self.x11.clone();
-        {
-            let mut window_state = self.window_state.write().await;
-            let win_attrs = block_in_place(move || {
-                x11_clone
-                    .damage_create(damage, wid, x11rb::protocol::damage::ReportLevel::NON_EMPTY)?
-                    .check()?;
-                Result::Ok(x11_clone.get_window_attributes(wid)?.reply()?)
-            })?;
+    /// The mirrored window for `id`, which is either a mirrored window or its client window.
+    async fn toplevel(&self, id: u32) -> u32 {
+        self.window_state
+            .read()
+            .await
+            .client_window_to_window
+            .get(&id)
+            .copied()
+            .unwrap_or(id)
+    }
 
-            // If we receive map -> unmap -> map event of the same window in quick
-            // succession, the unmap event could be processed before the first map event (the
-            // second map would never be processed before unmap OTOH). In that case that unmap
-            // event will fail to remove any window and we have duplicated window. so:
-            //
-            // if we are unmapped currently, we give up adding this window. this is to prevent an
-            // unmapped window from staying visible.
-            //
-            // if we are mapped, then we either didn't receive a unmap event, which is fine.
-            // Otherwise, if we are the first map event, the window we are going to add here will
-            // be replaced later by the second map event; if we are the second map event, we will
-            // replace the existing window.
-            if win_attrs.map_state != xproto::MapState::VIEWABLE {
-                debug!("Window {wid:#010x} not viewable, giving up");
-                return Ok(());
+    /// Run `f` on the VR window of `wid`, on the glib main context which owns it.
+    async fn with_xrd_window(&self, wid: u32, f: impl FnOnce(&xrd::Window) + Send + 'static) {
+        let xrd_window = match self.window_state.read().await.windows.get(&wid) {
+            Some(w) => w.read().await.xrd_window.lock().await.clone(),
+            None => {
+                warn!("{wid:#010x} is not mirrored");
+                return;
             }
+        };
+        let result = utils::invoke(&glib::MainContext::default(), move || f(&xrd_window)).await;
+        if let Err(e) = result {
+            error!("Failed to update VR window of {wid:#010x}: {e}");
+        }
+    }
 
-            let xrd_window = Mutex::new(xrd_window);
-            let window = Window {
-                id: wid,
-                gl: self.gl.clone(),
-                damage,
-                x11: self.x11.clone(),
-                xrd: self.xrd_client.clone(),
-                textures: None,
-                xrd_window,
-                client_wid,
-                drop_bomb: DropBomb::new("Window dropped unsafely"),
-            };
-            let parent_wid = window_state.client_window_to_window.insert(client_wid, wid);
-            if let Some(parent_wid) = parent_wid {
-                if parent_wid != wid {
-                    error!(
-                        "Replaced parent of {client_wid:#010x}: was {parent_wid:#010x}, now to {wid:#010x}"
-                    );
-                    debug_assert!(false);
+    /// Tell D-Bus clients that a window started or stopped being mirrored.
+    async fn windows_changed(&self) {
+        let result = async {
+            let ctxt = zbus::SignalContext::new(&self.dbus, dbus::OBJECT_PATH)?;
+            dbus::Control::windows_changed(&ctxt).await
+        }
+        .await;
+        if let Err(e) = result {
+            warn!("Failed to emit WindowsChanged: {e}");
+        }
+        self.update_status().await;
+    }
+
+    /// Show what we are mirroring in `systemctl status`.
+    async fn update_status(&self) {
+        let notifier = match &self.notifier {
+            Some(notifier) => notifier,
+            None => return,
+        };
+        if self.desktop_mirror.load(Ordering::Acquire) {
+            notifier.status("Mirroring the desktop");
+        } else {
+            let count = self.window_state.read().await.windows.len();
+            notifier.status(&format!("Mirroring {count} windows"));
+        }
+    }
+
+    /// Ask the window `id` (a mirrored window or its client window) to close, the same way the
+    /// close button of the window manager does. The window is removed from VR when picom tells
+    /// us it is unmapped. Windows that don't support `WM_DELETE_WINDOW` can only be killed, losing
+    /// what wasn't saved, so that takes a second request for the same window within
+    /// `KILL_CONFIRM_TIMEOUT`.
+    async fn close_window(&self, id: u32) -> Result<()> {
+        let client_wid = match self.window_state.read().await.windows.get(&id) {
+            Some(w) => w.read().await.client_wid,
+            None => id,
+        };
+        block_in_place(|| {
+            if self.supports_protocol(client_wid, self.atoms.WM_DELETE_WINDOW)? {
+                debug!("sending WM_DELETE_WINDOW to {client_wid:#010x}");
+                let event = xproto::ClientMessageEvent::new(
+                    32,
+                    client_wid,
+                    self.atoms.WM_PROTOCOLS,
+                    [self.atoms.WM_DELETE_WINDOW, x11rb::CURRENT_TIME, 0, 0, 0],
+                );
+                self.x11
+                    .send_event(false, client_wid, xproto::EventMask::NO_EVENT, event)?
+                    .check()?;
+            } else {
+                let now = std::time::Instant::now();
+                let mut kill_requested = self.kill_requested.lock().unwrap();
+                match kill_requested.take() {
+                    Some((wid, since))
+                        if wid == client_wid
+                            && now.duration_since(since) < KILL_CONFIRM_TIMEOUT =>
+                    {
+                        info!("Killing {client_wid:#010x}");
+                        self.x11.kill_client(client_wid)?.check()?;
+                    }
+                    _ => {
+                        info!(
+                            "{client_wid:#010x} doesn't support WM_DELETE_WINDOW, close it again \
+                             within {KILL_CONFIRM_TIMEOUT:?} to kill it"
+                        );
+                        *kill_requested = Some((client_wid, now));
+                    }
                 }
             }
-            debug!("inserting {}", wid);
-            let mut entry = window_state.windows.entry(wid);
-            let window = match entry {
-                Entry::Vacant(entry) => entry.insert(RwLock::new(window)),
-                Entry::Occupied(ref mut entry) => {
-                    let old = entry.insert(RwLock::new(window));
-                    // window_state is exclusively locked at this point, using the sync version
-                    // so it couldn't be cancelled.
-                    unsafe { block_in_place(|| old.into_inner().drop_sync().unwrap()) };
-                    error!("Replaced old window entry for {wid:#010x}");
-                    debug_assert!(false);
-                    entry.get_mut()
-                }
-            };
-            let mut window = window.write().await;
-            self.render_win(&mut window).await?;
+            Result::Ok(())
+        })
+    }
+
+    /// A mirrored window, or its client window, got a new parent. Either way the window we are
+    /// mirroring no longer has the content it used to, so we stop mirroring it. If the window
+    /// becomes a top level again, picom will tell us it is mapped.
+    async fn handle_reparent(self: &Arc<Self>, event: &xproto::ReparentNotifyEvent) {
+        let window_state = self.window_state.read().await;
+        let wid = if window_state.windows.contains_key(&event.window) {
+            // Top level windows are children of the root, being reparented to the root again
+            // doesn't change anything.
+            Some(event.window).filter(|_| !self.is_root(event.parent))
+        } else {
+            window_state
+                .client_window_to_window
+                .get(&event.window)
+                .copied()
+        };
+        drop(window_state);
+        if let Some(wid) = wid {
+            debug!(
+                "{:#010x} reparented to {:#010x}, unmapping {wid:#010x}",
+                event.window, event.parent
+            );
+            self.unmap_win(wid).await;
         }
-        info!("Added new window {:#010x}", wid);
-        //remove ourself from pending_windows
-        Ok(())
     }
 
+    #[tracing::instrument(skip_all, fields(wid = %format_args!("{:#010x}", wid)))]
     async fn map_win(&self, wid: u32) -> Result<()> {
         let result = self.map_win_impl(wid).await;
         self.pending_windows.lock().await.remove(&wid);
         result
     }
 
+    /// Mirror the windows that are already there. picom and X are asked about all of them at
+    /// once, then their VR windows and textures are created `SETUP_CONCURRENCY` at a time, so the
+    /// GPU doesn't allocate everything at once. Windows take layout slots in the order the source
+    /// lists them, however long each one takes.
+    #[tracing::instrument(skip_all, fields(windows))]
     async fn setup_initial_windows(self: &Arc<Self>) -> Result<()> {
-        let picom_service = format!("com.github.chjj.compton.{}", self.display);
-        let proxy: zbus::Proxy<'_> = zbus::ProxyBuilder::new_bare(&self.dbus)
-            .destination(picom_service)?
-            .interface("what.ever")?
-            .path(format!("{}/{}", PICOM_OBJECT_PATH, "windows"))?
-            .build()
-            .await?;
+        let start = std::time::Instant::now();
+        let wids = self.source.initial_windows().await?;
+        tracing::Span::current().record("windows", wids.len());
+        let futs: futures::stream::FuturesOrdered<_> = wids
+            .into_iter()
+            .map(|wid| {
+                let self_clone = self.clone();
+                tokio::spawn(async move {
+                    self_clone.prepare_map(wid).await.unwrap_or_else(|e| {
+                        info!("Failed to map window {}, {}", wid, e);
+                        None
+                    })
+                })
+            })
+            .collect();
+        let mappings: Vec<_> = futs.try_collect().await?;
+        let queried = start.elapsed();
 
-        let windows = zbus::xml::Node::from_reader(proxy.introspect().await?.as_bytes())?;
-        let futs: futures::stream::FuturesUnordered<_> = windows
-            .nodes()
+        let permits = Arc::new(tokio::sync::Semaphore::new(SETUP_CONCURRENCY));
+        let mut next_slot = self.window_state.read().await.windows.len();
+        let futs: futures::stream::FuturesUnordered<_> = mappings
             .into_iter()
-            .map(|w| {
+            .flatten()
+            .map(|mapping| {
+                let slot = matches!(mapping.decision, Decision::Mirror(_)).then(|| {
+                    next_slot += 1;
+                    next_slot - 1
+                });
                 let self_clone = self.clone();
-                let wid = w.name().unwrap().to_owned();
+                let permits = permits.clone();
                 tokio::spawn(async move {
-                    if let Ok(wid) = parse_int::parse(&wid) {
-                        if let Err(e) = self_clone.map_win(wid).await {
-                            info!("Failed to map window {}, {}", wid, e);
-                        }
-                    } else {
-                        error!("Invalid window id from picom: {}", wid);
+                    // Never closed
+                    let _permit = permits.acquire().await.unwrap();
+                    let wid = mapping.wid;
+                    if let Err(e) = self_clone.add_window(mapping, slot).await {
+                        info!("Failed to map window {}, {}", wid, e);
                     }
                 })
             })
             .collect();
         let () = futs.try_collect().await?;
+        info!(
+            "Set up initial windows in {:?}, {queried:?} of that querying them",
+            start.elapsed()
+        );
         Ok(())
     }
 }
 
-const PICOM_OBJECT_PATH: &str = "/com/github/chjj/compton";
+// Add a button in VR that closes the window last clicked, as xrdesktop windows don't have close
+// buttons of their own.
+/// Types of windows that are mirrored. Others, like docks and the desktop background, stay on the
+/// desktop.
+const MIRRORED_TYPES: &[&str] = &["normal", "menu", "popup_menu", "dropdown_menu", "utility"];
+
+/// A window about to be mirrored, see `App::prepare_map`
+#[derive(Debug)]
+struct Mapping {
+    wid: u32,
+    /// X screen it is on
+    screen: usize,
+    info: source::WindowInfo,
+    decision: Decision,
+}
+
+/// Whether a top level window is mirrored, see `decide`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    /// Mirrored, this is where it is on the desktop
+    Mirror(placement::Geometry),
+    Unmapped,
+    /// Belongs to the VR runtime or to us, mirroring it would show VR in VR
+    VrWindow,
+    /// Not one of `MIRRORED_TYPES`
+    Type,
+    /// Matched by `[ignore]`
+    Ignored,
+    /// Asks to be left out of taskbars or pagers, like docks and desktop widgets often do
+    SkipTaskbar,
+    /// Mapped, but minimized. It is mirrored once it is restored.
+    Minimized,
+    /// Entirely outside of the screen
+    Offscreen,
+}
+
+impl std::fmt::Display for Decision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Mirror(_) => "mirrored",
+            Self::Unmapped => "not mapped",
+            Self::VrWindow => "belongs to VR",
+            Self::Type => "type isn't mirrored",
+            Self::Ignored => "matched by [ignore]",
+            Self::SkipTaskbar => "skips the taskbar",
+            Self::Minimized => "minimized",
+            Self::Offscreen => "outside of the screen",
+        })
+    }
+}
+
+/// Decide whether `wid` is mirrored, from what the window source tells about it and its state in
+/// X. Shared by `App::map_win_impl` and `--dry-run`, windows ignored through D-Bus are left to
+/// the caller.
+fn decide(
+    x11: &RustConnection,
+    atoms: &AtomCollection,
+    config: &config::Config,
+    wid: u32,
+    info: &source::WindowInfo,
+    root_geometry: &placement::Geometry,
+    vr_pids: &HashSet<u32>,
+) -> Result<Decision> {
+    if !info.mapped {
+        return Ok(Decision::Unmapped);
+    }
+    if is_vr_window(x11, atoms, config, info, vr_pids)? {
+        return Ok(Decision::VrWindow);
+    }
+    if !MIRRORED_TYPES.contains(&info.ty.as_str()) {
+        return Ok(Decision::Type);
+    }
+    if config
+        .ignore
+        .matches(&info.name, &info.class_instance, &info.class_general)
+    {
+        return Ok(Decision::Ignored);
+    }
+    if skips_taskbar(x11, atoms, config, info)? {
+        return Ok(Decision::SkipTaskbar);
+    }
+    if is_minimized(x11, atoms, info.client_win)? {
+        return Ok(Decision::Minimized);
+    }
+    let geometry: placement::Geometry = (&x11.get_geometry(wid)?.reply()?).into();
+    if placement::is_offscreen(&geometry, root_geometry) {
+        return Ok(Decision::Offscreen);
+    }
+    Ok(Decision::Mirror(geometry))
+}
+
+/// Whether the window manager says `client_wid` is minimized, through either WM_STATE or
+/// _NET_WM_STATE.
+fn is_minimized(x11: &RustConnection, atoms: &AtomCollection, client_wid: u32) -> Result<bool> {
+    let wm_state = x11
+        .get_property(false, client_wid, atoms.WM_STATE, atoms.WM_STATE, 0, 1)?
+        .reply()?;
+    let net_wm_state = x11
+        .get_property(
+            false,
+            client_wid,
+            atoms._NET_WM_STATE,
+            xproto::AtomEnum::ATOM,
+            0,
+            u32::MAX,
+        )?
+        .reply()?;
+    let iconic = wm_state.value32().and_then(|mut s| s.next()) == Some(ICONIC_STATE);
+    let hidden = net_wm_state
+        .value32()
+        .map_or(false, |mut s| s.any(|a| a == atoms._NET_WM_STATE_HIDDEN));
+    Ok(iconic || hidden)
+}
+
+/// Whether the window of `info` is one of the VR runtime's or ours, by its class or by the process
+/// it says it belongs to (`_NET_WM_PID`), see `vr_windows`.
+fn is_vr_window(
+    x11: &RustConnection,
+    atoms: &AtomCollection,
+    config: &config::Config,
+    info: &source::WindowInfo,
+    vr_pids: &HashSet<u32>,
+) -> Result<bool> {
+    if vr_windows::is_vr_class(
+        &info.class_instance,
+        &info.class_general,
+        &config.ignore.vr_classes,
+    ) {
+        return Ok(true);
+    }
+    let pid = x11
+        .get_property(
+            false,
+            info.client_win,
+            atoms._NET_WM_PID,
+            xproto::AtomEnum::CARDINAL,
+            0,
+            1,
+        )?
+        .reply()?
+        .value32()
+        .and_then(|mut p| p.next());
+    Ok(pid.map_or(false, |pid| vr_pids.contains(&pid)))
+}
+
+/// Whether the window manager says the window of `info` is to be left out of taskbars or pagers,
+/// unless `[ignore] include-classes` has it mirrored anyway.
+fn skips_taskbar(
+    x11: &RustConnection,
+    atoms: &AtomCollection,
+    config: &config::Config,
+    info: &source::WindowInfo,
+) -> Result<bool> {
+    if config
+        .ignore
+        .includes(&info.class_instance, &info.class_general)
+    {
+        return Ok(false);
+    }
+    let net_wm_state = x11
+        .get_property(
+            false,
+            info.client_win,
+            atoms._NET_WM_STATE,
+            xproto::AtomEnum::ATOM,
+            0,
+            u32::MAX,
+        )?
+        .reply()?;
+    Ok(net_wm_state.value32().map_or(false, |mut s| {
+        s.any(|a| a == atoms._NET_WM_STATE_SKIP_TASKBAR || a == atoms._NET_WM_STATE_SKIP_PAGER)
+    }))
+}
+
+/// `$DISPLAY`, usable in D-Bus names
+fn display_id() -> String {
+    std::env::var("DISPLAY")
+        .unwrap()
+        .replace(':', "_")
+        .replace('.', "_")
+}
+
+/// `display_id` of `screen` of our display, as picom running there names its bus name
+fn screen_display_id(screen: usize) -> String {
+    picom::screen_display(&std::env::var("DISPLAY").unwrap(), screen)
+        .replace(':', "_")
+        .replace('.', "_")
+}
+
+/// Add `mask` to the events we select on `window`. Selecting replaces what we selected before,
+/// so that is read back and kept. Other clients have their own selection, it isn't affected.
+fn select_input(
+    x11: &RustConnection,
+    window: xproto::Window,
+    mask: xproto::EventMask,
+) -> Result<()> {
+    let selected = x11.get_window_attributes(window)?.reply()?.your_event_mask;
+    let events =
+        xproto::ChangeWindowAttributesAux::new().event_mask(u32::from(selected) | u32::from(mask));
+    x11.change_window_attributes(window, &events)?.check()?;
+    Ok(())
+}
+
+/// Grab `hotkey` on `root`, whatever the state of Caps Lock and Num Lock. Returns the grabbed
+/// keycode, or None if the key is taken or not on the keyboard. Then nothing is grabbed, so the
+/// key keeps working for whoever else wants it.
+fn grab_hotkey(
+    x11: &RustConnection,
+    root: xproto::Window,
+    hotkey: keymap::Hotkey,
+) -> Result<Option<xproto::Keycode>> {
+    let setup = x11.setup();
+    let mapping = synth::keyboard_mapping(x11)?;
+    let keymap = keymap::Keymap::new(
+        setup.min_keycode,
+        mapping.keysyms_per_keycode,
+        &mapping.keysyms,
+        &HashSet::new(),
+    );
+    let key = match keymap.get(hotkey.keysym) {
+        Some(key) => key,
+        None => {
+            warn!("Hotkey {hotkey:?} is not on the keyboard layout");
+            return Ok(None);
+        }
+    };
+    let mut modifiers = hotkey.modifiers;
+    if key.modifiers.shift {
+        modifiers |= u16::from(xproto::ModMask::SHIFT);
+    }
+    let caps_lock = u16::from(xproto::ModMask::LOCK);
+    let num_lock = u16::from(xproto::ModMask::M2);
+    let result = [0, caps_lock, num_lock, caps_lock | num_lock]
+        .into_iter()
+        .try_for_each(|locks| {
+            x11.grab_key(
+                false,
+                root,
+                modifiers | locks,
+                key.keycode,
+                xproto::GrabMode::ASYNC,
+                xproto::GrabMode::ASYNC,
+            )?
+            .check()?;
+            Result::Ok(())
+        });
+    if let Err(e) = result {
+        warn!("Failed to grab hotkey {hotkey:?}: {e}");
+        x11.ungrab_key(key.keycode, root, xproto::ModMask::ANY)?
+            .check()?;
+        return Ok(None);
+    }
+    Ok(Some(key.keycode))
+}
+
+/// Pass input events of `client` on to `input_tx`, quit requests to `exit_tx`, and notify
+/// `devices_changed` when devices are activated or deactivated. Clicks vibrate the controller
+/// through `haptics`. Presses of `toggle_button` are passed on wherever the controller points.
+/// Has to be called on the thread owning the glib main context, which emits the signals.
+fn connect_client_signals(
+    client: &xrd::Client,
+    input_tx: tokio::sync::mpsc::Sender<InputEvent>,
+    exit_tx: tokio::sync::mpsc::Sender<gxr::sys::GxrQuitEvent>,
+    devices_changed: Arc<tokio::sync::Notify>,
+    haptics: Option<Arc<haptics::Haptics>>,
+    toggle_button: Option<input::ControllerButton>,
+) {
+    // The client only passes on clicks on a window, and there are none left to point at while
+    // mirroring is toggled off. Its input synth clicks wherever the controller points.
+    if let (Some(toggle_button), Some(input_synth)) = (toggle_button, client.input_synth()) {
+        let tx = input_tx.clone();
+        connect_synth_click_event(&input_synth, move |event| {
+            if input::ControllerButton::from_xrd(event.button) == Some(toggle_button) {
+                let _ = tx.blocking_send(InputEvent::ToggleButton {
+                    pressed: event.state != 0,
+                });
+            }
+        });
+    }
+    let tx = input_tx.clone();
+    client.connect_move_cursor_event(move |_, event| {
+        if event.ignore != 0 {
+            return;
+        }
+        let window: xrd::Window = unsafe { glib::translate::from_glib_none(event.window) };
+        let point: graphene::Point = unsafe { glib::translate::from_glib_none(event.position) };
+        // If the queue is full, we drop the event
+        let _: std::result::Result<_, _> = tx.try_send(InputEvent::Move {
+            wid: native::wid(&window),
+            x: point.x(),
+            y: point.y(),
+        });
+    });
+    // if send() errors, that means run() has returned. so ignore those errors
+    let tx = input_tx.clone();
+    client.connect_click_event(move |_, event| {
+        let window: xrd::Window = unsafe { glib::translate::from_glib_none(event.window) };
+        let point: graphene::Point = unsafe { glib::translate::from_glib_none(event.position) };
+        let wid = native::wid(&window);
+        // xrdesktop already turns the analog scroll axis into discrete scroll "clicks",
+        // feed them into the scroll accumulator so inversion and threshold apply.
+        let scroll = match event.button {
+            xrd::sys::SCROLL_UP => Some((0.0, -1.0)),
+            xrd::sys::SCROLL_DOWN => Some((0.0, 1.0)),
+            xrd::sys::SCROLL_LEFT => Some((-1.0, 0.0)),
+            xrd::sys::SCROLL_RIGHT => Some((1.0, 0.0)),
+            _ => None,
+        };
+        if let Some((dx, dy)) = scroll {
+            if event.state != 0 {
+                let _ = tx.blocking_send(InputEvent::Scroll { wid, dx, dy });
+            }
+            return;
+        }
+        if let Some(haptics) = haptics.as_ref().filter(|_| event.state != 0) {
+            unsafe { haptics.pulse(event.controller, haptics::Pulse::Click) };
+        }
+        // We don't want to lose click events
+        let _ = tx.blocking_send(InputEvent::Click {
+            wid,
+            x: point.x(),
+            y: point.y(),
+            button: event.button,
+            pressed: event.state != 0,
+            controller: event.controller as usize,
+        });
+    });
+    let tx = input_tx;
+    client.connect_keyboard_press_event(move |_, event| {
+        let event: &gdk::EventKey = event.downcast_ref().unwrap();
+        let string = unsafe {
+            std::slice::from_raw_parts(event.as_ref().string as *const u8, event.length() as _)
+        };
+        let string = String::from_utf8_lossy(string).into_owned();
+        let keyval = event.as_ref().keyval;
+        let pressed = event.event_type() != gdk::EventType::KeyRelease;
+        let _ = tx.blocking_send(InputEvent::KeyPresses {
+            string,
+            keyval,
+            pressed,
+        });
+    });
+
+    client.connect_request_quit_event(move |_, reason| {
+        if reason.reason == gxr::sys::GXR_QUIT_SHUTDOWN {
+            let _ = exit_tx.blocking_send(reason.clone());
+        }
+    });
+
+    // gxr has no signal for the headset being put on or taken off, but one for it being
+    // (de)activated, e.g. when it is unplugged or goes to standby
+    if let Some(gxr) = client.gxr_context() {
+        let device_manager = unsafe { gxr::sys::gxr_context_get_device_manager(gxr.as_ptr()) };
+        let device_manager: glib::Object =
+            unsafe { glib::translate::from_glib_none(device_manager as *mut gobject_sys::GObject) };
+        for signal in ["device-activate-event", "device-deactivate-event"] {
+            let devices_changed = devices_changed.clone();
+            device_manager.connect_local(signal, false, move |_| {
+                devices_changed.notify_one();
+                None
+            });
+        }
+    }
+}
+
+/// The generated `InputSynth::connect_click_event` passes the event on as a GdkEvent, so connect
+/// by hand
+fn connect_synth_click_event<F: Fn(&xrd::sys::XrdClickEvent) + Send + 'static>(
+    input_synth: &xrd::InputSynth,
+    f: F,
+) {
+    unsafe extern "C" fn trampoline<F: Fn(&xrd::sys::XrdClickEvent) + Send + 'static>(
+        _input_synth: *mut xrd::sys::XrdInputSynth,
+        event: *mut xrd::sys::XrdClickEvent,
+        f: glib::ffi::gpointer,
+    ) {
+        let f = &*(f as *const F);
+        f(&*event)
+    }
+    unsafe {
+        glib::signal::connect_raw(
+            input_synth.as_ptr() as *mut _,
+            b"click-event\0".as_ptr() as *const _,
+            Some(std::mem::transmute::<_, unsafe extern "C" fn()>(
+                trampoline::<F> as *const (),
+            )),
+            Box::into_raw(Box::new(f)),
+        );
+    }
+}
+
+fn add_close_button(xrd_client: &xrd::Client, tx: tokio::sync::mpsc::Sender<dbus::Request>) {
+    use glib::translate::ToGlibPtrMut;
+    // Connected to the button's grab-start-event
+    unsafe extern "C" fn pressed(
+        _button: *mut xrd::sys::XrdWindow,
+        _event: glib::ffi::gpointer,
+        data: glib::ffi::gpointer,
+    ) {
+        let tx = &*(data as *const tokio::sync::mpsc::Sender<dbus::Request>);
+        let _ = tx.blocking_send(dbus::Request::CloseWindow(None));
+    }
+    // xrd_client_add_button can't free its callback data, so it gets a callback without any,
+    // and the one sending requests is connected with a destroy notify
+    unsafe extern "C" fn ignore(
+        _button: *mut xrd::sys::XrdWindow,
+        _event: glib::ffi::gpointer,
+        _data: glib::ffi::gpointer,
+    ) {
+    }
+    unsafe extern "C" fn free(data: glib::ffi::gpointer, _closure: *mut gobject_sys::GClosure) {
+        drop(Box::from_raw(
+            data as *mut tokio::sync::mpsc::Sender<dbus::Request>,
+        ));
+    }
+    type Callback =
+        unsafe extern "C" fn(*mut xrd::sys::XrdWindow, glib::ffi::gpointer, glib::ffi::gpointer);
+    let label = std::ffi::CString::new("Close window").unwrap();
+    let mut labels = [label.as_ptr() as *mut _];
+    let mut position = graphene::Point3D::new(0.0, -1.0, -3.0);
+    unsafe {
+        let button = xrd::sys::xrd_client_button_new_from_text(
+            xrd_client.as_ptr(),
+            0.5,
+            0.25,
+            450.0,
+            labels.len() as _,
+            labels.as_mut_ptr(),
+        );
+        gobject_sys::g_signal_connect_data(
+            button as *mut _,
+            b"grab-start-event\0".as_ptr() as *const _,
+            Some(std::mem::transmute(pressed as Callback)),
+            Box::into_raw(Box::new(tx)) as _,
+            Some(free),
+            0,
+        );
+        xrd::sys::xrd_client_add_button(
+            xrd_client.as_ptr(),
+            button,
+            position.to_glib_none_mut().0,
+            Some(std::mem::transmute(ignore as Callback)),
+            std::ptr::null_mut(),
+        );
+    }
+}
+
+/// Create the xrdesktop client, waiting for the VR runtime to start if it isn't up yet, for at
+/// most `timeout` seconds, or forever if it is 0. A client is only returned once it has a Vulkan
+/// context, which it needs to create textures.
+async fn wait_for_vr_runtime(mode: xrd::ClientMode, timeout: u64) -> Result<xrd::Client> {
+    let start = std::time::Instant::now();
+    let mut delay = VR_RUNTIME_RETRY_DELAY;
+    loop {
+        if let Some(client) = new_client(mode) {
+            return Ok(client);
+        }
+        if timeout > 0 && start.elapsed() >= std::time::Duration::from_secs(timeout) {
+            return Err(anyhow!(
+                "Failed to start xrdesktop, make sure SteamVR (or another VR runtime) is running"
+            ));
+        }
+        info!("Waiting for the VR runtime (SteamVR, Monado...) to start, retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(VR_RUNTIME_MAX_RETRY_DELAY);
+    }
+}
+
+/// Dim `xrd_window` to `brightness`, or undo that with None.
+fn set_dimmed(xrd_window: &xrd::Window, brightness: Option<f32>) {
+    let brightness = brightness.unwrap_or(1.0);
+    let color = graphene::Vec3::new(brightness, brightness, brightness);
+    unsafe { xrd::sys::xrd_window_set_color(xrd_window.as_ptr(), color.to_glib_none().0) };
+}
+
+/// Create an xrdesktop client, if the VR runtime is up and xrdesktop got a Vulkan context.
+fn new_client(mode: xrd::ClientMode) -> Option<xrd::Client> {
+    // xrd_client_new_with_mode returns NULL if the VR runtime can't be initialized
+    let client =
+        unsafe { xrd::sys::xrd_client_new_with_mode(glib::translate::IntoGlib::into_glib(mode)) };
+    if client.is_null() {
+        return None;
+    }
+    let client: xrd::Client = unsafe { glib::translate::from_glib_full(client) };
+    if client.gulkan().is_none() {
+        debug!("xrdesktop started without a Vulkan context");
+        return None;
+    }
+    Some(client)
+}
+
+/// Creates the client for `App::runtime_task` on the thread owning the glib main context, like
+/// the first one.
+struct Reconnector {
+    mode: xrd::ClientMode,
+    client: Option<xrd::Client>,
+}
+
+impl runtime::Client for Reconnector {
+    fn reconnect(&mut self) -> bool {
+        let mode = self.mode;
+        let context = glib::MainContext::default();
+        let client = futures::executor::block_on(utils::invoke(&context, move || new_client(mode)));
+        self.client = client.ok().flatten();
+        self.client.is_some()
+    }
+}
+
+/// Whether the VR runtime of `client` sees a headset.
+fn hmd_connected(client: &xrd::Client) -> bool {
+    let gxr = match client.gxr_context() {
+        Some(gxr) => gxr,
+        None => return false,
+    };
+    let hmd = gxr::sys::GXR_DEVICE_INDEX_HMD as u32;
+    unsafe { gxr::sys::gxr_context_is_tracked_device_connected(gxr.as_ptr(), hmd) != 0 }
+}
+
+/// Explain where we looked for the xrdesktop GSettings schema, and what the user can do about it
+fn schema_not_installed_error() -> anyhow::Error {
+    const SCHEMA_FILE: &str = "org.xrdesktop.gschema.xml";
+    let mut dirs: Vec<std::path::PathBuf> = std::env::var_os("GSETTINGS_SCHEMA_DIR")
+        .map(|dirs| std::env::split_paths(&dirs).collect())
+        .unwrap_or_default();
+    dirs.push(glib::user_data_dir().join("glib-2.0").join("schemas"));
+    dirs.extend(
+        glib::system_data_dirs()
+            .into_iter()
+            .map(|d| d.join("glib-2.0").join("schemas")),
+    );
+    let searched = dirs
+        .iter()
+        .map(|d| format!("  {}", d.display()))
+        .collect::<Vec<_>>()
+        .join("\n");
+    if let Some(dir) = dirs.iter().find(|d| d.join(SCHEMA_FILE).exists()) {
+        // The schema is there, but hasn't been compiled
+        anyhow!(
+            "xrdesktop GSettings schema found in {}, but it is not compiled. Try running \
+             `glib-compile-schemas {}`. Searched directories:\n{}",
+            dir.display(),
+            dir.display(),
+            searched
+        )
+    } else {
+        anyhow!(
+            "xrdesktop GSettings schema not installed. Please install xrdesktop (e.g. the \
+             `xrdesktop` package, or `xrdesktop` from AUR on Arch Linux), or point \
+             GSETTINGS_SCHEMA_DIR to the directory containing {}. Searched directories:\n{}",
+            SCHEMA_FILE,
+            searched
+        )
+    }
+}
+
 type RenderDoc = renderdoc::RenderDoc<renderdoc::V141>;
 fn maybe_load_renderdoc() -> Option<RenderDoc> {
     use libloading::os::unix::{Library, RTLD_NOW};
@@ -1030,6 +6151,45 @@ fn maybe_load_renderdoc() -> Option<RenderDoc> {
 lazy_static::lazy_static! {
     pub static ref RENDERDOC: std::sync::Mutex<Option<RenderDoc>> =
         std::sync::Mutex::new(maybe_load_renderdoc());
+    /// Notified by the panic hook, to make App::run return
+    static ref PANICKED: tokio::sync::Notify = tokio::sync::Notify::new();
+    /// Torn down by the panic hook, if shutting down cleanly takes too long
+    static ref APP: std::sync::Mutex<Option<Weak<App>>> = std::sync::Mutex::new(None);
+}
+
+/// Exit code after losing the X connection, so a service manager can tell it apart from failures
+const X_CONNECTION_LOST_EXIT_CODE: i32 = 3;
+/// How long a clean shutdown after a panic may take, before we abort
+const PANIC_SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// How long the teardown before aborting may take, it can be stuck behind a lock
+const PANIC_TEARDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+// A panic in a spawned task only kills that task, leaving the rest running with whatever it
+// had allocated, e.g. pixmaps and GL textures of a window half set up. Instead, log it and shut
+// down: App::run returns, and dropping App frees the windows. If that doesn't finish in time,
+// e.g. because the panic left something it needs stuck, abort. The X server frees our
+// resources once the connection is gone.
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        error!("{info}\n{backtrace}");
+        PANICKED.notify_one();
+        std::thread::spawn(|| {
+            std::thread::sleep(PANIC_SHUTDOWN_TIMEOUT);
+            error!("Shutting down after a panic timed out, aborting");
+            // Best effort, so there are no frozen windows left in VR
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let app = APP.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                if let Some(app) = app.and_then(|app| app.upgrade()) {
+                    app.try_teardown();
+                }
+                let _ = tx.send(());
+            });
+            let _ = rx.recv_timeout(PANIC_TEARDOWN_TIMEOUT);
+            std::process::abort();
+        });
+    }));
 }
 thread_local! {
     static LOCKWHEEL: RefCell<Option<Pin<Box<dyn Generator<(), Return=(), Yield=()>>>>> = RefCell::new(None);
@@ -1057,15 +6217,9 @@ fn locker(mut ctx: Weak<App>) {
     }
 }
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(
-        if cfg!(debug_assertions) {
-            "app=debug"
-        } else {
-            "app=info "
-        },
-    ))
-    .format_timestamp_millis()
-    .init();
+    let args = cli::Args::parse(std::env::args().skip(1))?;
+    logging::init(args.trace_timing);
+    install_panic_hook();
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .worker_threads(4)
         .enable_all()
@@ -1076,70 +6230,127 @@ fn main() -> Result<()> {
         std::env::set_var("RUST_BACKTRACE", "1");
         std::env::set_var("VK_INSTANCE_LAYERS", "VK_LAYER_KHRONOS_validation");
     }
-    let ctx = Arc::new(runtime.block_on(App::new())?);
+    if args.help {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+    let mut config = config::Config::load()?;
+    if let Some(input_backend) = args.input_backend {
+        config.input_backend = input_backend;
+    }
+    if let Some(backend) = args.backend {
+        config.backend = backend;
+    }
+    if let Some(mode) = args.mode {
+        config.desktop_mirror.enabled = mode != cli::Mode::Windows;
+        config.desktop_mirror.per_monitor = mode == cli::Mode::Monitors;
+    }
+    if let Some(screen) = args.screen {
+        config.screen = Some(screen);
+    }
+    if let Some(format) = args.force_format {
+        config.force_format = Some(format);
+    }
+    if let Some(size) = args.benchmark {
+        let iterations = args.iterations.unwrap_or(1000);
+        return runtime.block_on(benchmark::run(size, iterations));
+    }
+    if args.dry_run {
+        return runtime.block_on(dry_run::run(&config));
+    }
+
+    // The main thread owns the glib main context for the whole run, and runs its mainloop.
+    // Objects that aren't thread safe, like the input synthesizer, are created here and only
+    // used through sources attached to this context, see utils::invoke. xrdesktop's signals are
+    // emitted on this thread. App::new runs on this thread too, as block_on polls its future on
+    // the calling thread.
+    let glib_context = glib::MainContext::default();
+    let _glib_context_guard = glib_context
+        .acquire()
+        .context("Failed to acquire the glib main context")?;
+    let glib_mainloop = glib::MainLoop::new(Some(&glib_context), false);
+    let ctx = Arc::new(runtime.block_on(App::new(config, args.replace, args.no_hmd))?);
     let ctx_weak = ctx.downgrade();
+    *APP.lock().unwrap() = Some(ctx.downgrade());
 
-    let glib_mainloop = glib::MainLoop::new(None, false);
-    let glib_mainloop2 = glib_mainloop.clone();
-    let glib_thread = std::thread::spawn(move || {
-        // Potential thread safety issue: the mainloop has references to xrdesktop objects, which
-        // they might use concurrently with us, without locking.
-        // An example is that it could call xrd_window_manager_poll_window_events on a window while
-        // we are freeing it.
-
-        // To prevent this, we hijack their poll() function. We unlock xrd_client and window_state
-        // before blocking on poll() to not block the progress of App::run(), and lock them before
-        // returning and giving control back to glib/xrdesktop.
-        OLD_POLL_FN.with(|opf| {
-            *opf.borrow_mut() = unsafe {
-                glib_sys::g_main_context_get_poll_func(glib_mainloop2.context().to_glib_none().0)
-            }
-        });
-        mk_gen!(let mut lock_wheel = box locker(ctx_weak););
-        // Turn it once to lock it
-        lock_wheel.as_mut().resume(());
-        LOCKWHEEL.with(|lw| *lw.borrow_mut() = Some(lock_wheel as _));
-
-        unsafe extern "C" fn glib_poll_func_trampoline(
-            fd: *mut glib_sys::GPollFD,
-            a: libc::c_uint,
-            b: libc::c_int,
-        ) -> libc::c_int {
-            LOCKWHEEL.with(|lw| {
-                // Unlock
-                lw.borrow_mut().as_mut().unwrap().as_mut().resume(());
-                let ret = OLD_POLL_FN.with(|opf| (opf.borrow().as_ref().unwrap())(fd, a, b));
-                // Lock before return
-                lw.borrow_mut().as_mut().unwrap().as_mut().resume(());
-                trace!("lock wheel turned");
-                ret
-            })
+    let app = runtime.spawn({
+        let ctx = ctx.clone();
+        let glib_context = glib_context.clone();
+        let glib_mainloop = glib_mainloop.clone();
+        async move {
+            // Catch panics of the event loop itself, so the glib mainloop is still stopped
+            let result = futures::FutureExt::catch_unwind(std::panic::AssertUnwindSafe(ctx.run()))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("The event loop panicked")));
+            info!("App exited {:?}", result);
+            // Stop glib mainloop. Quitting from within the context, so this isn't lost if the
+            // mainloop hasn't started running yet.
+            glib_context.invoke(move || glib_mainloop.quit());
+            result
         }
-        unsafe {
-            glib_sys::g_main_context_set_poll_func(
-                glib_mainloop2.context().to_glib_none().0,
-                Some(glib_poll_func_trampoline),
-            )
-        };
+    });
 
-        // Running the glib mainloop is unsafe: we have to make sure App is locked and has a strong
-        // reference before returning control to glib.
-        // (OTOH dropping App is safe on its own)
-        glib_mainloop2.run();
+    // Potential thread safety issue: the mainloop has references to xrdesktop objects, which
+    // they might use concurrently with us, without locking.
+    // An example is that it could call xrd_window_manager_poll_window_events on a window while
+    // we are freeing it.
 
-        // Drop LOCKWHEEL
-        LOCKWHEEL.with(|lw| {
-            lw.borrow_mut().take();
-        });
+    // To prevent this, we hijack their poll() function. We unlock xrd_client and window_state
+    // before blocking on poll() to not block the progress of App::run(), and lock them before
+    // returning and giving control back to glib/xrdesktop.
+    OLD_POLL_FN.with(|opf| {
+        *opf.borrow_mut() =
+            unsafe { glib_sys::g_main_context_get_poll_func(glib_context.to_glib_none().0) }
     });
-    let result = runtime.block_on(ctx.clone().run());
-    info!("App exited {:?}", result);
+    mk_gen!(let mut lock_wheel = box locker(ctx_weak););
+    // Turn it once to lock it
+    lock_wheel.as_mut().resume(());
+    LOCKWHEEL.with(|lw| *lw.borrow_mut() = Some(lock_wheel as _));
 
-    // Stop glib mainloop
-    glib_mainloop.quit();
-    glib_thread.join().unwrap();
+    unsafe extern "C" fn glib_poll_func_trampoline(
+        fd: *mut glib_sys::GPollFD,
+        a: libc::c_uint,
+        b: libc::c_int,
+    ) -> libc::c_int {
+        LOCKWHEEL.with(|lw| {
+            // Unlock
+            lw.borrow_mut().as_mut().unwrap().as_mut().resume(());
+            let ret = OLD_POLL_FN.with(|opf| (opf.borrow().as_ref().unwrap())(fd, a, b));
+            // Lock before return
+            lw.borrow_mut().as_mut().unwrap().as_mut().resume(());
+            trace!("lock wheel turned");
+            ret
+        })
+    }
+    unsafe {
+        glib_sys::g_main_context_set_poll_func(
+            glib_context.to_glib_none().0,
+            Some(glib_poll_func_trampoline),
+        )
+    };
+
+    // Running the glib mainloop is unsafe: we have to make sure App is locked and has a strong
+    // reference before returning control to glib.
+    // (OTOH dropping App is safe on its own)
+    glib_mainloop.run();
+
+    // Drop LOCKWHEEL
+    LOCKWHEEL.with(|lw| {
+        lw.borrow_mut().take();
+    });
+    let result = runtime
+        .block_on(app)
+        .unwrap_or_else(|e| Err(anyhow!("App panicked: {e}")));
 
     // Wait for all tasks to finish
     drop(runtime);
+    if let Err(e) = &result {
+        if e.downcast_ref::<XConnectionLost>().is_some() {
+            // Remove our windows from VR before exiting
+            drop(ctx);
+            error!("{e}, exiting");
+            std::process::exit(X_CONNECTION_LOST_EXIT_CODE);
+        }
+    }
     result
 }
@@ -0,0 +1,68 @@
+//! Which X window an xrdesktop window mirrors. xrdesktop keeps an opaque `native` pointer per
+//! window for its embedder, and `xrd_client_add_window` takes the same pointer as the key to look
+//! the window up by. We store the X window id itself in that pointer, not a pointer to it: ids are
+//! 32 bits, so they fit on every platform, and there is nothing to free when the window goes away.
+use glib::{ffi::gpointer, prelude::*};
+
+const NATIVE: &[u8] = b"native\0";
+
+/// The `native` pointer standing for `wid`
+pub fn to_native(wid: u32) -> gpointer {
+    wid as usize as gpointer
+}
+
+/// The X window `native` stands for, see `to_native`
+pub fn to_wid(native: gpointer) -> u32 {
+    native as usize as u32
+}
+
+/// The X window mirrored by `window`, 0 if it isn't one of ours, e.g. a button.
+pub fn wid(window: &xrd::Window) -> u32 {
+    let mut native: gpointer = std::ptr::null_mut();
+    unsafe {
+        gobject_sys::g_object_get(
+            window.as_ptr() as *mut _,
+            NATIVE.as_ptr() as *const _,
+            &mut native as *mut gpointer,
+            std::ptr::null::<std::ffi::c_char>(),
+        );
+    }
+    to_wid(native)
+}
+
+/// Tag `window` as mirroring `wid`, and add it to `client` as a draggable window.
+pub fn add_window(client: &xrd::Client, window: &xrd::Window, wid: u32) {
+    unsafe {
+        gobject_sys::g_object_set(
+            window.as_ptr() as *mut _,
+            NATIVE.as_ptr() as *const _,
+            to_native(wid),
+            std::ptr::null::<std::ffi::c_char>(),
+        );
+        xrd::sys::xrd_client_add_window(
+            client.as_ptr(),
+            window.as_ptr(),
+            true as _,
+            to_native(wid),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for wid in [0, 1, 0x0120_0003, i32::MAX as u32, u32::MAX] {
+            assert_eq!(to_wid(to_native(wid)), wid);
+        }
+    }
+
+    #[test]
+    fn not_sign_extended() {
+        // The key xrdesktop hashes, has to be the same for the same window
+        assert_eq!(to_native(u32::MAX) as usize, u32::MAX as usize);
+        assert_ne!(to_native(1), to_native(2));
+    }
+}
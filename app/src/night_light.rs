@@ -0,0 +1,77 @@
+//! Tinting windows warmer at night, like redshift or GNOME's night light do on the monitors. They
+//! change the gamma ramps of the display, which never show up in window contents, so without this
+//! the VR view stays blue in the evening.
+use zbus::dbus_proxy;
+
+/// Color temperature that leaves colors as they are, the white point of sRGB
+pub const NEUTRAL: u32 = 6500;
+
+/// GNOME's night light, which is on when `Temperature` is below `NEUTRAL`
+#[dbus_proxy(
+    interface = "org.gnome.SettingsDaemon.Color",
+    default_service = "org.gnome.SettingsDaemon.Color",
+    default_path = "/org/gnome/SettingsDaemon/Color"
+)]
+trait GnomeColor {
+    /// Temperature property, in Kelvin
+    #[dbus_proxy(property)]
+    fn temperature(&self) -> zbus::Result<u32>;
+}
+
+/// Color of a black body at `kelvin`, from Tanner Helland's fit of the CIE color matching
+/// functions. Not normalized, `NEUTRAL` is slightly off white.
+fn black_body(kelvin: u32) -> [f32; 3] {
+    let t = kelvin.clamp(1000, 40000) as f32 / 100.0;
+    let r = if t <= 66.0 {
+        255.0
+    } else {
+        329.698_73 * (t - 60.0).powf(-0.133_204_76)
+    };
+    let g = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_17 * (t - 60.0).powf(-0.075_514_85)
+    };
+    let b = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    [r, g, b].map(|c| c.clamp(0.0, 255.0) / 255.0)
+}
+
+/// How much of each of red, green and blue is left at `kelvin`, 1.0 for all of them at `NEUTRAL`.
+/// Only warms, higher temperatures are taken as `NEUTRAL`.
+pub fn white_point(kelvin: u32) -> [f32; 3] {
+    let neutral = black_body(NEUTRAL);
+    let color = black_body(kelvin.min(NEUTRAL));
+    [0, 1, 2].map(|i| (color[i] / neutral[i]).min(1.0))
+}
+
+/// Color matrix tinting colors to `kelvin`, see `gl::Adjustment`
+pub fn matrix(kelvin: u32) -> [[f32; 3]; 3] {
+    let [r, g, b] = white_point(kelvin);
+    [[r, 0.0, 0.0], [0.0, g, 0.0], [0.0, 0.0, b]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral() {
+        assert_eq!(white_point(NEUTRAL), [1.0; 3]);
+        assert_eq!(white_point(10000), [1.0; 3]);
+    }
+
+    #[test]
+    fn warmer() {
+        let [r, g, b] = white_point(3400);
+        assert_eq!(r, 1.0);
+        assert!(b < g && g < 1.0);
+        let [_, g2, b2] = white_point(2000);
+        assert!(g2 < g && b2 < b);
+    }
+}
@@ -1,5 +1,22 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
 use zbus::dbus_proxy;
 
+use crate::{
+    dbus,
+    source::{WindowEvent, WindowInfo, WindowSource},
+    utils, Result,
+};
+
+/// picom's bus name, `{display}` is replaced by the display
+pub const DEFAULT_SERVICE_TEMPLATE: &str = "com.github.chjj.compton.{display}";
+/// Where picom exports its interfaces
+const OBJECT_PATH: &str = "/com/github/chjj/compton";
+/// Wait before looking for picom's windows again, doubled after each attempt
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
 /// # DBus interface proxy for: `com.github.chjj.compton`
 #[dbus_proxy(
     interface = "com.github.chjj.compton",
@@ -11,8 +28,14 @@ trait Picom {
 
     /// reset method
     fn reset(&self) -> zbus::Result<()>;
+
+    /// opts_get method
+    fn opts_get(&self, name: &str) -> zbus::Result<zbus::zvariant::OwnedValue>;
 }
 
+/// Interface of picom's signals about windows, which older versions don't have
+pub const COMPOSITOR_INTERFACE: &str = "picom.Compositor";
+
 #[dbus_proxy(
     interface = "picom.Compositor",
     default_path = "/com/github/chjj/compton"
@@ -35,8 +58,17 @@ trait Compositor {
     fn win_unmapped(&self, wid: u32) -> zbus::Result<()>;
 }
 
+/// `picom.Window` of picom before v10
 #[dbus_proxy(interface = "picom.Window")]
 trait Window {
+    /// ClassGeneral property
+    #[dbus_proxy(property)]
+    fn class_general(&self) -> zbus::Result<String>;
+
+    /// ClassInstance property
+    #[dbus_proxy(property)]
+    fn class_instance(&self) -> zbus::Result<String>;
+
     /// ClientWin property
     #[dbus_proxy(property)]
     fn client_win(&self) -> zbus::Result<u32>;
@@ -69,3 +101,533 @@ trait Window {
     #[dbus_proxy(property)]
     fn type_(&self) -> zbus::Result<String>;
 }
+
+/// `picom.Window` of picom v10 and later, where `Type` lists all of the window's types
+#[dbus_proxy(interface = "picom.Window")]
+trait WindowV10 {
+    /// ClassGeneral property
+    #[dbus_proxy(property)]
+    fn class_general(&self) -> zbus::Result<String>;
+
+    /// ClassInstance property
+    #[dbus_proxy(property)]
+    fn class_instance(&self) -> zbus::Result<String>;
+
+    /// ClientWin property
+    #[dbus_proxy(property)]
+    fn client_win(&self) -> zbus::Result<u32>;
+
+    /// Mapped property
+    #[dbus_proxy(property)]
+    fn mapped(&self) -> zbus::Result<bool>;
+
+    /// Name property
+    #[dbus_proxy(property)]
+    fn name(&self) -> zbus::Result<String>;
+
+    /// Type property
+    #[dbus_proxy(property)]
+    fn type_(&self) -> zbus::Result<Vec<String>>;
+}
+
+/// Which `picom.Window` interface picom has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InterfaceVersion {
+    /// `WindowProxy`
+    #[default]
+    Legacy,
+    /// `WindowV10Proxy`
+    V10,
+}
+
+impl InterfaceVersion {
+    /// From picom's version, like "v10.2", or "vgit-1a2b3" for builds from git.
+    pub fn from_version(version: &str) -> Self {
+        let version = version.trim_start_matches('v');
+        match version.split('.').next().map(str::parse::<u32>) {
+            Some(Ok(major)) if major < 10 => Self::Legacy,
+            // Builds from git are newer than any release
+            _ => Self::V10,
+        }
+    }
+
+    /// Ask picom at `service` for its version. picom too old to tell has the legacy interface.
+    pub async fn detect(connection: &zbus::Connection, service: String) -> zbus::Result<Self> {
+        let proxy = PicomProxy::builder(connection)
+            .destination(service)?
+            .build()
+            .await?;
+        let version = match proxy.opts_get("version").await {
+            Ok(version) => version,
+            Err(zbus::Error::MethodError(..)) => return Ok(Self::Legacy),
+            Err(e) => return Err(e),
+        };
+        Ok(match <&str>::try_from(&*version) {
+            Ok(version) => Self::from_version(version),
+            Err(_) => Self::Legacy,
+        })
+    }
+
+    /// Properties read from `picom.Window`, with their signatures
+    #[cfg(test)]
+    fn window_properties(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Self::Legacy => &[
+                ("ClassGeneral", "s"),
+                ("ClassInstance", "s"),
+                ("ClientWin", "u"),
+                ("Mapped", "b"),
+                ("Name", "s"),
+                ("Type", "s"),
+            ],
+            Self::V10 => &[
+                ("ClassGeneral", "s"),
+                ("ClassInstance", "s"),
+                ("ClientWin", "u"),
+                ("Mapped", "b"),
+                ("Name", "s"),
+                ("Type", "as"),
+            ],
+        }
+    }
+}
+
+/// Finds windows through picom's D-Bus interface
+pub struct PicomSource {
+    dbus: zbus::Connection,
+    /// picom's bus name on our display
+    service: String,
+    interface: InterfaceVersion,
+    /// How many times to list picom's windows before giving up
+    retries: u32,
+}
+
+impl PicomSource {
+    /// Wait for picom to show up on the bus of `dbus` as `service`, as it might be started after
+    /// us, for at most `timeout` if given. Then check it has the interfaces we need.
+    pub async fn connect(
+        dbus: &zbus::Connection,
+        service: String,
+        timeout: Option<Duration>,
+        retries: u32,
+    ) -> Result<Self> {
+        dbus::wait_for_name(dbus, &service, timeout)
+            .await
+            .context("picom isn't running, or wasn't started with --dbus")?;
+        let introspection = zbus::fdo::IntrospectableProxy::builder(dbus)
+            .destination(service.clone())?
+            .path(OBJECT_PATH)?
+            .build()
+            .await?
+            .introspect()
+            .await?;
+        let node = zbus::xml::Node::from_reader(introspection.as_bytes())?;
+        if !node
+            .interfaces()
+            .iter()
+            .any(|i| i.name() == COMPOSITOR_INTERFACE)
+        {
+            anyhow::bail!(
+                "picom doesn't have the {} D-Bus interface, it needs a recent picom, \
+                 built with D-Bus support",
+                COMPOSITOR_INTERFACE
+            );
+        }
+        let interface = InterfaceVersion::detect(dbus, service.clone()).await?;
+        tracing::info!("Using the {interface:?} picom window interface");
+        Ok(Self {
+            dbus: dbus.clone(),
+            service,
+            interface,
+            retries,
+        })
+    }
+
+    async fn list_windows(&self) -> Result<Vec<u32>> {
+        let proxy: zbus::Proxy<'_> = zbus::ProxyBuilder::new_bare(&self.dbus)
+            .destination(self.service.clone())?
+            .interface("what.ever")?
+            .path(format!("{}/{}", OBJECT_PATH, "windows"))?
+            .build()
+            .await?;
+
+        // picom might still be starting
+        let introspection = utils::retry(
+            "Listing picom's windows",
+            self.retries.max(1),
+            RETRY_DELAY,
+            || proxy.introspect(),
+        )
+        .await
+        .context("Failed to list picom's windows, is picom running with --dbus?")?;
+        let windows = zbus::xml::Node::from_reader(introspection.as_bytes())?;
+        Ok(windows
+            .nodes()
+            .into_iter()
+            .filter_map(|w| {
+                let wid = w.name()?;
+                match parse_int::parse(wid) {
+                    Ok(wid) => Some(wid),
+                    Err(_) => {
+                        tracing::error!("Invalid window id from picom: {}", wid);
+                        None
+                    }
+                }
+            })
+            .collect())
+    }
+
+    async fn fetch_window_info(&self, wid: u32) -> zbus::Result<WindowInfo> {
+        let path = format!("{}/{}/{}", OBJECT_PATH, "windows", wid);
+        match self.interface {
+            InterfaceVersion::Legacy => {
+                let proxy = WindowProxy::builder(&self.dbus)
+                    .destination(self.service.clone())?
+                    .path(path)?
+                    .cache_properties(zbus::CacheProperties::No)
+                    .build()
+                    .await?;
+                Ok(WindowInfo {
+                    mapped: proxy.mapped().await?,
+                    ty: proxy.type_().await?,
+                    name: proxy.name().await?,
+                    client_win: proxy.client_win().await?,
+                    // Older picom doesn't have the class properties
+                    class_instance: proxy.class_instance().await.unwrap_or_default(),
+                    class_general: proxy.class_general().await.unwrap_or_default(),
+                })
+            }
+            InterfaceVersion::V10 => {
+                let proxy = WindowV10Proxy::builder(&self.dbus)
+                    .destination(self.service.clone())?
+                    .path(path)?
+                    .cache_properties(zbus::CacheProperties::No)
+                    .build()
+                    .await?;
+                Ok(WindowInfo {
+                    mapped: proxy.mapped().await?,
+                    ty: primary_type(proxy.type_().await?),
+                    name: proxy.name().await?,
+                    client_win: proxy.client_win().await?,
+                    class_instance: proxy.class_instance().await?,
+                    class_general: proxy.class_general().await?,
+                })
+            }
+        }
+    }
+}
+
+impl WindowSource for PicomSource {
+    fn initial_windows(&self) -> BoxFuture<'_, Result<Vec<u32>>> {
+        self.list_windows().boxed()
+    }
+
+    fn window_info(&self, wid: u32) -> BoxFuture<'_, Result<WindowInfo>> {
+        async move { Ok(self.fetch_window_info(wid).await?) }.boxed()
+    }
+
+    fn events(&self) -> BoxFuture<'_, Result<BoxStream<'static, Result<WindowEvent>>>> {
+        async move {
+            let picom = CompositorProxy::builder(&self.dbus)
+                .destination(self.service.clone())?
+                .build()
+                .await?;
+            let mapped = picom
+                .receive_win_mapped()
+                .await?
+                .map(|s| -> Result<_> { Ok(WindowEvent::Mapped(s.args()?.wid)) });
+            let unmapped = picom
+                .receive_win_unmapped()
+                .await?
+                .map(|s| -> Result<_> { Ok(WindowEvent::Unmapped(s.args()?.wid)) });
+            let destroyed = picom
+                .receive_win_destroyed()
+                .await?
+                .map(|s| -> Result<_> { Ok(WindowEvent::Destroyed(s.args()?.wid)) });
+            Ok(
+                futures::stream::select_all([mapped.boxed(), unmapped.boxed(), destroyed.boxed()])
+                    .boxed(),
+            )
+        }
+        .boxed()
+    }
+}
+
+/// picom's bus name for `display`, from `template` where `{display}` stands for the display.
+pub fn service_name(template: &str, display: &str) -> String {
+    template.replace("{display}", display)
+}
+
+/// The `DISPLAY` picom mirroring `screen` of `display` (like `:0` or `:0.1`) runs with, which
+/// its bus name is made from. Screen 0 is plain `:0`, as picom is usually started on the first
+/// screen.
+pub fn screen_display(display: &str, screen: usize) -> String {
+    let (host, number) = display.rsplit_once(':').unwrap_or(("", display));
+    let number = number.split('.').next().unwrap_or(number);
+    match screen {
+        0 => format!("{host}:{number}"),
+        screen => format!("{host}:{number}.{screen}"),
+    }
+}
+
+/// The type a window with all of `types` is treated as. The most specific one comes first.
+fn primary_type(types: Vec<String>) -> String {
+    types.into_iter().next().unwrap_or_else(|| "unknown".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Introspection of a window object of picom v9.1
+    const LEGACY_INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node name="/com/github/chjj/compton/windows/0x01a00003">
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" direction="out" type="s"/>
+    </method>
+  </interface>
+  <interface name="picom.Window">
+    <property type="u" name="Leader" access="read"/>
+    <property type="u" name="ClientWin" access="read"/>
+    <property type="u" name="Id" access="read"/>
+    <property type="u" name="Next" access="read"/>
+    <property type="b" name="RawFocused" access="read"/>
+    <property type="b" name="Mapped" access="read"/>
+    <property type="s" name="Name" access="read"/>
+    <property type="s" name="Type" access="read"/>
+    <property type="s" name="ClassInstance" access="read"/>
+    <property type="s" name="ClassGeneral" access="read"/>
+  </interface>
+</node>"#;
+
+    /// Introspection of a window object of picom v11
+    const V10_INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node name="/com/github/chjj/compton/windows/0x01a00003">
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="data" direction="out" type="s"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg type="s" name="interface_name" direction="in"/>
+      <arg type="s" name="property_name" direction="in"/>
+      <arg type="v" name="value" direction="out"/>
+    </method>
+  </interface>
+  <interface name="picom.Window">
+    <property type="u" name="Leader" access="read"/>
+    <property type="u" name="ClientWin" access="read"/>
+    <property type="u" name="Id" access="read"/>
+    <property type="u" name="Next" access="read"/>
+    <property type="b" name="RawFocused" access="read"/>
+    <property type="b" name="Mapped" access="read"/>
+    <property type="s" name="Name" access="read"/>
+    <property type="as" name="Type" access="read"/>
+    <property type="s" name="ClassInstance" access="read"/>
+    <property type="s" name="ClassGeneral" access="read"/>
+  </interface>
+</node>"#;
+
+    /// Window the mock picom serves, an xterm
+    const WID: u32 = 0x01a00003;
+
+    /// `picom.Window` of picom v8, which has no class properties yet
+    struct MockWindow;
+
+    #[zbus::dbus_interface(name = "picom.Window")]
+    impl MockWindow {
+        #[dbus_interface(property)]
+        fn client_win(&self) -> u32 {
+            0x01a0000e
+        }
+
+        #[dbus_interface(property)]
+        fn mapped(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn name(&self) -> String {
+            "user@host: ~".into()
+        }
+
+        #[dbus_interface(property, name = "Type")]
+        fn type_(&self) -> String {
+            "normal".into()
+        }
+    }
+
+    /// `picom.Window` of picom v11, with the properties recorded from it
+    struct MockWindowV10;
+
+    #[zbus::dbus_interface(name = "picom.Window")]
+    impl MockWindowV10 {
+        #[dbus_interface(property)]
+        fn class_general(&self) -> String {
+            "XTerm".into()
+        }
+
+        #[dbus_interface(property)]
+        fn class_instance(&self) -> String {
+            "xterm".into()
+        }
+
+        #[dbus_interface(property)]
+        fn client_win(&self) -> u32 {
+            0x01a0000e
+        }
+
+        #[dbus_interface(property)]
+        fn mapped(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn name(&self) -> String {
+            "user@host: ~".into()
+        }
+
+        #[dbus_interface(property, name = "Type")]
+        fn type_(&self) -> Vec<String> {
+            vec!["normal".into()]
+        }
+    }
+
+    /// A `PicomSource` for `interface`, talking to a peer that serves `window` as `WID`. The
+    /// peer's connection is returned too, it stops serving when dropped.
+    async fn mock_picom(
+        interface: InterfaceVersion,
+        window: impl zbus::Interface,
+    ) -> (PicomSource, zbus::Connection) {
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair().unwrap();
+        let guid = zbus::Guid::generate();
+        let picom = zbus::ConnectionBuilder::unix_stream(theirs)
+            .server(&guid)
+            .p2p()
+            .serve_at(format!("{OBJECT_PATH}/windows/{WID}"), window)
+            .unwrap()
+            .build();
+        let dbus = zbus::ConnectionBuilder::unix_stream(ours).p2p().build();
+        let (dbus, picom) = futures::try_join!(dbus, picom).unwrap();
+        let source = PicomSource {
+            dbus,
+            service: service_name(DEFAULT_SERVICE_TEMPLATE, "_0"),
+            interface,
+            retries: 1,
+        };
+        (source, picom)
+    }
+
+    #[tokio::test]
+    async fn window_info() {
+        let (source, _picom) = mock_picom(InterfaceVersion::Legacy, MockWindow).await;
+        assert_eq!(
+            source.fetch_window_info(WID).await.unwrap(),
+            WindowInfo {
+                mapped: true,
+                ty: "normal".into(),
+                name: "user@host: ~".into(),
+                client_win: 0x01a0000e,
+                class_instance: String::new(),
+                class_general: String::new(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn window_info_v10() {
+        let (source, _picom) = mock_picom(InterfaceVersion::V10, MockWindowV10).await;
+        assert_eq!(
+            source.fetch_window_info(WID).await.unwrap(),
+            WindowInfo {
+                mapped: true,
+                ty: "normal".into(),
+                name: "user@host: ~".into(),
+                client_win: 0x01a0000e,
+                class_instance: "xterm".into(),
+                class_general: "XTerm".into(),
+            }
+        );
+        // The legacy proxy can't read the list of types
+        let (source, _picom) = mock_picom(InterfaceVersion::Legacy, MockWindowV10).await;
+        assert!(source.fetch_window_info(WID).await.is_err());
+    }
+
+    /// Check that everything `version` reads is in `introspection`, with the same type.
+    fn assert_matches(version: InterfaceVersion, introspection: &str) {
+        let node = zbus::xml::Node::from_reader(introspection.as_bytes()).unwrap();
+        let interfaces = node.interfaces();
+        let window = interfaces
+            .iter()
+            .find(|i| i.name() == "picom.Window")
+            .unwrap();
+        for (name, ty) in version.window_properties() {
+            let properties = window.properties();
+            let property = properties
+                .iter()
+                .find(|p| p.name() == *name)
+                .unwrap_or_else(|| panic!("{version:?}: no property {name}"));
+            assert_eq!(property.ty(), *ty, "{version:?}: type of {name}");
+        }
+    }
+
+    #[test]
+    fn window_interfaces() {
+        assert_matches(InterfaceVersion::Legacy, LEGACY_INTROSPECTION);
+        assert_matches(InterfaceVersion::V10, V10_INTROSPECTION);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(
+            InterfaceVersion::from_version("v9.1"),
+            InterfaceVersion::Legacy
+        );
+        assert_eq!(
+            InterfaceVersion::from_version("v8"),
+            InterfaceVersion::Legacy
+        );
+        assert_eq!(
+            InterfaceVersion::from_version("v10.2"),
+            InterfaceVersion::V10
+        );
+        assert_eq!(InterfaceVersion::from_version("v11"), InterfaceVersion::V10);
+        assert_eq!(
+            InterfaceVersion::from_version("vgit-1a2b3"),
+            InterfaceVersion::V10
+        );
+    }
+
+    #[test]
+    fn service_names() {
+        assert_eq!(
+            service_name(DEFAULT_SERVICE_TEMPLATE, "_0"),
+            "com.github.chjj.compton._0"
+        );
+        assert_eq!(
+            service_name("org.example.Compositor", "_0"),
+            "org.example.Compositor"
+        );
+    }
+
+    #[test]
+    fn screen_displays() {
+        assert_eq!(screen_display(":0", 0), ":0");
+        assert_eq!(screen_display(":0", 1), ":0.1");
+        assert_eq!(screen_display(":0.1", 0), ":0");
+        assert_eq!(screen_display("localhost:10.0", 2), "localhost:10.2");
+    }
+
+    #[test]
+    fn primary_types() {
+        assert_eq!(
+            primary_type(vec!["popup_menu".into(), "normal".into()]),
+            "popup_menu"
+        );
+        assert_eq!(primary_type(vec![]), "unknown");
+    }
+}
@@ -0,0 +1,155 @@
+//! Telling hung applications apart from idle ones. A window that hasn't been damaged for a while is
+//! sent a `_NET_WM_PING`, and if it doesn't answer in time, it is considered unresponsive until it
+//! answers or draws again.
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long a window has to be idle before it is pinged
+    pub idle: Duration,
+    /// How long it has to answer
+    pub answer: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Send a ping with this timestamp
+    Ping(u32),
+    /// The window didn't answer, show it as not responding
+    Unresponsive,
+}
+
+/// Ping state of one window
+#[derive(Debug, Clone)]
+pub struct Responsiveness {
+    /// Last damage or answer
+    last_activity: Instant,
+    /// Timestamp and time of the ping waiting for an answer
+    pending: Option<(u32, Instant)>,
+    unresponsive: bool,
+}
+
+impl Responsiveness {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_activity: now,
+            pending: None,
+            unresponsive: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_unresponsive(&self) -> bool {
+        self.unresponsive
+    }
+
+    /// The window drew something. Returns true if it was unresponsive until now.
+    pub fn activity(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        self.pending = None;
+        std::mem::replace(&mut self.unresponsive, false)
+    }
+
+    /// The window answered the ping with `timestamp`. Returns true if it was unresponsive until
+    /// now. Answers to pings we aren't waiting for anymore are ignored.
+    pub fn pong(&mut self, timestamp: u32, now: Instant) -> bool {
+        match self.pending {
+            Some((pending, _)) if pending == timestamp => self.activity(now),
+            _ => false,
+        }
+    }
+
+    /// Called periodically. `timestamp` is used for a new ping, it has to change between calls.
+    pub fn poll(&mut self, timeouts: &Timeouts, timestamp: u32, now: Instant) -> Option<Action> {
+        match self.pending {
+            Some((_, sent)) => {
+                if !self.unresponsive && now.duration_since(sent) >= timeouts.answer {
+                    self.unresponsive = true;
+                    return Some(Action::Unresponsive);
+                }
+                None
+            }
+            None if now.duration_since(self.last_activity) >= timeouts.idle => {
+                self.pending = Some((timestamp, now));
+                Some(Action::Ping(timestamp))
+            }
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        idle: Duration::from_secs(5),
+        answer: Duration::from_secs(3),
+    };
+
+    #[test]
+    fn busy_windows_are_not_pinged() {
+        let start = Instant::now();
+        let mut r = Responsiveness::new(start);
+        for i in 1..10 {
+            let now = start + Duration::from_secs(i * 4);
+            r.activity(now);
+            assert_eq!(r.poll(&TIMEOUTS, i as u32, now), None);
+        }
+    }
+
+    #[test]
+    fn answered_ping() {
+        let start = Instant::now();
+        let mut r = Responsiveness::new(start);
+        let idle = start + TIMEOUTS.idle;
+        assert_eq!(r.poll(&TIMEOUTS, 1, idle), Some(Action::Ping(1)));
+        // Only one ping at a time
+        assert_eq!(r.poll(&TIMEOUTS, 2, idle + Duration::from_secs(1)), None);
+        // An answer to something else
+        assert!(!r.pong(2, idle + Duration::from_secs(1)));
+        let answered = idle + Duration::from_secs(2);
+        assert!(!r.pong(1, answered));
+        assert!(!r.is_unresponsive());
+        // Pinged again once idle for as long again
+        assert_eq!(
+            r.poll(&TIMEOUTS, 3, answered + Duration::from_secs(1)),
+            None
+        );
+        assert_eq!(
+            r.poll(&TIMEOUTS, 4, answered + TIMEOUTS.idle),
+            Some(Action::Ping(4))
+        );
+    }
+
+    #[test]
+    fn unanswered_ping() {
+        let start = Instant::now();
+        let mut r = Responsiveness::new(start);
+        let idle = start + TIMEOUTS.idle;
+        assert_eq!(r.poll(&TIMEOUTS, 1, idle), Some(Action::Ping(1)));
+        let late = idle + TIMEOUTS.answer;
+        assert_eq!(r.poll(&TIMEOUTS, 2, late), Some(Action::Unresponsive));
+        assert!(r.is_unresponsive());
+        // Reported once
+        assert_eq!(r.poll(&TIMEOUTS, 3, late + TIMEOUTS.answer), None);
+        // A late answer brings it back
+        assert!(r.pong(1, late + TIMEOUTS.answer));
+        assert!(!r.is_unresponsive());
+    }
+
+    #[test]
+    fn damage_clears_unresponsive() {
+        let start = Instant::now();
+        let mut r = Responsiveness::new(start);
+        let idle = start + TIMEOUTS.idle;
+        r.poll(&TIMEOUTS, 1, idle);
+        let late = idle + TIMEOUTS.answer;
+        r.poll(&TIMEOUTS, 2, late);
+        assert!(r.activity(late + Duration::from_secs(1)));
+        assert!(!r.is_unresponsive());
+        // The old ping's answer doesn't matter anymore
+        assert!(!r.pong(1, late + Duration::from_secs(2)));
+        assert!(!r.activity(late + Duration::from_secs(2)));
+    }
+}
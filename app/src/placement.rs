@@ -0,0 +1,457 @@
+//! Where windows go in VR, computed from their X geometry.
+use x11rb::protocol::xproto;
+
+/// Position and size of a window, in root window coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl From<&xproto::GetGeometryReply> for Geometry {
+    fn from(g: &xproto::GetGeometryReply) -> Self {
+        Self {
+            x: g.x,
+            y: g.y,
+            width: g.width,
+            height: g.height,
+        }
+    }
+}
+
+impl From<xproto::Rectangle> for Geometry {
+    fn from(r: xproto::Rectangle) -> Self {
+        Self {
+            x: r.x,
+            y: r.y,
+            width: r.width,
+            height: r.height,
+        }
+    }
+}
+
+impl Geometry {
+    /// Smallest geometry containing both `self` and `other`.
+    pub fn union(&self, other: &Geometry) -> Geometry {
+        let left = self.x.min(other.x);
+        let top = self.y.min(other.y);
+        let right = (self.x as i32 + self.width as i32).max(other.x as i32 + other.width as i32);
+        let bottom = (self.y as i32 + self.height as i32).max(other.y as i32 + other.height as i32);
+        Geometry {
+            x: left,
+            y: top,
+            width: (right - left as i32) as u16,
+            height: (bottom - top as i32) as u16,
+        }
+    }
+
+    /// Area shared by `self` and `other`, in pixels.
+    pub fn overlap(&self, other: &Geometry) -> u32 {
+        // i32, so x + width can't overflow
+        let left = (self.x as i32).max(other.x as i32);
+        let top = (self.y as i32).max(other.y as i32);
+        let right = (self.x as i32 + self.width as i32).min(other.x as i32 + other.width as i32);
+        let bottom = (self.y as i32 + self.height as i32).min(other.y as i32 + other.height as i32);
+        if right <= left || bottom <= top {
+            0
+        } else {
+            (right - left) as u32 * (bottom - top) as u32
+        }
+    }
+
+    pub fn center(&self) -> (i16, i16) {
+        (
+            self.x + self.width as i16 / 2,
+            self.y + self.height as i16 / 2,
+        )
+    }
+}
+
+/// Whether `win` is entirely outside of the root window. Firefox does this and has a 1x1
+/// window outside the screen, such windows are not mirrored.
+pub fn is_offscreen(win: &Geometry, root: &Geometry) -> bool {
+    win.overlap(root) == 0
+}
+
+/// X screens, with the root geometries `roots`, laid out left to right. Returns `win`, on the
+/// screen `index`, moved into that layout, and the geometry of the whole layout, to place the
+/// window with `transform` without windows of different screens overlapping.
+pub fn side_by_side(roots: &[Geometry], index: usize, win: &Geometry) -> (Geometry, Geometry) {
+    let offset: i32 = roots[..index].iter().map(|r| r.width as i32).sum();
+    let width: i32 = roots.iter().map(|r| r.width as i32).sum();
+    let height = roots.iter().map(|r| r.height).max().unwrap_or(0);
+    let win = Geometry {
+        x: (win.x as i32 + offset).clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        ..*win
+    };
+    let layout = Geometry {
+        x: 0,
+        y: 0,
+        width: width.min(u16::MAX as i32) as u16,
+        height,
+    };
+    (win, layout)
+}
+
+/// Position in VR of a top level window: its center relative to a point horizontally centered,
+/// 3/4 down the root window, in meters, `depth` meters away. Each window is `z_spacing` closer
+/// than the previous one, so they don't overlap at the same depth.
+pub fn position(
+    win: &Geometry,
+    root: &Geometry,
+    index: usize,
+    pixels_per_meter: f32,
+    depth: f32,
+    z_spacing: f32,
+) -> (f32, f32, f32) {
+    let (center_x, center_y) = win.center();
+    (
+        (center_x - root.width as i16 / 2) as f32 / pixels_per_meter,
+        -(center_y - root.height as i16 * 3 / 4) as f32 / pixels_per_meter,
+        index as f32 * z_spacing - depth,
+    )
+}
+
+/// How the whole layout is held up in front of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Standing: windows are vertical, straight ahead
+    #[default]
+    Wall,
+    /// Sitting: windows lie lower, like on a desk, angled up toward the face
+    Desk,
+}
+
+/// How far the desk preset pitches the layout down, in degrees
+const DESK_PITCH: f32 = 25.0;
+/// How far the desk preset lowers the layout, in meters
+const DESK_DROP: f32 = 0.3;
+
+impl Preset {
+    /// Applied to the whole layout, after each window is placed in it. The desk is pitched down
+    /// around the user, so every window still faces them.
+    pub fn base_transform(self) -> graphene::Matrix {
+        match self {
+            Self::Wall => graphene::Matrix::new_identity(),
+            Self::Desk => graphene::Matrix::new_rotate(-DESK_PITCH, &graphene::Vec3::x_axis())
+                .multiply(&graphene::Matrix::new_translate(&graphene::Point3D::new(
+                    0.0, -DESK_DROP, 0.0,
+                ))),
+        }
+    }
+}
+
+/// Transformation of a top level window, see `position`, in the layout of `preset`.
+pub fn transform(
+    win: &Geometry,
+    root: &Geometry,
+    index: usize,
+    pixels_per_meter: f32,
+    depth: f32,
+    z_spacing: f32,
+    preset: Preset,
+) -> graphene::Matrix {
+    let (x, y, z) = position(win, root, index, pixels_per_meter, depth, z_spacing);
+    // Row vectors, the translation is applied first
+    graphene::Matrix::new_translate(&graphene::Point3D::new(x, y, z))
+        .multiply(&preset.base_transform())
+}
+
+/// Transformation of the theater screen: centered in front of the layout, `depth` meters away.
+pub fn theater_transform(depth: f32) -> graphene::Matrix {
+    graphene::Matrix::new_translate(&graphene::Point3D::new(0.0, 0.0, -depth))
+}
+
+/// Scale making a window `width` meters wide at scale 1.0 `theater_width` meters wide.
+pub fn theater_scale(width: f32, theater_width: f32) -> f32 {
+    if width > 0.0 {
+        theater_width / width
+    } else {
+        1.0
+    }
+}
+
+/// Transformation of a window pinned `offset` away from the head, when the head is at `head`.
+pub fn pinned_transform(head: &graphene::Matrix, offset: &graphene::Matrix) -> graphene::Matrix {
+    // Row vectors, the offset is applied in head space
+    offset.multiply(head)
+}
+
+/// The offset from the head keeping a window at `window` while the head is at `head`, the
+/// inverse of `pinned_transform`.
+pub fn pin_offset(window: &graphene::Matrix, head: &graphene::Matrix) -> graphene::Matrix {
+    let to_head = head
+        .inverse()
+        .unwrap_or_else(graphene::Matrix::new_identity);
+    window.multiply(&to_head)
+}
+
+/// The mip level to share a window's texture at, each halving its resolution: the window is
+/// `width` pixels and `width_meters` wide, `distance` meters from the head, on a headset with
+/// `pixels_per_degree`. `current` is kept while the best level is less than a quarter level
+/// past it, so windows at the edge don't switch back and forth.
+pub fn mip_level(
+    width: u32,
+    width_meters: f32,
+    distance: f32,
+    pixels_per_degree: f32,
+    current: u32,
+    max: u32,
+) -> u32 {
+    if width == 0 || width_meters <= 0.0 || distance <= 0.0 {
+        return 0;
+    }
+    let degrees = (2.0 * (width_meters / 2.0 / distance).atan()).to_degrees();
+    let best = (width as f32 / (degrees * pixels_per_degree)).log2();
+    if best >= current as f32 - 0.25 && best < current as f32 + 1.25 {
+        return current.min(max);
+    }
+    (best.max(0.0) as u32).min(max)
+}
+
+/// How a window is fit into a size that doesn't have its aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FitMode {
+    /// As large as possible inside the size, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// Exactly the size, distorting the window
+    Stretch,
+}
+
+/// Width and height in VR of a `width` x `height` pixels window, in meters. X pixels are
+/// square, so without `target` this is just the pixel size at `pixels_per_meter`. With a
+/// `target` size in meters, the window is fit into it according to `fit`.
+pub fn physical_size(
+    width: u32,
+    height: u32,
+    pixels_per_meter: f32,
+    target: Option<(f32, f32)>,
+    fit: FitMode,
+) -> (f32, f32) {
+    let natural = (
+        width as f32 / pixels_per_meter,
+        height as f32 / pixels_per_meter,
+    );
+    match (target, fit) {
+        (None, _) => natural,
+        (Some(target), FitMode::Stretch) => target,
+        (Some(_), FitMode::Contain) if width == 0 || height == 0 => natural,
+        (Some((target_width, target_height)), FitMode::Contain) => {
+            let scale = (target_width / natural.0).min(target_height / natural.1);
+            (natural.0 * scale, natural.1 * scale)
+        }
+    }
+}
+
+/// Offset of a child window's center from its parent's, in pixels, y pointing up.
+pub fn child_offset(win: &Geometry, parent: &Geometry) -> (f32, f32) {
+    let (center_x, center_y) = win.center();
+    let (parent_center_x, parent_center_y) = parent.center();
+    (
+        (center_x - parent_center_x) as _,
+        -(center_y - parent_center_y) as _,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: Geometry = Geometry {
+        x: 0,
+        y: 0,
+        width: 1920,
+        height: 1080,
+    };
+
+    fn geometry(x: i16, y: i16, width: u16, height: u16) -> Geometry {
+        Geometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn firefox_offscreen_window() {
+        assert!(is_offscreen(&geometry(-100, -100, 1, 1), &ROOT));
+    }
+
+    #[test]
+    fn partially_offscreen() {
+        // Hanging off the bottom right corner
+        let win = geometry(1820, 1000, 800, 600);
+        assert!(!is_offscreen(&win, &ROOT));
+        assert_eq!(win.overlap(&ROOT), 100 * 80);
+        // Only the top left pixel is on screen
+        assert!(!is_offscreen(&geometry(-799, -599, 800, 600), &ROOT));
+    }
+
+    #[test]
+    fn empty_window() {
+        assert!(is_offscreen(&geometry(100, 100, 0, 0), &ROOT));
+    }
+
+    #[test]
+    fn near_extremes() {
+        assert!(is_offscreen(
+            &geometry(i16::MAX, i16::MAX, u16::MAX, u16::MAX),
+            &ROOT
+        ));
+        assert!(!is_offscreen(
+            &geometry(i16::MIN, i16::MIN, u16::MAX, u16::MAX),
+            &ROOT
+        ));
+    }
+
+    #[test]
+    fn offscreen() {
+        assert!(is_offscreen(&geometry(1920, 0, 100, 100), &ROOT));
+        assert!(is_offscreen(&geometry(0, 1080, 100, 100), &ROOT));
+        assert!(is_offscreen(&geometry(-100, 0, 100, 100), &ROOT));
+        assert!(!is_offscreen(&geometry(-99, 0, 100, 100), &ROOT));
+        assert!(!is_offscreen(&geometry(1919, 1079, 100, 100), &ROOT));
+        assert!(!is_offscreen(&geometry(100, 100, 800, 600), &ROOT));
+    }
+
+    #[test]
+    fn centering() {
+        // Centered horizontally, 3/4 of the way down: right in front
+        let win = geometry(960 - 400, 810 - 300, 800, 600);
+        assert_eq!(position(&win, &ROOT, 0, 600.0, 8.0, 0.05), (0.0, 0.0, -8.0));
+
+        let win = geometry(1560 - 400, 210 - 300, 800, 600);
+        assert_eq!(position(&win, &ROOT, 0, 600.0, 8.0, 0.05), (1.0, 1.0, -8.0));
+    }
+
+    #[test]
+    fn depth_stacking() {
+        let win = geometry(0, 0, 800, 600);
+        let depths: Vec<_> = (0..4)
+            .map(|i| position(&win, &ROOT, i, 600.0, 8.0, 0.5).2)
+            .collect();
+        assert!(depths.windows(2).all(|d| d[0] < d[1]));
+        assert_eq!(depths[3], -6.5);
+    }
+
+    #[test]
+    fn transform_translation() {
+        let win = geometry(1560 - 400, 210 - 300, 800, 600);
+        let m = transform(&win, &ROOT, 3, 600.0, 3.0, 0.5, Preset::Wall).to_float();
+        // Row major, translation is in the last row
+        assert_eq!(&m[12..], &[1.0, 1.0, -1.5, 1.0]);
+    }
+
+    #[test]
+    fn desk() {
+        let win = geometry(1920 / 2 - 400, 1080 * 3 / 4 - 300, 800, 600);
+        let m = transform(&win, &ROOT, 0, 600.0, 3.0, 0.5, Preset::Desk);
+        let center = m.transform_point3d(&graphene::Point3D::new(0.0, 0.0, 0.0));
+        assert!(center.y() < -1.0);
+        assert!(center.z() < -2.0);
+        // Tilted back, the top edge is farther away than the bottom edge
+        let top = m.transform_point3d(&graphene::Point3D::new(0.0, 0.5, 0.0));
+        let bottom = m.transform_point3d(&graphene::Point3D::new(0.0, -0.5, 0.0));
+        assert!(top.z() < bottom.z());
+        // Same distance from the user as on the wall
+        let distance = (center.y() + DESK_DROP).hypot(center.z());
+        assert!((distance - 3.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn pinned() {
+        let offset = graphene::Matrix::new_translate(&graphene::Point3D::new(0.3, -0.2, -1.0));
+        // Turned left and stepped aside, the window stays in front of the head
+        let head = graphene::Matrix::new_rotate(90.0, &graphene::Vec3::y_axis()).multiply(
+            &graphene::Matrix::new_translate(&graphene::Point3D::new(1.0, 1.7, 0.0)),
+        );
+        let m = pinned_transform(&head, &offset);
+        let center = m.transform_point3d(&graphene::Point3D::new(0.0, 0.0, 0.0));
+        let expected = head.transform_point3d(&graphene::Point3D::new(0.3, -0.2, -1.0));
+        assert!(center.near(&expected, 1e-4));
+        // Moving a pinned window and pinning it again where it was left keeps it there
+        assert!(pinned_transform(&head, &pin_offset(&m, &head)).near(&m, 1e-4));
+    }
+
+    #[test]
+    fn mip_levels() {
+        // 1000 pixels, 1 m wide: about 53 degrees at 1 m, 19 at 3 m, 6 at 10 m
+        assert_eq!(mip_level(1000, 1.0, 1.0, 20.0, 0, 4), 0);
+        assert_eq!(mip_level(1000, 1.0, 3.0, 20.0, 0, 4), 1);
+        assert_eq!(mip_level(1000, 1.0, 10.0, 20.0, 0, 4), 3);
+        assert_eq!(mip_level(1000, 1.0, 10.0, 20.0, 0, 2), 2);
+        // Just past the edge between levels 0 and 1, the current one stays
+        assert_eq!(mip_level(1000, 1.0, 2.4, 20.0, 0, 4), 0);
+        assert_eq!(mip_level(1000, 1.0, 1.9, 20.0, 1, 4), 1);
+        assert_eq!(mip_level(1000, 1.0, 1.0, 20.0, 1, 4), 0);
+        assert_eq!(mip_level(0, 1.0, 3.0, 20.0, 2, 4), 0);
+    }
+
+    #[test]
+    fn child() {
+        let parent = geometry(100, 100, 800, 600);
+        let menu = geometry(200, 150, 100, 300);
+        assert_eq!(child_offset(&menu, &parent), (-250.0, 100.0));
+    }
+
+    #[test]
+    fn union() {
+        let a = geometry(10, 10, 100, 50);
+        let b = geometry(50, -20, 100, 40);
+        assert_eq!(a.union(&b), geometry(10, -20, 140, 80));
+        assert_eq!(a.union(&a), a);
+    }
+
+    #[test]
+    fn screens_side_by_side() {
+        let win = geometry(100, 100, 800, 600);
+        // A single screen is laid out as it is
+        assert_eq!(side_by_side(&[ROOT], 0, &win), (win, ROOT));
+
+        let second = geometry(0, 0, 1280, 1024);
+        let layout = geometry(0, 0, 3200, 1080);
+        assert_eq!(side_by_side(&[ROOT, second], 0, &win), (win, layout));
+        assert_eq!(
+            side_by_side(&[ROOT, second], 1, &win),
+            (geometry(2020, 100, 800, 600), layout)
+        );
+    }
+
+    #[test]
+    fn ultrawide() {
+        // 21:9
+        let (width, height) = physical_size(2520, 1080, 600.0, None, FitMode::Contain);
+        assert_eq!((width, height), (4.2, 1.8));
+        assert!((width / height - 21.0 / 9.0).abs() < 1e-6);
+
+        // Fit into a 2m x 2m override, the width limits the size
+        let (width, height) = physical_size(2520, 1080, 600.0, Some((2.0, 2.0)), FitMode::Contain);
+        assert!((width - 2.0).abs() < 1e-6);
+        assert!((height - 2.0 * 9.0 / 21.0).abs() < 1e-6);
+
+        assert_eq!(
+            physical_size(2520, 1080, 600.0, Some((2.0, 2.0)), FitMode::Stretch),
+            (2.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn tall() {
+        let (width, height) = physical_size(300, 1200, 600.0, Some((1.0, 1.0)), FitMode::Contain);
+        assert!((width - 0.25).abs() < 1e-6);
+        assert!((height - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn theater() {
+        // A 1920 pixels wide window is 3.2m wide, doubled for a 6.4m screen
+        assert_eq!(theater_scale(1920.0 / 600.0, 6.4), 2.0);
+        assert_eq!(theater_scale(0.0, 6.4), 1.0);
+    }
+}
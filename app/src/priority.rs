@@ -0,0 +1,137 @@
+//! Which windows stay mirrored when there are more than `[window-limit] max-windows`. The others
+//! are still tracked, but hidden in VR and without textures, until they are used again.
+use std::{collections::HashSet, time::Instant};
+
+/// When a window was last used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Activity {
+    pub focused: Option<Instant>,
+    pub damaged: Option<Instant>,
+    /// When the window was mapped, relative to the others: lower is older
+    pub sequence: u64,
+}
+
+impl Activity {
+    /// A window mapped at `now`, which counts as drawing, as the `sequence`th window
+    pub fn new(now: Instant, sequence: u64) -> Self {
+        Self {
+            focused: None,
+            damaged: Some(now),
+            sequence,
+        }
+    }
+
+    fn last(&self) -> Option<Instant> {
+        self.focused.max(self.damaged)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub wid: u32,
+    pub pinned: bool,
+    pub activity: Activity,
+}
+
+/// The windows that stay mirrored: pinned ones, however many there are, then the ones used last
+/// up to `max` in all. Focus breaks ties with drawing, and windows mapped earlier go before newer
+/// ones. Window ids are no help there, the X server reuses them.
+pub fn live(candidates: &[Candidate], max: usize) -> HashSet<u32> {
+    let mut ranked: Vec<_> = candidates.iter().collect();
+    ranked.sort_by_key(|c| {
+        std::cmp::Reverse((
+            c.pinned,
+            c.activity.last(),
+            c.activity.focused,
+            std::cmp::Reverse(c.activity.sequence),
+        ))
+    });
+    let pinned = ranked.iter().filter(|c| c.pinned).count();
+    ranked
+        .into_iter()
+        .take(max.max(pinned))
+        .map(|c| c.wid)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn candidate(
+        wid: u32,
+        focused: Option<u64>,
+        damaged: Option<u64>,
+        start: Instant,
+    ) -> Candidate {
+        let at = |secs| start + Duration::from_secs(secs);
+        Candidate {
+            wid,
+            pinned: false,
+            activity: Activity {
+                focused: focused.map(at),
+                damaged: damaged.map(at),
+                sequence: wid.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn under_limit() {
+        let start = Instant::now();
+        let candidates = [
+            candidate(1, None, None, start),
+            candidate(2, None, None, start),
+        ];
+        assert_eq!(live(&candidates, 2), HashSet::from([1, 2]));
+        assert!(live(&candidates, 0).is_empty());
+    }
+
+    #[test]
+    fn used_last() {
+        let start = Instant::now();
+        let candidates = [
+            candidate(1, Some(1), None, start),
+            candidate(2, None, Some(5), start),
+            candidate(3, Some(3), Some(2), start),
+            candidate(4, None, None, start),
+        ];
+        assert_eq!(live(&candidates, 2), HashSet::from([2, 3]));
+        assert_eq!(live(&candidates, 3), HashSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn focus_breaks_ties() {
+        let start = Instant::now();
+        let candidates = [
+            candidate(1, None, Some(2), start),
+            candidate(2, Some(2), None, start),
+        ];
+        assert_eq!(live(&candidates, 1), HashSet::from([2]));
+        // Then the window mapped first, whatever its id
+        let mut candidates = [
+            candidate(3, None, None, start),
+            candidate(7, None, None, start),
+        ];
+        assert_eq!(live(&candidates, 1), HashSet::from([3]));
+        candidates[0].activity.sequence = 8;
+        assert_eq!(live(&candidates, 1), HashSet::from([7]));
+    }
+
+    #[test]
+    fn pinned_always_live() {
+        let start = Instant::now();
+        let mut candidates = [
+            candidate(1, None, None, start),
+            candidate(2, Some(9), Some(9), start),
+            candidate(3, None, None, start),
+        ];
+        candidates[0].pinned = true;
+        assert_eq!(live(&candidates, 1), HashSet::from([1]));
+        assert_eq!(live(&candidates, 2), HashSet::from([1, 2]));
+        candidates[2].pinned = true;
+        assert_eq!(live(&candidates, 1), HashSet::from([1, 3]));
+    }
+}
@@ -0,0 +1,182 @@
+//! Surviving VR runtime restarts. When the runtime goes away, e.g. because SteamVR restarted,
+//! `App` stops doing VR work but keeps tracking X windows, and `Supervisor` tells it when a new
+//! xrdesktop client could be connected, so the windows can be added to it.
+use std::time::{Duration, Instant};
+
+/// First wait before connecting again, doubled after each failed attempt up to `MAX_DELAY`
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(16);
+
+/// Connects to the VR runtime, faked in tests
+pub trait Client {
+    /// Try to connect a new client. False if the runtime isn't back yet.
+    fn reconnect(&mut self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// The runtime went away, retrying at `next_attempt`
+    Lost {
+        attempts: u32,
+        next_attempt: Instant,
+    },
+}
+
+/// What `App` has to do after a state change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Stop VR work, the client is gone
+    Suspend,
+    /// A new client is connected, add the windows to it
+    Resume,
+}
+
+#[derive(Debug)]
+pub struct Supervisor {
+    state: State,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            state: State::Running,
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The runtime went away, e.g. it asked us to quit.
+    pub fn lost(&mut self, now: Instant) -> Option<Transition> {
+        match self.state {
+            State::Running => {
+                self.state = State::Lost {
+                    attempts: 0,
+                    next_attempt: now + INITIAL_DELAY,
+                };
+                Some(Transition::Suspend)
+            }
+            State::Lost { .. } => None,
+        }
+    }
+
+    /// Called periodically. While the runtime is lost, try to connect again when it is time.
+    pub fn poll(&mut self, client: &mut impl Client, now: Instant) -> Option<Transition> {
+        let attempts = match self.state {
+            State::Lost {
+                attempts,
+                next_attempt,
+            } if now >= next_attempt => attempts + 1,
+            _ => return None,
+        };
+        if client.reconnect() {
+            self.state = State::Running;
+            return Some(Transition::Resume);
+        }
+        let delay = INITIAL_DELAY
+            .saturating_mul(1 << attempts.min(16))
+            .min(MAX_DELAY);
+        self.state = State::Lost {
+            attempts,
+            next_attempt: now + delay,
+        };
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Comes back after `failures` attempts
+    struct FakeClient {
+        failures: u32,
+        attempts: u32,
+    }
+
+    impl Client for FakeClient {
+        fn reconnect(&mut self) -> bool {
+            self.attempts += 1;
+            self.attempts > self.failures
+        }
+    }
+
+    #[test]
+    fn running_until_lost() {
+        let mut supervisor = Supervisor::default();
+        let mut client = FakeClient {
+            failures: 0,
+            attempts: 0,
+        };
+        let now = Instant::now();
+        assert_eq!(supervisor.poll(&mut client, now + MAX_DELAY), None);
+        assert_eq!(client.attempts, 0);
+        assert_eq!(supervisor.state(), State::Running);
+    }
+
+    #[test]
+    fn lost_and_back() {
+        let mut supervisor = Supervisor::default();
+        let mut client = FakeClient {
+            failures: 2,
+            attempts: 0,
+        };
+        let start = Instant::now();
+        assert_eq!(supervisor.lost(start), Some(Transition::Suspend));
+        // Losing it again, e.g. a second quit event, changes nothing
+        assert_eq!(supervisor.lost(start), None);
+
+        // Too early
+        assert_eq!(supervisor.poll(&mut client, start), None);
+        assert_eq!(client.attempts, 0);
+
+        // Fails twice, waiting twice as long after the second time
+        let first = start + INITIAL_DELAY;
+        assert_eq!(supervisor.poll(&mut client, first), None);
+        let second = first + INITIAL_DELAY * 2;
+        assert_eq!(supervisor.poll(&mut client, second - INITIAL_DELAY), None);
+        assert_eq!(client.attempts, 1);
+        assert_eq!(supervisor.poll(&mut client, second), None);
+        assert_eq!(
+            supervisor.state(),
+            State::Lost {
+                attempts: 2,
+                next_attempt: second + INITIAL_DELAY * 4
+            }
+        );
+
+        let third = second + INITIAL_DELAY * 4;
+        assert_eq!(
+            supervisor.poll(&mut client, third),
+            Some(Transition::Resume)
+        );
+        assert_eq!(supervisor.state(), State::Running);
+
+        // And it can be lost again
+        assert_eq!(supervisor.lost(third), Some(Transition::Suspend));
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut supervisor = Supervisor::default();
+        let mut client = FakeClient {
+            failures: u32::MAX,
+            attempts: 0,
+        };
+        let mut now = Instant::now();
+        supervisor.lost(now);
+        for _ in 0..40 {
+            now += MAX_DELAY;
+            assert_eq!(supervisor.poll(&mut client, now), None);
+        }
+        assert_eq!(client.attempts, 40);
+        match supervisor.state() {
+            State::Lost { next_attempt, .. } => assert_eq!(next_attempt, now + MAX_DELAY),
+            state => panic!("unexpected state {state:?}"),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! systemd service notifications, see sd_notify(3).
+//!
+//! When started as a `Type=notify` service, systemd passes a datagram socket in `NOTIFY_SOCKET`,
+//! to which we report when we are ready, a status line for `systemctl status`, and watchdog
+//! pings if `WatchdogSec=` is set.
+use std::{
+    io,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    path::Path,
+    time::Duration,
+};
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// How often the watchdog has to be pinged, None if it isn't enabled
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Connect to the socket systemd gave us, None if we weren't started by systemd.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let path = path.to_string_lossy();
+        let addr = match path.strip_prefix('@') {
+            // Abstract socket
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            None => SocketAddr::from_pathname(&*path)?,
+        };
+        let watchdog = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+            std::process::id(),
+        );
+        Ok(Some(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+        }))
+    }
+
+    /// Notify the socket at `path`, with the watchdog set to `watchdog`.
+    #[cfg(test)]
+    fn new(path: &Path, watchdog: Option<Duration>) -> io::Result<Self> {
+        Ok(Self {
+            socket: UnixDatagram::unbound()?,
+            addr: SocketAddr::from_pathname(path)?,
+            watchdog,
+        })
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            tracing::warn!("Failed to notify systemd: {e}");
+        }
+    }
+
+    /// Startup is finished, units ordered after us can start.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    /// Free form status, shown by `systemctl status`.
+    pub fn status(&self, status: &str) {
+        // Newlines would start a new assignment
+        self.notify(&format!("STATUS={}", status.replace('\n', " ")));
+    }
+
+    /// How often `watchdog` has to be called. Half of what systemd asks for, so a late ping
+    /// doesn't get us killed.
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.watchdog.map(|timeout| timeout / 2)
+    }
+
+    /// Tell the watchdog we are still alive.
+    pub fn watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+}
+
+/// The watchdog timeout from `WATCHDOG_USEC`, if it is meant for the process `pid`.
+/// `WATCHDOG_PID` is only set when the variables might be inherited by other processes.
+fn watchdog_interval(usec: Option<&str>, watchdog_pid: Option<&str>, pid: u32) -> Option<Duration> {
+    if let Some(watchdog_pid) = watchdog_pid {
+        if watchdog_pid.parse::<u32>().ok()? != pid {
+            return None;
+        }
+    }
+    match usec?.parse().ok()? {
+        0 => None,
+        usec => Some(Duration::from_micros(usec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A socket standing in for systemd's, and a notifier sending to it.
+    fn mock(watchdog: Option<Duration>) -> (UnixDatagram, Notifier, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "picom-xrdesktop-companion-notify-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let notifier = Notifier::new(&path, watchdog).unwrap();
+        (socket, notifier, path)
+    }
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn messages() {
+        let (socket, notifier, path) = mock(None);
+        notifier.ready();
+        assert_eq!(recv(&socket), "READY=1");
+        notifier.status("Mirroring 2 windows\nof 3");
+        assert_eq!(recv(&socket), "STATUS=Mirroring 2 windows of 3");
+        notifier.watchdog();
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn watchdog() {
+        let (_socket, notifier, path) = mock(Some(Duration::from_secs(10)));
+        assert_eq!(notifier.watchdog_interval(), Some(Duration::from_secs(5)));
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(
+            watchdog_interval(Some("3000000"), None, 42),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(
+            watchdog_interval(Some("3000000"), Some("42"), 42),
+            Some(Duration::from_secs(3))
+        );
+        // Meant for another process
+        assert_eq!(watchdog_interval(Some("3000000"), Some("41"), 42), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 42), None);
+        assert_eq!(watchdog_interval(None, None, 42), None);
+        assert_eq!(watchdog_interval(Some("soon"), None, 42), None);
+    }
+}
@@ -0,0 +1,436 @@
+//! Where the windows to mirror come from: picom over D-Bus (`picom::PicomSource`), or the X
+//! server directly (`X11Source`), for when picom isn't running or was built without D-Bus.
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use tokio::task::block_in_place;
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        composite::{self, ConnectionExt as _},
+        xproto::{self, ConnectionExt as _},
+        Event,
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{config::Config, picom, Result};
+
+/// Which `WindowSource` finds the windows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// picom's D-Bus interface
+    Picom,
+    /// The X server, with whatever compositor, or none
+    X11,
+    /// picom if it is there, the X server otherwise
+    #[default]
+    Auto,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Picom => "picom",
+            Self::X11 => "x11",
+            Self::Auto => "auto",
+        })
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "picom" => Ok(Self::Picom),
+            "x11" => Ok(Self::X11),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!(
+                "unknown backend {s:?}, expected picom, x11 or auto"
+            )),
+        }
+    }
+}
+
+/// Connect to the window source `config.backend` says. With `Backend::Auto`, picom is only waited
+/// for as long as `picom-timeout` says, then windows are found through the X server. Returns
+/// the backend actually used.
+pub async fn connect(
+    config: &Config,
+    dbus: &zbus::Connection,
+    x11: &Arc<RustConnection>,
+    root: xproto::Window,
+    display: &str,
+) -> Result<(Backend, Box<dyn WindowSource>)> {
+    let picom_service = picom::service_name(&config.picom_service, display);
+    Ok(match config.backend {
+        Backend::Picom => {
+            let timeout = match config.picom_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            };
+            let source =
+                picom::PicomSource::connect(dbus, picom_service, timeout, config.picom_retries)
+                    .await?;
+            (Backend::Picom, Box::new(source))
+        }
+        Backend::Auto => {
+            let timeout = Duration::from_secs(config.picom_timeout);
+            match picom::PicomSource::connect(
+                dbus,
+                picom_service,
+                Some(timeout),
+                config.picom_retries,
+            )
+            .await
+            {
+                Ok(source) => (Backend::Picom, Box::new(source)),
+                Err(e) => {
+                    tracing::info!("Not using picom: {e:#}");
+                    (Backend::X11, Box::new(X11Source::new(x11.clone(), root)?))
+                }
+            }
+        }
+        Backend::X11 => (Backend::X11, Box::new(X11Source::new(x11.clone(), root)?)),
+    })
+}
+
+/// What `map_win` needs to know about a top level window
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WindowInfo {
+    pub mapped: bool,
+    /// The window's type, like "normal" or "popup_menu"
+    pub ty: String,
+    pub name: String,
+    pub client_win: u32,
+    /// Empty if unknown
+    pub class_instance: String,
+    pub class_general: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowEvent {
+    Mapped(u32),
+    /// Unmapped, or closed
+    Unmapped(u32),
+    /// The window id can be reused from now on
+    Destroyed(u32),
+}
+
+pub trait WindowSource: Send + Sync {
+    /// Top level windows that are already there when we start.
+    fn initial_windows(&self) -> BoxFuture<'_, Result<Vec<u32>>>;
+    fn window_info(&self, wid: u32) -> BoxFuture<'_, Result<WindowInfo>>;
+    /// Windows being mapped, unmapped and destroyed from now on. Only called once.
+    fn events(&self) -> BoxFuture<'_, Result<BoxStream<'static, Result<WindowEvent>>>>;
+    /// Called with every X event, before it is handled, for sources following windows through
+    /// X.
+    fn x_event(&self, _event: &Event) {}
+}
+
+x11rb::atom_manager! {
+    Atoms: AtomsCookie {
+        WM_STATE,
+        UTF8_STRING,
+        _NET_CLIENT_LIST,
+        _NET_WM_NAME,
+        _NET_WM_WINDOW_TYPE,
+    }
+}
+
+/// Finds windows through the X server, from `_NET_CLIENT_LIST` and the root window's
+/// SubstructureNotify events. Window contents come from Composite as with picom, so windows are
+/// redirected, in case no compositor does that.
+pub struct X11Source {
+    x11: Arc<RustConnection>,
+    root: xproto::Window,
+    atoms: Atoms,
+    tx: futures::channel::mpsc::UnboundedSender<WindowEvent>,
+    rx: std::sync::Mutex<Option<futures::channel::mpsc::UnboundedReceiver<WindowEvent>>>,
+}
+
+impl X11Source {
+    /// `root` has to have SubstructureNotify selected, see `App::new`.
+    pub fn new(x11: Arc<RustConnection>, root: xproto::Window) -> Result<Self> {
+        let atoms = block_in_place(|| {
+            x11.composite_query_version(0, 4)?.reply()?;
+            // Automatic redirection keeps the screen as it is. It can be combined with a
+            // compositor's manual redirection.
+            x11.composite_redirect_subwindows(root, composite::Redirect::AUTOMATIC)?
+                .check()?;
+            Result::Ok(Atoms::new(&*x11)?.reply()?)
+        })?;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        Ok(Self {
+            x11,
+            root,
+            atoms,
+            tx,
+            rx: std::sync::Mutex::new(Some(rx)),
+        })
+    }
+
+    fn has_wm_state(&self, wid: u32) -> Result<bool> {
+        let reply = self
+            .x11
+            .get_property(false, wid, self.atoms.WM_STATE, self.atoms.WM_STATE, 0, 0)?
+            .reply()?;
+        Ok(reply.type_ != x11rb::NONE)
+    }
+
+    /// The window with `WM_STATE` in `wid` or below, like picom's `ClientWin`: with a
+    /// reparenting window manager, `wid` is the frame and the client is inside. `wid` itself if
+    /// there is none.
+    fn client_window(&self, wid: u32) -> Result<u32> {
+        let mut queue = VecDeque::from([wid]);
+        while let Some(window) = queue.pop_front() {
+            if self.has_wm_state(window)? {
+                return Ok(window);
+            }
+            queue.extend(self.x11.query_tree(window)?.reply()?.children);
+        }
+        Ok(wid)
+    }
+
+    /// The child of the root window that contains `wid`.
+    fn toplevel(&self, mut wid: u32) -> Result<u32> {
+        loop {
+            let parent = self.x11.query_tree(wid)?.reply()?.parent;
+            if parent == self.root || parent == x11rb::NONE {
+                return Ok(wid);
+            }
+            wid = parent;
+        }
+    }
+
+    /// Named like picom does, the lowercase end of the first `_NET_WM_WINDOW_TYPE` atom.
+    fn window_type(&self, client: u32) -> Result<String> {
+        let types = self
+            .x11
+            .get_property(
+                false,
+                client,
+                self.atoms._NET_WM_WINDOW_TYPE,
+                xproto::AtomEnum::ATOM,
+                0,
+                1,
+            )?
+            .reply()?;
+        if let Some(ty) = types.value32().and_then(|mut types| types.next()) {
+            let name = self.x11.get_atom_name(ty)?.reply()?.name;
+            let name = String::from_utf8_lossy(&name);
+            let name = name.strip_prefix("_NET_WM_WINDOW_TYPE_").unwrap_or(&name);
+            return Ok(name.to_lowercase());
+        }
+        // Without a type, transient windows are dialogs, see the EWMH spec
+        let transient_for = self
+            .x11
+            .get_property(
+                false,
+                client,
+                xproto::AtomEnum::WM_TRANSIENT_FOR,
+                xproto::AtomEnum::WINDOW,
+                0,
+                1,
+            )?
+            .reply()?;
+        Ok(if transient_for.value_len > 0 {
+            "dialog".into()
+        } else {
+            "normal".into()
+        })
+    }
+
+    fn name(&self, client: u32) -> Result<String> {
+        let net_wm_name = self
+            .x11
+            .get_property(
+                false,
+                client,
+                self.atoms._NET_WM_NAME,
+                self.atoms.UTF8_STRING,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        if net_wm_name.value_len > 0 {
+            return Ok(String::from_utf8_lossy(&net_wm_name.value).into_owned());
+        }
+        let wm_name = self
+            .x11
+            .get_property(
+                false,
+                client,
+                xproto::AtomEnum::WM_NAME,
+                xproto::AtomEnum::ANY,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        Ok(String::from_utf8_lossy(&wm_name.value).into_owned())
+    }
+
+    /// Instance and class parts of `WM_CLASS`
+    fn class(&self, client: u32) -> Result<(String, String)> {
+        let wm_class = self
+            .x11
+            .get_property(
+                false,
+                client,
+                xproto::AtomEnum::WM_CLASS,
+                xproto::AtomEnum::STRING,
+                0,
+                u32::MAX,
+            )?
+            .reply()?;
+        let mut parts = wm_class
+            .value
+            .split(|&b| b == 0)
+            .map(|part| String::from_utf8_lossy(part).into_owned());
+        Ok((
+            parts.next().unwrap_or_default(),
+            parts.next().unwrap_or_default(),
+        ))
+    }
+}
+
+impl WindowSource for X11Source {
+    fn initial_windows(&self) -> BoxFuture<'_, Result<Vec<u32>>> {
+        async move {
+            block_in_place(|| {
+                let client_list = self
+                    .x11
+                    .get_property(
+                        false,
+                        self.root,
+                        self.atoms._NET_CLIENT_LIST,
+                        xproto::AtomEnum::WINDOW,
+                        0,
+                        u32::MAX,
+                    )?
+                    .reply()?;
+                let mut windows = Vec::new();
+                match client_list.value32() {
+                    // The list has client windows, we mirror their frames
+                    Some(clients) => {
+                        for client in clients {
+                            match self.toplevel(client) {
+                                Ok(wid) if !windows.contains(&wid) => windows.push(wid),
+                                Ok(_) => (),
+                                Err(e) => tracing::debug!("{client:#010x} is gone: {e}"),
+                            }
+                        }
+                    }
+                    // Without an EWMH window manager, all mapped top level windows
+                    None => {
+                        windows = self.x11.query_tree(self.root)?.reply()?.children;
+                    }
+                }
+                Ok(windows)
+            })
+        }
+        .boxed()
+    }
+
+    fn window_info(&self, wid: u32) -> BoxFuture<'_, Result<WindowInfo>> {
+        async move {
+            block_in_place(|| {
+                let attrs = self.x11.get_window_attributes(wid)?.reply()?;
+                let client_win = self.client_window(wid)?;
+                let (class_instance, class_general) = self.class(client_win)?;
+                Ok(WindowInfo {
+                    mapped: attrs.map_state == xproto::MapState::VIEWABLE,
+                    ty: self.window_type(client_win)?,
+                    name: self.name(client_win)?,
+                    client_win,
+                    class_instance,
+                    class_general,
+                })
+            })
+        }
+        .boxed()
+    }
+
+    fn events(&self) -> BoxFuture<'_, Result<BoxStream<'static, Result<WindowEvent>>>> {
+        let rx = self.rx.lock().unwrap().take();
+        async move {
+            let rx = rx.ok_or_else(|| anyhow::anyhow!("window events were already taken"))?;
+            Ok(rx.map(Ok).boxed())
+        }
+        .boxed()
+    }
+
+    fn x_event(&self, event: &Event) {
+        // Only events about children of the root window, the top level windows
+        let event = match event {
+            Event::MapNotify(e) if e.event == self.root => WindowEvent::Mapped(e.window),
+            Event::UnmapNotify(e) if e.event == self.root => WindowEvent::Unmapped(e.window),
+            Event::DestroyNotify(e) if e.event == self.root => WindowEvent::Destroyed(e.window),
+            _ => return,
+        };
+        // Only fails once the receiver is gone, when we are shutting down
+        let _ = self.tx.unbounded_send(event);
+    }
+}
+
+/// Windows of several X screens, each found by a source of its own, as picom runs once per
+/// screen
+pub struct Screens {
+    x11: Arc<RustConnection>,
+    /// By the root window of their screen
+    sources: Vec<(xproto::Window, Box<dyn WindowSource>)>,
+}
+
+impl Screens {
+    pub fn new(
+        x11: Arc<RustConnection>,
+        sources: Vec<(xproto::Window, Box<dyn WindowSource>)>,
+    ) -> Self {
+        Self { x11, sources }
+    }
+
+    /// The source of the screen `wid` is on
+    fn source(&self, wid: u32) -> Result<&dyn WindowSource> {
+        let root = block_in_place(|| Result::Ok(self.x11.get_geometry(wid)?.reply()?.root))?;
+        self.sources
+            .iter()
+            .find(|(r, _)| *r == root)
+            .map(|(_, source)| &**source)
+            .ok_or_else(|| anyhow::anyhow!("{wid:#010x} is on a screen that isn't mirrored"))
+    }
+}
+
+impl WindowSource for Screens {
+    fn initial_windows(&self) -> BoxFuture<'_, Result<Vec<u32>>> {
+        async move {
+            let mut windows = Vec::new();
+            for (_, source) in &self.sources {
+                windows.extend(source.initial_windows().await?);
+            }
+            Ok(windows)
+        }
+        .boxed()
+    }
+
+    fn window_info(&self, wid: u32) -> BoxFuture<'_, Result<WindowInfo>> {
+        async move { self.source(wid)?.window_info(wid).await }.boxed()
+    }
+
+    fn events(&self) -> BoxFuture<'_, Result<BoxStream<'static, Result<WindowEvent>>>> {
+        async move {
+            let mut events = Vec::new();
+            for (_, source) in &self.sources {
+                events.push(source.events().await?);
+            }
+            Ok(futures::stream::select_all(events).boxed())
+        }
+        .boxed()
+    }
+
+    fn x_event(&self, event: &Event) {
+        for (_, source) in &self.sources {
+            source.x_event(event);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Counters of what the companion is doing, to find out what makes it slow. They are only
+//! atomics bumped on the render path; everything else is computed when they are read.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Counters of one window
+#[derive(Debug, Default)]
+pub struct Counters {
+    damage_events: AtomicU64,
+    blits: AtomicU64,
+    submits: AtomicU64,
+    /// Renders skipped because the window is hidden in VR
+    hidden_skips: AtomicU64,
+    texture_allocations: AtomicU64,
+    render_time_us: AtomicU64,
+}
+
+impl Counters {
+    pub fn damage_event(&self) {
+        self.damage_events.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn blit(&self) {
+        self.blits.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn submit(&self) {
+        self.submits.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn hidden_skip(&self) {
+        self.hidden_skips.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn texture_allocation(&self) {
+        self.texture_allocations.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Time `render_win` took for one frame
+    pub fn render_time(&self, time: Duration) {
+        self.render_time_us
+            .fetch_add(time.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            damage_events: self.damage_events.load(Ordering::Relaxed),
+            blits: self.blits.load(Ordering::Relaxed),
+            submits: self.submits.load(Ordering::Relaxed),
+            hidden_skips: self.hidden_skips.load(Ordering::Relaxed),
+            texture_allocations: self.texture_allocations.load(Ordering::Relaxed),
+            render_time_us: self.render_time_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Values of `Counters` at some point
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Snapshot {
+    pub damage_events: u64,
+    pub blits: u64,
+    pub submits: u64,
+    pub hidden_skips: u64,
+    pub texture_allocations: u64,
+    pub render_time_us: u64,
+}
+
+impl Snapshot {
+    fn add(&mut self, other: &Self) {
+        self.damage_events += other.damage_events;
+        self.blits += other.blits;
+        self.submits += other.submits;
+        self.hidden_skips += other.hidden_skips;
+        self.texture_allocations += other.texture_allocations;
+        self.render_time_us += other.render_time_us;
+    }
+
+    /// What happened between `previous` and `self`.
+    pub fn since(&self, previous: &Self) -> Self {
+        Self {
+            damage_events: self.damage_events.saturating_sub(previous.damage_events),
+            blits: self.blits.saturating_sub(previous.blits),
+            submits: self.submits.saturating_sub(previous.submits),
+            hidden_skips: self.hidden_skips.saturating_sub(previous.hidden_skips),
+            texture_allocations: self
+                .texture_allocations
+                .saturating_sub(previous.texture_allocations),
+            render_time_us: self.render_time_us.saturating_sub(previous.render_time_us),
+        }
+    }
+
+    /// Average time to render a frame, every frame is blitted once.
+    pub fn average_render_time_us(&self) -> u64 {
+        self.render_time_us.checked_div(self.blits).unwrap_or(0)
+    }
+
+    /// As exposed over D-Bus
+    pub fn to_map(self) -> HashMap<String, u64> {
+        [
+            ("damage-events", self.damage_events),
+            ("blits", self.blits),
+            ("submits", self.submits),
+            ("hidden-skips", self.hidden_skips),
+            ("texture-allocations", self.texture_allocations),
+            ("average-render-time-us", self.average_render_time_us()),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_owned(), value))
+        .collect()
+    }
+}
+
+/// Counters of all mirrored windows
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    windows: Mutex<HashMap<u32, Arc<Counters>>>,
+    /// Sum of the counters of windows that are gone, so the totals don't go down
+    retired: Mutex<Snapshot>,
+    /// VR input isn't passed on to the desktop, because nobody is wearing the headset
+    input_blocked: AtomicBool,
+    /// Input events dropped while `input_blocked`
+    blocked_input_events: AtomicU64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            windows: Default::default(),
+            retired: Default::default(),
+            input_blocked: AtomicBool::new(false),
+            blocked_input_events: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Stats {
+    /// New counters for the window `wid`.
+    pub fn register(&self, wid: u32) -> Arc<Counters> {
+        let counters = Arc::new(Counters::default());
+        let old = self.windows.lock().unwrap().insert(wid, counters.clone());
+        if let Some(old) = old {
+            self.retired.lock().unwrap().add(&old.snapshot());
+        }
+        counters
+    }
+
+    /// The window `wid` isn't mirrored anymore.
+    pub fn unregister(&self, wid: u32) {
+        let old = self.windows.lock().unwrap().remove(&wid);
+        if let Some(old) = old {
+            self.retired.lock().unwrap().add(&old.snapshot());
+        }
+    }
+
+    pub fn windows(&self) -> HashMap<u32, Snapshot> {
+        let windows = self.windows.lock().unwrap();
+        windows
+            .iter()
+            .map(|(wid, counters)| (*wid, counters.snapshot()))
+            .collect()
+    }
+
+    /// Counters of all windows since we started, including the ones that are gone.
+    pub fn total(&self) -> Snapshot {
+        let mut total = *self.retired.lock().unwrap();
+        for snapshot in self.windows().values() {
+            total.add(snapshot);
+        }
+        total
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn input_blocked(&self) -> bool {
+        self.input_blocked.load(Ordering::Acquire)
+    }
+
+    pub fn set_input_blocked(&self, blocked: bool) {
+        self.input_blocked.store(blocked, Ordering::Release);
+    }
+
+    pub fn blocked_input_event(&self) {
+        self.blocked_input_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn blocked_input_events(&self) -> u64 {
+        self.blocked_input_events.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totals_include_gone_windows() {
+        let stats = Stats::default();
+        let a = stats.register(1);
+        let b = stats.register(2);
+        a.blit();
+        a.render_time(Duration::from_micros(300));
+        b.blit();
+        b.render_time(Duration::from_micros(100));
+        b.submit();
+        stats.unregister(2);
+        a.damage_event();
+
+        assert_eq!(stats.windows().len(), 1);
+        let total = stats.total();
+        assert_eq!(total.blits, 2);
+        assert_eq!(total.submits, 1);
+        assert_eq!(total.damage_events, 1);
+        assert_eq!(total.average_render_time_us(), 200);
+
+        // Registering a window id again starts from zero, without losing the old counts
+        stats.register(1);
+        assert_eq!(stats.windows()[&1], Snapshot::default());
+        assert_eq!(stats.total().blits, 2);
+    }
+
+    #[test]
+    fn since() {
+        let counters = Counters::default();
+        counters.submit();
+        let before = counters.snapshot();
+        counters.submit();
+        counters.hidden_skip();
+        counters.texture_allocation();
+        let delta = counters.snapshot().since(&before);
+        assert_eq!(delta.submits, 1);
+        assert_eq!(delta.hidden_skips, 1);
+        assert_eq!(delta.texture_allocations, 1);
+        assert_eq!(delta.average_render_time_us(), 0);
+        assert_eq!(delta.to_map()["submits"], 1);
+    }
+}
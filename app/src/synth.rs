@@ -0,0 +1,533 @@
+//! Handle to the input synthesizer.
+//!
+//! Pointer input goes through either libinputsynth's xdo backend or XTEST, see `Backend`. Keys
+//! always go through XTEST, as we need to follow the keyboard layout.
+//!
+//! Threading: `inputsynth::InputSynth` wraps a GObject that isn't thread safe, while input is
+//! forwarded from tokio tasks on any thread. So the synthesizer lives on the thread that owns the
+//! glib main context, and `InputSynth` is a cheap handle posting requests to it over a glib
+//! channel. Requests are carried out in the order they are posted, and a click moves the pointer
+//! in the same request, so nothing can move it in between.
+use std::{
+    cell::RefCell,
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        xproto::{self, ConnectionExt as _},
+        xtest::{self, ConnectionExt as _},
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::{
+    input::KeyRepeat,
+    keymap::{self, Key, Keymap},
+    Result,
+};
+
+/// Implementation of pointer input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// libinputsynth, which uses libxdo
+    #[default]
+    Xdo,
+    /// XTEST fake input, some applications handle it better
+    Xtest,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Xdo => "xdo",
+            Self::Xtest => "xtest",
+        })
+    }
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "xdo" => Ok(Self::Xdo),
+            "xtest" => Ok(Self::Xtest),
+            _ => Err(format!(
+                "unknown input backend {s:?}, expected xdo or xtest"
+            )),
+        }
+    }
+}
+
+enum Request {
+    MoveTo {
+        root: xproto::Window,
+        x: i32,
+        y: i32,
+    },
+    Click {
+        root: xproto::Window,
+        x: i32,
+        y: i32,
+        button: i32,
+        pressed: bool,
+    },
+    Text {
+        string: String,
+        held: Vec<xproto::Keysym>,
+    },
+    Key {
+        keysym: xproto::Keysym,
+        held: Vec<xproto::Keysym>,
+    },
+    MappingChanged {
+        first_keycode: xproto::Keycode,
+        count: u8,
+    },
+}
+
+/// Unused keycodes we bind keysyms to, and the keysym currently bound, `NO_SYMBOL` if none.
+/// Used round robin, so a keycode isn't rebound while the application might still be looking it
+/// up. Shared with `InputSynth`, so they can be unbound from any thread at shutdown.
+type SpareKeycodes = Arc<Mutex<VecDeque<(xproto::Keycode, xproto::Keysym)>>>;
+
+#[derive(Clone)]
+pub struct InputSynth {
+    tx: glib::Sender<Request>,
+    backend: Backend,
+    x11: Arc<RustConnection>,
+    spare_keycodes: SpareKeycodes,
+}
+
+impl InputSynth {
+    /// Use the `preferred` backend, or the other one if it can't be used.
+    ///
+    /// Has to be called on the thread owning the default glib main context, the synthesizer
+    /// lives there.
+    pub fn new(x11: Arc<RustConnection>, preferred: Backend) -> Result<Self> {
+        let context = glib::MainContext::default();
+        anyhow::ensure!(
+            context.is_owner(),
+            "The input synthesizer must be created on the glib main context thread"
+        );
+        let has_xtest = x11
+            .extension_information(xtest::X11_EXTENSION_NAME)?
+            .is_some();
+        let xdo = || inputsynth::InputSynth::new().context("Failed to initialize inputsynth");
+        let xdo = match (preferred, has_xtest) {
+            (Backend::Xtest, true) => None,
+            (Backend::Xtest, false) => {
+                tracing::warn!("XTEST is not available, falling back to xdo");
+                Some(xdo()?)
+            }
+            (Backend::Xdo, _) => match xdo() {
+                Ok(xdo) => Some(xdo),
+                Err(e) if has_xtest => {
+                    tracing::warn!("{e:#}, falling back to XTEST");
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        let backend = if xdo.is_some() {
+            Backend::Xdo
+        } else {
+            Backend::Xtest
+        };
+        tracing::info!("Synthesizing pointer input with {backend}");
+        let mapping = keyboard_mapping(&x11)?;
+        let spare_keycodes = spare_keycodes(&x11, &mapping);
+        if spare_keycodes.is_empty() {
+            tracing::warn!(
+                "No unused keycodes, characters not on the keyboard layout can't be typed"
+            );
+        }
+        let spare_keycodes: SpareKeycodes = Arc::new(Mutex::new(
+            spare_keycodes
+                .into_iter()
+                .map(|k| (k, x11rb::NO_SYMBOL))
+                .collect(),
+        ));
+        let synth = Synth {
+            xdo,
+            x11: x11.clone(),
+            keymap: Default::default(),
+            spare_keycodes: spare_keycodes.clone(),
+        };
+        synth.update_keymap(&mapping);
+        let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        rx.attach(Some(&context), move |request| {
+            if let Err(e) = synth.handle(request) {
+                tracing::error!("Failed to synthesize input: {e:#}");
+            }
+            glib::Continue(true)
+        });
+        Ok(Self {
+            tx,
+            backend,
+            x11,
+            spare_keycodes,
+        })
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// Move the pointer to `(x, y)` in coordinates of `root`, the root window of the screen to
+    /// move it to.
+    pub fn move_to(&self, root: xproto::Window, x: i32, y: i32) -> Result<()> {
+        self.send(Request::MoveTo { root, x, y })
+    }
+
+    /// Move the pointer to `(x, y)` on the screen of `root`, and press or release `button` there.
+    pub fn click(
+        &self,
+        root: xproto::Window,
+        x: i32,
+        y: i32,
+        button: i32,
+        pressed: bool,
+    ) -> Result<()> {
+        self.send(Request::Click {
+            root,
+            x,
+            y,
+            button,
+            pressed,
+        })
+    }
+
+    /// Type `string`, UTF-8 text, character by character. Characters the keyboard layout
+    /// doesn't have are typed through a spare keycode. The modifier keys `held` are held down
+    /// while typing.
+    pub fn text(&self, string: String, held: Vec<xproto::Keysym>) -> Result<()> {
+        self.send(Request::Text { string, held })
+    }
+
+    /// Press and release the key producing `keysym`, for keys without text like arrows, Return
+    /// or Backspace. The modifier keys `held` are held down meanwhile.
+    pub fn key(&self, keysym: xproto::Keysym, held: Vec<xproto::Keysym>) -> Result<()> {
+        self.send(Request::Key { keysym, held })
+    }
+
+    /// Unbind the keysyms bound to spare keycodes, leaving the keyboard mapping as we found it.
+    /// For shutdown, doesn't wait for the synthesizer thread.
+    pub fn unbind_spare_keycodes(&self) -> Result<()> {
+        unbind_spare_keycodes(&self.x11, &self.spare_keycodes)
+    }
+
+    /// Handle a MappingNotify for the keyboard, the layout might have changed.
+    pub fn mapping_changed(&self, first_keycode: xproto::Keycode, count: u8) -> Result<()> {
+        self.send(Request::MappingChanged {
+            first_keycode,
+            count,
+        })
+    }
+
+    fn send(&self, request: Request) -> Result<()> {
+        self.tx
+            .send(request)
+            .map_err(|_| anyhow::anyhow!("The input synthesizer has stopped"))
+    }
+}
+
+pub fn keyboard_mapping(x11: &RustConnection) -> Result<xproto::GetKeyboardMappingReply> {
+    let setup = x11.setup();
+    let count = setup.max_keycode - setup.min_keycode + 1;
+    Ok(x11
+        .get_keyboard_mapping(setup.min_keycode, count)?
+        .reply()?)
+}
+
+/// Keycodes that have no keysyms bound to them in the keyboard mapping.
+fn spare_keycodes(
+    x11: &RustConnection,
+    mapping: &xproto::GetKeyboardMappingReply,
+) -> Vec<xproto::Keycode> {
+    let setup = x11.setup();
+    let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+    mapping
+        .keysyms
+        .chunks(per_keycode)
+        .zip(setup.min_keycode..=setup.max_keycode)
+        .filter(|(keysyms, _)| keysyms.iter().all(|&k| k == x11rb::NO_SYMBOL))
+        .map(|(_, keycode)| keycode)
+        .collect()
+}
+
+/// Bind `NO_SYMBOL` to the spare keycodes that have a keysym bound.
+fn unbind_spare_keycodes(
+    x11: &RustConnection,
+    spare_keycodes: &Mutex<VecDeque<(xproto::Keycode, xproto::Keysym)>>,
+) -> Result<()> {
+    for (keycode, bound) in spare_keycodes.lock().unwrap().iter_mut() {
+        if *bound != x11rb::NO_SYMBOL {
+            x11.change_keyboard_mapping(1, *keycode, 1, &[x11rb::NO_SYMBOL])?
+                .check()?;
+            *bound = x11rb::NO_SYMBOL;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a key of the VR keyboard that produced `string` is typed as text, rather than by its
+/// keysym. Keys without text, like arrows, and control keys whose text is a control character,
+/// like Return or Backspace, are typed by their keysym.
+pub fn is_text(string: &str) -> bool {
+    !string.is_empty() && !string.chars().any(char::is_control)
+}
+
+/// The X server's key repeat delay and interval, None if auto-repeat is turned off.
+pub fn server_key_repeat(x11: &RustConnection) -> Result<Option<KeyRepeat>> {
+    use x11rb::protocol::xkb::{self, ConnectionExt as _};
+    if x11.get_keyboard_control()?.reply()?.global_auto_repeat == xproto::AutoRepeatMode::OFF {
+        return Ok(None);
+    }
+    // The core protocol only says whether keys repeat, the timing is in XKB
+    if x11.xkb_use_extension(1, 0)?.reply()?.supported {
+        let controls = x11
+            .xkb_get_controls(xkb::ID::USE_CORE_KBD.into())?
+            .reply()?;
+        return Ok(Some(KeyRepeat {
+            delay: Duration::from_millis(controls.repeat_delay.into()),
+            interval: Duration::from_millis(controls.repeat_interval.into()),
+        }));
+    }
+    Ok(Some(KeyRepeat::DEFAULT))
+}
+
+/// Keysym producing `ch`. Latin-1 characters have their own keysyms, everything else uses the
+/// Unicode keysym range.
+pub fn char_to_keysym(ch: char) -> xproto::Keysym {
+    match ch as u32 {
+        c @ (0x20..=0x7e | 0xa0..=0xff) => c,
+        c => 0x0100_0000 + c,
+    }
+}
+
+/// The input synthesizer. Types arbitrary text and keysyms through XTEST, following the
+/// active keyboard layout. Lives on the glib main context thread, see `InputSynth`.
+struct Synth {
+    /// None if pointer input goes through XTEST
+    xdo: Option<inputsynth::InputSynth>,
+    x11: Arc<RustConnection>,
+    keymap: RefCell<Keymap>,
+    spare_keycodes: SpareKeycodes,
+}
+
+impl Drop for Synth {
+    fn drop(&mut self) {
+        if let Err(e) = unbind_spare_keycodes(&self.x11, &self.spare_keycodes) {
+            tracing::warn!("Failed to unbind the spare keycodes: {e}");
+        }
+    }
+}
+
+impl Synth {
+    fn handle(&self, request: Request) -> Result<()> {
+        match request {
+            Request::MoveTo { root, x, y } => self.move_cursor(root, x, y),
+            Request::Click {
+                root,
+                x,
+                y,
+                button,
+                pressed,
+            } => {
+                // Always move the pointer right before pressing or releasing a button, otherwise
+                // the click lands wherever the pointer is if motion events were dropped.
+                self.move_cursor(root, x, y)?;
+                self.click(root, x, y, button, pressed)
+            }
+            Request::Text { string, held } => self.text(&string, &held),
+            Request::Key { keysym, held } => self.key(keysym, &held),
+            Request::MappingChanged {
+                first_keycode,
+                count,
+            } => self.mapping_changed(first_keycode, count),
+        }
+    }
+
+    /// The root window of the first screen, the only one xdo synthesizes input on
+    fn default_root(&self) -> xproto::Window {
+        self.x11.setup().roots[0].root
+    }
+
+    /// Move the pointer to `(x, y)` in coordinates of `root`.
+    fn move_cursor(&self, root: xproto::Window, x: i32, y: i32) -> Result<()> {
+        match &self.xdo {
+            Some(xdo) if root == self.default_root() => xdo.move_cursor(x as _, y as _)?,
+            // XTEST moves the pointer to the screen of `root`
+            _ => self.fake_input(xproto::MOTION_NOTIFY_EVENT, 0, root, x, y)?,
+        }
+        Ok(())
+    }
+
+    /// Press or release `button` with the pointer at `(x, y)` on the screen of `root`.
+    fn click(
+        &self,
+        root: xproto::Window,
+        x: i32,
+        y: i32,
+        button: i32,
+        pressed: bool,
+    ) -> Result<()> {
+        match &self.xdo {
+            Some(xdo) if root == self.default_root() => {
+                xdo.click(x as _, y as _, button as _, pressed)?
+            }
+            _ => {
+                let ty = if pressed {
+                    xproto::BUTTON_PRESS_EVENT
+                } else {
+                    xproto::BUTTON_RELEASE_EVENT
+                };
+                self.fake_input(ty, button as _, root, x, y)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Type `string` character by character. Control characters are typed with the keys
+    /// producing them.
+    fn text(&self, string: &str, held: &[xproto::Keysym]) -> Result<()> {
+        string.chars().try_for_each(|ch| {
+            let keysym = match ch {
+                '\n' | '\r' => keymap::RETURN,
+                '\t' => keymap::TAB,
+                '\x08' => keymap::BACKSPACE,
+                '\x1b' => keymap::ESCAPE,
+                '\x7f' => keymap::DELETE,
+                ch => char_to_keysym(ch),
+            };
+            self.key(keysym, held)
+        })
+    }
+
+    /// Press and release the key producing `keysym`, holding Shift or AltGr as the layout
+    /// requires. Keysyms the layout doesn't have are temporarily bound to an unused keycode.
+    /// The modifier keys `held` are pressed before the key and released after it, e.g.
+    /// `Control_L` for Ctrl+L.
+    fn key(&self, keysym: xproto::Keysym, held: &[xproto::Keysym]) -> Result<()> {
+        let bound = self
+            .spare_keycodes
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, bound)| *bound == keysym)
+            .map(|&(keycode, _)| keycode);
+        let key = bound
+            .map(|keycode| Key {
+                keycode,
+                modifiers: Default::default(),
+            })
+            .or_else(|| self.keymap.borrow().get(keysym));
+        let key = match key {
+            Some(key) => key,
+            None => Key {
+                keycode: self.bind_spare_keycode(keysym)?,
+                modifiers: Default::default(),
+            },
+        };
+        let keymap = self.keymap.borrow();
+        let held = held.iter().filter_map(|&modifier| {
+            let key = keymap.get(modifier);
+            if key.is_none() {
+                tracing::warn!("Modifier {modifier:#x} is not on the keyboard layout");
+            }
+            key.map(|k| k.keycode)
+        });
+        let modifiers: Vec<_> = held.chain(keymap.modifier_keys(key.modifiers)).collect();
+        drop(keymap);
+        let press = modifiers.iter().chain(Some(&key.keycode));
+        let release = press.clone().rev();
+        press
+            .map(|&k| (xproto::KEY_PRESS_EVENT, k))
+            .chain(release.map(|&k| (xproto::KEY_RELEASE_EVENT, k)))
+            .try_for_each(|(ty, keycode)| self.fake_key(ty, keycode))
+    }
+
+    fn fake_key(&self, ty: u8, keycode: xproto::Keycode) -> Result<()> {
+        self.fake_input(ty, keycode, self.default_root(), 0, 0)
+    }
+
+    fn fake_input(&self, ty: u8, detail: u8, root: xproto::Window, x: i32, y: i32) -> Result<()> {
+        self.x11
+            .xtest_fake_input(ty, detail, x11rb::CURRENT_TIME, root, x as _, y as _, 0)?
+            .check()?;
+        Ok(())
+    }
+
+    /// Handle a MappingNotify for the keyboard, the layout might have changed.
+    fn mapping_changed(&self, first_keycode: xproto::Keycode, count: u8) -> Result<()> {
+        let ours = count == 1
+            && self
+                .spare_keycodes
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|&(keycode, _)| keycode == first_keycode);
+        if !ours {
+            self.update_keymap(&keyboard_mapping(&self.x11)?);
+        }
+        Ok(())
+    }
+
+    fn update_keymap(&self, mapping: &xproto::GetKeyboardMappingReply) {
+        let skip: HashSet<_> = self
+            .spare_keycodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|&(k, _)| k)
+            .collect();
+        *self.keymap.borrow_mut() = Keymap::new(
+            self.x11.setup().min_keycode,
+            mapping.keysyms_per_keycode,
+            &mapping.keysyms,
+            &skip,
+        );
+    }
+
+    fn bind_spare_keycode(&self, keysym: xproto::Keysym) -> Result<xproto::Keycode> {
+        let mut spare_keycodes = self.spare_keycodes.lock().unwrap();
+        let (keycode, bound) = spare_keycodes
+            .front_mut()
+            .context("No unused keycode to bind the keysym to")?;
+        *bound = keysym;
+        let keycode = *keycode;
+        spare_keycodes.rotate_left(1);
+        self.x11
+            .change_keyboard_mapping(1, keycode, 1, &[keysym])?
+            .check()?;
+        Ok(keycode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{char_to_keysym, is_text};
+
+    #[test]
+    fn keysyms() {
+        assert_eq!(char_to_keysym('a'), 0x61);
+        assert_eq!(char_to_keysym('é'), 0xe9);
+        assert_eq!(char_to_keysym('€'), 0x0100_20ac);
+        assert_eq!(char_to_keysym('😀'), 0x0101_f600);
+    }
+
+    #[test]
+    fn text_or_keysym() {
+        assert!(is_text("a"));
+        assert!(is_text("€"));
+        assert!(!is_text(""));
+        assert!(!is_text("\r"));
+        assert!(!is_text("\x08"));
+    }
+}
@@ -0,0 +1,176 @@
+//! Keeping windows that draw all the time, like a game or a WebGL demo running at a high frame
+//! rate, from taking all the rendering time. A window damaged more often than `threshold` times a
+//! second is rendered at `interval` at most, until it calms down again. Damage in between isn't
+//! lost, it's rendered with the next frame.
+use std::time::{Duration, Instant};
+
+/// Damage is counted over periods of this length
+const PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Damage per second above which a window is throttled
+    pub threshold: u32,
+    /// Time between renders of a throttled window
+    pub interval: Duration,
+    /// How often to log that a window is still throttled
+    pub report_interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Report {
+    /// The window is throttled, it was damaged this many times a second
+    Throttled(u32),
+    /// The window isn't throttled anymore
+    Lifted,
+}
+
+/// Throttle state of one window
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    period_start: Instant,
+    damage: u32,
+    /// Damage per second in the last period, while throttled
+    throttled: Option<u32>,
+    last_render: Option<Instant>,
+    last_report: Option<Instant>,
+}
+
+impl Throttle {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            period_start: now,
+            damage: 0,
+            throttled: None,
+            last_render: None,
+            last_report: None,
+        }
+    }
+
+    /// Start a new period if the current one is over, and decide whether to throttle from the
+    /// damage in it
+    fn roll(&mut self, limits: &Limits, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.period_start);
+        if elapsed < PERIOD {
+            return;
+        }
+        let rate = (self.damage as f32 / elapsed.as_secs_f32()).round() as u32;
+        self.throttled = (rate > limits.threshold).then_some(rate);
+        self.period_start = now;
+        self.damage = 0;
+    }
+
+    /// The window was damaged
+    pub fn damage(&mut self, limits: &Limits, now: Instant) {
+        self.roll(limits, now);
+        self.damage += 1;
+    }
+
+    /// Whether the window can be rendered now. If not, the time it can be.
+    pub fn ready(&mut self, limits: &Limits, now: Instant) -> Result<(), Instant> {
+        self.roll(limits, now);
+        match self.last_render {
+            Some(last) if self.throttled.is_some() && now < last + limits.interval => {
+                Err(last + limits.interval)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The window was rendered
+    pub fn rendered(&mut self, now: Instant) {
+        self.last_render = Some(now);
+    }
+
+    /// Called periodically. Returns what to log: that the window got throttled, is still throttled
+    /// after `report_interval`, or isn't anymore.
+    pub fn poll(&mut self, limits: &Limits, now: Instant) -> Option<Report> {
+        self.roll(limits, now);
+        let due = self.last_report.map_or(true, |last| {
+            now.duration_since(last) >= limits.report_interval
+        });
+        match (self.throttled, self.last_report) {
+            (Some(rate), _) if due => {
+                self.last_report = Some(now);
+                Some(Report::Throttled(rate))
+            }
+            (None, Some(_)) => {
+                self.last_report = None;
+                Some(Report::Lifted)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        threshold: 90,
+        interval: Duration::from_millis(33),
+        report_interval: Duration::from_secs(10),
+    };
+
+    /// Damage `throttle` `rate` times a second for a second, rendering whenever it's ready.
+    /// Returns how often it was rendered.
+    fn draw(throttle: &mut Throttle, start: Instant, rate: u32) -> u32 {
+        let mut renders = 0;
+        for i in 0..rate {
+            let now = start + PERIOD * i / rate;
+            throttle.damage(&LIMITS, now);
+            if throttle.ready(&LIMITS, now).is_ok() {
+                throttle.rendered(now);
+                renders += 1;
+            }
+        }
+        renders
+    }
+
+    #[test]
+    fn slow_windows_not_throttled() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        assert_eq!(draw(&mut throttle, start, 60), 60);
+        assert_eq!(draw(&mut throttle, start + PERIOD, 60), 60);
+        assert_eq!(throttle.poll(&LIMITS, start + PERIOD * 2), None);
+    }
+
+    #[test]
+    fn busy_windows_throttled() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        // Only known once the first period is over
+        assert_eq!(draw(&mut throttle, start, 240), 240);
+        assert!(draw(&mut throttle, start + PERIOD, 240) <= 31);
+        assert_eq!(
+            throttle.poll(&LIMITS, start + PERIOD * 2),
+            Some(Report::Throttled(240))
+        );
+        // Damage right after a render waits for the interval
+        let now = start + PERIOD * 2;
+        throttle.rendered(now);
+        assert_eq!(throttle.ready(&LIMITS, now), Err(now + LIMITS.interval));
+        assert_eq!(throttle.ready(&LIMITS, now + LIMITS.interval), Ok(()));
+    }
+
+    #[test]
+    fn lifted_when_calm() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(start);
+        draw(&mut throttle, start, 240);
+        draw(&mut throttle, start + PERIOD, 240);
+        assert!(throttle.poll(&LIMITS, start + PERIOD * 2).is_some());
+        // Not again until report_interval
+        draw(&mut throttle, start + PERIOD * 2, 240);
+        assert_eq!(throttle.poll(&LIMITS, start + PERIOD * 3), None);
+        draw(&mut throttle, start + PERIOD * 3, 30);
+        assert_eq!(
+            throttle.poll(&LIMITS, start + PERIOD * 4),
+            Some(Report::Lifted)
+        );
+        assert_eq!(draw(&mut throttle, start + PERIOD * 4, 30), 30);
+        assert_eq!(throttle.poll(&LIMITS, start + PERIOD * 5), None);
+    }
+}
@@ -109,6 +109,49 @@ impl<T: 'static> Remote<T> {
     }
 }
 
+/// Run `f` on the thread owning `context`, as part of its dispatch, and return what `f` returns.
+/// Objects that aren't thread safe can be used in `f`, and signals connected there are emitted
+/// on that thread when the mainloop gets to them.
+///
+/// For the default context, this must not be awaited while holding `App::xrd_client` or
+/// `App::window_state`: the mainloop locks them before dispatching.
+pub async fn invoke<R: Send + 'static>(
+    context: &glib::MainContext,
+    f: impl FnOnce() -> R + Send + 'static,
+) -> Result<R> {
+    let (tx, rx) = oneshot_channel();
+    context.invoke(move || {
+        let _: Result<_, _> = tx.send(f());
+    });
+    Ok(rx.await?)
+}
+
+/// Call `f` until it succeeds, at most `attempts` times. After the first failure we wait `delay`,
+/// and twice as long after each further one. Failures are logged as `what`, the last one is
+/// returned.
+pub async fn retry<T, E: std::fmt::Display, F: std::future::Future<Output = Result<T, E>>>(
+    what: &str,
+    attempts: u32,
+    mut delay: std::time::Duration,
+    mut f: impl FnMut() -> F,
+) -> Result<T, E> {
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < attempts => {
+                tracing::warn!(
+                    "{what} failed (attempt {attempt}/{attempts}): {e}, retrying in {delay:?}"
+                );
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! gen_remote_fn {
     ($name:ident($($arg:ident : $ty:path),*) -> $reply:ty) => {
@@ -143,3 +186,69 @@ impl Drop for CompileDropBomb {
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, time::Duration};
+
+    use gio::prelude::*;
+
+    use super::{invoke, retry};
+
+    #[test]
+    fn signal_delivered_on_context_thread() {
+        // Like the default context owned by the main thread, while tasks run elsewhere
+        let context = glib::MainContext::new();
+        let mainloop = glib::MainLoop::new(Some(&context), false);
+        let glib_thread = std::thread::spawn({
+            let context = context.clone();
+            let mainloop = mainloop.clone();
+            move || {
+                context.with_thread_default(|| mainloop.run()).unwrap();
+            }
+        });
+
+        let (tx, rx) = mpsc::channel();
+        futures::executor::block_on(invoke(&context, move || {
+            let action = gio::SimpleAction::new("test", None);
+            action.connect_activate(move |_, _| {
+                tx.send(std::thread::current().id()).unwrap();
+            });
+            // Emitted later by the mainloop, like xrdesktop emits its input events
+            glib::idle_add_local_once(move || action.activate(None));
+        }))
+        .unwrap();
+        let emitted_on = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(emitted_on, glib_thread.thread().id());
+
+        mainloop.quit();
+        glib_thread.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_until_success_or_attempts_run_out() {
+        let mut calls = 0;
+        let result = retry("test", 5, Duration::from_millis(1), || {
+            calls += 1;
+            let calls = calls;
+            async move {
+                if calls < 3 {
+                    Err(calls)
+                } else {
+                    Ok(calls)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok(3));
+
+        let mut calls = 0;
+        let result: Result<(), _> = retry("test", 2, Duration::from_millis(1), || {
+            calls += 1;
+            let calls = calls;
+            async move { Err(calls) }
+        })
+        .await;
+        assert_eq!(result, Err(2));
+    }
+}
@@ -0,0 +1,62 @@
+//! Windows of the VR pipeline itself, like SteamVR's desktop view or Monado's GUI. Mirroring one
+//! of them shows the headset view in the headset, which shows the window again, and so on.
+use std::collections::HashSet;
+
+/// Instance or class parts of `WM_CLASS` of VR runtime windows
+pub const CLASSES: &[&str] = &["vrmonitor", "vrcompositor", "vrwebhelper", "monado"];
+
+/// Processes of VR runtimes, as in `/proc/<pid>/comm`. The xrdesktop client talks to these.
+pub const PROCESSES: &[&str] = &["vrcompositor", "vrserver", "vrmonitor", "monado-service"];
+
+/// Whether either part of `WM_CLASS` is one of `CLASSES` or `extra`, ignoring case
+pub fn is_vr_class(class_instance: &str, class_general: &str, extra: &[String]) -> bool {
+    CLASSES
+        .iter()
+        .copied()
+        .chain(extra.iter().map(String::as_str))
+        .any(|c| c.eq_ignore_ascii_case(class_instance) || c.eq_ignore_ascii_case(class_general))
+}
+
+/// Our own pid and those of running `PROCESSES`. Windows with one of these as `_NET_WM_PID` are
+/// VR windows, whatever their class.
+pub fn pids() -> HashSet<u32> {
+    let mut pids = HashSet::from([std::process::id()]);
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::debug!("Failed to list processes: {e}");
+            return pids;
+        }
+    };
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // Gone in the meantime, or not ours to read
+        if let Ok(comm) = std::fs::read_to_string(entry.path().join("comm")) {
+            if PROCESSES.contains(&comm.trim_end()) {
+                pids.insert(pid);
+            }
+        }
+    }
+    pids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classes() {
+        assert!(is_vr_class("vrmonitor", "", &[]));
+        assert!(is_vr_class("", "Monado", &[]));
+        assert!(!is_vr_class("xterm", "XTerm", &[]));
+        assert!(is_vr_class("alvr", "ALVR", &["alvr".into()]));
+    }
+
+    #[test]
+    fn own_pid() {
+        assert!(pids().contains(&std::process::id()));
+    }
+}
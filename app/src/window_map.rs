@@ -0,0 +1,130 @@
+//! Handles to the windows in `WindowState`. Each window has a lock of its own, and the handle is
+//! shared, so a window can be rendered without holding `WindowState`, while windows are mapped
+//! and unmapped meanwhile. A window taken out of `WindowState` is only freed once nothing holds
+//! its handle anymore.
+use std::{collections::HashMap, hash::Hash, sync::Arc};
+
+use tokio::sync::RwLock;
+
+pub type Handle<T> = Arc<RwLock<T>>;
+
+pub fn handle<T>(value: T) -> Handle<T> {
+    Arc::new(RwLock::new(value))
+}
+
+/// The value of a handle that was removed from the map, once the others holding it let go of
+/// it. Nobody can get hold of it anymore, so this doesn't wait long.
+pub async fn take<T>(mut handle: Handle<T>) -> T {
+    loop {
+        match Arc::try_unwrap(handle) {
+            Ok(lock) => return lock.into_inner(),
+            Err(shared) => {
+                // Whoever holds it is probably rendering, wait for that to finish
+                drop(shared.write().await);
+                tokio::task::yield_now().await;
+                handle = shared;
+            }
+        }
+    }
+}
+
+/// Snapshot of the keys in the map `f` picks from `state`, to go through without holding
+/// `state`. Their handles are fetched one at a time with `get`, as each handle held keeps `take`
+/// waiting.
+pub async fn keys<S, K: Clone, T>(
+    state: &RwLock<S>,
+    f: impl FnOnce(&S) -> &HashMap<K, Handle<T>>,
+) -> Vec<K> {
+    f(&*state.read().await).keys().cloned().collect()
+}
+
+/// The handle of `key` in the map `f` picks from `state`, None if it was removed
+pub async fn get<S, K: Eq + Hash, T>(
+    state: &RwLock<S>,
+    f: impl FnOnce(&S) -> &HashMap<K, Handle<T>>,
+    key: &K,
+) -> Option<Handle<T>> {
+    f(&*state.read().await).get(key).cloned()
+}
+
+/// Remove a handle from `state` with `f`, and `take` its value. `state` is unlocked first, as
+/// whoever holds the handle may be waiting for it.
+pub async fn remove<S, T>(
+    state: &RwLock<S>,
+    f: impl FnOnce(&mut S) -> Option<Handle<T>>,
+) -> Option<T> {
+    let handle = f(&mut *state.write().await)?;
+    Some(take(handle).await)
+}
+
+/// `take`, for when blocking is fine, like in `block_in_place`
+pub fn take_blocking<T>(mut handle: Handle<T>) -> T {
+    loop {
+        match Arc::try_unwrap(handle) {
+            Ok(lock) => return lock.into_inner(),
+            Err(shared) => {
+                drop(shared.blocking_write());
+                std::thread::yield_now();
+                handle = shared;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn taken_once_released() {
+        let handle = handle(1);
+        let held = handle.clone();
+        let guard = held.write().await;
+        let mut taken = tokio::spawn(take(handle));
+        let waited = tokio::time::timeout(Duration::from_millis(10), &mut taken).await;
+        assert!(waited.is_err());
+        drop(guard);
+        drop(held);
+        assert_eq!(taken.await.unwrap(), 1);
+    }
+
+    /// Windows mapped and unmapped while damage floods in, like `App::render_task` and
+    /// `App::unmap_win` do it
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn map_and_unmap_while_rendering() {
+        let windows: Arc<RwLock<HashMap<u32, Handle<u64>>>> = Default::default();
+        let renderer = {
+            let windows = windows.clone();
+            tokio::spawn(async move {
+                for _ in 0..2000 {
+                    for wid in keys(&windows, |map| map).await {
+                        let handle = match get(&windows, |map| map, &wid).await {
+                            Some(handle) => handle,
+                            None => continue,
+                        };
+                        let mut frames = handle.write().await;
+                        // Like a render looking up other windows
+                        drop(windows.read().await);
+                        *frames += 1;
+                    }
+                }
+            })
+        };
+        let mut live = 0;
+        for i in 0..500 {
+            let wid = i % 16;
+            match remove(&windows, |map| map.remove(&wid)).await {
+                Some(_) => live -= 1,
+                None => {
+                    windows.write().await.insert(wid, handle(0));
+                    live += 1;
+                }
+            }
+            tokio::task::yield_now().await;
+        }
+        renderer.await.unwrap();
+        assert_eq!(windows.read().await.len(), live);
+    }
+}
@@ -0,0 +1,228 @@
+//! Window settings made in VR or through D-Bus, like the scale set with `[scale-gesture]` or the
+//! brightness, kept across restarts. Window ids don't survive a restart, so settings are
+//! remembered by window class, and every window of the class gets them.
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{BrightnessGestureConfig, ScaleGestureConfig},
+    gl, Result,
+};
+
+/// What is remembered for a class, None for what wasn't changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gamma: Option<f32>,
+}
+
+impl Settings {
+    pub fn adjustment(&self) -> gl::Adjustment {
+        let default = gl::Adjustment::default();
+        gl::Adjustment {
+            brightness: self.brightness.unwrap_or(default.brightness),
+            gamma: self.gamma.unwrap_or(default.gamma),
+            ..default
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Store {
+    classes: HashMap<String, Settings>,
+}
+
+impl Store {
+    pub fn path() -> PathBuf {
+        glib::user_data_dir()
+            .join("picom-xrdesktop-companion")
+            .join("window-settings.toml")
+    }
+
+    /// Where versions that only remembered the scale kept it
+    fn scales_path() -> PathBuf {
+        glib::user_data_dir()
+            .join("picom-xrdesktop-companion")
+            .join("scales.toml")
+    }
+
+    /// Read the saved settings, none if they can't be read
+    pub fn load() -> Self {
+        let path = Self::path();
+        let classes = match std::fs::read_to_string(&path) {
+            Ok(content) => toml::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!("Failed to parse {}, ignoring it: {e}", path.display());
+                HashMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::migrate_scales(),
+            Err(e) => {
+                tracing::warn!("Failed to read {}: {e}", path.display());
+                HashMap::new()
+            }
+        };
+        Self { classes }
+    }
+
+    /// Settings with the scales saved in `scales.toml`, if there is one. They are written to
+    /// `window-settings.toml`, and the old file is removed once that worked.
+    fn migrate_scales() -> Self {
+        let scales_path = Self::scales_path();
+        let scales: HashMap<String, f32> = match std::fs::read_to_string(&scales_path) {
+            Ok(content) => match toml::from_str(&content) {
+                Ok(scales) => scales,
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to parse {}, ignoring it: {e}",
+                        scales_path.display()
+                    );
+                    return Self::default();
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                tracing::warn!("Failed to read {}: {e}", scales_path.display());
+                return Self::default();
+            }
+        };
+        let store = Self::from_scales(scales);
+        match store.save() {
+            Ok(()) => {
+                tracing::info!(
+                    "Moved the window scales from {} to {}",
+                    scales_path.display(),
+                    Self::path().display()
+                );
+                if let Err(e) = std::fs::remove_file(&scales_path) {
+                    tracing::warn!("Failed to remove {}: {e}", scales_path.display());
+                }
+            }
+            Err(e) => tracing::warn!("Failed to move the window scales: {e:#}"),
+        }
+        store
+    }
+
+    fn from_scales(scales: HashMap<String, f32>) -> Self {
+        let classes = scales
+            .into_iter()
+            .map(|(class, scale)| {
+                let settings = Settings {
+                    scale: Some(scale),
+                    ..Default::default()
+                };
+                (class, settings)
+            })
+            .collect();
+        Self { classes }
+    }
+
+    pub fn get(&self, class_instance: &str, class_general: &str) -> Settings {
+        self.classes
+            .get(class_instance)
+            .or_else(|| self.classes.get(class_general))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Change the settings of the class, by its instance part if it has one, and save all
+    /// classes.
+    pub fn update(
+        &mut self,
+        class_instance: &str,
+        class_general: &str,
+        f: impl FnOnce(&mut Settings),
+    ) -> Result<()> {
+        let class = if class_instance.is_empty() {
+            class_general
+        } else {
+            class_instance
+        };
+        if class.is_empty() {
+            return Ok(());
+        }
+        let mut settings = self.get(class_instance, class_general);
+        f(&mut settings);
+        self.classes.insert(class.into(), settings);
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&path, toml::to_string(&self.classes)?)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// `scale` after turning the stick `detents` wheel detents, positive is down and shrinks
+pub fn adjust_scale(scale: f32, detents: f64, config: &ScaleGestureConfig) -> f32 {
+    (scale * config.step.powf(-detents as f32)).clamp(config.min, config.max)
+}
+
+/// `brightness` after turning the stick `detents` wheel detents, positive is down and dims
+pub fn adjust_brightness(brightness: f32, detents: f64, config: &BrightnessGestureConfig) -> f32 {
+    (brightness - config.step * detents as f32).clamp(config.min, config.max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adjust_clamped() {
+        let config = ScaleGestureConfig::default();
+        assert!(adjust_scale(1.0, -1.0, &config) > 1.0);
+        assert!(adjust_scale(1.0, 1.0, &config) < 1.0);
+        let back = adjust_scale(adjust_scale(1.0, -2.0, &config), 2.0, &config);
+        assert!((back - 1.0).abs() < 1e-5);
+        assert_eq!(adjust_scale(1.0, -1000.0, &config), config.max);
+        assert_eq!(adjust_scale(1.0, 1000.0, &config), config.min);
+        let config = BrightnessGestureConfig::default();
+        assert!(adjust_brightness(1.0, 2.0, &config) < 1.0);
+        assert_eq!(adjust_brightness(0.5, 1000.0, &config), config.min);
+    }
+
+    #[test]
+    fn by_class() {
+        let settings = Settings {
+            scale: Some(4.0),
+            ..Default::default()
+        };
+        let store = Store {
+            classes: [("mpv".to_string(), settings)].into_iter().collect(),
+        };
+        assert_eq!(store.get("mpv", "mpv").scale, Some(4.0));
+        assert_eq!(store.get("", "mpv").scale, Some(4.0));
+        assert_eq!(store.get("xterm", "XTerm"), Settings::default());
+    }
+
+    #[test]
+    fn scales_migrated() {
+        let scales = toml::from_str("mpv = 2.0\nXTerm = 0.5\n").unwrap();
+        let store = Store::from_scales(scales);
+        assert_eq!(store.get("mpv", "mpv").scale, Some(2.0));
+        assert_eq!(store.get("xterm", "XTerm").scale, Some(0.5));
+        assert_eq!(store.get("xterm", "XTerm").brightness, None);
+    }
+
+    #[test]
+    fn only_changes_saved() {
+        let settings = Settings {
+            brightness: Some(0.5),
+            ..Default::default()
+        };
+        let saved = toml::to_string(&settings).unwrap();
+        assert_eq!(saved.trim(), "brightness = 0.5");
+        assert_eq!(toml::from_str::<Settings>(&saved).unwrap(), settings);
+    }
+}
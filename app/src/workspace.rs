@@ -0,0 +1,41 @@
+//! Showing only the windows of the current virtual desktop, see `current-desktop-only`. Window
+//! managers tell which desktop a window is on in its `_NET_WM_DESKTOP`, and the current one in
+//! `_NET_CURRENT_DESKTOP` on the root window.
+
+/// `_NET_WM_DESKTOP` of windows shown on all desktops
+pub const ALL_DESKTOPS: u32 = 0xFFFF_FFFF;
+
+/// Whether a window on `desktop` is shown while `current` is the current desktop. Windows the
+/// window manager didn't put on a desktop are shown, and so is everything if it doesn't tell
+/// which desktop is current.
+pub fn is_shown(desktop: Option<u32>, current: Option<u32>) -> bool {
+    match (desktop, current) {
+        (Some(ALL_DESKTOPS), _) | (None, _) | (_, None) => true,
+        (Some(desktop), Some(current)) => desktop == current,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_desktop() {
+        assert!(is_shown(Some(2), Some(2)));
+        assert!(!is_shown(Some(1), Some(2)));
+        assert!(!is_shown(Some(0), Some(5)));
+    }
+
+    #[test]
+    fn sticky() {
+        for current in [Some(0), Some(3), None] {
+            assert!(is_shown(Some(ALL_DESKTOPS), current));
+        }
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(is_shown(None, Some(1)));
+        assert!(is_shown(Some(1), None));
+    }
+}